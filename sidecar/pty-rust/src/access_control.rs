@@ -0,0 +1,234 @@
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+pub const TOKEN_ENV: &str = "DISCODE_PTY_SIDECAR_TOKEN";
+const SOCKET_DIR_MODE: u32 = 0o700;
+const SOCKET_MODE: u32 = 0o600;
+
+/// Who may talk to the sidecar: peers must run as one of `allowed_uids`
+/// and, when a token is configured, present it in `hello` before any other
+/// request on the connection.
+#[derive(Clone)]
+pub struct AccessPolicy {
+    pub allowed_uids: Vec<u32>,
+    pub token: Option<String>,
+}
+
+impl AccessPolicy {
+    pub fn same_user() -> Self {
+        Self {
+            allowed_uids: vec![current_uid()],
+            token: None,
+        }
+    }
+
+    /// Lets peers running as one of `uids` connect, besides the sidecar's own
+    /// uid, which is always allowed.
+    pub fn allow_uids(&mut self, uids: &[u32]) {
+        let mut allowed = vec![current_uid()];
+        for uid in uids {
+            if !allowed.contains(uid) {
+                allowed.push(*uid);
+            }
+        }
+        self.allowed_uids = allowed;
+    }
+
+    pub fn check_peer(&self, stream: &UnixStream) -> Result<u32, String> {
+        let uid = peer_uid(stream).map_err(|e| format!("peer credentials unavailable: {e}"))?;
+        if !self.allowed_uids.contains(&uid) {
+            return Err(format!("peer uid {uid} is not allowed"));
+        }
+        Ok(uid)
+    }
+
+    pub fn requires_token(&self) -> bool {
+        self.token.is_some()
+    }
+
+    pub fn token_matches(&self, provided: Option<&str>) -> bool {
+        match (&self.token, provided) {
+            (None, _) => true,
+            (Some(expected), Some(provided)) => {
+                constant_time_eq(expected.as_bytes(), provided.as_bytes())
+            }
+            (Some(_), None) => false,
+        }
+    }
+}
+
+pub fn current_uid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+pub fn read_token_file(path: &Path) -> Result<String, String> {
+    let raw =
+        fs::read_to_string(path).map_err(|e| format!("read token {}: {e}", path.display()))?;
+    let token = raw.trim().to_string();
+    if token.is_empty() {
+        return Err(format!("token file {} is empty", path.display()));
+    }
+    Ok(token)
+}
+
+/// Creates a missing parent directory of `socket_path` with mode 0700; an
+/// existing one is left untouched.
+pub fn ensure_socket_parent(socket_path: &Path) -> Result<(), String> {
+    if let Some(parent) = socket_path.parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(SOCKET_DIR_MODE)
+                .create(parent)
+                .map_err(|e| format!("create socket parent {}: {e}", parent.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Binds `socket_path` so that only the owner can connect.
+pub fn bind_restricted(socket_path: &Path) -> Result<UnixListener, String> {
+    ensure_socket_parent(socket_path)?;
+
+    let previous_umask = unsafe { libc::umask(0o177) };
+    let bound = UnixListener::bind(socket_path);
+    unsafe {
+        libc::umask(previous_umask);
+    }
+    let listener = bound.map_err(|e| format!("bind {}: {e}", socket_path.display()))?;
+
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(SOCKET_MODE))
+        .map_err(|e| format!("chmod {}: {e}", socket_path.display()))?;
+    Ok(listener)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    let result = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Parses a comma-separated list of uids, as given to `--allow-uid`.
+pub fn parse_uid_list(raw: &str) -> Result<Vec<u32>, String> {
+    raw.split(',')
+        .map(|item| {
+            item.trim()
+                .parse::<u32>()
+                .map_err(|_| format!("invalid uid {:?} in --allow-uid {raw}", item.trim()))
+        })
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn checks_peer_uid_against_allowed_list() {
+        let (left, _right) = UnixStream::pair().expect("socket pair");
+        assert_eq!(peer_uid(&left).expect("peer uid"), current_uid());
+
+        assert!(AccessPolicy::same_user().check_peer(&left).is_ok());
+        let stranger = AccessPolicy {
+            allowed_uids: vec![current_uid().wrapping_add(1)],
+            token: None,
+        };
+        assert!(stranger.check_peer(&left).is_err());
+
+        let mut shared = stranger;
+        shared.allow_uids(&[current_uid().wrapping_add(1)]);
+        assert!(shared.check_peer(&left).is_ok());
+        assert_eq!(shared.allowed_uids.len(), 2);
+    }
+
+    #[test]
+    fn rejects_uid_lists_with_anything_but_uids() {
+        assert_eq!(parse_uid_list("1000, 1001"), Ok(vec![1000, 1001]));
+        for raw in ["alice", "1000,", "1000,,1001", "-1", ""] {
+            assert!(parse_uid_list(raw).is_err(), "{raw:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn matches_tokens_only_when_identical() {
+        let open = AccessPolicy::same_user();
+        assert!(open.token_matches(None));
+
+        let guarded = AccessPolicy {
+            token: Some("s3cret".to_string()),
+            ..AccessPolicy::same_user()
+        };
+        assert!(guarded.token_matches(Some("s3cret")));
+        assert!(!guarded.token_matches(Some("s3cre")));
+        assert!(!guarded.token_matches(Some("s3creT")));
+        assert!(!guarded.token_matches(None));
+    }
+
+    #[test]
+    fn binds_socket_owner_only_inside_private_directory() {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let dir = std::env::temp_dir().join(format!(
+            "discode-pty-sidecar-acl-{}-{stamp}",
+            std::process::id()
+        ));
+        let socket_path = dir.join("nested").join("sidecar.sock");
+
+        let listener = bind_restricted(&socket_path).expect("bind should succeed");
+        let dir_mode = fs::metadata(socket_path.parent().unwrap())
+            .expect("socket dir metadata")
+            .permissions()
+            .mode();
+        let socket_mode = fs::metadata(&socket_path)
+            .expect("socket metadata")
+            .permissions()
+            .mode();
+        drop(listener);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(dir_mode & 0o777, 0o700);
+        assert_eq!(socket_mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Process groups the watchdog can remember; extra registrations are ignored.
+const MAX_TRACKED_GROUPS: usize = 4096;
+// How long the watchdog waits after SIGHUP before sending SIGKILL.
+const HANGUP_GRACE: Duration = Duration::from_millis(500);
+// Record that tells the watchdog to forget every tracked group.
+const RELEASE_ALL: i32 = 0;
+
+/// Handle to a forked watchdog process that outlives the sidecar just long
+/// enough to kill the process groups of its windows. The watchdog holds the
+/// read end of a pipe; when the sidecar dies for any reason (including
+/// SIGKILL or a panic) the write end closes, and the watchdog hangs up and
+/// then kills every group still registered.
+#[derive(Clone, Debug)]
+pub struct ChildWatchdog {
+    pid: u32,
+    writer: Arc<Mutex<File>>,
+}
+
+impl ChildWatchdog {
+    pub fn spawn() -> Result<Self, String> {
+        let (read_fd, write_fd) = cloexec_pipe()?;
+        let max_fd = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) }.clamp(256, 65_536) as RawFd;
+
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            let err = std::io::Error::last_os_error();
+            unsafe {
+                libc::close(read_fd);
+                libc::close(write_fd);
+            }
+            return Err(format!("fork watchdog failed: {err}"));
+        }
+        if pid == 0 {
+            // Only async-signal-safe calls from here on: the parent may be
+            // multi-threaded and other threads' locks are frozen in this copy.
+            unsafe { run_watchdog(read_fd, max_fd) }
+        }
+
+        unsafe {
+            libc::close(read_fd);
+        }
+        Ok(Self {
+            pid: pid as u32,
+            writer: Arc::new(Mutex::new(unsafe { File::from_raw_fd(write_fd) })),
+        })
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn track(&self, pgid: u32) {
+        self.send(pgid as i32);
+    }
+
+    pub fn untrack(&self, pgid: u32) {
+        self.send(-(pgid as i32));
+    }
+
+    /// Forgets every tracked group, e.g. after handing the windows over to a
+    /// successor sidecar that tracks them with its own watchdog.
+    pub fn release_all(&self) {
+        self.send(RELEASE_ALL);
+    }
+
+    fn send(&self, record: i32) {
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Four-byte writes are atomic on a pipe, so records never interleave.
+        let _ = writer.write_all(&record.to_le_bytes());
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn cloexec_pipe() -> Result<(RawFd, RawFd), String> {
+    let mut fds = [0 as RawFd; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(format!(
+            "watchdog pipe failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok((fds[0], fds[1]))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn cloexec_pipe() -> Result<(RawFd, RawFd), String> {
+    let mut fds = [0 as RawFd; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(format!(
+            "watchdog pipe failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    for fd in fds {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    Ok((fds[0], fds[1]))
+}
+
+/// Body of the forked watchdog. Never returns.
+unsafe fn run_watchdog(read_fd: RawFd, max_fd: RawFd) -> ! {
+    // Survive terminal and group-wide signals aimed at the sidecar; the pipe
+    // closing is the only shutdown trigger.
+    for signo in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGPIPE] {
+        libc::signal(signo, libc::SIG_IGN);
+    }
+    libc::setsid();
+    // Drop inherited fds (listener, PTY masters, the pipe's write end) so
+    // they close when the sidecar dies.
+    for fd in 0..max_fd {
+        if fd != read_fd {
+            libc::close(fd);
+        }
+    }
+
+    let mut groups = [0 as libc::pid_t; MAX_TRACKED_GROUPS];
+    let mut count = 0usize;
+    let mut record = [0u8; 4];
+    let mut filled = 0usize;
+    loop {
+        let read = libc::read(
+            read_fd,
+            record.as_mut_ptr().add(filled).cast(),
+            record.len() - filled,
+        );
+        if read <= 0 {
+            if read < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted
+            {
+                continue;
+            }
+            break;
+        }
+        filled += read as usize;
+        if filled < record.len() {
+            continue;
+        }
+        filled = 0;
+
+        let value = i32::from_le_bytes(record);
+        if value == RELEASE_ALL {
+            count = 0;
+        } else if value > 0 {
+            if count < MAX_TRACKED_GROUPS {
+                groups[count] = value;
+                count += 1;
+            }
+        } else if let Some(idx) = groups[..count].iter().position(|pgid| *pgid == -value) {
+            count -= 1;
+            groups[idx] = groups[count];
+        }
+    }
+
+    for pgid in &groups[..count] {
+        libc::kill(-pgid, libc::SIGHUP);
+        libc::kill(-pgid, libc::SIGCONT);
+    }
+    if count > 0 {
+        let pause = libc::timespec {
+            tv_sec: HANGUP_GRACE.as_secs() as libc::time_t,
+            tv_nsec: HANGUP_GRACE.subsec_nanos() as libc::c_long,
+        };
+        libc::nanosleep(&pause, std::ptr::null_mut());
+    }
+    for pgid in &groups[..count] {
+        libc::kill(-pgid, libc::SIGKILL);
+    }
+    libc::_exit(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    use std::thread;
+
+    fn group_alive(pgid: u32) -> bool {
+        unsafe { libc::kill(-(pgid as libc::pid_t), 0) == 0 }
+    }
+
+    #[test]
+    fn kills_tracked_groups_when_sidecar_side_closes() {
+        let watchdog = ChildWatchdog::spawn().expect("watchdog should fork");
+        let spawn_group = |script: &str| {
+            Command::new("/bin/sh")
+                .args(["-c", script])
+                .process_group(0)
+                .spawn()
+                .expect("spawn test group")
+        };
+        let mut tracked = spawn_group("sleep 30");
+        let mut stubborn = spawn_group("trap '' HUP; sleep 30; true");
+        let mut released = spawn_group("sleep 30");
+        watchdog.track(tracked.id());
+        watchdog.track(stubborn.id());
+        watchdog.track(released.id());
+        watchdog.untrack(released.id());
+
+        let watchdog_pid = watchdog.pid();
+        drop(watchdog);
+
+        let mut tracked_gone = false;
+        for _ in 0..100 {
+            let tracked_exited = matches!(tracked.try_wait(), Ok(Some(_)));
+            let stubborn_exited = matches!(stubborn.try_wait(), Ok(Some(_)));
+            if tracked_exited && stubborn_exited {
+                tracked_gone = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let untracked_alive = group_alive(released.id());
+        unsafe {
+            libc::kill(-(tracked.id() as libc::pid_t), libc::SIGKILL);
+            libc::kill(-(stubborn.id() as libc::pid_t), libc::SIGKILL);
+            libc::kill(-(released.id() as libc::pid_t), libc::SIGKILL);
+            libc::waitpid(watchdog_pid as libc::pid_t, std::ptr::null_mut(), 0);
+        }
+        let _ = released.wait();
+
+        assert!(tracked_gone, "tracked process groups should be killed");
+        assert!(untracked_alive, "untracked group must be left alone");
+    }
+}
@@ -0,0 +1,178 @@
+use crate::instance_lock::{is_locked, lock_file_path, probe_socket, read_pid_file, SocketProbe};
+use crate::rpc::RpcRequest;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+fn hello_line(token: &str) -> Vec<u8> {
+    let mut line = json!({ "method": "hello", "params": { "token": token } })
+        .to_string()
+        .into_bytes();
+    line.push(b'\n');
+    line
+}
+
+pub fn send_request(
+    socket_path: &Path,
+    req: &RpcRequest,
+    token: Option<&str>,
+) -> Result<String, String> {
+    let mut stream = UnixStream::connect(socket_path)
+        .map_err(|e| format!("connect {}: {e}", socket_path.display()))?;
+
+    if let Some(token) = token {
+        stream
+            .write_all(&hello_line(token))
+            .map_err(|e| format!("write hello: {e}"))?;
+        // Wait for the hello verdict so a rejection is reported instead
+        // of being lost when the sidecar closes the connection.
+        let greeting = read_line_unbuffered(&mut stream)?;
+        let accepted = serde_json::from_str::<Value>(&greeting)
+            .ok()
+            .and_then(|value| value["ok"].as_bool())
+            .unwrap_or(false);
+        if !accepted {
+            return Ok(greeting);
+        }
+    }
+    let payload = serde_json::to_vec(req).map_err(|e| format!("encode request: {e}"))?;
+    stream
+        .write_all(&payload)
+        .map_err(|e| format!("write request: {e}"))?;
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+
+    let mut out = String::new();
+    stream
+        .read_to_string(&mut out)
+        .map_err(|e| format!("read response: {e}"))?;
+    Ok(out)
+}
+
+fn read_line_unbuffered(stream: &mut UnixStream) -> Result<String, String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        let read = stream
+            .read(&mut byte)
+            .map_err(|e| format!("read response: {e}"))?;
+        if read == 0 || byte[0] == b'\n' {
+            break;
+        }
+        line.push(byte[0]);
+    }
+    String::from_utf8(line).map_err(|e| format!("invalid response: {e}"))
+}
+
+pub fn instance_status(socket_path: &Path, token: Option<&str>) -> Value {
+    let pid = read_pid_file(socket_path);
+    let lock_held = is_locked(socket_path);
+    let socket_exists = socket_path.exists();
+    let (responding, hello) = if socket_exists {
+        match probe_socket(socket_path, token) {
+            SocketProbe::Answered(response) => (true, Some(response)),
+            _ => (false, None),
+        }
+    } else {
+        (false, None)
+    };
+
+    let authorized = hello
+        .as_ref()
+        .and_then(|response| response["ok"].as_bool())
+        .unwrap_or(false);
+    let health = if authorized {
+        let req = RpcRequest {
+            id: None,
+            method: "health".to_string(),
+            params: json!({}),
+            timeout_ms: None,
+        };
+        send_request(socket_path, &req, token)
+            .ok()
+            .and_then(|raw| serde_json::from_str::<Value>(raw.trim()).ok())
+            .map(|response| response["result"].clone())
+    } else {
+        None
+    };
+
+    json!({
+        "running": lock_held || responding,
+        "pid": if lock_held { pid } else { None },
+        "socket": socket_path.display().to_string(),
+        "socketExists": socket_exists,
+        "lockFile": lock_file_path(socket_path).display().to_string(),
+        "lockHeld": lock_held,
+        "responding": responding,
+        "authorized": authorized,
+        "health": health,
+    })
+}
+
+pub fn run_client(socket_path: PathBuf, token: Option<&str>) -> Result<(), String> {
+    let mut stream = UnixStream::connect(&socket_path)
+        .map_err(|e| format!("connect {}: {e}", socket_path.display()))?;
+    let _ = stream.set_read_timeout(Some(Duration::from_millis(5000)));
+
+    let reader_stream = stream
+        .try_clone()
+        .map_err(|e| format!("clone stream for client read failed: {e}"))?;
+    let mut socket_reader = BufReader::new(reader_stream);
+
+    if let Some(token) = token {
+        stream
+            .write_all(&hello_line(token))
+            .map_err(|e| format!("write hello failed: {e}"))?;
+        let mut greeting = String::new();
+        socket_reader
+            .read_line(&mut greeting)
+            .map_err(|e| format!("read hello failed: {e}"))?;
+        let accepted = serde_json::from_str::<Value>(greeting.trim())
+            .ok()
+            .and_then(|value| value["ok"].as_bool())
+            .unwrap_or(false);
+        if !accepted {
+            return Err(format!("sidecar rejected hello: {}", greeting.trim()));
+        }
+    }
+
+    let stdin = std::io::stdin();
+    let mut stdin_reader = BufReader::new(stdin.lock());
+    let mut stdout = std::io::stdout();
+
+    loop {
+        let mut inbound = String::new();
+        let read = stdin_reader
+            .read_line(&mut inbound)
+            .map_err(|e| format!("read stdin failed: {e}"))?;
+        if read == 0 {
+            break;
+        }
+
+        if inbound.trim().is_empty() {
+            continue;
+        }
+
+        stream
+            .write_all(inbound.as_bytes())
+            .map_err(|e| format!("write to sidecar failed: {e}"))?;
+
+        let mut outbound = String::new();
+        let received = socket_reader
+            .read_line(&mut outbound)
+            .map_err(|e| format!("read from sidecar failed: {e}"))?;
+        if received == 0 {
+            return Err("sidecar closed connection".to_string());
+        }
+
+        stdout
+            .write_all(outbound.as_bytes())
+            .map_err(|e| format!("write stdout failed: {e}"))?;
+        stdout
+            .flush()
+            .map_err(|e| format!("flush stdout failed: {e}"))?;
+    }
+
+    Ok(())
+}
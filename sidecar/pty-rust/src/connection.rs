@@ -0,0 +1,477 @@
+use crate::access_control::AccessPolicy;
+use crate::handoff::HANDOFF_METHOD;
+use crate::jsonrpc::{self, is_jsonrpc_message};
+use crate::pipeline::{is_pipelined, Pipeline, ReplyTo};
+use crate::protocol::{Negotiation, CANCEL_METHOD};
+use crate::request_control::RequestControl;
+use crate::rpc::{
+    dispatch_request, invalid_request, request_cancelled, request_timeout, unauthorized, RpcError,
+    RpcRequest, RpcResponse, RpcResult, ERROR_INTERNAL, ERROR_INVALID_PARAMS, ERROR_PARSE,
+};
+use crate::session_manager::{
+    record_rejected_connection, record_rpc_observation, SharedSidecarState,
+};
+use crate::transport::{
+    MessageChannel, WireEncoding, MAX_MESSAGE_BYTES, UNAUTHENTICATED_MESSAGE_BYTES,
+};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+pub fn write_response<C: MessageChannel + ?Sized>(
+    channel: &mut C,
+    response: &RpcResponse,
+) -> Result<(), String> {
+    channel.send(response)
+}
+
+pub fn internal_error_response(err: String) -> RpcResponse {
+    RpcResponse {
+        ok: false,
+        id: None,
+        result: None,
+        error: Some(RpcError::new(ERROR_INTERNAL, err)),
+    }
+}
+
+pub fn reject_connection<C: MessageChannel + ?Sized>(
+    channel: &mut C,
+    state: &SharedSidecarState,
+    request_id: Option<u64>,
+    reason: String,
+) {
+    record_rejected_connection(state);
+    let _ = write_response(
+        channel,
+        &RpcResponse {
+            ok: false,
+            id: request_id,
+            result: None,
+            error: Some(unauthorized(reason)),
+        },
+    );
+}
+
+/// Per-connection authentication and request execution, shared by the
+/// `{ok, id}` envelope and JSON-RPC 2.0.
+struct Session<'a> {
+    state: &'a SharedSidecarState,
+    policy: &'a AccessPolicy,
+    authenticated: bool,
+    negotiation: Negotiation,
+    /// Encoding accepted by `hello`, applied once its reply is written.
+    pending_encoding: Option<WireEncoding>,
+}
+
+impl<'a> Session<'a> {
+    fn new(state: &'a SharedSidecarState, policy: &'a AccessPolicy) -> Self {
+        Self {
+            state,
+            policy,
+            authenticated: !policy.requires_token(),
+            negotiation: Negotiation::default(),
+            pending_encoding: None,
+        }
+    }
+
+    /// Messages are kept small until the client has authenticated.
+    fn message_limit(&self) -> usize {
+        if self.authenticated {
+            MAX_MESSAGE_BYTES
+        } else {
+            UNAUTHENTICATED_MESSAGE_BYTES
+        }
+    }
+
+    /// Until a `hello` with a valid token arrives, every request is
+    /// rejected (and counted) with the reason returned here.
+    fn authorize(&mut self, req: &RpcRequest) -> Result<(), RpcError> {
+        if self.authenticated {
+            return Ok(());
+        }
+        let presented = req.params.get("token").and_then(Value::as_str);
+        if req.method == "hello" && self.policy.token_matches(presented) {
+            self.authenticated = true;
+            return Ok(());
+        }
+        record_rejected_connection(self.state);
+        Err(unauthorized(if req.method == "hello" {
+            "invalid token"
+        } else {
+            "send hello with a valid token first"
+        }))
+    }
+
+    /// Runs `req` on the connection thread. Returns the outcome and
+    /// whether the server should shut down.
+    fn execute(
+        &mut self,
+        req: RpcRequest,
+        pipeline: &Pipeline<'_, '_>,
+    ) -> (Result<RpcResult, RpcError>, bool) {
+        let control = RequestControl::with_timeout(req.timeout_ms);
+        let method_name = req.method.clone();
+        let mut should_shutdown = false;
+        let outcome = match req.method.as_str() {
+            "hello" => self.hello(req),
+            CANCEL_METHOD => self
+                .negotiation
+                .check_request(&req.method, &req.params)
+                .and_then(|()| cancel_request(pipeline, &req.params)),
+            _ => self
+                .negotiation
+                .check_request(&req.method, &req.params)
+                .and_then(|()| dispatch_request(self.state, req, &control, &mut should_shutdown)),
+        };
+        let outcome = finish_request(self.state, &method_name, &control, outcome);
+        (outcome, should_shutdown)
+    }
+
+    /// Hands `req` to the pipeline; its reply is written when it finishes.
+    fn submit(
+        &self,
+        req: RpcRequest,
+        reply_to: ReplyTo,
+        pipeline: &mut Pipeline<'_, '_>,
+    ) -> Result<(), RpcError> {
+        self.negotiation.check_request(&req.method, &req.params)?;
+        pipeline.submit(req, reply_to)
+    }
+
+    /// `hello` negotiates the protocol version and features for the rest
+    /// of the connection, and may ask for a binary `encoding`. Nothing
+    /// changes if any of it is rejected.
+    fn hello(&mut self, req: RpcRequest) -> Result<RpcResult, RpcError> {
+        let negotiation = Negotiation::from_hello(&req.params)?;
+        let encoding = match req.params.get("encoding") {
+            None => None,
+            Some(raw) => Some(raw.as_str().and_then(WireEncoding::parse).ok_or_else(|| {
+                RpcError::new(
+                    ERROR_INVALID_PARAMS,
+                    format!("unsupported encoding: {raw}; expected json or msgpack"),
+                )
+            })?),
+        };
+        if let Some(encoding) = encoding {
+            negotiation.check_encoding(encoding)?;
+        }
+        let mut result = negotiation.hello_result();
+        self.negotiation = negotiation;
+        if let Some(encoding) = encoding {
+            result["encoding"] = json!(encoding.name());
+            self.pending_encoding = Some(encoding);
+        }
+        Ok(result.into())
+    }
+}
+
+/// Reports an interrupted request as cancelled or timed out, whatever
+/// the interrupted work returned, and records latency and outcome.
+fn finish_request(
+    state: &SharedSidecarState,
+    method: &str,
+    control: &RequestControl,
+    outcome: Result<RpcResult, RpcError>,
+) -> Result<RpcResult, RpcError> {
+    let outcome = if control.is_cancelled() {
+        Err(request_cancelled(method))
+    } else if control.is_expired() {
+        Err(request_timeout(
+            method,
+            control.timeout_ms().unwrap_or_default(),
+            control.elapsed().as_millis(),
+        ))
+    } else {
+        outcome
+    };
+    let latency_ms = control.elapsed().as_millis().min(u128::from(u64::MAX)) as u64;
+    record_rpc_observation(
+        state,
+        method,
+        latency_ms,
+        outcome.as_ref().err().map(|error| error.code.as_str()),
+    );
+    outcome
+}
+
+fn cancel_request(pipeline: &Pipeline<'_, '_>, params: &Value) -> Result<RpcResult, RpcError> {
+    let id = params
+        .get("id")
+        .filter(|id| !id.is_null())
+        .ok_or_else(|| RpcError::new(ERROR_INVALID_PARAMS, "missing or invalid 'id'"))?;
+    let cancelled = pipeline
+        .cancel(id)
+        .map_err(|err| RpcError::new(ERROR_INTERNAL, err))?;
+    Ok(json!({ "cancelled": cancelled }).into())
+}
+
+/// What the connection loop does after a message has been answered.
+#[derive(PartialEq, Eq)]
+enum Flow {
+    Continue,
+    /// Authentication failed; close the connection.
+    Close,
+    /// `dispose` or a completed handoff; stop the server.
+    Stop,
+}
+
+/// Answers requests from `channel` until the peer disconnects or asks the
+/// server to stop. The first message fixes the protocol: JSON-RPC 2.0 if
+/// it is a batch or has a `jsonrpc` member, the `{ok, id}` envelope
+/// otherwise. Requests with an id are pipelined and may be answered out
+/// of order; the rest are answered in order on this thread. `hand_off`
+/// handles `HANDOFF_METHOD` (envelope only) and returns whether the
+/// server has been handed over.
+pub fn serve_channel<C, F>(
+    channel: &mut C,
+    state: &SharedSidecarState,
+    running: &AtomicBool,
+    policy: &AccessPolicy,
+    hand_off: F,
+) -> Result<(), String>
+where
+    C: MessageChannel,
+    F: FnMut(&mut C, Option<u64>) -> Result<bool, String>,
+{
+    let runner = |req: RpcRequest, control: &RequestControl| {
+        let method = req.method.clone();
+        let outcome = dispatch_request(state, req, control, &mut false);
+        finish_request(state, &method, control, outcome)
+    };
+    thread::scope(|scope| {
+        let mut pipeline = Pipeline::new(scope, channel.writer().clone(), &runner);
+        serve_messages(channel, state, running, policy, &mut pipeline, hand_off)
+    })
+}
+
+fn serve_messages<C, F>(
+    channel: &mut C,
+    state: &SharedSidecarState,
+    running: &AtomicBool,
+    policy: &AccessPolicy,
+    pipeline: &mut Pipeline<'_, '_>,
+    mut hand_off: F,
+) -> Result<(), String>
+where
+    C: MessageChannel,
+    F: FnMut(&mut C, Option<u64>) -> Result<bool, String>,
+{
+    let mut session = Session::new(state, policy);
+    let mut jsonrpc = None;
+
+    loop {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+
+        channel.set_message_limit(session.message_limit());
+        let Some(parsed) = channel.read_message()? else {
+            break;
+        };
+
+        if jsonrpc.is_none() {
+            if let Ok(message) = &parsed {
+                jsonrpc = Some(is_jsonrpc_message(message));
+            }
+        }
+        let flow = if jsonrpc == Some(true) {
+            serve_jsonrpc_message(channel, &mut session, pipeline, parsed)?
+        } else {
+            serve_envelope_message(channel, &mut session, pipeline, parsed, &mut hand_off)?
+        };
+        match flow {
+            Flow::Continue => {
+                if let Some(encoding) = session.pending_encoding.take() {
+                    channel.set_encoding(encoding);
+                }
+            }
+            Flow::Close => break,
+            Flow::Stop => {
+                running.store(false, Ordering::SeqCst);
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn serve_envelope_message<C, F>(
+    channel: &mut C,
+    session: &mut Session<'_>,
+    pipeline: &mut Pipeline<'_, '_>,
+    parsed: Result<Value, String>,
+    hand_off: &mut F,
+) -> Result<Flow, String>
+where
+    C: MessageChannel,
+    F: FnMut(&mut C, Option<u64>) -> Result<bool, String>,
+{
+    let req = match parsed.and_then(|message| {
+        serde_json::from_value::<RpcRequest>(message).map_err(|e| e.to_string())
+    }) {
+        Ok(req) => req,
+        Err(err) => {
+            let response = RpcResponse {
+                ok: false,
+                id: None,
+                result: None,
+                error: Some(invalid_request(format!("invalid request JSON: {err}"))),
+            };
+            write_response(channel, &response)?;
+            return Ok(Flow::Continue);
+        }
+    };
+
+    if let Err(error) = session.authorize(&req) {
+        let response = RpcResponse {
+            ok: false,
+            id: req.id,
+            result: None,
+            error: Some(error),
+        };
+        let _ = write_response(channel, &response);
+        return Ok(Flow::Close);
+    }
+
+    if req.method == HANDOFF_METHOD {
+        if let Err(error) = session.negotiation.check_request(&req.method, &req.params) {
+            let response = RpcResponse {
+                ok: false,
+                id: req.id,
+                result: None,
+                error: Some(error),
+            };
+            write_response(channel, &response)?;
+            return Ok(Flow::Continue);
+        }
+        return Ok(if hand_off(channel, req.id)? {
+            Flow::Stop
+        } else {
+            Flow::Continue
+        });
+    }
+
+    if let (Some(id), true) = (req.id, is_pipelined(&req.method)) {
+        if let Err(error) = session.submit(req, ReplyTo::Envelope(id), pipeline) {
+            write_response(channel, &RpcResponse::from_outcome(Some(id), Err(error)))?;
+        }
+        return Ok(Flow::Continue);
+    }
+
+    let request_id = req.id;
+    let (outcome, should_shutdown) = session.execute(req, pipeline);
+    write_response(channel, &RpcResponse::from_outcome(request_id, outcome))?;
+    Ok(if should_shutdown {
+        Flow::Stop
+    } else {
+        Flow::Continue
+    })
+}
+
+fn serve_jsonrpc_message<C: MessageChannel>(
+    channel: &mut C,
+    session: &mut Session<'_>,
+    pipeline: &mut Pipeline<'_, '_>,
+    parsed: Result<Value, String>,
+) -> Result<Flow, String> {
+    let message = match parsed {
+        Ok(message) => message,
+        Err(err) => {
+            let error = RpcError::new(ERROR_PARSE, format!("invalid message: {err}"));
+            write_jsonrpc(channel, &jsonrpc::failure(Value::Null, &error))?;
+            return Ok(Flow::Continue);
+        }
+    };
+
+    let Value::Array(calls) = message else {
+        let (reply, flow) = answer_jsonrpc_call(session, pipeline, message, true);
+        if let Some(reply) = reply {
+            write_jsonrpc(channel, &reply)?;
+        }
+        return Ok(flow);
+    };
+    if calls.is_empty() {
+        let error = invalid_request("batch must not be empty");
+        write_jsonrpc(channel, &jsonrpc::failure(Value::Null, &error))?;
+        return Ok(Flow::Continue);
+    }
+
+    let mut replies = Vec::with_capacity(calls.len());
+    let mut flow = Flow::Continue;
+    for call in calls {
+        let (reply, call_flow) = answer_jsonrpc_call(session, pipeline, call, false);
+        replies.extend(reply);
+        match call_flow {
+            // Later calls in the batch cannot be authorized either.
+            Flow::Close => {
+                flow = Flow::Close;
+                break;
+            }
+            // Finish the batch, then stop.
+            Flow::Stop => flow = Flow::Stop,
+            Flow::Continue => {}
+        }
+    }
+    // A batch of only notifications gets no response at all.
+    if !replies.is_empty() {
+        write_jsonrpc(channel, &Value::Array(replies))?;
+    }
+    Ok(flow)
+}
+
+/// Answers one call, or hands it to the pipeline when `pipelined` and it
+/// has an id. Calls in a batch are answered in order, in one reply.
+fn answer_jsonrpc_call(
+    session: &mut Session<'_>,
+    pipeline: &mut Pipeline<'_, '_>,
+    message: Value,
+    pipelined: bool,
+) -> (Option<Value>, Flow) {
+    let call = match jsonrpc::parse_call(message) {
+        Ok(call) => call,
+        Err(rejection) => {
+            return (
+                Some(jsonrpc::failure(rejection.id, &rejection.error)),
+                Flow::Continue,
+            );
+        }
+    };
+    let id = call.id;
+    let reply = |outcome: Result<RpcResult, RpcError>| {
+        id.clone().map(|id| match outcome {
+            Ok(result) => jsonrpc::success(id, result.into_value()),
+            Err(error) => jsonrpc::failure(id, &error),
+        })
+    };
+
+    if let Err(error) = session.authorize(&call.request) {
+        // Answered even for notifications, since the connection closes.
+        let id = id.clone().unwrap_or(Value::Null);
+        return (Some(jsonrpc::failure(id, &error)), Flow::Close);
+    }
+    if call.request.method == HANDOFF_METHOD {
+        let error = invalid_request("handoff is not available over JSON-RPC");
+        return (reply(Err(error)), Flow::Continue);
+    }
+
+    if let (Some(id), true) = (&id, pipelined && is_pipelined(&call.request.method)) {
+        let reply_to = ReplyTo::JsonRpc(id.clone());
+        return match session.submit(call.request, reply_to, pipeline) {
+            Ok(()) => (None, Flow::Continue),
+            Err(error) => (reply(Err(error)), Flow::Continue),
+        };
+    }
+
+    let (outcome, should_shutdown) = session.execute(call.request, pipeline);
+    let flow = if should_shutdown {
+        Flow::Stop
+    } else {
+        Flow::Continue
+    };
+    (reply(outcome), flow)
+}
+
+fn write_jsonrpc<C: MessageChannel + ?Sized>(channel: &mut C, reply: &Value) -> Result<(), String> {
+    channel.send(reply)
+}
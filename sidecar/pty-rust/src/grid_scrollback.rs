@@ -1,31 +1,301 @@
-use serde_json::{Map, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-#[derive(Clone, Default, PartialEq, Eq)]
+/// Foreground or background colour of a cell.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Color {
+    #[default]
+    Default,
+    /// Entry of the xterm 256-colour palette.
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+const ANSI_16_PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0xcd, 0x31, 0x31),
+    (0x0d, 0xbc, 0x79),
+    (0xe5, 0xe5, 0x10),
+    (0x24, 0x72, 0xc8),
+    (0xbc, 0x3f, 0xbc),
+    (0x11, 0xa8, 0xcd),
+    (0xe5, 0xe5, 0xe5),
+    (0x66, 0x66, 0x66),
+    (0xf1, 0x4c, 0x4c),
+    (0x23, 0xd1, 0x8b),
+    (0xf5, 0xf5, 0x43),
+    (0x3b, 0x8e, 0xea),
+    (0xd6, 0x70, 0xd6),
+    (0x29, 0xb8, 0xdb),
+    (0xff, 0xff, 0xff),
+];
+
+impl Color {
+    /// The colour as red, green and blue, or `None` for the default colour.
+    pub fn rgb(self) -> Option<(u8, u8, u8)> {
+        match self {
+            Self::Default => None,
+            Self::Rgb(r, g, b) => Some((r, g, b)),
+            Self::Indexed(index) if index < 16 => Some(ANSI_16_PALETTE[index as usize]),
+            Self::Indexed(index) if index >= 232 => {
+                let v = 8 + (index - 232) * 10;
+                Some((v, v, v))
+            }
+            Self::Indexed(index) => {
+                let i = index - 16;
+                let level = |step: u8| if step == 0 { 0 } else { 55 + step * 40 };
+                Some((level(i / 36), level((i % 36) / 6), level(i % 6)))
+            }
+        }
+    }
+
+    /// `#rrggbb`, as used in frames.
+    pub fn to_hex(self) -> Option<String> {
+        self.rgb()
+            .map(|(r, g, b)| format!("#{r:02x}{g:02x}{b:02x}"))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub struct CellStyle {
-    pub fg: Option<String>,
-    pub bg: Option<String>,
+    pub fg: Color,
+    pub bg: Color,
     pub bold: bool,
     pub italic: bool,
     pub underline: bool,
     pub inverse: bool,
 }
 
-#[derive(Clone, PartialEq, Eq)]
+/// Index of a style in the pane's `CellTables`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct StyleId(u32);
+
+impl StyleId {
+    pub const DEFAULT: Self = Self(0);
+}
+
+/// What a cell shows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Glyph {
+    Char(char),
+    /// A grapheme of several characters, interned in the pane's
+    /// `CellTables`.
+    Cluster(u32),
+    /// Right half of a wide glyph in the cell before it.
+    Continuation,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Cell {
-    pub text: String,
+    pub glyph: Glyph,
+    pub style: StyleId,
+}
+
+/// Characters kept in one cell's grapheme cluster; further combining marks
+/// are dropped, as other terminals do past their own limit.
+const MAX_CLUSTER_CHARS: usize = 16;
+
+/// Styles and multi-character graphemes of one pane, shared by its cells.
+/// Entries stay until `retain_used` drops the ones no cell uses any more.
+#[derive(Clone)]
+pub struct CellTables {
+    styles: Vec<CellStyle>,
+    style_ids: HashMap<CellStyle, StyleId>,
+    clusters: Vec<Box<str>>,
+    cluster_ids: HashMap<Box<str>, u32>,
+}
+
+impl Default for CellTables {
+    fn default() -> Self {
+        Self {
+            styles: vec![CellStyle::default()],
+            style_ids: HashMap::from([(CellStyle::default(), StyleId::DEFAULT)]),
+            clusters: Vec::new(),
+            cluster_ids: HashMap::new(),
+        }
+    }
+}
+
+impl CellTables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern_style(&mut self, style: CellStyle) -> StyleId {
+        if let Some(id) = self.style_ids.get(&style) {
+            return *id;
+        }
+        let id = StyleId(self.styles.len() as u32);
+        self.styles.push(style);
+        self.style_ids.insert(style, id);
+        id
+    }
+
+    pub fn style(&self, id: StyleId) -> CellStyle {
+        self.styles.get(id.0 as usize).copied().unwrap_or_default()
+    }
+
+    /// `glyph` with `ch` appended, such as a combining mark or the parts of
+    /// a ZWJ sequence. A cluster already `MAX_CLUSTER_CHARS` long is kept as
+    /// it is.
+    pub fn extend_glyph(&mut self, glyph: Glyph, ch: char) -> Glyph {
+        let mut text = String::new();
+        self.push_glyph(glyph, &mut text);
+        if text.chars().count() >= MAX_CLUSTER_CHARS {
+            return glyph;
+        }
+        text.push(ch);
+        self.intern_cluster(&text)
+    }
+
+    fn intern_cluster(&mut self, text: &str) -> Glyph {
+        if let Some(id) = self.cluster_ids.get(text) {
+            return Glyph::Cluster(*id);
+        }
+        let id = self.clusters.len() as u32;
+        let text = Box::<str>::from(text);
+        self.clusters.push(text.clone());
+        self.cluster_ids.insert(text, id);
+        Glyph::Cluster(id)
+    }
+
+    /// Drops the styles and clusters none of `cells` uses, renumbering the
+    /// ids in `cells`, which must be every cell that refers to these tables.
+    pub fn retain_used<'a>(&mut self, cells: impl IntoIterator<Item = &'a mut Cell>) {
+        let mut kept = Self::new();
+        for cell in cells {
+            cell.style = kept.intern_style(self.style(cell.style));
+            if let Glyph::Cluster(id) = cell.glyph {
+                cell.glyph = match self.clusters.get(id as usize) {
+                    Some(text) => kept.intern_cluster(text),
+                    None => Glyph::Char(' '),
+                };
+            }
+        }
+        *self = kept;
+    }
+
+    /// Number of styles and clusters held.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.styles.len() + self.clusters.len()
+    }
+
+    pub fn push_glyph(&self, glyph: Glyph, out: &mut String) {
+        match glyph {
+            Glyph::Char(ch) => out.push(ch),
+            Glyph::Cluster(id) => {
+                if let Some(text) = self.clusters.get(id as usize) {
+                    out.push_str(text);
+                }
+            }
+            Glyph::Continuation => {}
+        }
+    }
+}
+
+/// A character set that can be designated into G0 to G3.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Charset {
+    #[default]
+    Ascii,
+    /// DEC Special Graphics, the line-drawing set.
+    DecSpecialGraphics,
+    /// United Kingdom: `#` is the pound sign.
+    Uk,
+}
+
+impl Charset {
+    /// The set an SCS sequence with `final_byte` designates; sets panes do
+    /// not implement act as ASCII.
+    pub fn from_final(final_byte: u8) -> Self {
+        match final_byte {
+            b'0' => Self::DecSpecialGraphics,
+            b'A' => Self::Uk,
+            _ => Self::Ascii,
+        }
+    }
+
+    fn final_byte(self) -> char {
+        match self {
+            Self::Ascii => 'B',
+            Self::DecSpecialGraphics => '0',
+            Self::Uk => 'A',
+        }
+    }
+
+    fn translate(self, ch: char) -> char {
+        match self {
+            Self::Ascii => ch,
+            Self::Uk if ch == '#' => '£',
+            Self::Uk => ch,
+            Self::DecSpecialGraphics => match ch {
+                '_' => '\u{a0}',
+                '`'..='~' => DEC_SPECIAL_GRAPHICS[ch as usize - '`' as usize],
+                _ => ch,
+            },
+        }
+    }
+}
+
+// `0x60..=0x7e` in DEC Special Graphics.
+const DEC_SPECIAL_GRAPHICS: [char; 31] = [
+    '◆', '▒', '␉', '␌', '␍', '␊', '°', '±', '␤', '␋', '┘', '┐', '┌', '└', '┼', '⎺', '⎻', '─', '⎼',
+    '⎽', '├', '┤', '┴', '┬', '│', '≤', '≥', 'π', '≠', '£', '·',
+];
+
+/// The sets designated into G0 to G3, and which of them is invoked into GL.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Charsets {
+    pub slots: [Charset; 4],
+    pub gl: usize,
+}
+
+impl Charsets {
+    /// What printing `ch` shows with these sets.
+    pub fn translate(&self, ch: char) -> char {
+        if ch.is_ascii() {
+            self.slots[self.gl].translate(ch)
+        } else {
+            ch
+        }
+    }
+
+    /// Escape sequences that designate these sets and invoke the one in GL.
+    pub fn sequence(&self) -> String {
+        let mut out = String::new();
+        for (slot, intermediate) in self.slots.iter().zip(['(', ')', '*', '+']) {
+            out.push_str(&format!("\x1b{intermediate}{}", slot.final_byte()));
+        }
+        out.push_str(["\x0f", "\x0e", "\x1bn", "\x1bo"][self.gl]);
+        out
+    }
+}
+
+/// Cursor state that DECSC saves and DECRC restores. The default is what
+/// DECRC restores when nothing was saved.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SavedCursor {
+    pub row: usize,
+    pub col: usize,
     pub style: CellStyle,
+    pub charsets: Charsets,
+    pub origin: bool,
+    pub wrap_pending: bool,
 }
 
 #[derive(Clone)]
 pub struct SavedScreen {
     pub lines: Vec<Vec<Cell>>,
-    pub cursor_row: usize,
-    pub cursor_col: usize,
-    pub saved_row: usize,
-    pub saved_col: usize,
-    pub style: CellStyle,
+    /// The cursor as it was when the alternate screen was entered.
+    pub cursor: SavedCursor,
+    pub saved_cursor: SavedCursor,
     pub scroll_top: usize,
     pub scroll_bottom: usize,
+    pub margin_left: usize,
+    pub margin_right: usize,
     pub cursor_visible: bool,
 }
 
@@ -35,90 +305,62 @@ pub fn make_row(cols: usize) -> Vec<Cell> {
 
 pub fn blank_cell() -> Cell {
     Cell {
-        text: " ".to_string(),
-        style: CellStyle::default(),
+        glyph: Glyph::Char(' '),
+        style: StyleId::DEFAULT,
     }
 }
 
-pub fn style_key(style: &CellStyle) -> String {
-    format!(
-        "{}|{}|{}|{}|{}",
-        style.fg.as_deref().unwrap_or(""),
-        style.bg.as_deref().unwrap_or(""),
-        if style.bold { "1" } else { "0" },
-        if style.italic { "1" } else { "0" },
-        if style.underline { "1" } else { "0" },
-    )
-}
-
-pub fn applied_style(style: &CellStyle) -> CellStyle {
+pub fn applied_style(style: CellStyle) -> CellStyle {
     if !style.inverse {
-        return style.clone();
+        return style;
     }
     CellStyle {
-        fg: style.bg.clone(),
-        bg: style.fg.clone(),
-        bold: style.bold,
-        italic: style.italic,
-        underline: style.underline,
+        fg: style.bg,
+        bg: style.fg,
         inverse: false,
+        ..style
     }
 }
 
-pub fn segment_json(text: &str, style: &CellStyle) -> Value {
-    let mut map = Map::new();
-    map.insert("text".to_string(), Value::String(text.to_string()));
-    if let Some(fg) = &style.fg {
-        map.insert("fg".to_string(), Value::String(fg.clone()));
-    }
-    if let Some(bg) = &style.bg {
-        map.insert("bg".to_string(), Value::String(bg.clone()));
-    }
-    if style.bold {
-        map.insert("bold".to_string(), Value::Bool(true));
-    }
-    if style.italic {
-        map.insert("italic".to_string(), Value::Bool(true));
-    }
-    if style.underline {
-        map.insert("underline".to_string(), Value::Bool(true));
-    }
-    Value::Object(map)
+/// How wide characters of ambiguous East Asian width are drawn: one
+/// column, as in most Western locales, or two, as in CJK ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AmbiguousWidth {
+    #[default]
+    Narrow,
+    Wide,
 }
 
-pub fn char_display_width(ch: char) -> usize {
-    let cp = ch as u32;
-    if cp == 0 {
-        return 0;
-    }
-    if cp < 32 || (cp >= 0x7f && cp < 0xa0) {
-        return 0;
-    }
-    if (0x0300..=0x036f).contains(&cp)
-        || (0x1ab0..=0x1aff).contains(&cp)
-        || (0x1dc0..=0x1dff).contains(&cp)
-        || (0x20d0..=0x20ff).contains(&cp)
-        || (0xfe20..=0xfe2f).contains(&cp)
-        || cp == 0x200d
-        || (0xfe00..=0xfe0f).contains(&cp)
-    {
-        return 0;
+impl AmbiguousWidth {
+    /// The setting for an ambiguous character `columns` wide.
+    pub fn from_columns(columns: u64) -> Option<Self> {
+        match columns {
+            1 => Some(Self::Narrow),
+            2 => Some(Self::Wide),
+            _ => None,
+        }
     }
+}
 
-    if (0x1100..=0x115f).contains(&cp)
-        || (0x2329..=0x232a).contains(&cp)
-        || (0x2e80..=0xa4cf).contains(&cp)
-        || (0xac00..=0xd7a3).contains(&cp)
-        || (0xf900..=0xfaff).contains(&cp)
-        || (0xfe10..=0xfe19).contains(&cp)
-        || (0xfe30..=0xfe6f).contains(&cp)
-        || (0xff00..=0xff60).contains(&cp)
-        || (0xffe0..=0xffe6).contains(&cp)
-        || (0x1f300..=0x1faff).contains(&cp)
-        || (0x20000..=0x3fffd).contains(&cp)
-    {
-        return 2;
-    }
+/// Columns a grapheme cluster takes on screen: 0 for a cluster that is only
+/// marks with nothing to combine with, otherwise 1 or 2.
+pub fn cluster_width(cluster: &str, ambiguous: AmbiguousWidth) -> usize {
+    let width = match ambiguous {
+        AmbiguousWidth::Narrow => cluster.width(),
+        AmbiguousWidth::Wide => cluster.width_cjk(),
+    };
+    width.min(2)
+}
 
-    1
+/// Whether `ch` continues the grapheme cluster `cluster` rather than starting
+/// a new one.
+pub fn extends_cluster(cluster: &str, ch: char) -> bool {
+    if cluster.is_empty() {
+        return false;
+    }
+    let mut joined = String::with_capacity(cluster.len() + ch.len_utf8());
+    joined.push_str(cluster);
+    joined.push(ch);
+    joined.graphemes(true).nth(1).is_none()
 }
@@ -0,0 +1,335 @@
+use crate::pty_bus::{adopt_window_process, resume_reading, stop_reading};
+use crate::session_manager::{
+    lock_state, lock_window, window_key, SharedSidecarState, SharedWindowState,
+};
+use crate::state_store::{restore_snapshot, snapshot_state, PersistedState};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+pub const HANDOFF_METHOD: &str = "handoff";
+const HANDOFF_FORMAT_VERSION: u32 = 1;
+// Linux caps SCM_RIGHTS at 253 descriptors per message.
+const MAX_FDS_PER_MESSAGE: usize = 200;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HandoffManifest {
+    version: u32,
+    state: PersistedState,
+    windows: Vec<HandoffWindow>,
+}
+
+/// A running window whose PTY master fd travels with the manifest. Fds are
+/// sent in manifest order, right after the listening socket.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HandoffWindow {
+    session_name: String,
+    window_name: String,
+    pid: u32,
+}
+
+pub struct HandoffPackage {
+    manifest: Vec<u8>,
+    master_fds: Vec<RawFd>,
+    windows: Vec<SharedWindowState>,
+}
+
+impl HandoffPackage {
+    pub fn manifest_len(&self) -> usize {
+        self.manifest.len()
+    }
+
+    pub fn window_count(&self) -> usize {
+        self.windows.len()
+    }
+}
+
+/// Collects window state and the PTY master fds of every running window.
+/// Their readers are stopped first, so that the state includes everything
+/// read from them and the rest of their output is left to the successor.
+/// Unless the package is sent, `resume_handoff` has to start them again.
+pub fn prepare_handoff(state: &SharedSidecarState) -> Result<HandoffPackage, String> {
+    let candidates = {
+        let guard = lock_state(state);
+        guard.windows.values().cloned().collect::<Vec<_>>()
+    };
+
+    let mut live_windows = Vec::new();
+    let mut master_fds = Vec::new();
+    let mut shared = Vec::new();
+    for window in candidates {
+        let w = lock_window(&window);
+        if w.snapshot.status != "running" {
+            continue;
+        }
+        let fd = w.master.as_ref().and_then(|master| master.as_raw_fd());
+        let (Some(fd), Some(pid)) = (fd, w.snapshot.pid) else {
+            continue;
+        };
+        live_windows.push(HandoffWindow {
+            session_name: w.snapshot.session_name.clone(),
+            window_name: w.snapshot.window_name.clone(),
+            pid,
+        });
+        master_fds.push(fd);
+        drop(w);
+        shared.push(window);
+    }
+    stop_reading(&shared);
+
+    let manifest = HandoffManifest {
+        version: HANDOFF_FORMAT_VERSION,
+        state: snapshot_state(state),
+        windows: live_windows,
+    };
+    let manifest = match serde_json::to_vec(&manifest) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            resume_windows(state, &shared);
+            return Err(format!("encode handoff manifest: {err}"));
+        }
+    };
+
+    Ok(HandoffPackage {
+        manifest,
+        master_fds,
+        windows: shared,
+    })
+}
+
+/// Streams the manifest and passes the listener plus master fds to the
+/// successor. The windows' readers stay stopped and their writers are let go
+/// once it succeeds; if it fails the readers are started again.
+pub fn send_handoff(
+    state: &SharedSidecarState,
+    stream: &mut UnixStream,
+    listener: &UnixListener,
+    package: HandoffPackage,
+) -> Result<(), String> {
+    let mut fds = Vec::with_capacity(package.master_fds.len() + 1);
+    fds.push(listener.as_raw_fd());
+    fds.extend(&package.master_fds);
+    let sent = stream
+        .write_all(&package.manifest)
+        .map_err(|e| format!("write handoff manifest: {e}"))
+        .and_then(|()| send_fds(stream, &fds));
+    if sent.is_err() {
+        resume_handoff(state, package);
+        return sent;
+    }
+    for window in &package.windows {
+        // Dropping a PTY writer sends the program an end-of-file, and the
+        // program now talks to the successor.
+        std::mem::forget(lock_window(window).input.take());
+    }
+    Ok(())
+}
+
+/// Starts the readers `prepare_handoff` stopped again, for a handoff that
+/// will not be sent.
+pub fn resume_handoff(state: &SharedSidecarState, package: HandoffPackage) {
+    resume_windows(state, &package.windows);
+}
+
+fn resume_windows(state: &SharedSidecarState, windows: &[SharedWindowState]) {
+    for window in windows {
+        if let Err(err) = resume_reading(state, window) {
+            eprintln!("resume window output after failed handoff: {err}");
+        }
+    }
+}
+
+/// Asks the sidecar at `socket_path` to hand over its listener and windows,
+/// restores them into `state`, and resumes reading every adopted PTY. When
+/// the running sidecar requires a token, `token` is presented in `hello` first.
+pub fn receive_handoff(
+    socket_path: &Path,
+    state: &SharedSidecarState,
+    token: Option<&str>,
+) -> Result<UnixListener, String> {
+    let mut stream = UnixStream::connect(socket_path)
+        .map_err(|e| format!("connect {}: {e}", socket_path.display()))?;
+    if let Some(token) = token {
+        let hello = json!({ "method": "hello", "params": { "token": token } });
+        stream
+            .write_all(format!("{hello}\n").as_bytes())
+            .map_err(|e| format!("write handoff hello: {e}"))?;
+        let line = read_line_unbuffered(&mut stream)?;
+        let response = serde_json::from_str::<Value>(&line)
+            .map_err(|e| format!("invalid hello response: {e}"))?;
+        if response["ok"].as_bool() != Some(true) {
+            return Err(format!("handoff hello refused: {}", response["error"]));
+        }
+    }
+    stream
+        .write_all(format!("{{\"method\":\"{HANDOFF_METHOD}\"}}\n").as_bytes())
+        .map_err(|e| format!("write handoff request: {e}"))?;
+
+    let line = read_line_unbuffered(&mut stream)?;
+    let response = serde_json::from_str::<Value>(&line)
+        .map_err(|e| format!("invalid handoff response: {e}"))?;
+    if response["ok"].as_bool() != Some(true) {
+        return Err(format!("handoff refused: {}", response["error"]));
+    }
+    let manifest_len = response["result"]["manifestBytes"]
+        .as_u64()
+        .ok_or_else(|| "handoff response missing manifestBytes".to_string())?;
+
+    let mut body = vec![0u8; manifest_len as usize];
+    stream
+        .read_exact(&mut body)
+        .map_err(|e| format!("read handoff manifest: {e}"))?;
+    let manifest = serde_json::from_slice::<HandoffManifest>(&body)
+        .map_err(|e| format!("decode handoff manifest: {e}"))?;
+    if manifest.version != HANDOFF_FORMAT_VERSION {
+        return Err(format!("unsupported handoff version: {}", manifest.version));
+    }
+
+    let mut fds = receive_fds(&stream, manifest.windows.len() + 1)?.into_iter();
+    let listener = fds
+        .next()
+        .map(UnixListener::from)
+        .ok_or_else(|| "handoff did not include a listener".to_string())?;
+
+    let live_keys = manifest
+        .windows
+        .iter()
+        .map(|w| window_key(&w.session_name, &w.window_name))
+        .collect::<HashSet<_>>();
+    restore_snapshot(state, manifest.state, &live_keys)?;
+
+    for (handed, master_fd) in manifest.windows.iter().zip(fds) {
+        let key = window_key(&handed.session_name, &handed.window_name);
+        let window = lock_state(state)
+            .windows
+            .get(&key)
+            .cloned()
+            .ok_or_else(|| format!("window not found: {key}"))?;
+        adopt_window_process(state, &window, master_fd, handed.pid)?;
+    }
+
+    Ok(listener)
+}
+
+fn read_line_unbuffered(stream: &mut UnixStream) -> Result<String, String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        let read = stream
+            .read(&mut byte)
+            .map_err(|e| format!("read handoff response: {e}"))?;
+        if read == 0 {
+            return Err("sidecar closed connection during handoff".to_string());
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        line.push(byte[0]);
+    }
+    String::from_utf8(line).map_err(|e| format!("invalid handoff response: {e}"))
+}
+
+fn control_buffer(fd_count: usize) -> Vec<u64> {
+    let space =
+        unsafe { libc::CMSG_SPACE((fd_count * std::mem::size_of::<RawFd>()) as u32) } as usize;
+    vec![0u64; space.div_ceil(std::mem::size_of::<u64>())]
+}
+
+fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> Result<(), String> {
+    for chunk in fds.chunks(MAX_FDS_PER_MESSAGE) {
+        let mut payload = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: payload.as_mut_ptr().cast(),
+            iov_len: payload.len(),
+        };
+        let data_len = std::mem::size_of_val(chunk);
+        let mut control = control_buffer(chunk.len());
+
+        let sent = unsafe {
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = libc::CMSG_SPACE(data_len as u32) as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(data_len as u32) as _;
+            std::ptr::copy_nonoverlapping(
+                chunk.as_ptr(),
+                libc::CMSG_DATA(cmsg).cast::<RawFd>(),
+                chunk.len(),
+            );
+
+            libc::sendmsg(stream.as_raw_fd(), &msg, 0)
+        };
+        if sent != 1 {
+            return Err(format!(
+                "sendmsg(SCM_RIGHTS) failed: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn receive_fds(stream: &UnixStream, count: usize) -> Result<Vec<OwnedFd>, String> {
+    let mut fds = Vec::with_capacity(count);
+    while fds.len() < count {
+        let want = (count - fds.len()).min(MAX_FDS_PER_MESSAGE);
+        let mut payload = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: payload.as_mut_ptr().cast(),
+            iov_len: payload.len(),
+        };
+        let mut control = control_buffer(want);
+
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = std::mem::size_of_val(control.as_slice()) as _;
+
+        let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+        if received < 0 {
+            return Err(format!(
+                "recvmsg(SCM_RIGHTS) failed: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        if received == 0 {
+            return Err("sidecar closed connection before passing all fds".to_string());
+        }
+
+        let before = fds.len();
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                    for idx in 0..data_len / std::mem::size_of::<RawFd>() {
+                        let raw = std::ptr::read_unaligned(data.add(idx));
+                        libc::fcntl(raw, libc::F_SETFD, libc::FD_CLOEXEC);
+                        fds.push(OwnedFd::from_raw_fd(raw));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err("handoff fds were truncated".to_string());
+        }
+        if fds.len() == before {
+            return Err("handoff message carried no fds".to_string());
+        }
+    }
+    Ok(fds)
+}
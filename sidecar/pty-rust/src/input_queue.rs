@@ -0,0 +1,215 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Prefix of the error returned when a window's input queue has no room.
+pub const INPUT_QUEUE_FULL: &str = "input queue full";
+/// Prefix of the error returned while the PTY has stopped accepting input.
+pub const INPUT_WRITE_TIMEOUT: &str = "input write timed out";
+
+pub const DEFAULT_INPUT_QUEUE_BYTES: usize = 256 * 1024;
+pub const DEFAULT_INPUT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bytes waiting to be written to a window's PTY. A dedicated thread drains
+/// the queue, so a child that stops reading stdin blocks that thread instead
+/// of the RPC thread and the window mutex. Dropping the queue stops the
+/// thread and discards whatever was not written yet.
+pub struct InputQueue {
+    shared: Arc<Shared>,
+    capacity: usize,
+    write_timeout: Duration,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    chunks: VecDeque<Vec<u8>>,
+    // Queued bytes, including the chunk being written.
+    queued_bytes: usize,
+    writing_since: Option<Instant>,
+    failed: Option<String>,
+    closed: bool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl InputQueue {
+    pub fn spawn(writer: Box<dyn Write + Send>, capacity: usize, write_timeout: Duration) -> Self {
+        let shared = Arc::new(Shared::default());
+        let drained = shared.clone();
+        thread::spawn(move || drain(&drained, writer));
+        Self {
+            shared,
+            capacity,
+            write_timeout,
+        }
+    }
+
+    /// Queues `input` behind earlier input. Fails without queueing anything
+    /// if it does not fit, if the PTY has not accepted a write for longer
+    /// than the write timeout, or if an earlier write failed.
+    pub fn push(&self, input: &[u8]) -> Result<(), String> {
+        if input.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.shared.lock();
+        if let Some(error) = state.failed.as_ref() {
+            return Err(error.clone());
+        }
+        if let Some(stalled) = state.writing_since.map(|since| since.elapsed()) {
+            if stalled >= self.write_timeout {
+                return Err(format!(
+                    "{INPUT_WRITE_TIMEOUT}: the PTY has not accepted input for {}ms ({} bytes queued)",
+                    stalled.as_millis(),
+                    state.queued_bytes
+                ));
+            }
+        }
+        if state.queued_bytes + input.len() > self.capacity {
+            return Err(format!(
+                "{INPUT_QUEUE_FULL}: {} bytes queued, {} more do not fit in {}",
+                state.queued_bytes,
+                input.len(),
+                self.capacity
+            ));
+        }
+        state.queued_bytes += input.len();
+        state.chunks.push_back(input.to_vec());
+        drop(state);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    pub fn queued_bytes(&self) -> usize {
+        self.shared.lock().queued_bytes
+    }
+
+    /// Whether the current write has been blocked for at least the write
+    /// timeout.
+    pub fn is_stalled(&self) -> bool {
+        self.shared
+            .lock()
+            .writing_since
+            .is_some_and(|since| since.elapsed() >= self.write_timeout)
+    }
+}
+
+impl Drop for InputQueue {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.closed = true;
+        state.queued_bytes = 0;
+        state.chunks.clear();
+        drop(state);
+        self.shared.changed.notify_all();
+    }
+}
+
+fn drain(shared: &Shared, mut writer: Box<dyn Write + Send>) {
+    let mut state = shared.lock();
+    loop {
+        if state.closed {
+            return;
+        }
+        let Some(chunk) = state.chunks.pop_front() else {
+            state = shared
+                .changed
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            continue;
+        };
+        state.writing_since = Some(Instant::now());
+        drop(state);
+
+        let result = writer.write_all(&chunk).and_then(|()| writer.flush());
+
+        state = shared.lock();
+        state.writing_since = None;
+        state.queued_bytes = state.queued_bytes.saturating_sub(chunk.len());
+        if let Err(err) = result {
+            state.failed = Some(format!("write input failed: {err}"));
+            state.queued_bytes = 0;
+            state.chunks.clear();
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver, Sender};
+
+    /// A PTY stand-in that only accepts a write when the test allows it.
+    struct GatedWriter {
+        gate: Receiver<()>,
+        written: Sender<Vec<u8>>,
+    }
+
+    impl Write for GatedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.gate
+                .recv()
+                .map_err(|_| std::io::Error::other("gate closed"))?;
+            let _ = self.written.send(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !condition() {
+            assert!(Instant::now() < deadline, "condition not reached");
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    #[test]
+    fn rejects_input_when_full_or_stalled_and_drains_in_order() {
+        let (open_gate, gate) = mpsc::channel();
+        let (written, received) = mpsc::channel();
+        let queue = InputQueue::spawn(
+            Box::new(GatedWriter { gate, written }),
+            8,
+            Duration::from_millis(40),
+        );
+
+        queue.push(b"abc").expect("first chunk fits");
+        queue.push(b"defgh").expect("second chunk fits");
+        assert_eq!(queue.queued_bytes(), 8);
+        let full = queue.push(b"i").expect_err("queue is full");
+        assert!(full.starts_with(INPUT_QUEUE_FULL), "{full}");
+
+        wait_until(|| queue.is_stalled());
+        let stalled = queue.push(b"i").expect_err("writer is stalled");
+        assert!(stalled.starts_with(INPUT_WRITE_TIMEOUT), "{stalled}");
+
+        open_gate.send(()).expect("open");
+        open_gate.send(()).expect("open");
+        let timeout = Duration::from_secs(2);
+        assert_eq!(received.recv_timeout(timeout).expect("abc"), b"abc");
+        assert_eq!(received.recv_timeout(timeout).expect("defgh"), b"defgh");
+        wait_until(|| queue.queued_bytes() == 0);
+        assert!(!queue.is_stalled());
+        queue.push(b"i").expect("room again");
+        drop(open_gate);
+        wait_until(|| queue.push(b"j").is_err());
+    }
+}
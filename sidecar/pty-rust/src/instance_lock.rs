@@ -0,0 +1,208 @@
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LOCK_FILE_MODE: u32 = 0o600;
+const PROBE_TIMEOUT: Duration = Duration::from_millis(1_000);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(25);
+
+pub fn lock_file_path(socket_path: &Path) -> PathBuf {
+    sibling_path(socket_path, "lock")
+}
+
+pub fn pid_file_path(socket_path: &Path) -> PathBuf {
+    sibling_path(socket_path, "pid")
+}
+
+fn sibling_path(socket_path: &Path, suffix: &str) -> PathBuf {
+    let mut name = socket_path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Advisory `flock` held on `<socket>.lock` for the lifetime of a server,
+/// plus the `<socket>.pid` file it owns. Dropping it removes the pidfile
+/// before the lock is released.
+pub struct InstanceLock {
+    pid_path: PathBuf,
+    _file: File,
+}
+
+impl InstanceLock {
+    pub fn acquire(socket_path: &Path) -> Result<Self, String> {
+        Self::acquire_within(socket_path, Duration::ZERO)
+    }
+
+    /// Retries until `wait` has elapsed, e.g. while a predecessor that just
+    /// handed off its socket is still shutting down.
+    pub fn acquire_within(socket_path: &Path, wait: Duration) -> Result<Self, String> {
+        let lock_path = lock_file_path(socket_path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(LOCK_FILE_MODE)
+            .open(&lock_path)
+            .map_err(|e| format!("open lock file {}: {e}", lock_path.display()))?;
+
+        let deadline = Instant::now() + wait;
+        loop {
+            match try_lock(&file) {
+                Ok(true) => break,
+                Ok(false) if Instant::now() < deadline => thread::sleep(LOCK_RETRY_INTERVAL),
+                Ok(false) => {
+                    let owner = read_pid_file(socket_path)
+                        .map(|pid| format!(" (pid {pid})"))
+                        .unwrap_or_default();
+                    return Err(format!(
+                        "another sidecar instance{owner} holds {}",
+                        lock_path.display()
+                    ));
+                }
+                Err(err) => return Err(format!("lock {}: {err}", lock_path.display())),
+            }
+        }
+
+        let pid_path = pid_file_path(socket_path);
+        fs::write(&pid_path, format!("{}\n", std::process::id()))
+            .map_err(|e| format!("write pid file {}: {e}", pid_path.display()))?;
+        Ok(Self {
+            pid_path,
+            _file: file,
+        })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.pid_path);
+    }
+}
+
+fn try_lock(file: &File) -> io::Result<bool> {
+    let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if result == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        return Ok(false);
+    }
+    Err(err)
+}
+
+/// Whether some process currently holds the instance lock for `socket_path`.
+pub fn is_locked(socket_path: &Path) -> bool {
+    let Ok(file) = File::open(lock_file_path(socket_path)) else {
+        return false;
+    };
+    // The probe lock is released again when `file` is closed.
+    matches!(try_lock(&file), Ok(false))
+}
+
+pub fn read_pid_file(socket_path: &Path) -> Option<u32> {
+    fs::read_to_string(pid_file_path(socket_path))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+pub enum SocketProbe {
+    /// Nothing is listening; the socket file (if any) is left over.
+    Stale,
+    /// A listener accepted the connection but did not answer `hello`.
+    Unresponsive,
+    /// A sidecar answered `hello` with this response.
+    Answered(Value),
+}
+
+/// Connects to `socket_path` and sends `hello`, presenting `token` if given.
+pub fn probe_socket(socket_path: &Path, token: Option<&str>) -> SocketProbe {
+    let Ok(mut stream) = UnixStream::connect(socket_path) else {
+        return SocketProbe::Stale;
+    };
+    let _ = stream.set_read_timeout(Some(PROBE_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(PROBE_TIMEOUT));
+
+    let hello = match token {
+        Some(token) => json!({ "method": "hello", "params": { "token": token } }),
+        None => json!({ "method": "hello" }),
+    };
+    if stream.write_all(format!("{hello}\n").as_bytes()).is_err() {
+        return SocketProbe::Unresponsive;
+    }
+    let mut line = String::new();
+    match BufReader::new(stream).read_line(&mut line) {
+        Ok(read) if read > 0 => serde_json::from_str(line.trim())
+            .map(SocketProbe::Answered)
+            .unwrap_or(SocketProbe::Unresponsive),
+        _ => SocketProbe::Unresponsive,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn unique_socket_path() -> PathBuf {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        std::env::temp_dir().join(format!(
+            "discode-pty-sidecar-lock-{}-{stamp}.sock",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn second_lock_holder_is_refused_until_first_is_dropped() {
+        let socket_path = unique_socket_path();
+        let first = InstanceLock::acquire(&socket_path).expect("first lock");
+        assert!(is_locked(&socket_path));
+        assert_eq!(read_pid_file(&socket_path), Some(std::process::id()));
+
+        let err = InstanceLock::acquire(&socket_path)
+            .err()
+            .expect("second lock should be refused");
+        assert!(err.contains("another sidecar instance"));
+        assert!(err.contains(&format!("pid {}", std::process::id())));
+
+        drop(first);
+        assert!(!is_locked(&socket_path));
+        assert_eq!(read_pid_file(&socket_path), None);
+        let again = InstanceLock::acquire(&socket_path).expect("lock after release");
+        drop(again);
+        let _ = fs::remove_file(lock_file_path(&socket_path));
+    }
+
+    #[test]
+    fn probe_distinguishes_stale_and_unresponsive_sockets() {
+        let socket_path = unique_socket_path();
+        let listener = UnixListener::bind(&socket_path).expect("bind");
+        let _ = listener.set_nonblocking(true);
+        assert!(matches!(
+            probe_socket(&socket_path, None),
+            SocketProbe::Unresponsive
+        ));
+
+        drop(listener);
+        assert!(socket_path.exists(), "socket file outlives its listener");
+        assert!(matches!(
+            probe_socket(&socket_path, None),
+            SocketProbe::Stale
+        ));
+        let _ = fs::remove_file(&socket_path);
+    }
+}
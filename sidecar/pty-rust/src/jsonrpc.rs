@@ -0,0 +1,228 @@
+use crate::rpc::{
+    RpcError, RpcRequest, ERROR_FEATURE_NOT_NEGOTIATED, ERROR_INPUT_QUEUE_FULL,
+    ERROR_INPUT_WRITE_TIMEOUT, ERROR_INTERNAL, ERROR_INVALID_PARAMS, ERROR_INVALID_REQUEST,
+    ERROR_PARSE, ERROR_RECORDING_ACTIVE, ERROR_REQUEST_CANCELLED, ERROR_REQUEST_TIMEOUT,
+    ERROR_UNAUTHORIZED, ERROR_UNKNOWN_METHOD, ERROR_WINDOW_NOT_FOUND,
+};
+use serde_json::{json, Map, Value};
+
+pub const JSONRPC_VERSION: &str = "2.0";
+
+pub const CODE_PARSE_ERROR: i64 = -32700;
+pub const CODE_INVALID_REQUEST: i64 = -32600;
+pub const CODE_METHOD_NOT_FOUND: i64 = -32601;
+pub const CODE_INVALID_PARAMS: i64 = -32602;
+pub const CODE_INTERNAL_ERROR: i64 = -32603;
+// Implementation-defined server errors (-32000 to -32099).
+pub const CODE_SERVER_ERROR: i64 = -32000;
+pub const CODE_WINDOW_NOT_FOUND: i64 = -32001;
+pub const CODE_UNAUTHORIZED: i64 = -32002;
+pub const CODE_REQUEST_TIMEOUT: i64 = -32003;
+pub const CODE_FEATURE_NOT_NEGOTIATED: i64 = -32004;
+pub const CODE_INPUT_QUEUE_FULL: i64 = -32005;
+pub const CODE_INPUT_WRITE_TIMEOUT: i64 = -32006;
+pub const CODE_RECORDING_ACTIVE: i64 = -32007;
+// Same value as the Language Server Protocol's RequestCancelled.
+pub const CODE_REQUEST_CANCELLED: i64 = -32800;
+
+/// Numeric JSON-RPC code for a sidecar error code. The sidecar code itself is
+/// always sent as `error.data.code`.
+pub fn error_code(code: &str) -> i64 {
+    match code {
+        ERROR_PARSE => CODE_PARSE_ERROR,
+        ERROR_INVALID_REQUEST => CODE_INVALID_REQUEST,
+        ERROR_UNKNOWN_METHOD => CODE_METHOD_NOT_FOUND,
+        ERROR_INVALID_PARAMS => CODE_INVALID_PARAMS,
+        ERROR_INTERNAL => CODE_INTERNAL_ERROR,
+        ERROR_WINDOW_NOT_FOUND => CODE_WINDOW_NOT_FOUND,
+        ERROR_UNAUTHORIZED => CODE_UNAUTHORIZED,
+        ERROR_REQUEST_TIMEOUT => CODE_REQUEST_TIMEOUT,
+        ERROR_FEATURE_NOT_NEGOTIATED => CODE_FEATURE_NOT_NEGOTIATED,
+        ERROR_INPUT_QUEUE_FULL => CODE_INPUT_QUEUE_FULL,
+        ERROR_INPUT_WRITE_TIMEOUT => CODE_INPUT_WRITE_TIMEOUT,
+        ERROR_RECORDING_ACTIVE => CODE_RECORDING_ACTIVE,
+        ERROR_REQUEST_CANCELLED => CODE_REQUEST_CANCELLED,
+        _ => CODE_SERVER_ERROR,
+    }
+}
+
+/// A connection speaks JSON-RPC 2.0 when its first message is a batch or
+/// carries a `jsonrpc` member; otherwise it uses the `{ok, id}` envelope.
+pub fn is_jsonrpc_message(message: &Value) -> bool {
+    message.is_array() || message.get("jsonrpc").is_some()
+}
+
+pub struct JsonRpcCall {
+    /// `None` for a notification, which gets no response.
+    pub id: Option<Value>,
+    pub request: RpcRequest,
+}
+
+/// An invalid call, answered with `id` (null when it could not be read).
+pub struct JsonRpcRejection {
+    pub id: Value,
+    pub error: RpcError,
+}
+
+pub fn parse_call(message: Value) -> Result<JsonRpcCall, JsonRpcRejection> {
+    let reject = |id: Value, code: &str, message: &str| JsonRpcRejection {
+        id,
+        error: RpcError::new(code, message),
+    };
+    let Value::Object(mut object) = message else {
+        return Err(reject(
+            Value::Null,
+            ERROR_INVALID_REQUEST,
+            "request must be an object",
+        ));
+    };
+
+    let id = object.remove("id");
+    if let Some(id) = &id {
+        if !(id.is_string() || id.is_number() || id.is_null()) {
+            return Err(reject(
+                Value::Null,
+                ERROR_INVALID_REQUEST,
+                "id must be a string, number or null",
+            ));
+        }
+    }
+    let reply_id = id.clone().unwrap_or(Value::Null);
+
+    if object.get("jsonrpc").and_then(Value::as_str) != Some(JSONRPC_VERSION) {
+        return Err(reject(
+            reply_id,
+            ERROR_INVALID_REQUEST,
+            "jsonrpc must be \"2.0\"",
+        ));
+    }
+    let Some(Value::String(method)) = object.remove("method") else {
+        return Err(reject(
+            reply_id,
+            ERROR_INVALID_REQUEST,
+            "method must be a string",
+        ));
+    };
+    let params = match object.remove("params") {
+        None => Value::Object(Map::new()),
+        Some(params @ Value::Object(_)) => params,
+        Some(Value::Array(_)) => {
+            return Err(reject(
+                reply_id,
+                ERROR_INVALID_PARAMS,
+                "params must be an object; positional params are not supported",
+            ));
+        }
+        Some(_) => {
+            return Err(reject(
+                reply_id,
+                ERROR_INVALID_REQUEST,
+                "params must be an object",
+            ));
+        }
+    };
+    let timeout_ms = match object.remove("timeoutMs") {
+        None => None,
+        Some(raw) => Some(raw.as_u64().ok_or_else(|| {
+            reject(
+                reply_id.clone(),
+                ERROR_INVALID_REQUEST,
+                "timeoutMs must be a non-negative integer",
+            )
+        })?),
+    };
+
+    Ok(JsonRpcCall {
+        id,
+        request: RpcRequest {
+            id: None,
+            method,
+            params,
+            timeout_ms,
+        },
+    })
+}
+
+pub fn success(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": JSONRPC_VERSION, "id": id, "result": result })
+}
+
+pub fn failure(id: Value, error: &RpcError) -> Value {
+    json!({
+        "jsonrpc": JSONRPC_VERSION,
+        "id": id,
+        "error": {
+            "code": error_code(&error.code),
+            "message": error.message,
+            "data": { "code": error.code },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_calls_and_notifications_with_any_id_type() {
+        let call = parse_call(json!({
+            "jsonrpc": "2.0",
+            "id": "frame-7",
+            "method": "get_window_frame",
+            "params": { "sessionName": "s", "windowName": "w" },
+            "timeoutMs": 500,
+        }))
+        .ok()
+        .expect("valid call");
+        assert_eq!(call.id, Some(json!("frame-7")));
+        assert_eq!(call.request.method, "get_window_frame");
+        assert_eq!(call.request.params["windowName"], "w");
+        assert_eq!(call.request.timeout_ms, Some(500));
+
+        let notification = parse_call(json!({ "jsonrpc": "2.0", "method": "health" }))
+            .ok()
+            .expect("valid notification");
+        assert_eq!(notification.id, None);
+        assert!(notification.request.params.is_object());
+
+        let null_id = parse_call(json!({ "jsonrpc": "2.0", "id": null, "method": "hello" }))
+            .ok()
+            .expect("null id is a call, not a notification");
+        assert_eq!(null_id.id, Some(Value::Null));
+    }
+
+    #[test]
+    fn rejects_malformed_calls_with_standard_codes() {
+        let code_of = |message: Value| {
+            let rejection = parse_call(message).err().expect("should be rejected");
+            (rejection.id, error_code(&rejection.error.code))
+        };
+        assert_eq!(code_of(json!(1)), (Value::Null, CODE_INVALID_REQUEST));
+        assert_eq!(
+            code_of(json!({ "jsonrpc": "1.0", "id": 4, "method": "hello" })),
+            (json!(4), CODE_INVALID_REQUEST)
+        );
+        assert_eq!(
+            code_of(json!({ "jsonrpc": "2.0", "id": [1], "method": "hello" })),
+            (Value::Null, CODE_INVALID_REQUEST)
+        );
+        assert_eq!(
+            code_of(json!({ "jsonrpc": "2.0", "id": 5, "method": "x", "params": [1] })),
+            (json!(5), CODE_INVALID_PARAMS)
+        );
+    }
+
+    #[test]
+    fn failure_carries_sidecar_code_in_data() {
+        let reply = failure(
+            json!(9),
+            &RpcError::new(ERROR_WINDOW_NOT_FOUND, "window not found: s:w"),
+        );
+        assert_eq!(reply["jsonrpc"], "2.0");
+        assert_eq!(reply["id"], 9);
+        assert_eq!(reply["error"]["code"], CODE_WINDOW_NOT_FOUND);
+        assert_eq!(reply["error"]["data"]["code"], "WINDOW_NOT_FOUND");
+        assert!(success(json!("a"), json!({})).get("error").is_none());
+        assert_eq!(error_code("SOMETHING_NEW"), CODE_SERVER_ERROR);
+    }
+}
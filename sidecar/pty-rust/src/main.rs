@@ -1,30 +1,93 @@
+#[cfg(unix)]
+mod access_control;
+
+#[cfg(unix)]
+mod child_watchdog;
+
+#[cfg(unix)]
+mod client;
+
+#[cfg(unix)]
+mod connection;
+
 #[cfg(unix)]
 mod grid_scrollback;
 
+#[cfg(unix)]
+mod handoff;
+
+#[cfg(unix)]
+mod input_queue;
+
+#[cfg(unix)]
+mod instance_lock;
+
+#[cfg(unix)]
+mod jsonrpc;
+
+#[cfg(unix)]
+mod modes;
+
+#[cfg(unix)]
+mod output_flow;
+
+#[cfg(unix)]
+mod pipeline;
+
+#[cfg(unix)]
+mod protocol;
+
 #[cfg(unix)]
 mod pty_bus;
 
 #[cfg(unix)]
 mod query_policy;
 
+#[cfg(unix)]
+mod recording;
+
 #[cfg(unix)]
 mod renderer;
 
+#[cfg(unix)]
+mod request_control;
+
 #[cfg(unix)]
 mod rpc;
 
 #[cfg(unix)]
 mod screen;
 
+#[cfg(unix)]
+mod server;
+
 #[cfg(unix)]
 mod session_manager;
 
+#[cfg(unix)]
+mod signals;
+
+#[cfg(unix)]
+mod socket_activation;
+
+#[cfg(unix)]
+mod state_store;
+
 #[cfg(unix)]
 mod terminal_pane;
 
+#[cfg(unix)]
+mod transport;
+
 #[cfg(unix)]
 mod vt_lite;
 
+#[cfg(unix)]
+mod vt_parser;
+
+#[cfg(unix)]
+mod websocket;
+
 #[cfg(not(unix))]
 fn main() {
     eprintln!("discode-pty-sidecar currently supports unix domain sockets only");
@@ -33,33 +96,97 @@ fn main() {
 
 #[cfg(unix)]
 mod unix_main {
-    use crate::rpc::{
-        handle_request, invalid_request, request_timeout, RpcError, RpcRequest, RpcResponse,
-    };
-    use crate::session_manager::{new_shared_state, record_rpc_observation};
+    use crate::access_control::{parse_uid_list, read_token_file, TOKEN_ENV};
+    use crate::client::{instance_status, run_client, send_request};
+    use crate::output_flow::ThrottleMode;
+    use crate::recording::replay_cast;
+    use crate::rpc::RpcRequest;
+    use crate::server::{run_server, ServerConfig};
+    use crate::socket_activation::{bound_socket_path, listen_fd_from_env};
+    use crate::transport::ListenAddress;
     use serde_json::{json, Value};
     use std::fs;
-    use std::io::{BufRead, BufReader, Read, Write};
-    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::io::Write;
+    use std::os::fd::RawFd;
     use std::path::{Path, PathBuf};
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::sync::Arc;
-    use std::time::{Duration, Instant};
+
+    // LSB `status` convention: 0 = running, 3 = not running.
+    const STATUS_NOT_RUNNING: i32 = 3;
 
     pub fn main() {
         let args = std::env::args().collect::<Vec<_>>();
         if args.len() < 2 {
-            eprintln!("usage: discode-pty-sidecar <server|request|client> ...");
+            eprintln!("usage: discode-pty-sidecar <server|request|client|status|replay> ...");
             std::process::exit(1);
         }
 
         match args[1].as_str() {
             "server" => {
-                let socket = parse_flag(&args, "--socket").unwrap_or_else(|| {
-                    eprintln!("missing --socket");
-                    std::process::exit(1);
-                });
-                if let Err(err) = run_server(PathBuf::from(socket)) {
+                let listen_fd = match parse_flag(&args, "--listen-fd") {
+                    Some(raw) => Some(raw.parse::<RawFd>().unwrap_or_else(|_| {
+                        eprintln!("invalid --listen-fd: {raw}");
+                        std::process::exit(1);
+                    })),
+                    None => listen_fd_from_env().unwrap_or_else(|err| {
+                        eprintln!("{err}");
+                        std::process::exit(1);
+                    }),
+                };
+                let socket = parse_flag(&args, "--socket")
+                    .map(PathBuf::from)
+                    .or_else(|| listen_fd.and_then(bound_socket_path))
+                    .unwrap_or_else(|| {
+                        eprintln!("missing --socket");
+                        std::process::exit(1);
+                    });
+                let mut config = ServerConfig::new(socket);
+                config.listen_fd = listen_fd;
+                config.state_dir = parse_flag(&args, "--state-dir").map(PathBuf::from);
+                if let Some(interval_ms) = parse_flag_u64(&args, "--snapshot-interval-ms") {
+                    config.snapshot_interval_ms = interval_ms.max(100);
+                }
+                config.restart_interrupted = has_flag(&args, "--restart-interrupted");
+                config.handoff = has_flag(&args, "--handoff");
+                if let Some(raw) = parse_flag(&args, "--allow-uid") {
+                    let uids = parse_uid_list(&raw).unwrap_or_else(|err| {
+                        eprintln!("{err}");
+                        std::process::exit(1);
+                    });
+                    config.access.allow_uids(&uids);
+                }
+                config.token_file = parse_flag(&args, "--token-file").map(PathBuf::from);
+                if config.token_file.is_none() {
+                    config.access.token = env_token();
+                }
+                if let Some(grace_ms) = parse_flag_u64(&args, "--shutdown-grace-ms") {
+                    config.shutdown_grace_ms = grace_ms;
+                }
+                config.config_file = parse_flag(&args, "--config").map(PathBuf::from);
+                for raw in parse_flags(&args, "--listen") {
+                    match ListenAddress::parse(&raw) {
+                        Ok(address) => config.listen.push(address),
+                        Err(err) => {
+                            eprintln!("{err}");
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                config.listen_allow_remote = has_flag(&args, "--listen-allow-remote");
+                config.ws_allowed_origins = parse_flags(&args, "--ws-allow-origin");
+                if let Some(max) = parse_flag_u64(&args, "--max-network-connections") {
+                    config.max_network_connections = max as usize;
+                }
+                if let Some(limit) = parse_flag_u64(&args, "--output-rate-limit") {
+                    config.output_flow.rate_limit = (limit > 0).then_some(limit);
+                }
+                if let Some(raw) = parse_flag(&args, "--output-throttle") {
+                    config.output_flow.mode = ThrottleMode::parse(&raw).unwrap_or_else(|err| {
+                        eprintln!("{err}");
+                        std::process::exit(1);
+                    });
+                }
+                config.handle_signals = true;
+                if let Err(err) = run_server(config) {
                     eprintln!("server error: {err}");
                     std::process::exit(1);
                 }
@@ -85,7 +212,8 @@ mod unix_main {
                     timeout_ms,
                 };
 
-                match send_request(Path::new(&socket), &req) {
+                let token = resolve_token(&args);
+                match send_request(Path::new(&socket), &req, token.as_deref()) {
                     Ok(value) => {
                         print!("{value}");
                     }
@@ -100,11 +228,41 @@ mod unix_main {
                     eprintln!("missing --socket");
                     std::process::exit(1);
                 });
-                if let Err(err) = run_client(PathBuf::from(socket)) {
+                let token = resolve_token(&args);
+                if let Err(err) = run_client(PathBuf::from(socket), token.as_deref()) {
                     eprintln!("client error: {err}");
                     std::process::exit(1);
                 }
             }
+            "status" => {
+                let socket = parse_flag(&args, "--socket").unwrap_or_else(|| {
+                    eprintln!("missing --socket");
+                    std::process::exit(1);
+                });
+                let token = resolve_token(&args);
+                let report = instance_status(Path::new(&socket), token.as_deref());
+                println!("{report}");
+                if report["running"].as_bool() != Some(true) {
+                    std::process::exit(STATUS_NOT_RUNNING);
+                }
+            }
+            "replay" => {
+                let cast = parse_flag(&args, "--cast").unwrap_or_else(|| {
+                    eprintln!("missing --cast");
+                    std::process::exit(1);
+                });
+                let timestamps = parse_flag(&args, "--at")
+                    .map(|raw| {
+                        raw.split(',')
+                            .filter_map(|item| item.trim().parse::<f64>().ok())
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                if let Err(err) = run_replay(Path::new(&cast), &timestamps) {
+                    eprintln!("replay error: {err}");
+                    std::process::exit(1);
+                }
+            }
             _ => {
                 eprintln!("unknown command: {}", args[1]);
                 std::process::exit(1);
@@ -117,277 +275,54 @@ mod unix_main {
         args.get(idx + 1).cloned()
     }
 
-    fn parse_flag_u64(args: &[String], name: &str) -> Option<u64> {
-        parse_flag(args, name).and_then(|raw| raw.parse::<u64>().ok())
+    fn parse_flags(args: &[String], name: &str) -> Vec<String> {
+        args.windows(2)
+            .filter(|pair| pair[0] == name)
+            .map(|pair| pair[1].clone())
+            .collect()
     }
 
-    fn send_request(socket_path: &Path, req: &RpcRequest) -> Result<String, String> {
-        let mut stream = UnixStream::connect(socket_path)
-            .map_err(|e| format!("connect {}: {e}", socket_path.display()))?;
-
-        let payload = serde_json::to_vec(req).map_err(|e| format!("encode request: {e}"))?;
-        stream
-            .write_all(&payload)
-            .map_err(|e| format!("write request: {e}"))?;
-        let _ = stream.shutdown(std::net::Shutdown::Write);
-
-        let mut out = String::new();
-        stream
-            .read_to_string(&mut out)
-            .map_err(|e| format!("read response: {e}"))?;
-        Ok(out)
+    fn has_flag(args: &[String], name: &str) -> bool {
+        args.iter().any(|it| it == name)
     }
 
-    fn run_server(socket_path: PathBuf) -> Result<(), String> {
-        if socket_path.exists() {
-            let _ = fs::remove_file(&socket_path);
-        }
-        if let Some(parent) = socket_path.parent() {
-            fs::create_dir_all(parent)
-                .map_err(|e| format!("create socket parent {}: {e}", parent.display()))?;
-        }
-
-        let listener = UnixListener::bind(&socket_path)
-            .map_err(|e| format!("bind {}: {e}", socket_path.display()))?;
-        let state = new_shared_state();
-        let running = Arc::new(AtomicBool::new(true));
-
-        while running.load(Ordering::SeqCst) {
-            let (mut stream, _) = match listener.accept() {
-                Ok(tuple) => tuple,
-                Err(err) => return Err(format!("accept failed: {err}")),
-            };
-
-            if let Err(err) = handle_connection(&mut stream, &state, &running) {
-                let _ = write_response(
-                    &mut stream,
-                    &RpcResponse {
-                        ok: false,
-                        id: None,
-                        result: None,
-                        error: Some(RpcError::new("INTERNAL", err)),
-                    },
-                );
-            }
-        }
-
-        let _ = fs::remove_file(&socket_path);
-        Ok(())
-    }
-
-    fn write_response(stream: &mut UnixStream, response: &RpcResponse) -> Result<(), String> {
-        let mut payload =
-            serde_json::to_vec(response).map_err(|e| format!("encode response: {e}"))?;
-        payload.push(b'\n');
-        stream
-            .write_all(&payload)
-            .map_err(|e| format!("write response: {e}"))
+    fn parse_flag_u64(args: &[String], name: &str) -> Option<u64> {
+        parse_flag(args, name).and_then(|raw| raw.parse::<u64>().ok())
     }
 
-    fn handle_connection(
-        stream: &mut UnixStream,
-        state: &crate::session_manager::SharedSidecarState,
-        running: &Arc<AtomicBool>,
-    ) -> Result<(), String> {
-        let reader_stream = stream
-            .try_clone()
-            .map_err(|e| format!("clone stream for read failed: {e}"))?;
-        let mut reader = BufReader::new(reader_stream);
-
-        loop {
-            if !running.load(Ordering::SeqCst) {
-                break;
-            }
-
-            let mut raw = String::new();
-            let read = reader
-                .read_line(&mut raw)
-                .map_err(|e| format!("failed to read request: {e}"))?;
-
-            if read == 0 {
-                break;
-            }
-
-            let req = match serde_json::from_str::<RpcRequest>(raw.trim()) {
-                Ok(req) => req,
+    /// Token from `--token-file`, falling back to `DISCODE_PTY_SIDECAR_TOKEN`.
+    fn resolve_token(args: &[String]) -> Option<String> {
+        if let Some(path) = parse_flag(args, "--token-file") {
+            return match read_token_file(Path::new(&path)) {
+                Ok(token) => Some(token),
                 Err(err) => {
-                    let response = RpcResponse {
-                        ok: false,
-                        id: None,
-                        result: None,
-                        error: Some(invalid_request(format!("invalid request JSON: {err}"))),
-                    };
-                    write_response(stream, &response)?;
-                    continue;
+                    eprintln!("{err}");
+                    std::process::exit(1);
                 }
             };
-
-            let request_id = req.id;
-            let timeout_ms = req.timeout_ms;
-            let method_name = req.method.clone();
-            let started_at = Instant::now();
-
-            let mut should_shutdown = false;
-            let mut response = match handle_request(state, req, &mut should_shutdown) {
-                Ok(value) => RpcResponse {
-                    ok: true,
-                    id: request_id,
-                    result: Some(value),
-                    error: None,
-                },
-                Err(err) => RpcResponse {
-                    ok: false,
-                    id: request_id,
-                    result: None,
-                    error: Some(err),
-                },
-            };
-
-            if let Some(limit_ms) = timeout_ms {
-                let elapsed_ms = started_at.elapsed().as_millis();
-                if elapsed_ms > u128::from(limit_ms) {
-                    response = RpcResponse {
-                        ok: false,
-                        id: request_id,
-                        result: None,
-                        error: Some(request_timeout(&method_name, limit_ms, elapsed_ms)),
-                    };
-                }
-            }
-
-            let observed_latency_ms =
-                started_at.elapsed().as_millis().min(u128::from(u64::MAX)) as u64;
-            record_rpc_observation(
-                state,
-                &method_name,
-                observed_latency_ms,
-                response.error.as_ref().map(|error| error.code.as_str()),
-            );
-
-            write_response(stream, &response)?;
-            if should_shutdown {
-                running.store(false, Ordering::SeqCst);
-                break;
-            }
         }
-
-        Ok(())
+        env_token()
     }
 
-    fn run_client(socket_path: PathBuf) -> Result<(), String> {
-        let mut stream = UnixStream::connect(&socket_path)
-            .map_err(|e| format!("connect {}: {e}", socket_path.display()))?;
-        let _ = stream.set_read_timeout(Some(Duration::from_millis(5000)));
-
-        let reader_stream = stream
-            .try_clone()
-            .map_err(|e| format!("clone stream for client read failed: {e}"))?;
-        let mut socket_reader = BufReader::new(reader_stream);
-
-        let stdin = std::io::stdin();
-        let mut stdin_reader = BufReader::new(stdin.lock());
-        let mut stdout = std::io::stdout();
-
-        loop {
-            let mut inbound = String::new();
-            let read = stdin_reader
-                .read_line(&mut inbound)
-                .map_err(|e| format!("read stdin failed: {e}"))?;
-            if read == 0 {
-                break;
-            }
-
-            if inbound.trim().is_empty() {
-                continue;
-            }
-
-            stream
-                .write_all(inbound.as_bytes())
-                .map_err(|e| format!("write to sidecar failed: {e}"))?;
-
-            let mut outbound = String::new();
-            let received = socket_reader
-                .read_line(&mut outbound)
-                .map_err(|e| format!("read from sidecar failed: {e}"))?;
-            if received == 0 {
-                return Err("sidecar closed connection".to_string());
-            }
-
-            stdout
-                .write_all(outbound.as_bytes())
-                .map_err(|e| format!("write stdout failed: {e}"))?;
-            stdout
-                .flush()
-                .map_err(|e| format!("flush stdout failed: {e}"))?;
-        }
-
-        Ok(())
+    fn env_token() -> Option<String> {
+        std::env::var(TOKEN_ENV)
+            .ok()
+            .map(|raw| raw.trim().to_string())
+            .filter(|token| !token.is_empty())
     }
 
-    #[cfg(test)]
-    mod tests {
-        use super::{run_server, send_request};
-        use crate::rpc::RpcRequest;
-        use serde_json::json;
-        use std::fs;
-        use std::path::PathBuf;
-        use std::thread;
-        use std::time::{Duration, SystemTime, UNIX_EPOCH};
-
-        fn unique_test_socket() -> PathBuf {
-            let stamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .map(|d| d.as_millis())
-                .unwrap_or_default();
-            std::env::temp_dir().join(format!(
-                "discode-pty-sidecar-test-{}-{}.sock",
-                std::process::id(),
-                stamp
-            ))
-        }
-
-        #[test]
-        fn server_removes_socket_file_on_dispose_shutdown() {
-            let socket_path = unique_test_socket();
-            if socket_path.exists() {
-                let _ = fs::remove_file(&socket_path);
-            }
-
-            let server_socket = socket_path.clone();
-            let handle = thread::spawn(move || run_server(server_socket));
+    fn run_replay(cast_path: &Path, timestamps: &[f64]) -> Result<(), String> {
+        let raw = fs::read_to_string(cast_path)
+            .map_err(|e| format!("read {}: {e}", cast_path.display()))?;
+        let frames = replay_cast(&raw, timestamps)?;
 
-            let mut ready = false;
-            for _ in 0..80 {
-                if socket_path.exists() {
-                    ready = true;
-                    break;
-                }
-                thread::sleep(Duration::from_millis(10));
-            }
-            assert!(ready, "socket should appear for server startup");
-
-            let response = send_request(
-                &socket_path,
-                &RpcRequest {
-                    id: Some(1),
-                    method: "dispose".to_string(),
-                    params: json!({}),
-                    timeout_ms: Some(2_000),
-                },
-            )
-            .unwrap_or_else(|err| panic!("dispose request failed: {err}"));
-            assert!(response.contains("\"ok\":true"));
-
-            let joined = handle
-                .join()
-                .unwrap_or_else(|_| panic!("server thread should not panic"));
-            assert!(joined.is_ok(), "server should stop cleanly");
-
-            assert!(
-                !socket_path.exists(),
-                "socket file should be removed after shutdown"
-            );
+        let mut stdout = std::io::stdout();
+        for frame in frames {
+            writeln!(stdout, "{frame}").map_err(|e| format!("write stdout failed: {e}"))?;
         }
+        stdout
+            .flush()
+            .map_err(|e| format!("flush stdout failed: {e}"))
     }
 }
 
@@ -0,0 +1,141 @@
+/// A mode set with SM and reset with RM (`CSI Pn h` / `CSI Pn l`), or with
+/// DECSET and DECRST (`CSI ? Pn h` / `CSI ? Pn l`) when private.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mode {
+    pub private: bool,
+    pub number: u16,
+}
+
+impl Mode {
+    pub const fn ansi(number: u16) -> Self {
+        Self {
+            private: false,
+            number,
+        }
+    }
+
+    pub const fn dec(number: u16) -> Self {
+        Self {
+            private: true,
+            number,
+        }
+    }
+
+    /// The sequence that sets the mode to `value`.
+    pub fn sequence(self, value: bool) -> String {
+        let private = if self.private { "?" } else { "" };
+        let end = if value { 'h' } else { 'l' };
+        format!("\x1b[{private}{}{end}", self.number)
+    }
+}
+
+/// Insert/replace mode: printed characters push the rest of the line right.
+pub const IRM: Mode = Mode::ansi(4);
+/// Line feed/new line mode: line feeds also return the carriage.
+pub const LNM: Mode = Mode::ansi(20);
+/// Reverse video for the whole screen.
+pub const DECSCNM: Mode = Mode::dec(5);
+/// Origin mode: cursor addresses count from the margins and stay inside them.
+pub const DECOM: Mode = Mode::dec(6);
+/// Autowrap: printing past the right margin continues on the next line.
+pub const DECAWM: Mode = Mode::dec(7);
+/// Left/right margin mode: enables DECSLRM (`CSI Pl ; Pr s`).
+pub const DECLRMM: Mode = Mode::dec(69);
+
+/// The modes a pane implements, with their values after a reset.
+const MODES: [(Mode, bool); 6] = [
+    (IRM, false),
+    (LNM, false),
+    (DECSCNM, false),
+    (DECOM, false),
+    (DECAWM, true),
+    (DECLRMM, false),
+];
+
+/// Values of the modes a pane implements.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Modes {
+    bits: u8,
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        let bits = MODES
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, value))| *value)
+            .fold(0, |bits, (index, _)| bits | 1 << index);
+        Self { bits }
+    }
+}
+
+impl Modes {
+    /// The value of `mode`, or `None` if panes do not implement it.
+    pub fn get(self, mode: Mode) -> Option<bool> {
+        let index = MODES.iter().position(|(known, _)| *known == mode)?;
+        Some(self.bits & 1 << index != 0)
+    }
+
+    pub fn is_set(self, mode: Mode) -> bool {
+        self.get(mode).unwrap_or(false)
+    }
+
+    /// Sets `mode` to `value`. Returns whether the value changed; modes
+    /// panes do not implement never do.
+    pub fn set(&mut self, mode: Mode, value: bool) -> bool {
+        let Some(index) = MODES.iter().position(|(known, _)| *known == mode) else {
+            return false;
+        };
+        let before = self.bits;
+        if value {
+            self.bits |= 1 << index;
+        } else {
+            self.bits &= !(1 << index);
+        }
+        self.bits != before
+    }
+
+    /// Modes whose value differs from the one after a reset.
+    pub fn changed(self) -> impl Iterator<Item = (Mode, bool)> {
+        let reset = Self::default();
+        MODES
+            .iter()
+            .map(move |(mode, _)| (*mode, self.is_set(*mode)))
+            .filter(move |(mode, value)| reset.is_set(*mode) != *value)
+    }
+}
+
+/// The DECRPM status of a mode: 1 when set, 2 when reset, 0 when not
+/// recognised.
+pub fn report_status(value: Option<bool>) -> u8 {
+    match value {
+        Some(true) => 1,
+        Some(false) => 2,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mode, Modes, DECAWM, DECOM, IRM};
+
+    #[test]
+    fn tracks_only_implemented_modes_and_their_changes() {
+        let mut modes = Modes::default();
+        assert_eq!(modes.get(DECAWM), Some(true));
+        assert_eq!(modes.get(IRM), Some(false));
+        assert_eq!(modes.get(Mode::dec(4)), None);
+        assert_eq!(modes.changed().count(), 0);
+
+        assert!(modes.set(DECOM, true));
+        assert!(!modes.set(DECOM, true));
+        assert!(modes.set(DECAWM, false));
+        assert!(!modes.set(Mode::dec(2004), true));
+        assert_eq!(
+            modes.changed().collect::<Vec<_>>(),
+            vec![(DECOM, true), (DECAWM, false)]
+        );
+        assert_eq!(DECOM.sequence(true), "\x1b[?6h");
+        assert_eq!(IRM.sequence(false), "\x1b[4l");
+    }
+}
@@ -0,0 +1,346 @@
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_OUTPUT_RATE_LIMIT: u64 = 4 * 1024 * 1024;
+/// How long the reader waits for more output before showing a kept tail.
+pub const TAIL_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+// Output kept while a window is in keep-tail mode.
+const TAIL_BYTES: usize = 64 * 1024;
+// Diverted output past the tail is handed back in batches of at least this
+// much, so that the window is not locked for every chunk.
+const SKIP_BATCH_BYTES: usize = 16 * 1024;
+// Longest single pause, so that a stop or dispose is noticed quickly.
+const MAX_PAUSE: Duration = Duration::from_millis(250);
+// Throughput is measured over windows of this length; a measurement older
+// than two windows means the output has gone quiet.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// What a window does once its output exceeds the rate limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThrottleMode {
+    /// Stop reading for a while; the kernel then blocks the writer.
+    Pause,
+    /// Keep reading but only keep the most recent output.
+    KeepTail,
+}
+
+impl ThrottleMode {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "pause" => Ok(Self::Pause),
+            "keep-tail" => Ok(Self::KeepTail),
+            _ => Err(format!(
+                "unknown output throttle mode '{raw}' (expected pause or keep-tail)"
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pause => "pause",
+            Self::KeepTail => "keep-tail",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputFlowConfig {
+    /// Bytes per second a window may produce before it is throttled; `None`
+    /// disables throttling.
+    pub rate_limit: Option<u64>,
+    pub mode: ThrottleMode,
+}
+
+impl Default for OutputFlowConfig {
+    fn default() -> Self {
+        Self {
+            rate_limit: Some(DEFAULT_OUTPUT_RATE_LIMIT),
+            mode: ThrottleMode::Pause,
+        }
+    }
+}
+
+/// Throughput and throttling state of one window, as of the last time its
+/// reader thread held the window lock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OutputStats {
+    pub total_bytes: u64,
+    pub bytes_per_sec: u64,
+    pub dropped_bytes: u64,
+    pub throttled: bool,
+    pub throttle_events: u64,
+    pub paused_ms: u64,
+    pub updated_at: Option<Instant>,
+}
+
+impl OutputStats {
+    fn is_fresh(self) -> bool {
+        self.updated_at
+            .is_some_and(|at| at.elapsed() < RATE_WINDOW * 2)
+    }
+
+    pub fn current_rate(self) -> u64 {
+        if self.is_fresh() {
+            self.bytes_per_sec
+        } else {
+            0
+        }
+    }
+
+    pub fn is_throttled(self) -> bool {
+        self.throttled && self.is_fresh()
+    }
+
+    pub fn to_value(self) -> Value {
+        json!({
+            "totalBytes": self.total_bytes,
+            "bytesPerSec": self.current_rate(),
+            "droppedBytes": self.dropped_bytes,
+            "throttled": self.is_throttled(),
+            "throttleEvents": self.throttle_events,
+            "pausedMs": self.paused_ms,
+        })
+    }
+}
+
+/// What the reader does with a chunk it has just read.
+#[derive(Debug, PartialEq)]
+pub enum Admit {
+    Pass,
+    /// Process the chunk, then stop reading for the given time.
+    Pause(Duration),
+    /// Hand the chunk to `divert` instead of the window.
+    Divert,
+}
+
+/// Diverted output once the window catches up.
+#[derive(Debug, PartialEq)]
+pub struct KeptTail {
+    /// The part of a line cut by the last overflow; parsed but not shown.
+    pub skipped: Vec<u8>,
+    /// What to append to the window.
+    pub output: Vec<u8>,
+}
+
+/// Per-window token bucket with a one-second burst, owned by the reader
+/// thread.
+pub struct OutputMeter {
+    config: OutputFlowConfig,
+    tokens: f64,
+    last_refill: Instant,
+    rate_started: Instant,
+    rate_bytes: u64,
+    tail: Vec<u8>,
+    // Dropped bytes already announced in the window.
+    reported_dropped: u64,
+    stats: OutputStats,
+}
+
+impl OutputMeter {
+    pub fn new(config: OutputFlowConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            config,
+            tokens: config.rate_limit.unwrap_or_default() as f64,
+            last_refill: now,
+            rate_started: now,
+            rate_bytes: 0,
+            tail: Vec::new(),
+            reported_dropped: 0,
+            stats: OutputStats::default(),
+        }
+    }
+
+    pub fn admit(&mut self, len: usize) -> Admit {
+        let now = Instant::now();
+        self.measure(now, len as u64);
+        let Some(limit) = self.config.rate_limit.filter(|limit| *limit > 0) else {
+            return Admit::Pass;
+        };
+        let limit = limit as f64;
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * limit;
+        self.tokens = (self.tokens + refill).min(limit);
+        self.last_refill = now;
+        let len = len as f64;
+
+        match self.config.mode {
+            ThrottleMode::Pause => {
+                self.tokens -= len;
+                if self.tokens >= 0.0 {
+                    self.stats.throttled = false;
+                    return Admit::Pass;
+                }
+                self.start_throttling();
+                let deficit = Duration::from_secs_f64(-self.tokens / limit);
+                Admit::Pause(deficit.min(MAX_PAUSE))
+            }
+            ThrottleMode::KeepTail => {
+                // Once throttled, wait for half a burst before letting output
+                // through again, so that the window is not re-rendered for
+                // every chunk.
+                let needed = if self.stats.throttled {
+                    len.max(limit / 2.0)
+                } else {
+                    len
+                };
+                if self.tokens >= needed {
+                    self.tokens -= len;
+                    self.stats.throttled = false;
+                    Admit::Pass
+                } else {
+                    self.start_throttling();
+                    Admit::Divert
+                }
+            }
+        }
+    }
+
+    /// Keeps the newest `TAIL_BYTES` of diverted output. Once a batch more
+    /// has piled up, the oldest part is handed back: it is not shown, but the
+    /// caller still runs it through the window's parser so that the styles
+    /// and modes it sets are not lost.
+    pub fn divert(&mut self, chunk: &[u8]) -> Option<Vec<u8>> {
+        self.tail.extend_from_slice(chunk);
+        if self.tail.len() < TAIL_BYTES + SKIP_BATCH_BYTES {
+            return None;
+        }
+        let overflow = self.tail.len() - TAIL_BYTES;
+        self.stats.dropped_bytes += overflow as u64;
+        Some(self.tail.drain(..overflow).collect())
+    }
+
+    pub fn has_tail(&self) -> bool {
+        !self.tail.is_empty()
+    }
+
+    /// The kept tail, ready to append to the window. If anything was skipped
+    /// the tail starts at its first line break, so that it does not begin in
+    /// the middle of a line, behind a note saying how much was skipped.
+    pub fn take_tail(&mut self) -> Option<KeptTail> {
+        if self.tail.is_empty() {
+            return None;
+        }
+        let mut tail = std::mem::take(&mut self.tail);
+        let mut skipped = Vec::new();
+        if self.stats.dropped_bytes > self.reported_dropped {
+            if let Some(line_start) = tail.iter().position(|byte| *byte == b'\n') {
+                skipped = tail.drain(..=line_start).collect();
+                self.stats.dropped_bytes += skipped.len() as u64;
+            }
+        }
+        let skipped_bytes = self.stats.dropped_bytes - self.reported_dropped;
+        let mut output = Vec::with_capacity(tail.len() + 64);
+        if skipped_bytes > 0 {
+            output.extend_from_slice(
+                format!("\n[runtime] output throttled ({skipped_bytes} bytes skipped)\n")
+                    .as_bytes(),
+            );
+        }
+        output.extend_from_slice(&tail);
+        self.reported_dropped = self.stats.dropped_bytes;
+        Some(KeptTail { skipped, output })
+    }
+
+    pub fn record_pause(&mut self, pause: Duration) {
+        self.stats.paused_ms += pause.as_millis() as u64;
+    }
+
+    /// Marks the end of a quiet period in keep-tail mode.
+    pub fn settle(&mut self) {
+        self.stats.throttled = false;
+        self.stats.updated_at = Some(Instant::now());
+    }
+
+    pub fn stats(&self) -> OutputStats {
+        self.stats
+    }
+
+    fn start_throttling(&mut self) {
+        if !self.stats.throttled {
+            self.stats.throttled = true;
+            self.stats.throttle_events += 1;
+        }
+    }
+
+    fn measure(&mut self, now: Instant, len: u64) {
+        self.stats.total_bytes += len;
+        self.rate_bytes += len;
+        let elapsed = now.duration_since(self.rate_started);
+        if elapsed >= RATE_WINDOW {
+            self.stats.bytes_per_sec =
+                (self.rate_bytes as f64 / elapsed.as_secs_f64()).round() as u64;
+            self.rate_started = now;
+            self.rate_bytes = 0;
+        }
+        self.stats.updated_at = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meter(mode: ThrottleMode, rate_limit: u64) -> OutputMeter {
+        OutputMeter::new(OutputFlowConfig {
+            rate_limit: Some(rate_limit),
+            mode,
+        })
+    }
+
+    #[test]
+    fn pauses_once_the_burst_is_spent() {
+        let mut meter = meter(ThrottleMode::Pause, 10_000);
+        assert_eq!(meter.admit(8_000), Admit::Pass);
+        let Admit::Pause(pause) = meter.admit(4_000) else {
+            panic!("expected a pause");
+        };
+        assert!(pause > Duration::from_millis(150) && pause <= MAX_PAUSE);
+        meter.record_pause(pause);
+        let stats = meter.stats();
+        assert!(stats.is_throttled());
+        assert_eq!(stats.throttle_events, 1);
+        assert_eq!(stats.total_bytes, 12_000);
+
+        let mut unlimited = OutputMeter::new(OutputFlowConfig {
+            rate_limit: None,
+            mode: ThrottleMode::Pause,
+        });
+        assert_eq!(unlimited.admit(1 << 30), Admit::Pass);
+    }
+
+    #[test]
+    fn keeps_the_tail_from_a_line_start_and_reports_skipped_bytes() {
+        let mut meter = meter(ThrottleMode::KeepTail, 100);
+        assert_eq!(meter.admit(100), Admit::Pass);
+        assert_eq!(meter.admit(10), Admit::Divert);
+        assert!(!meter.has_tail());
+
+        let end = b"\x1b[31mcut\nlast line\n";
+        let mut flood = vec![b'x'; TAIL_BYTES + SKIP_BATCH_BYTES];
+        flood.extend_from_slice(end);
+        let overflow = meter.divert(&flood).expect("overflow");
+        assert_eq!(overflow.len(), SKIP_BATCH_BYTES + end.len());
+        assert!(meter.divert(b"\x1b[1mmore\nend\n").is_none());
+        let tail = meter.take_tail().expect("tail");
+        let mut kept = vec![b'x'; TAIL_BYTES - end.len()];
+        kept.extend_from_slice(b"\x1b[31mcut\n");
+        assert_eq!(tail.skipped, kept);
+        let skipped = overflow.len() + kept.len();
+        assert_eq!(
+            String::from_utf8(tail.output).expect("utf8"),
+            format!(
+                "\n[runtime] output throttled ({skipped} bytes skipped)\nlast line\n\x1b[1mmore\nend\n"
+            )
+        );
+        assert_eq!(meter.stats().dropped_bytes, skipped as u64);
+
+        assert!(meter.divert(b"short").is_none());
+        let tail = meter.take_tail().expect("tail");
+        assert!(tail.skipped.is_empty());
+        assert_eq!(tail.output, b"short");
+        assert!(meter.take_tail().is_none());
+        meter.settle();
+        assert!(!meter.stats().is_throttled());
+    }
+}
@@ -0,0 +1,484 @@
+use crate::jsonrpc;
+use crate::protocol::CANCEL_METHOD;
+use crate::request_control::RequestControl;
+use crate::rpc::{
+    invalid_request, request_cancelled, request_timeout, RpcError, RpcRequest, RpcResponse,
+    RpcResult,
+};
+use crate::session_manager::window_key;
+use crate::transport::{send_message, SharedWriter};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::Scope;
+use std::time::Instant;
+
+/// Most lanes one connection keeps at a time. A lane runs on its own thread
+/// while it has requests and is retired once it has none.
+pub const MAX_LANES: usize = 64;
+
+/// Runs one request under its control and returns the outcome.
+pub type Runner<'env> =
+    dyn Fn(RpcRequest, &RequestControl) -> Result<RpcResult, RpcError> + Sync + 'env;
+
+/// Methods answered on the connection thread, in order, because they change
+/// the connection or the whole server.
+pub fn is_pipelined(method: &str) -> bool {
+    !matches!(method, "hello" | "dispose" | CANCEL_METHOD)
+}
+
+/// Where the reply of a pipelined request goes. Only requests with an id are
+/// pipelined, since the id is what matches an out-of-order reply.
+#[derive(Clone)]
+pub enum ReplyTo {
+    Envelope(u64),
+    JsonRpc(Value),
+}
+
+impl ReplyTo {
+    fn key(&self) -> String {
+        match self {
+            Self::Envelope(id) => id.to_string(),
+            Self::JsonRpc(id) => id.to_string(),
+        }
+    }
+
+    fn send(
+        &self,
+        writer: &SharedWriter,
+        outcome: Result<RpcResult, RpcError>,
+    ) -> Result<(), String> {
+        match self {
+            Self::Envelope(id) => {
+                send_message(writer, &RpcResponse::from_outcome(Some(*id), outcome))
+            }
+            Self::JsonRpc(id) => {
+                let reply = match outcome {
+                    Ok(result) => jsonrpc::success(id.clone(), result.into_value()),
+                    Err(error) => jsonrpc::failure(id.clone(), &error),
+                };
+                send_message(writer, &reply)
+            }
+        }
+    }
+}
+
+struct Job {
+    req: RpcRequest,
+    reply_to: ReplyTo,
+    control: RequestControl,
+}
+
+/// A window's queue of requests and the number of them not yet finished.
+struct Lane {
+    sender: Sender<Job>,
+    unfinished: Arc<AtomicUsize>,
+}
+
+struct InFlight {
+    method: String,
+    reply_to: ReplyTo,
+    control: RequestControl,
+}
+
+/// Requests of one connection that have been accepted but not answered.
+/// Whoever claims a request's reply first (its lane, the deadline watcher or
+/// a `cancel`) removes it here and sends the reply.
+#[derive(Default)]
+struct Pending {
+    requests: Mutex<PendingState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct PendingState {
+    by_key: HashMap<String, InFlight>,
+    closed: bool,
+}
+
+impl Pending {
+    fn lock(&self) -> MutexGuard<'_, PendingState> {
+        self.requests
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Runs the pipelined requests of one connection. Requests for the same
+/// window run in order on that window's lane; different windows and
+/// server-wide requests run concurrently and may be answered out of order.
+pub struct Pipeline<'scope, 'env> {
+    scope: &'scope Scope<'scope, 'env>,
+    writer: SharedWriter,
+    runner: &'env Runner<'env>,
+    pending: Arc<Pending>,
+    lanes: HashMap<String, Lane>,
+}
+
+impl<'scope, 'env> Pipeline<'scope, 'env> {
+    pub fn new(
+        scope: &'scope Scope<'scope, 'env>,
+        writer: SharedWriter,
+        runner: &'env Runner<'env>,
+    ) -> Self {
+        let pending = Arc::new(Pending::default());
+        let watched = pending.clone();
+        let watcher_writer = writer.clone();
+        scope.spawn(move || watch_deadlines(&watched, &watcher_writer));
+        Self {
+            scope,
+            writer,
+            runner,
+            pending,
+            lanes: HashMap::new(),
+        }
+    }
+
+    /// Queues `req` on its lane. Fails if a request with the same id is
+    /// still in flight, or if `req` needs a new lane while `MAX_LANES` are
+    /// busy.
+    pub fn submit(&mut self, req: RpcRequest, reply_to: ReplyTo) -> Result<(), RpcError> {
+        let lane = lane_key(&req);
+        // Only this thread adds work to a lane, so one with nothing left to
+        // finish can be closed without losing a request; its thread exits.
+        self.lanes
+            .retain(|key, idle| *key == lane || idle.unfinished.load(Ordering::SeqCst) > 0);
+        if !self.lanes.contains_key(&lane) && self.lanes.len() >= MAX_LANES {
+            return Err(invalid_request(format!(
+                "requests for more than {MAX_LANES} windows are in flight; wait for a reply first"
+            )));
+        }
+
+        let key = reply_to.key();
+        let control = RequestControl::with_timeout(req.timeout_ms);
+        {
+            let mut pending = self.pending.lock();
+            if pending.by_key.contains_key(&key) {
+                return Err(invalid_request(format!(
+                    "request id {key} is already in flight"
+                )));
+            }
+            pending.by_key.insert(
+                key,
+                InFlight {
+                    method: req.method.clone(),
+                    reply_to: reply_to.clone(),
+                    control: control.clone(),
+                },
+            );
+        }
+        self.pending.changed.notify_all();
+
+        let job = Job {
+            req,
+            reply_to,
+            control,
+        };
+        let job = match self.lanes.get(&lane) {
+            Some(existing) => {
+                existing.unfinished.fetch_add(1, Ordering::SeqCst);
+                match existing.sender.send(job) {
+                    Ok(()) => return Ok(()),
+                    Err(mpsc::SendError(job)) => job,
+                }
+            }
+            None => job,
+        };
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let unfinished = Arc::new(AtomicUsize::new(1));
+        let finished = unfinished.clone();
+        let pending = self.pending.clone();
+        let writer = self.writer.clone();
+        let runner = self.runner;
+        self.scope.spawn(move || {
+            for job in receiver {
+                // Skipped if already answered by the watcher or a `cancel`.
+                if job.control.check().is_ok() {
+                    let outcome = runner(job.req, &job.control);
+                    if claim(&pending, &job.reply_to.key(), &job.control) {
+                        let _ = job.reply_to.send(&writer, outcome);
+                    }
+                }
+                finished.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+        let _ = sender.send(job);
+        self.lanes.insert(lane, Lane { sender, unfinished });
+        Ok(())
+    }
+
+    /// Cancels the in-flight request with `id` and answers it with
+    /// `REQUEST_CANCELLED`. Returns whether such a request was pending.
+    pub fn cancel(&self, id: &Value) -> Result<bool, String> {
+        let key = id.to_string();
+        let cancelled = {
+            let mut pending = self.pending.lock();
+            let Some(entry) = pending.by_key.get(&key) else {
+                return Ok(false);
+            };
+            entry.control.cancel();
+            if !entry.control.claim_reply() {
+                return Ok(false);
+            }
+            pending.by_key.remove(&key)
+        };
+        match cancelled {
+            Some(entry) => {
+                entry
+                    .reply_to
+                    .send(&self.writer, Err(request_cancelled(&entry.method)))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl Drop for Pipeline<'_, '_> {
+    /// Lets the lanes drain and stops the deadline watcher once nothing is
+    /// left to answer; the enclosing scope then joins them.
+    fn drop(&mut self) {
+        self.lanes.clear();
+        self.pending.lock().closed = true;
+        self.pending.changed.notify_all();
+    }
+}
+
+/// Requests naming a window share that window's lane; everything else runs
+/// on the server-wide lane.
+fn lane_key(req: &RpcRequest) -> String {
+    let field = |name: &str| req.params.get(name).and_then(Value::as_str);
+    match (field("sessionName"), field("windowName")) {
+        (Some(session_name), Some(window_name)) => window_key(session_name, window_name),
+        _ => String::new(),
+    }
+}
+
+fn claim(pending: &Pending, key: &str, control: &RequestControl) -> bool {
+    let mut state = pending.lock();
+    if !control.claim_reply() {
+        return false;
+    }
+    state.by_key.remove(key);
+    drop(state);
+    pending.changed.notify_all();
+    true
+}
+
+/// Answers requests whose deadline passes before their lane finishes them.
+/// Exits once the connection is closed and every request has been answered.
+fn watch_deadlines(pending: &Pending, writer: &SharedWriter) {
+    let mut state = pending.lock();
+    loop {
+        let now = Instant::now();
+        let expired = state
+            .by_key
+            .iter()
+            .filter(|(_, entry)| entry.control.deadline().is_some_and(|at| at <= now))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        let mut timed_out = Vec::new();
+        for key in expired {
+            let claimed = state
+                .by_key
+                .get(&key)
+                .is_some_and(|entry| entry.control.claim_reply());
+            if claimed {
+                timed_out.extend(state.by_key.remove(&key));
+            }
+        }
+        if !timed_out.is_empty() {
+            drop(state);
+            for entry in timed_out {
+                let error = request_timeout(
+                    &entry.method,
+                    entry.control.timeout_ms().unwrap_or_default(),
+                    entry.control.elapsed().as_millis(),
+                );
+                let _ = entry.reply_to.send(writer, Err(error));
+            }
+            state = pending.lock();
+            continue;
+        }
+
+        if state.closed && state.by_key.is_empty() {
+            return;
+        }
+        let next_deadline = state
+            .by_key
+            .values()
+            .filter_map(|entry| entry.control.deadline())
+            .min();
+        state = match next_deadline {
+            Some(at) => {
+                let wait = at.saturating_duration_since(Instant::now());
+                pending
+                    .changed
+                    .wait_timeout(state, wait)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .0
+            }
+            None => pending
+                .changed
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::ERROR_INTERNAL;
+    use crate::transport::{LineChannel, MessageChannel};
+    use serde_json::json;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+
+    fn request(id: u64, method: &str, window: &str, timeout_ms: Option<u64>) -> RpcRequest {
+        RpcRequest {
+            id: Some(id),
+            method: method.to_string(),
+            params: json!({ "sessionName": "s", "windowName": window }),
+            timeout_ms,
+        }
+    }
+
+    #[test]
+    fn answers_out_of_order_times_out_and_cancels() {
+        let (server, client) = UnixStream::pair().expect("pair");
+        let server_channel = LineChannel::new(server.try_clone().expect("clone"), server);
+        let mut client = LineChannel::new(client.try_clone().expect("clone"), client);
+        let _ = client
+            .get_mut()
+            .set_read_timeout(Some(Duration::from_secs(5)));
+        let mut read = || -> Value {
+            client
+                .read_message()
+                .expect("read")
+                .expect("reply")
+                .expect("decodable")
+        };
+
+        let gate = Mutex::new(());
+        let runner = |req: RpcRequest, control: &RequestControl| {
+            if req.method == "slow" {
+                // Blocks like a request waiting on a busy window, and gives
+                // up like the connection's runner does, so that it answers
+                // the same as the deadline watcher if it gets there first.
+                let _guard = control.lock(&gate).map_err(|_| {
+                    request_timeout(
+                        &req.method,
+                        control.timeout_ms().unwrap_or_default(),
+                        control.elapsed().as_millis(),
+                    )
+                })?;
+            }
+            Ok(RpcResult::Value(json!({ "method": req.method })))
+        };
+        let runner: &Runner = &runner;
+
+        thread::scope(|scope| {
+            // Held inside the scope, so that a failed assertion releases it
+            // and the scope can join the lanes.
+            let held = gate.lock().expect("gate");
+            let mut pipeline = Pipeline::new(scope, server_channel.writer().clone(), runner);
+            pipeline
+                .submit(request(1, "slow", "a", None), ReplyTo::Envelope(1))
+                .unwrap_or_else(|err| panic!("submit failed: {err}"));
+            pipeline
+                .submit(request(2, "fast", "b", None), ReplyTo::Envelope(2))
+                .unwrap_or_else(|err| panic!("submit failed: {err}"));
+            pipeline
+                .submit(request(3, "slow", "c", Some(50)), ReplyTo::Envelope(3))
+                .unwrap_or_else(|err| panic!("submit failed: {err}"));
+            assert!(pipeline
+                .submit(request(1, "fast", "d", None), ReplyTo::Envelope(1))
+                .is_err());
+
+            // The window-b request and the window-c timeout overtake the
+            // blocked window-a request, in whichever order a loaded machine
+            // gets to them.
+            let mut replies = [read(), read()];
+            replies.sort_by_key(|reply| reply["id"].as_u64());
+            let [fast, timed_out] = replies;
+            assert_eq!(fast["id"], 2);
+            assert_eq!(fast["result"]["method"], "fast");
+            assert_eq!(timed_out["id"], 3);
+            assert_eq!(timed_out["error"]["code"], "REQUEST_TIMEOUT");
+
+            assert_eq!(pipeline.cancel(&json!(1)), Ok(true));
+            let cancelled = read();
+            assert_eq!(cancelled["id"], 1);
+            assert_eq!(cancelled["error"]["code"], "REQUEST_CANCELLED");
+            assert_eq!(pipeline.cancel(&json!(1)), Ok(false));
+            drop(pipeline);
+            drop(held);
+        });
+    }
+
+    #[test]
+    fn bounds_busy_lanes_and_retires_idle_ones() {
+        let (server, client) = UnixStream::pair().expect("pair");
+        let server_channel = LineChannel::new(server.try_clone().expect("clone"), server);
+        let mut client = LineChannel::new(client.try_clone().expect("clone"), client);
+        let _ = client
+            .get_mut()
+            .set_read_timeout(Some(Duration::from_secs(5)));
+
+        let gate = Mutex::new(());
+        let runner = |req: RpcRequest, control: &RequestControl| {
+            let _guard = control
+                .lock(&gate)
+                .map_err(|err| RpcError::new(ERROR_INTERNAL, err))?;
+            Ok(RpcResult::Value(json!({ "method": req.method })))
+        };
+        let runner: &Runner = &runner;
+
+        thread::scope(|scope| {
+            let held = gate.lock().expect("gate");
+            let mut pipeline = Pipeline::new(scope, server_channel.writer().clone(), runner);
+            for id in 0..MAX_LANES as u64 {
+                pipeline
+                    .submit(
+                        request(id, "slow", &format!("w{id}"), None),
+                        ReplyTo::Envelope(id),
+                    )
+                    .unwrap_or_else(|err| panic!("submit failed: {err}"));
+            }
+            let error = pipeline
+                .submit(request(100, "slow", "other", None), ReplyTo::Envelope(100))
+                .expect_err("every lane is busy");
+            assert_eq!(error.code, "INVALID_REQUEST");
+            // A busy window's lane still takes more requests.
+            pipeline
+                .submit(request(101, "slow", "w0", None), ReplyTo::Envelope(101))
+                .unwrap_or_else(|err| panic!("submit failed: {err}"));
+
+            drop(held);
+            for _ in 0..=MAX_LANES {
+                let reply = client
+                    .read_message()
+                    .expect("read")
+                    .expect("reply")
+                    .expect("decodable");
+                assert_eq!(reply["ok"], true, "{reply}");
+            }
+            while pipeline
+                .lanes
+                .values()
+                .any(|lane| lane.unfinished.load(Ordering::SeqCst) > 0)
+            {
+                thread::sleep(Duration::from_millis(5));
+            }
+            pipeline
+                .submit(request(102, "slow", "other", None), ReplyTo::Envelope(102))
+                .unwrap_or_else(|err| panic!("submit failed: {err}"));
+            assert_eq!(pipeline.lanes.len(), 1);
+            drop(pipeline);
+        });
+    }
+}
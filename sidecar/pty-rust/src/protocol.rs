@@ -0,0 +1,332 @@
+use crate::handoff::HANDOFF_METHOD;
+use crate::renderer::PatchGranularity;
+use crate::rpc::{RpcError, ERROR_FEATURE_NOT_NEGOTIATED, ERROR_INVALID_PARAMS};
+use crate::terminal_pane::MAX_VIEWPORT_OFFSET;
+use crate::transport::WireEncoding;
+use serde_json::{json, Value};
+
+/// Cancels an in-flight request; served by the connection loop.
+pub const CANCEL_METHOD: &str = "cancel";
+
+/// Protocol versions this build can speak, oldest first.
+pub const PROTOCOL_VERSIONS: &[u64] = &[1];
+
+/// Optional parts of the protocol a client opts into with `hello`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// `hello` `encoding` other than JSON.
+    BinaryFraming,
+    /// `start_recording` / `stop_recording`.
+    Recording,
+    /// `handoff` on the unix socket.
+    Handoff,
+    /// `cancel` for pipelined requests.
+    Cancellation,
+    /// `get_window_frame` `sinceRevision`, answered with patches.
+    FrameRevisions,
+    /// `get_window_frame` `viewportOffset`, scrolled into history.
+    Scrollback,
+    /// `start_window` `ambiguousWidth`.
+    AmbiguousWidth,
+    /// `get_window_frame` `granularity`, for patches with column spans.
+    PatchGranularity,
+    /// `get_window_buffer` `checkpointBytes` and `compactedBytes`. Extra
+    /// result fields gate nothing; the feature only advertises them.
+    BufferCheckpoints,
+}
+
+impl Feature {
+    pub const ALL: &'static [Feature] = &[
+        Self::BinaryFraming,
+        Self::Recording,
+        Self::Handoff,
+        Self::Cancellation,
+        Self::FrameRevisions,
+        Self::Scrollback,
+        Self::AmbiguousWidth,
+        Self::PatchGranularity,
+        Self::BufferCheckpoints,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::BinaryFraming => "binaryFraming",
+            Self::Recording => "recording",
+            Self::Handoff => "handoff",
+            Self::Cancellation => "cancellation",
+            Self::FrameRevisions => "frameRevisions",
+            Self::Scrollback => "scrollback",
+            Self::AmbiguousWidth => "ambiguousWidth",
+            Self::PatchGranularity => "patchGranularity",
+            Self::BufferCheckpoints => "bufferCheckpoints",
+        }
+    }
+}
+
+/// Every method and the feature it needs, if any.
+pub const METHODS: &[(&str, Option<Feature>)] = &[
+    ("hello", None),
+    ("health", None),
+    ("get_or_create_session", None),
+    ("set_session_env", None),
+    ("window_exists", None),
+    ("start_window", None),
+    ("type_keys", None),
+    ("send_enter", None),
+    ("resize_window", None),
+    ("list_windows", None),
+    ("get_window_buffer", None),
+    ("get_window_frame", None),
+    ("start_recording", Some(Feature::Recording)),
+    ("stop_recording", Some(Feature::Recording)),
+    ("stop_window", None),
+    ("dispose", None),
+    (HANDOFF_METHOD, Some(Feature::Handoff)),
+    (CANCEL_METHOD, Some(Feature::Cancellation)),
+];
+
+/// Optional params and the feature each needs, as `(method, param,
+/// feature)`. A request that passes one of them without its feature is
+/// rejected like a method outside the negotiated features.
+pub const PARAMS: &[(&str, &str, Feature)] = &[
+    ("get_window_frame", "sinceRevision", Feature::FrameRevisions),
+    ("get_window_frame", "viewportOffset", Feature::Scrollback),
+    ("start_window", "ambiguousWidth", Feature::AmbiguousWidth),
+    ("get_window_frame", "granularity", Feature::PatchGranularity),
+];
+
+/// What a connection agreed on in `hello`. A client that never lists its
+/// features gets all of them, as before negotiation existed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Negotiation {
+    pub version: u64,
+    pub features: Vec<Feature>,
+}
+
+impl Default for Negotiation {
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSIONS[PROTOCOL_VERSIONS.len() - 1],
+            features: Feature::ALL.to_vec(),
+        }
+    }
+}
+
+impl Negotiation {
+    /// Reads `versions` and `features` from `hello` params. The highest
+    /// common version wins; feature names this build does not know are
+    /// ignored so that newer clients can talk to older sidecars.
+    pub fn from_hello(params: &Value) -> Result<Self, RpcError> {
+        let mut negotiation = Self::default();
+
+        if let Some(raw) = params.get("versions") {
+            let offered = raw
+                .as_array()
+                .and_then(|versions| {
+                    versions
+                        .iter()
+                        .map(Value::as_u64)
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| {
+                    RpcError::new(
+                        ERROR_INVALID_PARAMS,
+                        "versions must be an array of integers",
+                    )
+                })?;
+            negotiation.version = PROTOCOL_VERSIONS
+                .iter()
+                .rev()
+                .copied()
+                .find(|version| offered.contains(version))
+                .ok_or_else(|| {
+                    RpcError::new(
+                        ERROR_INVALID_PARAMS,
+                        format!(
+                            "no common protocol version; client offered {offered:?}, sidecar supports {PROTOCOL_VERSIONS:?}"
+                        ),
+                    )
+                })?;
+        }
+
+        if let Some(raw) = params.get("features") {
+            let requested = raw
+                .as_array()
+                .and_then(|features| {
+                    features
+                        .iter()
+                        .map(Value::as_str)
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| {
+                    RpcError::new(ERROR_INVALID_PARAMS, "features must be an array of strings")
+                })?;
+            negotiation.features = Feature::ALL
+                .iter()
+                .copied()
+                .filter(|feature| requested.contains(&feature.name()))
+                .collect();
+        }
+
+        Ok(negotiation)
+    }
+
+    pub fn allows(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    /// Rejects a call of `method` with `params` if the method or one of the
+    /// params given belongs to a feature that was not negotiated. Unknown
+    /// methods pass through and fail later as `UNKNOWN_METHOD`.
+    pub fn check_request(&self, method: &str, params: &Value) -> Result<(), RpcError> {
+        let required = METHODS
+            .iter()
+            .find(|(name, _)| *name == method)
+            .and_then(|(_, feature)| *feature);
+        if let Some(feature) = required {
+            self.require(feature, method)?;
+        }
+        for (_, param, feature) in PARAMS
+            .iter()
+            .filter(|(name, param, _)| *name == method && !params[*param].is_null())
+        {
+            self.require(*feature, &format!("{method} {param}"))?;
+        }
+        Ok(())
+    }
+
+    pub fn check_encoding(&self, encoding: WireEncoding) -> Result<(), RpcError> {
+        if encoding.is_binary() {
+            self.require(
+                Feature::BinaryFraming,
+                &format!("{} encoding", encoding.name()),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn require(&self, feature: Feature, what: &str) -> Result<(), RpcError> {
+        if self.allows(feature) {
+            return Ok(());
+        }
+        Err(RpcError::new(
+            ERROR_FEATURE_NOT_NEGOTIATED,
+            format!(
+                "{what} needs the {} feature, which was not negotiated in hello",
+                feature.name()
+            ),
+        ))
+    }
+
+    /// The `hello` result: negotiated version, callable methods and the
+    /// negotiated capabilities.
+    pub fn hello_result(&self) -> Value {
+        let methods = METHODS
+            .iter()
+            .filter(|(_, feature)| feature.is_none_or(|feature| self.allows(feature)))
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        let mut capabilities = serde_json::Map::new();
+        for feature in &self.features {
+            let detail = match feature {
+                Feature::BinaryFraming => json!({ "encodings": ["json", "msgpack"] }),
+                Feature::Recording => json!({ "format": "asciicast-v2" }),
+                Feature::Handoff => json!(true),
+                Feature::Cancellation => json!(true),
+                Feature::FrameRevisions => json!({ "patches": true }),
+                Feature::Scrollback => json!({ "maxViewportOffset": MAX_VIEWPORT_OFFSET }),
+                Feature::AmbiguousWidth => json!({ "columns": [1, 2] }),
+                Feature::PatchGranularity => {
+                    json!({ "granularities": PatchGranularity::NAMES })
+                }
+                Feature::BufferCheckpoints => {
+                    json!({ "fields": ["checkpointBytes", "compactedBytes"] })
+                }
+            };
+            capabilities.insert(feature.name().to_string(), detail);
+        }
+        json!({
+            "version": self.version,
+            "versions": PROTOCOL_VERSIONS,
+            "methods": methods,
+            "capabilities": capabilities,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_highest_common_version_and_known_features() {
+        let negotiation = Negotiation::from_hello(&json!({
+            "versions": [1, 7],
+            "features": ["recording", "subscriptions"],
+        }))
+        .unwrap_or_else(|err| panic!("negotiation failed: {err}"));
+        assert_eq!(negotiation.version, 1);
+        assert_eq!(negotiation.features, vec![Feature::Recording]);
+
+        let result = negotiation.hello_result();
+        assert_eq!(result["version"], 1);
+        assert!(result["capabilities"]["recording"].is_object());
+        assert!(result["capabilities"].get("binaryFraming").is_none());
+        assert!(result["capabilities"].get("scrollback").is_none());
+        let methods = result["methods"].as_array().expect("methods");
+        assert!(methods.contains(&json!("start_recording")));
+        assert!(!methods.contains(&json!(HANDOFF_METHOD)));
+
+        let legacy = Negotiation::from_hello(&json!({ "token": "t" }))
+            .unwrap_or_else(|err| panic!("legacy hello failed: {err}"));
+        assert_eq!(legacy, Negotiation::default());
+
+        let error =
+            Negotiation::from_hello(&json!({ "versions": [9] })).expect_err("no common version");
+        assert_eq!(error.code, ERROR_INVALID_PARAMS);
+        assert!(Negotiation::from_hello(&json!({ "features": "recording" })).is_err());
+    }
+
+    #[test]
+    fn rejects_methods_and_encodings_outside_negotiated_features() {
+        let negotiation = Negotiation::from_hello(&json!({ "features": [] }))
+            .unwrap_or_else(|err| panic!("empty features failed: {err}"));
+        let error = negotiation
+            .check_request("start_recording", &json!({}))
+            .expect_err("recording not negotiated");
+        assert_eq!(error.code, ERROR_FEATURE_NOT_NEGOTIATED);
+        assert!(negotiation
+            .check_request("get_window_frame", &json!({}))
+            .is_ok());
+        assert!(negotiation
+            .check_request("no_such_method", &json!({}))
+            .is_ok());
+        assert!(negotiation.check_encoding(WireEncoding::Json).is_ok());
+        assert!(negotiation
+            .check_encoding(WireEncoding::MessagePack)
+            .is_err());
+
+        let error = negotiation
+            .check_request("get_window_frame", &json!({ "sinceRevision": 4 }))
+            .expect_err("frame revisions not negotiated");
+        assert_eq!(error.code, ERROR_FEATURE_NOT_NEGOTIATED);
+        assert!(error.message.contains("sinceRevision"), "{}", error.message);
+        assert!(negotiation
+            .check_request("get_window_frame", &json!({ "sinceRevision": null }))
+            .is_ok());
+        assert!(Negotiation::default()
+            .check_request("get_window_frame", &json!({ "sinceRevision": 4 }))
+            .is_ok());
+
+        let error = negotiation
+            .check_request("get_window_frame", &json!({ "viewportOffset": 10 }))
+            .expect_err("scrollback not negotiated");
+        assert!(error.message.contains("scrollback"), "{}", error.message);
+        assert!(negotiation
+            .check_request("start_window", &json!({ "ambiguousWidth": 2 }))
+            .is_err());
+        assert!(negotiation
+            .check_request("get_window_frame", &json!({ "granularity": "spans" }))
+            .is_err());
+    }
+}
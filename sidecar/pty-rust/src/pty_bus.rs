@@ -1,26 +1,49 @@
-use crate::query_policy::build_terminal_response;
+use crate::child_watchdog::ChildWatchdog;
+use crate::input_queue::InputQueue;
+use crate::output_flow::{Admit, KeptTail, OutputMeter, TAIL_FLUSH_INTERVAL};
+use crate::query_policy::QueryResponder;
+use crate::recording::CastRecorder;
 use crate::session_manager::{
     lock_state, lock_window, mark_output_mutation, transition_window_state, SharedSidecarState,
     SharedWindowState, WindowLifecycleState, WindowState,
 };
-use crate::terminal_pane::build_styled_frame;
-use portable_pty::{native_pty_system, CommandBuilder, PtySize};
+use portable_pty::{
+    native_pty_system, Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtySize,
+};
 use std::collections::BTreeMap;
+use std::fs::File;
 use std::io::ErrorKind;
 use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
 use std::path::PathBuf;
-use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How often an idle reader checks whether it has been asked to stop.
+const READER_STOP_CHECK: Duration = Duration::from_millis(100);
+// How long group members get after SIGHUP before the group is SIGKILLed.
+const GROUP_HANGUP_GRACE: Duration = Duration::from_millis(200);
+// Allowed drift between a recorded `startedAt` and a live process' start time
+// before a leftover pid is assumed to have been reused.
+const LEFTOVER_START_TOLERANCE_SECS: i64 = 5;
+// A checkpoint keeps history lines worth up to this fraction of the buffer
+// limit, so that scrolling back still reaches past it.
+pub const CHECKPOINT_HISTORY_SHARE: usize = 4;
 
+/// Queues `input` for the window's writer thread; it is written to the PTY
+/// after the call returns.
 pub fn write_input(window: &mut WindowState, input: &[u8]) -> Result<(), String> {
-    let writer = window
-        .writer
-        .as_mut()
-        .ok_or_else(|| "window writer unavailable".to_string())?;
-    writer
-        .write_all(input)
-        .map_err(|e| format!("write input failed: {e}"))?;
-    writer.flush().map_err(|e| format!("flush failed: {e}"))
+    window
+        .input
+        .as_ref()
+        .ok_or_else(|| "window writer unavailable".to_string())?
+        .push(input)?;
+    record(window, |recorder| {
+        recorder.record_input(&String::from_utf8_lossy(input))
+    });
+    Ok(())
 }
 
 pub fn resize_window(window: &mut WindowState, cols: u16, rows: u16) {
@@ -34,9 +57,23 @@ pub fn resize_window(window: &mut WindowState, cols: u16, rows: u16) {
     }
     window.snapshot.cols = cols;
     window.snapshot.rows = rows;
+    window.rebuild_pane();
+    record(window, |recorder| recorder.record_resize(cols, rows));
     mark_output_mutation(window);
 }
 
+/// Adds an event to the window's recording, if any. A failed write ends the
+/// recording; the error is kept for `stop_recording` to report.
+fn record(window: &mut WindowState, event: impl FnOnce(&mut CastRecorder) -> Result<(), String>) {
+    let Some(recorder) = window.recorder.as_mut() else {
+        return;
+    };
+    if let Err(err) = event(recorder) {
+        window.recorder = None;
+        window.recording_error = Some(err);
+    }
+}
+
 pub fn stop_window(window: &mut WindowState) -> Result<bool, String> {
     if let Some(child) = window.child.as_mut() {
         if let Err(err) = child.kill() {
@@ -50,7 +87,7 @@ pub fn stop_window(window: &mut WindowState) -> Result<bool, String> {
         window.snapshot.exit_code = None;
         window.child = None;
         window.master = None;
-        window.writer = None;
+        window.input = None;
         return Ok(true);
     }
 
@@ -64,17 +101,62 @@ pub fn stop_window(window: &mut WindowState) -> Result<bool, String> {
     Ok(true)
 }
 
-pub fn dispose_window(window: &mut WindowState) {
+pub fn dispose_window(window: &mut WindowState, reason: &str) {
     if let Some(child) = window.child.as_mut() {
         let _ = child.kill();
     }
     window.child = None;
-    window.writer = None;
+    window.input = None;
     window.master = None;
-    let _ = transition_window_state(window, WindowLifecycleState::Exited, "dispose");
+    if let Some(recorder) = window.recorder.take() {
+        if let Err(err) = recorder.finish() {
+            window.recording_error = Some(err);
+        }
+    }
+    let _ = transition_window_state(window, WindowLifecycleState::Exited, reason);
     window.snapshot.exited_at = Some(now_unix_seconds());
 }
 
+/// Sends SIGTERM to every window's child, waits up to `grace` for them to
+/// exit, then disposes all windows (which hangs up and kills stragglers).
+pub fn shutdown_windows(state: &SharedSidecarState, grace: Duration, reason: &str) {
+    let windows = {
+        let guard = lock_state(state);
+        guard.windows.values().cloned().collect::<Vec<_>>()
+    };
+
+    for window in &windows {
+        let w = lock_window(window);
+        if let Some(pid) = w.child.as_ref().and_then(|child| child.process_id()) {
+            signal_group(pid, libc::SIGTERM);
+        }
+    }
+
+    let deadline = Instant::now() + grace;
+    while Instant::now() < deadline {
+        let pending = windows
+            .iter()
+            .filter(|window| child_is_running(&mut lock_window(window)))
+            .count();
+        if pending == 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    for window in &windows {
+        dispose_window(&mut lock_window(window), reason);
+    }
+}
+
+fn child_is_running(window: &mut WindowState) -> bool {
+    window
+        .child
+        .as_mut()
+        .map(|child| matches!(child.try_wait(), Ok(None)))
+        .unwrap_or(false)
+}
+
 pub fn spawn_window_process(
     state: &SharedSidecarState,
     window: &SharedWindowState,
@@ -120,48 +202,457 @@ pub fn spawn_window_process(
         .slave
         .spawn_command(cmd)
         .map_err(|e| format!("spawn failed: {e}"))?;
+    let child = GroupChild::new(child, lock_state(state).child_watchdog.clone());
     let pid = child.process_id();
-    let mut reader = pair
+    let reader = pair
         .master
-        .try_clone_reader()
-        .map_err(|e| format!("clone reader failed: {e}"))?;
+        .as_raw_fd()
+        .ok_or_else(|| "pty master has no file descriptor".to_string())
+        .and_then(PtyReader::dup)?;
     let writer = pair
         .master
         .take_writer()
         .map_err(|e| format!("take writer failed: {e}"))?;
 
+    let input = spawn_input_queue(state, writer);
+
     {
         let mut w = lock_window(window);
         transition_window_state(&mut w, WindowLifecycleState::Running, "spawned")?;
         w.snapshot.pid = pid;
         w.master = Some(pair.master);
-        w.child = Some(child);
-        w.writer = Some(writer);
+        w.child = Some(Box::new(child));
+        w.input = Some(input);
         w.launch_env = launch_env.into_iter().collect();
-        w.query_carry.clear();
         w.private_modes.clear();
-        w.buffer.push_str(&format!(
-            "[runtime] process started (pid={})\n",
-            pid.unwrap_or(0)
-        ));
+        append_note(
+            &mut w,
+            &format!("[runtime] process started (pid={})\n", pid.unwrap_or(0)),
+        );
+    }
+
+    spawn_reader_thread(state, window, reader, lifecycle_generation);
+    Ok(())
+}
+
+/// Takes over a PTY whose master fd was handed over by a previous sidecar
+/// instance. The child was not spawned by this process, so it cannot be
+/// reaped here and its exit code is reported as unknown.
+pub fn adopt_window_process(
+    state: &SharedSidecarState,
+    window: &SharedWindowState,
+    master_fd: OwnedFd,
+    pid: u32,
+) -> Result<(), String> {
+    let master = AdoptedMaster { fd: master_fd };
+    let reader = PtyReader::dup(master.fd.as_raw_fd())?;
+    let writer = master
+        .take_writer()
+        .map_err(|e| format!("take writer failed: {e}"))?;
+
+    let child = GroupChild::new(
+        Box::new(AdoptedChild { pid }),
+        lock_state(state).child_watchdog.clone(),
+    );
+    let input = spawn_input_queue(state, writer);
+    let lifecycle_generation = {
+        let mut w = lock_window(window);
+        w.lifecycle_generation = w.lifecycle_generation.saturating_add(1);
+        w.snapshot.pid = Some(pid);
+        w.master = Some(Box::new(master));
+        w.child = Some(Box::new(child));
+        w.input = Some(input);
         mark_output_mutation(&mut w);
+        w.lifecycle_generation
+    };
+
+    spawn_reader_thread(state, window, reader, lifecycle_generation);
+    Ok(())
+}
+
+fn spawn_input_queue(state: &SharedSidecarState, writer: Box<dyn Write + Send>) -> InputQueue {
+    let (capacity, write_timeout) = {
+        let guard = lock_state(state);
+        (guard.input_queue_bytes, guard.input_write_timeout)
+    };
+    InputQueue::spawn(writer, capacity, write_timeout)
+}
+
+/// A window's child process. Every window runs in its own session, so the
+/// child's pid is also its process group id. The group is registered with
+/// the sidecar's watchdog while this handle lives, and `kill` takes down the
+/// whole group rather than only the leader.
+#[derive(Debug)]
+struct GroupChild {
+    inner: Box<dyn Child + Send>,
+    pgid: Option<u32>,
+    watchdog: Option<ChildWatchdog>,
+}
+
+impl GroupChild {
+    fn new(inner: Box<dyn Child + Send>, watchdog: Option<ChildWatchdog>) -> Self {
+        let pgid = inner.process_id();
+        if let (Some(watchdog), Some(pgid)) = (watchdog.as_ref(), pgid) {
+            watchdog.track(pgid);
+        }
+        Self {
+            inner,
+            pgid,
+            watchdog,
+        }
+    }
+}
+
+impl Drop for GroupChild {
+    fn drop(&mut self) {
+        if let (Some(watchdog), Some(pgid)) = (self.watchdog.as_ref(), self.pgid) {
+            watchdog.untrack(pgid);
+        }
+    }
+}
+
+impl ChildKiller for GroupChild {
+    fn kill(&mut self) -> std::io::Result<()> {
+        if let Some(pgid) = self.pgid {
+            signal_group(pgid, libc::SIGHUP);
+        }
+        let result = self.inner.kill();
+        if let Some(pgid) = self.pgid {
+            terminate_group(pgid, GROUP_HANGUP_GRACE);
+        }
+        result
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        self.inner.clone_killer()
+    }
+}
+
+impl Child for GroupChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        self.inner.try_wait()
+    }
+
+    fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        self.inner.wait()
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        self.inner.process_id()
     }
+}
+
+fn signal_group(pgid: u32, signo: libc::c_int) -> bool {
+    unsafe { libc::kill(-(pgid as libc::pid_t), signo) == 0 }
+}
+
+/// Waits up to `grace` for every member of `pgid` to exit, then SIGKILLs
+/// whatever is left.
+fn terminate_group(pgid: u32, grace: Duration) {
+    let deadline = Instant::now() + grace;
+    while signal_group(pgid, 0) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+    }
+    signal_group(pgid, libc::SIGKILL);
+}
+
+/// Kills the process group of a window that was running when a previous
+/// sidecar instance died without cleaning up. The pid is only trusted if it
+/// still leads its own session and, where `/proc` is available, is not a
+/// zombie and was started around `started_at`. Returns whether a group was
+/// killed.
+pub fn reap_leftover_process(pid: u32, started_at: Option<i64>) -> bool {
+    let leads_session = unsafe { libc::getsid(pid as libc::pid_t) } == pid as libc::pid_t;
+    if !leads_session {
+        return false;
+    }
+    if let Some(stat) = read_process_stat(pid) {
+        if stat.zombie {
+            return false;
+        }
+        if let Some(expected) = started_at {
+            if (expected - stat.started_at_unix).abs() > LEFTOVER_START_TOLERANCE_SECS {
+                return false;
+            }
+        }
+    }
+    if !signal_group(pid, libc::SIGHUP) {
+        return false;
+    }
+    signal_group(pid, libc::SIGCONT);
+    terminate_group(pid, GROUP_HANGUP_GRACE);
+    true
+}
+
+struct ProcessStat {
+    zombie: bool,
+    started_at_unix: i64,
+}
+
+#[cfg(target_os = "linux")]
+fn read_process_stat(pid: u32) -> Option<ProcessStat> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // Fields after the parenthesised command name: state is field 3 and
+    // starttime (clock ticks since boot) is field 22.
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace();
+    let zombie = fields.next()? == "Z";
+    let start_ticks = fields.nth(18)?.parse::<i64>().ok()?;
+    let boot_time = std::fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse::<i64>()
+        .ok()?;
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return None;
+    }
+    Some(ProcessStat {
+        zombie,
+        started_at_unix: boot_time + start_ticks / ticks_per_second as i64,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_process_stat(_pid: u32) -> Option<ProcessStat> {
+    None
+}
+
+struct AdoptedMaster {
+    fd: OwnedFd,
+}
+
+impl AdoptedMaster {
+    fn dup_file(&self) -> anyhow::Result<File> {
+        Ok(File::from(self.fd.try_clone()?))
+    }
+}
+
+impl MasterPty for AdoptedMaster {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        let winsize = libc::winsize {
+            ws_row: size.rows,
+            ws_col: size.cols,
+            ws_xpixel: size.pixel_width,
+            ws_ypixel: size.pixel_height,
+        };
+        let result = unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                libc::TIOCSWINSZ as _,
+                &winsize as *const libc::winsize,
+            )
+        };
+        if result != 0 {
+            anyhow::bail!(
+                "ioctl(TIOCSWINSZ) failed: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        let mut winsize = libc::winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let result = unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                libc::TIOCGWINSZ as _,
+                &mut winsize as *mut libc::winsize,
+            )
+        };
+        if result != 0 {
+            anyhow::bail!(
+                "ioctl(TIOCGWINSZ) failed: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(PtySize {
+            rows: winsize.ws_row,
+            cols: winsize.ws_col,
+            pixel_width: winsize.ws_xpixel,
+            pixel_height: winsize.ws_ypixel,
+        })
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(PtyReader(self.dup_file()?)))
+    }
+
+    fn take_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.dup_file()?))
+    }
+
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        match unsafe { libc::tcgetpgrp(self.fd.as_raw_fd()) } {
+            pid if pid > 0 => Some(pid),
+            _ => None,
+        }
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(self.fd.as_raw_fd())
+    }
+}
+
+/// Reading end of a PTY master, on its own copy of the fd so that the reader
+/// thread can poll it.
+struct PtyReader(File);
 
-    let max_buffer = {
+impl PtyReader {
+    fn dup(master_fd: RawFd) -> Result<Self, String> {
+        // The master outlives this call; the copy is owned by the reader.
+        let fd = unsafe { BorrowedFd::borrow_raw(master_fd) }
+            .try_clone_to_owned()
+            .map_err(|e| format!("clone reader failed: {e}"))?;
+        Ok(Self(File::from(fd)))
+    }
+
+    /// Whether output is waiting, or the fd needs a `read` to report its
+    /// state, within `timeout`.
+    fn wait_readable(&self, timeout: Duration) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd: self.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        unsafe { libc::poll(&mut pollfd, 1, timeout_ms) != 0 }
+    }
+}
+
+impl Read for PtyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.0.read(buf) {
+            // EIO means the slave side has been closed; treat it as EOF.
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => Ok(0),
+            other => other,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AdoptedChild {
+    pid: u32,
+}
+
+impl AdoptedChild {
+    fn is_alive(&self) -> bool {
+        let result = unsafe { libc::kill(self.pid as libc::pid_t, 0) };
+        result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    fn exit_status_unavailable() -> std::io::Error {
+        std::io::Error::other("exit status of an adopted process is unavailable")
+    }
+}
+
+impl ChildKiller for AdoptedChild {
+    fn kill(&mut self) -> std::io::Result<()> {
+        let result = unsafe { libc::kill(self.pid as libc::pid_t, libc::SIGHUP) };
+        if result != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ESRCH) {
+                return Err(std::io::Error::new(ErrorKind::NotFound, err));
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(AdoptedChild { pid: self.pid })
+    }
+}
+
+impl Child for AdoptedChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        if self.is_alive() {
+            return Ok(None);
+        }
+        Err(Self::exit_status_unavailable())
+    }
+
+    /// Only the process' parent can learn its exit status, so there is
+    /// nothing to wait for; the status is reported as unavailable at once.
+    fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        Err(Self::exit_status_unavailable())
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        Some(self.pid)
+    }
+}
+
+fn spawn_reader_thread(
+    state: &SharedSidecarState,
+    window: &SharedWindowState,
+    mut reader: PtyReader,
+    lifecycle_generation: u64,
+) {
+    let (max_buffer, output_flow) = {
         let guard = lock_state(state);
-        guard.max_buffer_bytes
+        (guard.max_buffer_bytes, guard.output_flow)
     };
 
     let read_window = window.clone();
-    thread::spawn(move || {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_requested = stop.clone();
+    let thread = thread::spawn(move || {
         let mut buf = [0u8; 4096];
+        let mut meter = OutputMeter::new(output_flow);
         loop {
+            let wait = if meter.has_tail() {
+                TAIL_FLUSH_INTERVAL
+            } else {
+                READER_STOP_CHECK
+            };
+            let readable = reader.wait_readable(wait);
+            if stop_requested.load(Ordering::SeqCst) {
+                // Whatever was read goes to the window before the PTY is read
+                // by someone else.
+                if let Ok(mut w) = read_window.lock() {
+                    if w.lifecycle_generation == lifecycle_generation {
+                        if let Some(tail) = meter.take_tail() {
+                            append_tail(&mut w, tail, max_buffer);
+                        }
+                        w.output_stats = meter.stats();
+                    }
+                }
+                break;
+            }
+            if !readable {
+                if !meter.has_tail() {
+                    continue;
+                }
+                // The output went quiet; show what was kept of it.
+                let Ok(mut w) = read_window.lock() else { break };
+                if w.lifecycle_generation != lifecycle_generation {
+                    break;
+                }
+                if let Some(tail) = meter.take_tail() {
+                    append_tail(&mut w, tail, max_buffer);
+                }
+                meter.settle();
+                w.output_stats = meter.stats();
+                continue;
+            }
             match reader.read(&mut buf) {
                 Ok(0) => {
                     if let Ok(mut w) = read_window.lock() {
                         if w.lifecycle_generation != lifecycle_generation {
                             break;
                         }
+                        if let Some(tail) = meter.take_tail() {
+                            append_tail(&mut w, tail, max_buffer);
+                        }
+                        w.output_stats = meter.stats();
                         if w.snapshot.status == "running" || w.snapshot.status == "starting" {
                             let mut exit_code = None;
                             if let Some(child) = w.child.as_mut() {
@@ -187,57 +678,48 @@ pub fn spawn_window_process(
                             w.snapshot.exit_code = exit_code;
                             w.child = None;
                             w.master = None;
-                            w.writer = None;
-                            w.buffer.push_str(&format!(
+                            w.input = None;
+                            let note = format!(
                                 "[runtime] process exited (code={}, signal={})\n",
                                 exit_code
                                     .map(|code| code.to_string())
                                     .unwrap_or_else(|| "null".to_string()),
                                 "null"
-                            ));
+                            );
+                            append_note(&mut w, &note);
                         }
                     }
                     break;
                 }
                 Ok(n) => {
-                    if let Ok(mut w) = read_window.lock() {
+                    let admit = meter.admit(n);
+                    if admit == Admit::Divert {
+                        if let Some(overflow) = meter.divert(&buf[..n]) {
+                            let Ok(mut w) = read_window.lock() else { break };
+                            if w.lifecycle_generation != lifecycle_generation {
+                                break;
+                            }
+                            skip_output(&mut w, &overflow, max_buffer);
+                            w.output_stats = meter.stats();
+                        }
+                        continue;
+                    }
+                    {
+                        let Ok(mut w) = read_window.lock() else { break };
                         if w.lifecycle_generation != lifecycle_generation {
                             break;
                         }
-                        let text = String::from_utf8_lossy(&buf[..n]);
-                        w.buffer.push_str(&text);
-                        if w.buffer.len() > max_buffer {
-                            trim_buffer_to_max_bytes(&mut w.buffer, max_buffer);
-                        }
-                        mark_output_mutation(&mut w);
-
-                        if text.contains('\x1b') {
-                            let cols = w.snapshot.cols;
-                            let rows = w.snapshot.rows;
-                            let frame = build_styled_frame(&w.buffer, cols, rows);
-                            let cursor_row = frame["cursorRow"].as_u64().unwrap_or(0) as usize;
-                            let cursor_col = frame["cursorCol"].as_u64().unwrap_or(0) as usize;
-                            let mut query_carry = std::mem::take(&mut w.query_carry);
-                            let mut private_modes = std::mem::take(&mut w.private_modes);
-
-                            let response = build_terminal_response(
-                                &mut query_carry,
-                                &mut private_modes,
-                                &text,
-                                cols,
-                                rows,
-                                cursor_row,
-                                cursor_col,
-                            );
-                            w.query_carry = query_carry;
-                            w.private_modes = private_modes;
-                            if !response.is_empty() {
-                                if let Some(writer) = w.writer.as_mut() {
-                                    let _ = writer.write_all(response.as_bytes());
-                                    let _ = writer.flush();
-                                }
-                            }
+                        if let Some(tail) = meter.take_tail() {
+                            append_tail(&mut w, tail, max_buffer);
                         }
+                        append_output(&mut w, &buf[..n], max_buffer);
+                        w.output_stats = meter.stats();
+                    }
+                    if let Admit::Pause(pause) = admit {
+                        // Not reading lets the PTY fill up, which blocks the
+                        // program's writes until the window catches up.
+                        thread::sleep(pause);
+                        meter.record_pause(pause);
                     }
                 }
                 Err(_) => {
@@ -253,31 +735,181 @@ pub fn spawn_window_process(
                         w.snapshot.exited_at = Some(now_unix_seconds());
                         w.child = None;
                         w.master = None;
-                        w.writer = None;
-                        w.buffer
-                            .push_str("[runtime] process error: pty read failed\n");
+                        w.input = None;
+                        append_note(&mut w, "[runtime] process error: pty read failed\n");
                     }
                     break;
                 }
             }
         }
     });
+    lock_window(window).reader = Some(ReaderHandle { stop, thread });
+}
 
+/// A window's reader thread.
+pub struct ReaderHandle {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+/// Stops the reader threads of `windows` and waits for them to exit, after
+/// they have appended what they already read. Output written from then on
+/// stays in the PTY until a reader is started again.
+pub fn stop_reading(windows: &[SharedWindowState]) {
+    let readers = windows
+        .iter()
+        .filter_map(|window| lock_window(window).reader.take())
+        .collect::<Vec<_>>();
+    for reader in &readers {
+        reader.stop.store(true, Ordering::SeqCst);
+    }
+    for reader in readers {
+        let _ = reader.thread.join();
+    }
+}
+
+/// Starts reading a window's PTY again after `stop_reading`.
+pub fn resume_reading(
+    state: &SharedSidecarState,
+    window: &SharedWindowState,
+) -> Result<(), String> {
+    let (reader, lifecycle_generation) = {
+        let w = lock_window(window);
+        let fd = w
+            .master
+            .as_ref()
+            .and_then(|master| master.as_raw_fd())
+            .ok_or_else(|| "pty master has no file descriptor".to_string())?;
+        (PtyReader::dup(fd)?, w.lifecycle_generation)
+    };
+    spawn_reader_thread(state, window, reader, lifecycle_generation);
     Ok(())
 }
 
-fn trim_buffer_to_max_bytes(buffer: &mut String, max_bytes: usize) {
-    if buffer.len() <= max_bytes {
-        return;
+/// Appends PTY output to the window buffer and feeds it to the window's
+/// pane, which answers the terminal queries in it.
+fn append_output(w: &mut WindowState, bytes: &[u8], max_buffer: usize) {
+    let text = decode_output(&mut w.utf8_carry, bytes);
+    w.buffer.push_str(&text);
+    record(w, |recorder| recorder.record_output(&text));
+    feed_pane(w, bytes);
+    compact_buffer(w, max_buffer);
+    mark_output_mutation(w);
+}
+
+/// Decodes `bytes` following the `carry` left by the previous read. A
+/// character cut off at the end is left in `carry` for the next read.
+fn decode_output(carry: &mut Vec<u8>, bytes: &[u8]) -> String {
+    carry.extend_from_slice(bytes);
+    let complete = carry.len() - incomplete_utf8_len(carry);
+    let text = String::from_utf8_lossy(&carry[..complete]).into_owned();
+    carry.drain(..complete);
+    text
+}
+
+/// Length of the start of a multi-byte UTF-8 character at the end of
+/// `bytes` that is still missing bytes.
+fn incomplete_utf8_len(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(3) {
+        let needed = match bytes[bytes.len() - back] {
+            0x80..=0xbf => continue,
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => return 0,
+        };
+        return if needed > back { back } else { 0 };
     }
+    0
+}
+
+/// Feeds output the reader skipped while throttled to the window's pane, so
+/// that the styles, modes and screen switches in it still apply. The output
+/// itself is not kept, so the buffer is replaced by a checkpoint of the pane.
+fn skip_output(w: &mut WindowState, bytes: &[u8], max_buffer: usize) {
+    let text = decode_output(&mut w.utf8_carry, bytes);
+    feed_pane(w, bytes);
+    let mut raw = w.buffer.split_off(w.checkpoint_bytes);
+    let kept_raw = raw.len();
+    raw.push_str(&text);
+    // As in `compact_buffer`, an unfinished escape sequence stays raw.
+    let raw_start = pending_escape_start(w, &raw, max_buffer / 2);
+    let checkpoint = w
+        .pane
+        .serialize_state(max_buffer / CHECKPOINT_HISTORY_SHARE);
+    w.pane.drop_unused_cell_data();
+    w.compacted_bytes += raw_start.min(kept_raw) as u64;
+    w.checkpoint_bytes = checkpoint.len();
+    w.buffer = checkpoint + &raw[raw_start..];
+    mark_output_mutation(w);
+}
 
-    let overflow = buffer.len() - max_bytes;
-    let mut start = overflow;
-    while start < buffer.len() && !buffer.is_char_boundary(start) {
-        start += 1;
+fn append_tail(w: &mut WindowState, tail: KeptTail, max_buffer: usize) {
+    if !tail.skipped.is_empty() {
+        skip_output(w, &tail.skipped, max_buffer);
     }
+    append_output(w, &tail.output, max_buffer);
+}
+
+fn feed_pane(w: &mut WindowState, bytes: &[u8]) {
+    let mut responder = QueryResponder::new(&mut w.private_modes, w.snapshot.cols, w.snapshot.rows);
+    w.pane.feed_answering(bytes, &mut responder);
+    let response = responder.into_response();
+    if !response.is_empty() {
+        if let Some(input) = w.input.as_ref() {
+            let _ = input.push(response.as_bytes());
+        }
+    }
+}
+
+/// Appends a `[runtime]` line of the sidecar's own to the window output.
+pub fn append_note(w: &mut WindowState, note: &str) {
+    w.buffer.push_str(note);
+    w.pane.feed(note.as_bytes());
+    mark_output_mutation(w);
+}
 
-    buffer.drain(..start);
+/// Once the buffer outgrows `max_bytes`, replaces it with a checkpoint of the
+/// pane's terminal state and its newest history lines, so that replaying it
+/// still gives the same screen and scrollback. The pane then lets go of the
+/// styles and clusters that are no longer on screen or in its history.
+/// Raw output is only compacted once there is at least half a buffer of it,
+/// even if the checkpoint itself is large.
+fn compact_buffer(w: &mut WindowState, max_bytes: usize) {
+    let keep = max_bytes / 2;
+    if w.buffer.len() <= max_bytes || w.buffer.len() - w.checkpoint_bytes < keep {
+        return;
+    }
+    // An escape sequence still unfinished at the end stays raw output, unless
+    // it is too long to ever finish; then it is cut like plain output.
+    let raw_start = pending_escape_start(w, &w.buffer, keep);
+    let checkpoint = w.pane.serialize_state(max_bytes / CHECKPOINT_HISTORY_SHARE);
+    w.pane.drop_unused_cell_data();
+
+    let mut buffer = String::with_capacity(checkpoint.len() + w.buffer.len() - raw_start);
+    buffer.push_str(&checkpoint);
+    buffer.push_str(&w.buffer[raw_start..]);
+    w.compacted_bytes += raw_start.saturating_sub(w.checkpoint_bytes) as u64;
+    w.checkpoint_bytes = checkpoint.len();
+    w.buffer = buffer;
+}
+
+/// Where the escape sequence the pane has not seen the end of starts in
+/// `raw`, the end of the buffer, or `raw.len()` if it is longer than `limit`.
+fn pending_escape_start(w: &WindowState, raw: &str, limit: usize) -> usize {
+    // The pane has seen the carried bytes, which are not in the buffer yet.
+    let pending = w
+        .pane
+        .pending_escape_len()
+        .saturating_sub(w.utf8_carry.len());
+    if pending > limit || pending > raw.len() {
+        return raw.len();
+    }
+    let mut start = raw.len() - pending;
+    while !raw.is_char_boundary(start) {
+        start -= 1;
+    }
+    start
 }
 
 fn now_unix_seconds() -> i64 {
@@ -314,3 +946,137 @@ fn build_window_launch_env(
 
     merged
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid_scrollback::AmbiguousWidth;
+    use crate::session_manager::idle_window_state;
+    use crate::terminal_pane::{render_frame, render_viewport};
+    use std::sync::mpsc::{self, Sender};
+
+    struct ChannelWriter(Sender<Vec<u8>>);
+
+    impl Write for ChannelWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let _ = self.0.send(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn feeds_each_read_once_and_answers_queries_split_across_reads() {
+        let (written, received) = mpsc::channel();
+        let mut window = idle_window_state("s".to_string(), "w".to_string());
+        window.input = Some(InputQueue::spawn(
+            Box::new(ChannelWriter(written)),
+            1024,
+            Duration::from_secs(1),
+        ));
+
+        let reads: [&[u8]; 4] = [b"\x1b[31mred\r\n", b"\x1b[3;", b"5H\x1b[", b"6nok"];
+        for read in reads {
+            append_output(&mut window, read, 1 << 20);
+        }
+
+        let response = received
+            .recv_timeout(Duration::from_secs(2))
+            .expect("query answered");
+        assert_eq!(response, b"\x1b[3;5R");
+        assert!(received.try_recv().is_err());
+        let replayed = render_frame(&window.buffer, 140, 40, AmbiguousWidth::Narrow);
+        assert_eq!(window.pane.styled_frame().to_value(), replayed.to_value());
+        assert_eq!(window.output_revision, 4);
+    }
+
+    #[test]
+    fn joins_characters_split_across_reads() {
+        let mut window = idle_window_state("s".to_string(), "w".to_string());
+        let text = "caf\u{e9} \u{1f600}!";
+        let (first, rest) = text.as_bytes().split_at(4);
+        let (second, last) = rest.split_at(4);
+        append_output(&mut window, first, 1 << 20);
+        assert_eq!(window.buffer, "caf");
+        append_output(&mut window, second, 1 << 20);
+        append_output(&mut window, last, 1 << 20);
+
+        assert_eq!(window.buffer, text);
+        assert!(window.utf8_carry.is_empty());
+        let frame = window.pane.styled_frame().to_value();
+        assert_eq!(frame["lines"][0]["segments"][0]["text"], text);
+    }
+
+    #[test]
+    fn keeps_the_state_set_by_skipped_output() {
+        let mut window = idle_window_state("s".to_string(), "w".to_string());
+        append_output(&mut window, b"before\r\n", 1 << 20);
+        skip_output(&mut window, b"\x1b[?1049h\x1b[33mhidden\r\n\x1b[4", 1 << 20);
+        assert!(window.buffer.ends_with("\x1b[4"), "{:?}", window.buffer);
+        append_output(&mut window, b"mshown", 1 << 20);
+
+        let frame = window.pane.styled_frame().to_value();
+        let replayed = render_frame(&window.buffer, 140, 40, AmbiguousWidth::Narrow);
+        assert_eq!(frame, replayed.to_value());
+        assert_eq!(frame["lines"][0]["segments"][0]["text"], "hidden");
+        let shown = &frame["lines"][1]["segments"][0];
+        assert_eq!(shown["text"], "shown");
+        assert!(!shown["fg"].is_null());
+        assert_eq!(shown["underline"], true);
+
+        append_output(&mut window, b"\x1b[?1049l", 1 << 20);
+        let frame = window.pane.styled_frame().to_value();
+        assert_eq!(frame["lines"][0]["segments"][0]["text"], "before");
+    }
+
+    #[test]
+    fn drops_styles_no_longer_shown_when_compacting() {
+        let mut window = idle_window_state("s".to_string(), "w".to_string());
+        for n in 0..20_000u32 {
+            let [_, r, g, b] = n.to_be_bytes();
+            let mut output = format!("\x1b[38;2;{r};{g};{b}m#").into_bytes();
+            if n % 10 == 9 {
+                output.extend_from_slice(b"\r\n");
+            }
+            append_output(&mut window, &output, 16 * 1024);
+        }
+        assert!(window.compacted_bytes > 0, "buffer was not compacted");
+        // What is left are the styles of the ten cells on each line of history
+        // and screen, and of the output since the last compaction.
+        let raw = window.buffer.len() - window.checkpoint_bytes;
+        let kept = window.pane.cell_data_len();
+        assert!(
+            kept <= 10 * (1_000 + 40) + 1 + raw / 15,
+            "{kept} styles kept"
+        );
+    }
+
+    #[test]
+    fn scrolls_back_past_a_compaction_into_checkpointed_history() {
+        let mut window = idle_window_state("s".to_string(), "w".to_string());
+        for n in 0..1_000 {
+            append_output(&mut window, format!("line {n}\r\n").as_bytes(), 4_096);
+        }
+        assert!(window.compacted_bytes > 0, "buffer was not compacted");
+
+        // The screen shows lines 961 to 999. Go back to a line from before
+        // the checkpoint's own screen, which only its history holds.
+        let raw = &window.buffer[window.checkpoint_bytes..];
+        let first_raw = (0..1_000)
+            .find(|n| raw.contains(&format!("line {n}\r")))
+            .expect("raw output");
+        let target = first_raw - 60;
+        let offset = 961 - target;
+        let frame =
+            render_viewport(&window.buffer, 140, 40, AmbiguousWidth::Narrow, offset).to_value();
+        assert_eq!(frame["viewportOffset"].as_u64(), Some(offset as u64));
+        let top = frame["lines"][0]["segments"][0]["text"].as_str();
+        assert_eq!(
+            top.map(str::trim_end),
+            Some(format!("line {target}").as_str())
+        );
+    }
+}
@@ -1,174 +1,98 @@
+use crate::modes::{report_status, Mode, Modes};
+use crate::vt_parser::Csi;
 use std::collections::HashMap;
 
-pub fn build_terminal_response(
-    query_carry: &mut String,
-    private_modes: &mut HashMap<i32, bool>,
-    chunk: &str,
+/// Answers the queries a program writes to its terminal: cursor position,
+/// status, modes, colours and capabilities. It is driven by the pane that
+/// renders the window, so both agree on where each sequence starts and ends.
+pub struct QueryResponder<'a> {
+    private_modes: &'a mut HashMap<i32, bool>,
     cols: u16,
     rows: u16,
-    cursor_row: usize,
-    cursor_col: usize,
-) -> String {
-    let mut data = String::new();
-    data.push_str(query_carry);
-    data.push_str(chunk);
-    query_carry.clear();
-
-    let bytes = data.as_bytes();
-    let mut out = String::new();
-    let mut i = 0usize;
-
-    while i < bytes.len() {
-        if bytes[i] != 0x1b {
-            i += 1;
-            continue;
-        }
+    response: String,
+}
 
-        if i + 1 >= bytes.len() {
-            query_carry.push_str(&data[i..]);
-            break;
+impl<'a> QueryResponder<'a> {
+    pub fn new(private_modes: &'a mut HashMap<i32, bool>, cols: u16, rows: u16) -> Self {
+        Self {
+            private_modes,
+            cols,
+            rows,
+            response: String::new(),
         }
+    }
 
-        let next = bytes[i + 1];
-
-        if next == b'[' {
-            let mut j = i + 2;
-            while j < bytes.len() {
-                if (0x40..=0x7e).contains(&bytes[j]) {
-                    break;
-                }
-                j += 1;
-            }
-
-            if j >= bytes.len() {
-                query_carry.push_str(&data[i..]);
-                break;
-            }
+    /// Everything to write back to the program so far.
+    pub fn into_response(self) -> String {
+        self.response
+    }
 
-            let final_char = bytes[j] as char;
-            let raw = std::str::from_utf8(&bytes[i + 2..j]).unwrap_or_default();
+    /// Handles a CSI sequence, given the cursor, as a report gives it, and
+    /// the pane's modes as they were when it arrived.
+    pub fn csi(&mut self, csi: &Csi<'_>, (cursor_row, cursor_col): (usize, usize), modes: Modes) {
+        let params = csi.params;
+        let single = |value: u16| params.len() == 1 && params.get(0) == Some(value);
+        let out = &mut self.response;
 
-            if final_char == 'n' && raw == "6" {
+        match (csi.private, csi.intermediates, csi.final_byte) {
+            (None, b"", b'n') if single(6) => {
                 out.push_str(&format!("\x1b[{};{}R", cursor_row + 1, cursor_col + 1));
             }
-
-            if final_char == 'n' && raw == "?6" {
+            (Some(b'?'), b"", b'n') if single(6) => {
                 out.push_str(&format!("\x1b[?{};{}R", cursor_row + 1, cursor_col + 1));
             }
-
-            if final_char == 'n' && raw == "5" {
-                out.push_str("\x1b[0n");
-            }
-
-            if final_char == 'p' && raw.starts_with('?') && raw.ends_with('$') {
-                let mode = raw[1..raw.len().saturating_sub(1)].parse::<i32>().ok();
-                if let Some(mode) = mode {
-                    out.push_str(&format!(
-                        "\x1b[?{};{}$y",
-                        mode,
-                        private_mode_state(private_modes, mode)
-                    ));
+            (None, b"", b'n') if single(5) => out.push_str("\x1b[0n"),
+            (Some(b'?'), b"$", b'p') => {
+                if let Some(mode) = params.get(0) {
+                    let status = match modes.get(Mode::dec(mode)) {
+                        Some(value) => i32::from(report_status(Some(value))),
+                        None => private_mode_state(self.private_modes, i32::from(mode)),
+                    };
+                    out.push_str(&format!("\x1b[?{mode};{status}$y"));
                 }
             }
-
-            if (final_char == 'h' || final_char == 'l') && raw.starts_with('?') {
-                let enable = final_char == 'h';
-                for item in raw[1..].split(';') {
-                    if let Ok(mode) = item.parse::<i32>() {
-                        private_modes.insert(mode, enable);
-                    }
+            (None, b"$", b'p') => {
+                if let Some(mode) = params.get(0) {
+                    let status = report_status(modes.get(Mode::ansi(mode)));
+                    out.push_str(&format!("\x1b[{mode};{status}$y"));
                 }
             }
-
-            if final_char == 'u' && raw == "?" {
-                out.push_str("\x1b[?0u");
+            (Some(b'?'), b"", b'h' | b'l') => {
+                let enable = csi.final_byte == b'h';
+                for mode in params.iter().flatten() {
+                    self.private_modes.insert(i32::from(mode), enable);
+                }
             }
-
-            if final_char == 't' && raw == "14" {
-                let width_px = (cols as usize * 11).max(320);
-                let height_px = (rows as usize * 22).max(200);
+            (Some(b'?'), b"", b'u') if params.get(0).is_none() => out.push_str("\x1b[?0u"),
+            (None, b"", b't') if single(14) => {
+                let width_px = (self.cols as usize * 11).max(320);
+                let height_px = (self.rows as usize * 22).max(200);
                 out.push_str(&format!("\x1b[4;{};{}t", height_px, width_px));
             }
-
-            if final_char == 'c' && raw.is_empty() {
-                out.push_str("\x1b[?62;c");
-            }
-
-            i = j + 1;
-            continue;
+            (None, b"", b'c') if params.or(0, 0) == 0 => out.push_str("\x1b[?62;c"),
+            _ => {}
         }
+    }
 
-        if next == b']' {
-            let mut j = i + 2;
-            let mut terminated = false;
-            let mut end_index = 0usize;
-            while j < bytes.len() {
-                if bytes[j] == 0x07 {
-                    end_index = j;
-                    j += 1;
-                    terminated = true;
-                    break;
-                }
-                if bytes[j] == 0x1b && j + 1 < bytes.len() && bytes[j + 1] == b'\\' {
-                    end_index = j;
-                    j += 2;
-                    terminated = true;
-                    break;
+    pub fn osc(&mut self, body: &[u8]) {
+        let out = &mut self.response;
+        match body {
+            b"10;?" => out.push_str("\x1b]10;rgb:e5e5/e5e5/e5e5\x07"),
+            b"11;?" => out.push_str("\x1b]11;rgb:0a0a/0a0a/0a0a\x07"),
+            _ => {
+                if let Some(index) = parse_osc_indexed_color_query(body) {
+                    let (r, g, b) = xterm_256_color(index);
+                    out.push_str(&format!("\x1b]4;{};rgb:{}/{}/{}\x07", index, r, g, b));
                 }
-                j += 1;
-            }
-
-            if !terminated {
-                query_carry.push_str(&data[i..]);
-                break;
             }
-
-            let body = std::str::from_utf8(&bytes[i + 2..end_index]).unwrap_or_default();
-            if body == "10;?" {
-                out.push_str("\x1b]10;rgb:e5e5/e5e5/e5e5\x07");
-            }
-            if body == "11;?" {
-                out.push_str("\x1b]11;rgb:0a0a/0a0a/0a0a\x07");
-            }
-            if let Some(index) = parse_osc_indexed_color_query(body) {
-                let (r, g, b) = xterm_256_color(index);
-                out.push_str(&format!("\x1b]4;{};rgb:{}/{}/{}\x07", index, r, g, b));
-            }
-
-            i = j;
-            continue;
         }
+    }
 
-        if next == b'_' {
-            let mut j = i + 2;
-            let mut terminated = false;
-            while j < bytes.len() {
-                if bytes[j] == 0x1b && j + 1 < bytes.len() && bytes[j + 1] == b'\\' {
-                    j += 2;
-                    terminated = true;
-                    break;
-                }
-                j += 1;
-            }
-
-            if !terminated {
-                query_carry.push_str(&data[i..]);
-                break;
-            }
-
-            let body = std::str::from_utf8(&bytes[i + 2..j.saturating_sub(2)]).unwrap_or_default();
-            if body.contains("a=q") {
-                out.push_str("\x1b_Gi=31337;OK\x1b\\");
-            }
-
-            i = j;
-            continue;
+    pub fn apc(&mut self, body: &[u8]) {
+        if body.windows(3).any(|window| window == b"a=q") {
+            self.response.push_str("\x1b_Gi=31337;OK\x1b\\");
         }
-
-        i += 2;
     }
-
-    out
 }
 
 fn private_mode_state(private_modes: &HashMap<i32, bool>, mode: i32) -> i32 {
@@ -176,15 +100,15 @@ fn private_mode_state(private_modes: &HashMap<i32, bool>, mode: i32) -> i32 {
         return if *value { 1 } else { 2 };
     }
 
-    if mode == 7 || mode == 25 {
+    if mode == 25 {
         return 1;
     }
 
     2
 }
 
-fn parse_osc_indexed_color_query(body: &str) -> Option<i32> {
-    let mut parts = body.split(';');
+fn parse_osc_indexed_color_query(body: &[u8]) -> Option<i32> {
+    let mut parts = std::str::from_utf8(body).ok()?.split(';');
     if parts.next()? != "4" {
         return None;
     }
@@ -248,10 +172,55 @@ fn xterm_256_color(index: i32) -> (String, String, String) {
 
 #[cfg(test)]
 mod tests {
-    use super::build_terminal_response;
+    use super::QueryResponder;
+    use crate::modes::{Modes, DECAWM, IRM};
+    use crate::vt_parser::{Csi, Parser, Perform};
     use serde::Deserialize;
     use std::collections::HashMap;
 
+    /// Feeds a responder from its own parser, with a fixed cursor.
+    struct FixedCursor<'a, 'b> {
+        responder: &'a mut QueryResponder<'b>,
+        cursor_row: usize,
+        cursor_col: usize,
+        modes: Modes,
+    }
+
+    impl Perform for FixedCursor<'_, '_> {
+        fn csi_dispatch(&mut self, csi: &Csi<'_>) {
+            self.responder
+                .csi(csi, (self.cursor_row, self.cursor_col), self.modes);
+        }
+
+        fn osc_dispatch(&mut self, body: &[u8]) {
+            self.responder.osc(body);
+        }
+
+        fn apc_dispatch(&mut self, body: &[u8]) {
+            self.responder.apc(body);
+        }
+    }
+
+    fn build_terminal_response(
+        parser: &mut Parser,
+        private_modes: &mut HashMap<i32, bool>,
+        chunk: &str,
+        cols: u16,
+        rows: u16,
+        cursor_row: usize,
+        cursor_col: usize,
+    ) -> String {
+        let mut responder = QueryResponder::new(private_modes, cols, rows);
+        let mut performer = FixedCursor {
+            responder: &mut responder,
+            cursor_row,
+            cursor_col,
+            modes: Modes::default(),
+        };
+        parser.advance(&mut performer, chunk.as_bytes());
+        responder.into_response()
+    }
+
     #[derive(Deserialize)]
     struct QueryFixture {
         name: String,
@@ -265,15 +234,21 @@ mod tests {
 
     #[test]
     fn handles_split_sequences_and_private_modes() {
-        let mut carry = String::new();
+        let mut parser = Parser::new();
         let mut modes = HashMap::new();
 
         let mut response = String::new();
         response.push_str(&build_terminal_response(
-            &mut carry, &mut modes, "\x1b[", 80, 24, 0, 2,
+            &mut parser,
+            &mut modes,
+            "\x1b[",
+            80,
+            24,
+            0,
+            2,
         ));
         response.push_str(&build_terminal_response(
-            &mut carry,
+            &mut parser,
             &mut modes,
             "?25$p\x1b[6n",
             80,
@@ -284,7 +259,38 @@ mod tests {
 
         assert!(response.contains("\x1b[?25;1$y"));
         assert!(response.contains("\x1b[1;3R"));
-        assert!(carry.is_empty());
+        assert_eq!(parser.pending_len(), 0);
+    }
+
+    #[test]
+    fn reports_pane_modes_from_the_mode_table() {
+        let mut private_modes = HashMap::new();
+        let mut responder = QueryResponder::new(&mut private_modes, 80, 24);
+        let mut modes = Modes::default();
+        modes.set(DECAWM, false);
+        modes.set(IRM, true);
+        let mut performer = FixedCursor {
+            responder: &mut responder,
+            cursor_row: 0,
+            cursor_col: 0,
+            modes,
+        };
+        Parser::new().advance(
+            &mut performer,
+            b"\x1b[?7$p\x1b[?6$p\x1b[4$p\x1b[20$p\x1b[12$p\x1b[?2004h\x1b[?2004$p",
+        );
+        let response = responder.into_response();
+
+        for expected in [
+            "\x1b[?7;2$y",
+            "\x1b[?6;2$y",
+            "\x1b[4;1$y",
+            "\x1b[20;2$y",
+            "\x1b[12;0$y",
+            "\x1b[?2004;1$y",
+        ] {
+            assert!(response.contains(expected), "missing {expected:?}");
+        }
     }
 
     #[test]
@@ -295,13 +301,13 @@ mod tests {
         .expect("fixtures should parse");
 
         for fixture in fixtures {
-            let mut carry = String::new();
+            let mut parser = Parser::new();
             let mut modes = HashMap::new();
             let mut response = String::new();
 
             for chunk in fixture.chunks {
                 response.push_str(&build_terminal_response(
-                    &mut carry,
+                    &mut parser,
                     &mut modes,
                     &chunk,
                     fixture.cols,
@@ -319,11 +325,11 @@ mod tests {
                     expected
                 );
             }
-            assert!(
-                carry.is_empty(),
-                "fixture '{}' left carry bytes: {:?}",
-                fixture.name,
-                carry
+            assert_eq!(
+                parser.pending_len(),
+                0,
+                "fixture '{}' left an unfinished sequence",
+                fixture.name
             );
         }
     }
@@ -0,0 +1,336 @@
+use crate::terminal_pane::TerminalPane;
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const CAST_VERSION: u64 = 2;
+// Casts can hold typed input, so only the owner may read them.
+const RECORDING_FILE_MODE: u32 = 0o600;
+
+/// Prefix of the error for `start_recording` on a window that is already
+/// being recorded.
+pub const RECORDING_ACTIVE: &str = "recording already running";
+
+#[derive(Clone, Copy)]
+pub struct RecordingOptions {
+    pub include_input: bool,
+    pub include_resize: bool,
+}
+
+impl Default for RecordingOptions {
+    fn default() -> Self {
+        Self {
+            include_input: false,
+            include_resize: true,
+        }
+    }
+}
+
+pub struct CastRecorder {
+    path: PathBuf,
+    file: BufWriter<File>,
+    started: Instant,
+    options: RecordingOptions,
+    events: u64,
+}
+
+impl CastRecorder {
+    /// Starts a cast at `path`, which must not exist yet.
+    pub fn create(
+        path: &Path,
+        cols: u16,
+        rows: u16,
+        options: RecordingOptions,
+    ) -> Result<Self, String> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("create recording parent {}: {e}", parent.display()))?;
+            }
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(RECORDING_FILE_MODE)
+            .open(path)
+            .map_err(|e| format!("create recording {}: {e}", path.display()))?;
+        let mut file = BufWriter::new(file);
+
+        let header = json!({
+            "version": CAST_VERSION,
+            "width": cols,
+            "height": rows,
+            "timestamp": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            "env": {
+                "TERM": std::env::var("TERM").unwrap_or_else(|_| "xterm-256color".to_string()),
+            },
+        });
+        write_line(&mut file, &header)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            started: Instant::now(),
+            options,
+            events: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    /// Flushes buffered events and closes the cast.
+    pub fn finish(mut self) -> Result<(), String> {
+        self.file
+            .flush()
+            .map_err(|e| format!("write recording {}: {e}", self.path.display()))
+    }
+
+    pub fn record_output(&mut self, data: &str) -> Result<(), String> {
+        self.record_event("o", data)
+    }
+
+    pub fn record_input(&mut self, data: &str) -> Result<(), String> {
+        if !self.options.include_input {
+            return Ok(());
+        }
+        self.record_event("i", data)
+    }
+
+    pub fn record_resize(&mut self, cols: u16, rows: u16) -> Result<(), String> {
+        if !self.options.include_resize {
+            return Ok(());
+        }
+        self.record_event("r", &format!("{cols}x{rows}"))
+    }
+
+    fn record_event(&mut self, kind: &str, data: &str) -> Result<(), String> {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        write_line(&mut self.file, &json!([elapsed, kind, data]))?;
+        self.events = self.events.saturating_add(1);
+        Ok(())
+    }
+}
+
+fn write_line(file: &mut impl Write, value: &Value) -> Result<(), String> {
+    let mut line = serde_json::to_vec(value).map_err(|e| format!("encode cast event: {e}"))?;
+    line.push(b'\n');
+    file.write_all(&line)
+        .map_err(|e| format!("write cast event: {e}"))
+}
+
+pub struct CastHeader {
+    pub width: u16,
+    pub height: u16,
+}
+
+pub struct CastEvent {
+    pub time: f64,
+    pub kind: String,
+    pub data: String,
+}
+
+pub fn parse_cast(raw: &str) -> Result<(CastHeader, Vec<CastEvent>), String> {
+    let mut lines = raw.lines().filter(|line| !line.trim().is_empty());
+    let header_line = lines.next().ok_or_else(|| "empty cast file".to_string())?;
+    let header = serde_json::from_str::<Value>(header_line)
+        .map_err(|e| format!("invalid cast header: {e}"))?;
+    if header["version"].as_u64() != Some(CAST_VERSION) {
+        return Err(format!("unsupported cast version: {}", header["version"]));
+    }
+    let width = header["width"]
+        .as_u64()
+        .ok_or_else(|| "cast header missing width".to_string())?;
+    let height = header["height"]
+        .as_u64()
+        .ok_or_else(|| "cast header missing height".to_string())?;
+
+    let mut events = Vec::new();
+    for (idx, line) in lines.enumerate() {
+        let value = serde_json::from_str::<Value>(line)
+            .map_err(|e| format!("invalid cast event #{}: {e}", idx + 1))?;
+        let (Some(time), Some(kind), Some(data)) =
+            (value[0].as_f64(), value[1].as_str(), value[2].as_str())
+        else {
+            return Err(format!("invalid cast event #{}: {line}", idx + 1));
+        };
+        events.push(CastEvent {
+            time,
+            kind: kind.to_string(),
+            data: data.to_string(),
+        });
+    }
+
+    Ok((
+        CastHeader {
+            width: width.min(u64::from(u16::MAX)) as u16,
+            height: height.min(u64::from(u16::MAX)) as u16,
+        },
+        events,
+    ))
+}
+
+fn parse_resize(data: &str) -> Option<(u16, u16)> {
+    let (cols, rows) = data.split_once('x')?;
+    Some((cols.parse().ok()?, rows.parse().ok()?))
+}
+
+/// Replays output events into a `TerminalPane` and renders one frame per
+/// requested timestamp. Without timestamps only the final frame is returned.
+pub fn replay_cast(raw: &str, timestamps: &[f64]) -> Result<Vec<Value>, String> {
+    let (header, events) = parse_cast(raw)?;
+    let mut stops = timestamps.to_vec();
+    stops.sort_by(|a, b| a.total_cmp(b));
+
+    let mut cols = header.width;
+    let mut rows = header.height;
+    let mut output = String::new();
+    let mut pane = TerminalPane::new(cols, rows);
+    let mut frames = Vec::new();
+    let mut next_stop = 0usize;
+
+    for event in &events {
+        while next_stop < stops.len() && stops[next_stop] < event.time {
+            frames.push(json!({ "at": stops[next_stop], "frame": pane.frame() }));
+            next_stop += 1;
+        }
+        match event.kind.as_str() {
+            "o" => {
+                output.push_str(&event.data);
+                pane.feed(event.data.as_bytes());
+            }
+            "r" => {
+                if let Some((next_cols, next_rows)) = parse_resize(&event.data) {
+                    cols = next_cols;
+                    rows = next_rows;
+                    pane = TerminalPane::new(cols, rows);
+                    pane.feed(output.as_bytes());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if stops.is_empty() {
+        let at = events.last().map(|event| event.time).unwrap_or_default();
+        frames.push(json!({ "at": at, "frame": pane.frame() }));
+    }
+    while next_stop < stops.len() {
+        frames.push(json!({ "at": stops[next_stop], "frame": pane.frame() }));
+        next_stop += 1;
+    }
+
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line_text(frame: &Value, row: usize) -> String {
+        frame["lines"][row]["segments"]
+            .as_array()
+            .map(|segments| {
+                segments
+                    .iter()
+                    .filter_map(|seg| seg["text"].as_str())
+                    .collect::<String>()
+            })
+            .unwrap_or_default()
+    }
+
+    fn unique_cast_path() -> PathBuf {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        std::env::temp_dir().join(format!(
+            "discode-pty-sidecar-cast-{}-{}.cast",
+            std::process::id(),
+            stamp
+        ))
+    }
+
+    #[test]
+    fn writes_asciicast_v2_header_and_filtered_events() {
+        let path = unique_cast_path();
+        let mut recorder = CastRecorder::create(&path, 80, 24, RecordingOptions::default())
+            .expect("recorder should open");
+        recorder.record_output("hello").expect("output event");
+        recorder.record_input("secret").expect("input event");
+        recorder.record_resize(100, 30).expect("resize event");
+        assert_eq!(recorder.events(), 2);
+        recorder.finish().expect("recording should flush");
+
+        let raw = fs::read_to_string(&path).expect("cast should be readable");
+        let _ = fs::remove_file(&path);
+        let (header, events) = parse_cast(&raw).expect("cast should parse");
+        assert_eq!((header.width, header.height), (80, 24));
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, "o");
+        assert_eq!(events[0].data, "hello");
+        assert_eq!(events[1].kind, "r");
+        assert_eq!(events[1].data, "100x30");
+        assert!(!raw.contains("secret"));
+    }
+
+    #[test]
+    fn refuses_to_overwrite_an_existing_file() {
+        let path = unique_cast_path();
+        fs::write(&path, "keep me").expect("file should be written");
+
+        let err = CastRecorder::create(&path, 80, 24, RecordingOptions::default())
+            .err()
+            .expect("existing file should be refused");
+        let kept = fs::read_to_string(&path).expect("file should still exist");
+        let _ = fs::remove_file(&path);
+        assert!(err.contains("create recording"));
+        assert_eq!(kept, "keep me");
+    }
+
+    #[test]
+    fn replays_frames_at_requested_timestamps() {
+        let cast = [
+            r#"{"version":2,"width":20,"height":6}"#,
+            r#"[0.5,"o","first"]"#,
+            r#"[1.0,"r","30x8"]"#,
+            r#"[1.5,"o","\r\n\u001b[31msecond"]"#,
+        ]
+        .join("\n");
+
+        let frames = replay_cast(&cast, &[2.0, 0.1, 1.2]).expect("replay should succeed");
+        assert_eq!(frames.len(), 3);
+
+        assert_eq!(frames[0]["at"].as_f64(), Some(0.1));
+        assert_eq!(line_text(&frames[0]["frame"], 0), "");
+
+        assert_eq!(frames[1]["frame"]["cols"].as_u64(), Some(30));
+        assert_eq!(line_text(&frames[1]["frame"], 0), "first");
+        assert_eq!(line_text(&frames[1]["frame"], 1), "");
+
+        assert_eq!(line_text(&frames[2]["frame"], 1), "second");
+        assert_eq!(
+            frames[2]["frame"]["lines"][1]["segments"][0]["fg"].as_str(),
+            Some("#cd3131")
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_cast_versions() {
+        let err = parse_cast(r#"{"version":1,"width":80,"height":24}"#)
+            .err()
+            .expect("version 1 should be rejected");
+        assert!(err.contains("unsupported cast version"));
+    }
+}
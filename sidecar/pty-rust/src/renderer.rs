@@ -1,6 +1,140 @@
-use crate::grid_scrollback::{applied_style, segment_json, style_key};
-use crate::screen::ScreenFrame;
-use serde_json::{json, Value};
+use crate::grid_scrollback::{applied_style, Cell, CellStyle, CellTables, Glyph};
+use crate::screen::{Damage, ScreenFrame};
+use serde::Serialize;
+use serde_json::Value;
+use std::ops::Range;
+
+/// Rendered screen: rows of styled segments plus cursor state. Serializes to
+/// the `TerminalStyledFrame` JSON shape, and just as well to MessagePack.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StyledFrame {
+    pub cols: usize,
+    pub rows: usize,
+    pub lines: Vec<FrameLine>,
+    pub cursor_row: usize,
+    pub cursor_col: usize,
+    pub cursor_visible: bool,
+    /// Lines scrolled back into history, for frames rendered for a viewport.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub viewport_offset: Option<usize>,
+    /// The screen is in reverse video (DECSCNM): the default colours swap.
+    /// Cell colours are already swapped.
+    #[serde(skip_serializing_if = "is_false")]
+    pub reverse_video: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FrameLine {
+    pub segments: Vec<FrameSegment>,
+}
+
+/// Run of cells sharing one style. Unset colours and attributes are omitted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FrameSegment {
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fg: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bg: Option<String>,
+    #[serde(skip_serializing_if = "is_false")]
+    pub bold: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    pub italic: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    pub underline: bool,
+}
+
+/// Rows changed since an earlier frame, plus the new size and cursor.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FramePatch {
+    pub cols: usize,
+    pub rows: usize,
+    pub changed_lines: Vec<ChangedLine>,
+    /// Parts of rows, for rows where only a few columns changed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changed_spans: Vec<ChangedSpan>,
+    pub cursor_row: usize,
+    pub cursor_col: usize,
+    pub cursor_visible: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    pub reverse_video: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ChangedLine {
+    pub row: usize,
+    pub line: FrameLine,
+}
+
+/// Cells from `start_col` on, replacing as many columns as the segments
+/// cover; unlike a line, trailing blanks are kept.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangedSpan {
+    pub row: usize,
+    pub start_col: usize,
+    pub segments: Vec<FrameSegment>,
+}
+
+/// How finely a patch describes a changed row.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PatchGranularity {
+    /// Whole lines.
+    #[default]
+    Lines,
+    /// The changed columns of a row, when they take at most two thirds of
+    /// the size of the whole line.
+    Spans,
+}
+
+impl PatchGranularity {
+    pub const NAMES: &'static [&'static str] = &["lines", "spans"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "lines" => Some(Self::Lines),
+            "spans" => Some(Self::Spans),
+            _ => None,
+        }
+    }
+}
+
+// Rough serialized size of a segment besides its text, used to weigh a span
+// against the whole line.
+const SEGMENT_OVERHEAD: usize = 40;
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+impl StyledFrame {
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+impl FrameLine {
+    fn blank() -> Self {
+        Self {
+            segments: vec![FrameSegment::styled("", &CellStyle::default())],
+        }
+    }
+}
+
+impl FrameSegment {
+    fn styled(text: &str, style: &CellStyle) -> Self {
+        Self {
+            text: text.to_string(),
+            fg: style.fg.to_hex(),
+            bg: style.bg.to_hex(),
+            bold: style.bold,
+            italic: style.italic,
+            underline: style.underline,
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct Renderer;
@@ -10,172 +144,234 @@ impl Renderer {
         Self
     }
 
-    pub fn render_styled_frame(&self, screen: &ScreenFrame) -> Value {
-        let mut line_values = Vec::with_capacity(screen.rows);
-
-        for row in &screen.lines {
-            let mut end = row.len();
-            while end > 0 && row[end - 1].text == " " {
-                end -= 1;
-            }
-
-            if end == 0 {
-                line_values.push(json!({ "segments": [ { "text": "" } ] }));
-                continue;
-            }
-
-            let mut segments = Vec::new();
-            let mut current_text = String::new();
-            let mut current_style = applied_style(&row[0].style);
+    pub fn render_styled_frame(&self, screen: &ScreenFrame<'_>) -> StyledFrame {
+        StyledFrame {
+            cols: screen.cols,
+            rows: screen.rows,
+            lines: (0..screen.rows)
+                .map(|row| render_row(screen, row))
+                .collect(),
+            cursor_row: screen.cursor_row,
+            cursor_col: screen.cursor_col,
+            cursor_visible: screen.cursor_visible,
+            viewport_offset: None,
+            reverse_video: screen.reverse_video,
+        }
+    }
 
-            for cell in row.iter().take(end) {
-                let style = applied_style(&cell.style);
-                if style_key(&style) != style_key(&current_style) {
-                    segments.push(segment_json(&current_text, &current_style));
-                    current_text.clear();
-                    current_style = style;
+    /// Patch with the cells in `damage` and the current cursor.
+    pub fn render_patch(
+        &self,
+        screen: &ScreenFrame<'_>,
+        damage: &Damage,
+        granularity: PatchGranularity,
+    ) -> FramePatch {
+        let mut changed_lines = Vec::new();
+        let mut changed_spans = Vec::new();
+        for (row, cols) in damage.spans().filter(|(row, _)| *row < screen.rows) {
+            let line = render_row(screen, row);
+            if granularity == PatchGranularity::Spans {
+                if let Some(span) = render_span(screen, row, cols) {
+                    if segments_size(&span.segments) * 3 <= segments_size(&line.segments) * 2 {
+                        changed_spans.push(span);
+                        continue;
+                    }
                 }
-                current_text.push_str(&cell.text);
             }
-
-            segments.push(segment_json(&current_text, &current_style));
-            line_values.push(json!({ "segments": segments }));
+            changed_lines.push(ChangedLine { row, line });
         }
 
-        json!({
-            "cols": screen.cols,
-            "rows": screen.rows,
-            "lines": line_values,
-            "cursorRow": screen.cursor_row,
-            "cursorCol": screen.cursor_col,
-            "cursorVisible": screen.cursor_visible,
-        })
+        FramePatch {
+            cols: screen.cols,
+            rows: screen.rows,
+            changed_lines,
+            changed_spans,
+            cursor_row: screen.cursor_row,
+            cursor_col: screen.cursor_col,
+            cursor_visible: screen.cursor_visible,
+            reverse_video: screen.reverse_video,
+        }
     }
+}
 
-    #[allow(dead_code)]
-    pub fn render_patch(&self, previous: &ScreenFrame, next: &ScreenFrame) -> Option<Value> {
-        let max_rows = previous.rows.max(next.rows);
-        let mut changed_lines = Vec::new();
+fn render_row(screen: &ScreenFrame<'_>, row: usize) -> FrameLine {
+    match screen.row(row) {
+        Some(cells) => render_line(cells, screen.tables, screen.reverse_video),
+        None => FrameLine::blank(),
+    }
+}
 
-        for row in 0..max_rows {
-            let prev = previous.lines.get(row);
-            let curr = next.lines.get(row);
-            if prev == curr {
-                continue;
-            }
+/// `cols` of `row`, widened to whole wide glyphs. `None` if that is the
+/// whole row.
+fn render_span(screen: &ScreenFrame<'_>, row: usize, cols: Range<usize>) -> Option<ChangedSpan> {
+    let cells = screen.row(row)?;
+    let mut start = cols.start;
+    let mut end = cols.end.min(cells.len());
+    if start >= end {
+        return None;
+    }
+    while start > 0 && cells[start].glyph == Glyph::Continuation {
+        start -= 1;
+    }
+    while end < cells.len() && cells[end].glyph == Glyph::Continuation {
+        end += 1;
+    }
+    if start == 0 && end == cells.len() {
+        return None;
+    }
+    Some(ChangedSpan {
+        row,
+        start_col: start,
+        segments: render_cells(&cells[start..end], screen.tables, screen.reverse_video),
+    })
+}
 
-            let rendered = if row < next.lines.len() {
-                let mut single_row = next.clone();
-                single_row.lines = vec![next.lines[row].clone()];
-                single_row.rows = 1;
-                self.render_styled_frame(&single_row)["lines"][0].clone()
-            } else {
-                json!({ "segments": [ { "text": "" } ] })
-            };
+fn segments_size(segments: &[FrameSegment]) -> usize {
+    segments
+        .iter()
+        .map(|segment| segment.text.len() + SEGMENT_OVERHEAD)
+        .sum()
+}
 
-            changed_lines.push(json!({ "row": row, "line": rendered }));
-        }
+fn render_line(row: &[Cell], tables: &CellTables, reverse: bool) -> FrameLine {
+    let mut end = row.len();
+    while end > 0 && row[end - 1].glyph == Glyph::Char(' ') {
+        end -= 1;
+    }
 
-        let cursor_changed = previous.cursor_row != next.cursor_row
-            || previous.cursor_col != next.cursor_col
-            || previous.cursor_visible != next.cursor_visible;
+    if end == 0 {
+        return FrameLine::blank();
+    }
 
-        let size_changed = previous.cols != next.cols || previous.rows != next.rows;
+    FrameLine {
+        segments: render_cells(&row[..end], tables, reverse),
+    }
+}
 
-        if changed_lines.is_empty() && !cursor_changed && !size_changed {
-            return None;
+/// Runs of same-looking cells; `row` must not be empty. On a `reverse`
+/// screen every cell shows inverted.
+fn render_cells(row: &[Cell], tables: &CellTables, reverse: bool) -> Vec<FrameSegment> {
+    let shown = |style: CellStyle| {
+        applied_style(CellStyle {
+            inverse: style.inverse != reverse,
+            ..style
+        })
+    };
+    let mut segments = Vec::new();
+    let mut current_text = String::new();
+    let mut current_id = row[0].style;
+    let mut current_style = shown(tables.style(current_id));
+
+    for cell in row {
+        // Different styles can look the same once inverse is applied, so
+        // compare what is shown rather than the ids alone.
+        if cell.style != current_id {
+            let style = shown(tables.style(cell.style));
+            current_id = cell.style;
+            if style != current_style {
+                segments.push(FrameSegment::styled(&current_text, &current_style));
+                current_text.clear();
+                current_style = style;
+            }
         }
-
-        Some(json!({
-            "cols": next.cols,
-            "rows": next.rows,
-            "changedLines": changed_lines,
-            "cursorRow": next.cursor_row,
-            "cursorCol": next.cursor_col,
-            "cursorVisible": next.cursor_visible,
-        }))
+        tables.push_glyph(cell.glyph, &mut current_text);
     }
+
+    segments.push(FrameSegment::styled(&current_text, &current_style));
+    segments
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Renderer;
-    use crate::grid_scrollback::{blank_cell, Cell, CellStyle};
-    use crate::screen::ScreenFrame;
-    use std::time::Instant;
+    use super::{PatchGranularity, Renderer};
+    use crate::grid_scrollback::{blank_cell, Cell, CellStyle, CellTables, Color, Glyph, StyleId};
+    use crate::screen::{Damage, Screen, ScreenFrame};
+    use crate::terminal_pane::TerminalPane;
+    use std::time::{Duration, Instant};
 
-    fn cell(text: &str) -> Cell {
+    fn cell(ch: char) -> Cell {
         Cell {
-            text: text.to_string(),
-            style: CellStyle::default(),
+            glyph: Glyph::Char(ch),
+            style: StyleId::DEFAULT,
         }
     }
 
-    fn screen_with_text(text: &str) -> ScreenFrame {
-        let mut row = vec![blank_cell(); 20];
-        for (idx, ch) in text.chars().enumerate() {
-            if idx >= row.len() {
-                break;
-            }
-            row[idx] = cell(&ch.to_string());
-        }
-        ScreenFrame {
-            cols: 20,
-            rows: 6,
-            lines: vec![
-                row,
-                vec![blank_cell(); 20],
-                vec![blank_cell(); 20],
-                vec![blank_cell(); 20],
-                vec![blank_cell(); 20],
-                vec![blank_cell(); 20],
-            ],
-            cursor_row: 0,
-            cursor_col: text.chars().count().min(19),
-            cursor_visible: true,
+    fn lines_with_text(text: &str) -> Vec<Vec<Cell>> {
+        let mut lines = vec![vec![blank_cell(); 20]; 6];
+        for (idx, ch) in text.chars().take(20).enumerate() {
+            lines[0][idx] = cell(ch);
         }
+        lines
+    }
+
+    fn screen<'a>(lines: &'a [Vec<Cell>], tables: &'a CellTables) -> ScreenFrame<'a> {
+        let cols = lines.first().map_or(0, Vec::len);
+        let cursor_col = lines.first().map_or(0, |row| {
+            row.iter().take_while(|cell| **cell != blank_cell()).count()
+        });
+        Screen::new().compose(lines, tables, cols, lines.len(), (0, cursor_col), true)
     }
 
-    fn dense_screen(cols: usize, rows: usize) -> ScreenFrame {
+    fn dense_lines(tables: &mut CellTables, cols: usize, rows: usize) -> Vec<Vec<Cell>> {
         let mut lines = Vec::with_capacity(rows);
         for row in 0..rows {
             let mut line = vec![blank_cell(); cols];
             for (col, item) in line.iter_mut().enumerate().take(cols) {
                 let ch = match (row + col) % 4 {
-                    0 => "A",
-                    1 => "B",
-                    2 => "C",
-                    _ => "D",
+                    0 => 'A',
+                    1 => 'B',
+                    2 => 'C',
+                    _ => 'D',
                 };
                 *item = Cell {
-                    text: ch.to_string(),
-                    style: CellStyle {
+                    glyph: Glyph::Char(ch),
+                    style: tables.intern_style(CellStyle {
                         bold: (row + col) % 3 == 0,
                         italic: (row + col) % 5 == 0,
                         underline: (row + col) % 7 == 0,
-                        fg: Some("#ffffff".to_string()),
-                        bg: Some("#000000".to_string()),
+                        fg: Color::Rgb(0xff, 0xff, 0xff),
+                        bg: Color::Rgb(0, 0, 0),
                         inverse: false,
-                    },
+                    }),
                 };
             }
             lines.push(line);
         }
+        lines
+    }
 
-        ScreenFrame {
-            cols,
-            rows,
-            lines,
-            cursor_row: rows.saturating_sub(1),
-            cursor_col: cols.saturating_sub(1),
-            cursor_visible: true,
+    /// Colourful full-screen output, as a TUI redraw would produce.
+    fn dense_output(cols: usize, rows: usize) -> String {
+        let mut out = String::from("\x1b[H");
+        for row in 0..rows {
+            for col in 0..cols {
+                let n = row + col;
+                out.push_str(&format!(
+                    "\x1b[{};38;5;{};48;2;0;{};0m{}",
+                    if n % 3 == 0 { 1 } else { 22 },
+                    n % 256,
+                    n % 200,
+                    char::from(b'A' + (n % 26) as u8)
+                ));
+            }
         }
+        out
+    }
+
+    fn time_per_run(runs: u32, mut run: impl FnMut()) -> Duration {
+        let started = Instant::now();
+        for _ in 0..runs {
+            run();
+        }
+        started.elapsed() / runs
     }
 
     #[test]
     fn renders_deterministic_frame_for_same_input() {
         let renderer = Renderer::new();
-        let screen = screen_with_text("hello");
+        let tables = CellTables::new();
+        let lines = lines_with_text("hello");
+        let screen = screen(&lines, &tables);
 
         let a = renderer.render_styled_frame(&screen);
         let b = renderer.render_styled_frame(&screen);
@@ -183,35 +379,73 @@ mod tests {
     }
 
     #[test]
-    fn emits_no_patch_when_state_unchanged() {
+    fn sends_only_the_cursor_when_nothing_is_damaged() {
         let renderer = Renderer::new();
-        let previous = screen_with_text("hello");
-        let next = screen_with_text("hello");
-
-        assert!(renderer.render_patch(&previous, &next).is_none());
+        let tables = CellTables::new();
+        let lines = lines_with_text("hello");
+        let screen = screen(&lines, &tables);
+
+        let patch = renderer.render_patch(&screen, &Damage::new(20, 6), PatchGranularity::Lines);
+        assert!(patch.changed_lines.is_empty());
+        assert!(patch.changed_spans.is_empty());
+        assert_eq!(patch.cursor_col, 5);
     }
 
     #[test]
     fn emits_changed_rows_when_content_changes() {
         let renderer = Renderer::new();
-        let previous = screen_with_text("hello");
-        let next = screen_with_text("hallo");
-
-        let patch = renderer
-            .render_patch(&previous, &next)
-            .expect("patch should be produced");
+        let tables = CellTables::new();
+        let lines = lines_with_text("hallo");
+        let screen = screen(&lines, &tables);
+        let mut damage = Damage::new(20, 6);
+        damage.mark_cells(0, 1..2);
+
+        let patch = renderer.render_patch(&screen, &damage, PatchGranularity::Lines);
+
+        assert_eq!(patch.changed_lines.len(), 1);
+        assert_eq!(patch.changed_lines[0].row, 0);
+        assert_eq!(
+            serde_json::to_value(&patch).expect("patch JSON")["changedLines"][0]["line"]
+                ["segments"][0]["text"],
+            "hallo"
+        );
+    }
 
-        let changed = patch["changedLines"]
-            .as_array()
-            .expect("changed lines should be array");
-        assert_eq!(changed.len(), 1);
-        assert_eq!(changed[0]["row"].as_u64(), Some(0));
+    #[test]
+    fn merges_cells_that_look_the_same_once_inverse_is_applied() {
+        let renderer = Renderer::new();
+        let mut tables = CellTables::new();
+        let plain = tables.intern_style(CellStyle {
+            fg: Color::Indexed(1),
+            bg: Color::Indexed(4),
+            ..CellStyle::default()
+        });
+        let inverse = tables.intern_style(CellStyle {
+            fg: Color::Indexed(4),
+            bg: Color::Indexed(1),
+            inverse: true,
+            ..CellStyle::default()
+        });
+        let mut lines = lines_with_text("ab");
+        lines[0][0].style = plain;
+        lines[0][1].style = inverse;
+
+        let frame = renderer
+            .render_styled_frame(&screen(&lines, &tables))
+            .to_value();
+        let segments = frame["lines"][0]["segments"].as_array().expect("segments");
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0]["text"], "ab");
+        assert_eq!(segments[0]["fg"], "#cd3131");
+        assert_eq!(segments[0]["bg"], "#2472c8");
     }
 
     #[test]
     fn keeps_frame_generation_cost_within_budget() {
         let renderer = Renderer::new();
-        let screen = dense_screen(120, 40);
+        let mut tables = CellTables::new();
+        let lines = dense_lines(&mut tables, 120, 40);
+        let screen = screen(&lines, &tables);
 
         let started = Instant::now();
         for _ in 0..20 {
@@ -225,4 +459,29 @@ mod tests {
             elapsed_ms
         );
     }
+
+    #[test]
+    #[ignore = "benchmark: cargo test --release benchmark -- --ignored --nocapture"]
+    fn benchmark_frame_generation() {
+        let renderer = Renderer::new();
+        for (cols, rows) in [(120, 40), (300, 200)] {
+            let mut tables = CellTables::new();
+            let lines = dense_lines(&mut tables, cols, rows);
+            let screen = screen(&lines, &tables);
+            let render = time_per_run(50, || {
+                let _ = renderer.render_styled_frame(&screen);
+            });
+
+            let output = dense_output(cols, rows);
+            let parse_and_render = time_per_run(20, || {
+                let mut pane = TerminalPane::new(cols as u16, rows as u16);
+                pane.feed(output.as_bytes());
+                let _ = pane.styled_frame();
+            });
+            println!(
+                "{cols}x{rows}: render {render:?}/frame, parse {} KiB + render {parse_and_render:?}/frame",
+                output.len() / 1024
+            );
+        }
+    }
 }
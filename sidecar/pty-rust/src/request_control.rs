@@ -0,0 +1,157 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Error text of work abandoned because its request was cancelled.
+pub const REQUEST_CANCELLED: &str = "request cancelled";
+/// Error text of work abandoned because its request's deadline passed.
+pub const REQUEST_DEADLINE_EXCEEDED: &str = "request deadline exceeded";
+
+// How long to back off between attempts on a contended lock. It is both the
+// most a waiter can lag behind the lock being released and the most it can
+// lag behind a cancel or its deadline.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Deadline and cancellation state of one request, shared between the
+/// thread running it and whoever may answer it early (the deadline watcher
+/// or a `cancel` request).
+#[derive(Clone, Default)]
+pub struct RequestControl {
+    inner: Arc<ControlState>,
+}
+
+#[derive(Default)]
+struct ControlState {
+    started_at: Option<Instant>,
+    timeout_ms: Option<u64>,
+    deadline: Option<Instant>,
+    cancelled: AtomicBool,
+    answered: AtomicBool,
+}
+
+impl RequestControl {
+    pub fn with_timeout(timeout_ms: Option<u64>) -> Self {
+        let started_at = Instant::now();
+        Self {
+            inner: Arc::new(ControlState {
+                started_at: Some(started_at),
+                timeout_ms,
+                deadline: timeout_ms.map(|ms| started_at + Duration::from_millis(ms)),
+                ..ControlState::default()
+            }),
+        }
+    }
+
+    pub fn timeout_ms(&self) -> Option<u64> {
+        self.inner.timeout_ms
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.inner.deadline
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.inner
+            .started_at
+            .map(|started_at| started_at.elapsed())
+            .unwrap_or_default()
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.inner
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Fails once the request has been cancelled or its deadline has passed,
+    /// so that work nobody is waiting for is not started.
+    pub fn check(&self) -> Result<(), String> {
+        if self.is_cancelled() {
+            return Err(REQUEST_CANCELLED.to_string());
+        }
+        if self.is_expired() {
+            return Err(REQUEST_DEADLINE_EXCEEDED.to_string());
+        }
+        Ok(())
+    }
+
+    /// Takes the right to answer the request. Only the first caller gets
+    /// `true`; later results are dropped.
+    pub fn claim_reply(&self) -> bool {
+        !self.inner.answered.swap(true, Ordering::SeqCst)
+    }
+
+    /// Locks `mutex`, giving up when the request is cancelled or expires
+    /// while waiting. Poisoned locks are recovered like `lock_state` does.
+    ///
+    /// `std::sync::Mutex` has no timed or interruptible lock, so a contended
+    /// lock is polled every `LOCK_RETRY_INTERVAL` instead of being woken on
+    /// release, cancel or timeout. That costs a waiting request one wakeup
+    /// per interval, and only requests stuck behind a busy window wait at
+    /// all; the uncontended path is a single `try_lock`.
+    pub fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> Result<MutexGuard<'a, T>, String> {
+        loop {
+            match mutex.try_lock() {
+                Ok(guard) => return Ok(guard),
+                Err(TryLockError::Poisoned(poisoned)) => return Ok(poisoned.into_inner()),
+                Err(TryLockError::WouldBlock) => {
+                    self.check()?;
+                    thread::sleep(LOCK_RETRY_INTERVAL);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_gives_up_at_deadline_and_on_cancel() {
+        let mutex = Mutex::new(0);
+        let held = mutex.lock().expect("lock");
+
+        let control = RequestControl::with_timeout(Some(30));
+        let started = Instant::now();
+        let result = control.lock(&mutex).map(|_| ());
+        assert_eq!(result, Err(REQUEST_DEADLINE_EXCEEDED.to_string()));
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let control = RequestControl::with_timeout(None);
+        let canceller = control.clone();
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                canceller.cancel();
+            });
+            let result = control.lock(&mutex).map(|_| ());
+            assert_eq!(result, Err(REQUEST_CANCELLED.to_string()));
+        });
+
+        drop(held);
+        let control = RequestControl::with_timeout(Some(1_000));
+        assert!(control.lock(&mutex).is_ok());
+    }
+
+    #[test]
+    fn only_the_first_reply_is_claimed() {
+        let control = RequestControl::with_timeout(None);
+        let other = control.clone();
+        assert!(control.claim_reply());
+        assert!(!other.claim_reply());
+        assert!(control.check().is_ok());
+        other.cancel();
+        assert_eq!(control.check(), Err(REQUEST_CANCELLED.to_string()));
+    }
+}
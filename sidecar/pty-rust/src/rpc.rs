@@ -1,24 +1,38 @@
+use crate::grid_scrollback::AmbiguousWidth;
+use crate::input_queue::{INPUT_QUEUE_FULL, INPUT_WRITE_TIMEOUT};
+use crate::protocol::{Negotiation, METHODS};
 use crate::pty_bus::{
-    dispose_window, resize_window, spawn_window_process, stop_window, write_input,
+    append_note, dispose_window, resize_window, spawn_window_process, stop_window, write_input,
 };
+use crate::recording::{CastRecorder, RecordingOptions, RECORDING_ACTIVE};
+use crate::renderer::{FramePatch, PatchGranularity, StyledFrame};
+use crate::request_control::{RequestControl, REQUEST_CANCELLED, REQUEST_DEADLINE_EXCEEDED};
 use crate::session_manager::{
     idle_window_state, lock_state, lock_window, mark_output_mutation, should_coalesce_frame,
-    transition_window_state, window_key, with_window, FrameRenderCache, SharedSidecarState,
-    WindowLifecycleState,
+    transition_window_state, window_key, with_window_within, FrameRenderCache, SharedSidecarState,
+    WindowLifecycleState, WindowState,
 };
-use crate::vt_lite::build_styled_frame;
+use crate::vt_lite::{render_frame, render_viewport};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::fmt;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+pub const ERROR_PARSE: &str = "PARSE_ERROR";
 pub const ERROR_INVALID_REQUEST: &str = "INVALID_REQUEST";
 pub const ERROR_INVALID_PARAMS: &str = "INVALID_PARAMS";
 pub const ERROR_UNKNOWN_METHOD: &str = "UNKNOWN_METHOD";
 pub const ERROR_WINDOW_NOT_FOUND: &str = "WINDOW_NOT_FOUND";
 pub const ERROR_REQUEST_TIMEOUT: &str = "REQUEST_TIMEOUT";
+pub const ERROR_UNAUTHORIZED: &str = "UNAUTHORIZED";
 pub const ERROR_INTERNAL: &str = "INTERNAL";
+pub const ERROR_FEATURE_NOT_NEGOTIATED: &str = "FEATURE_NOT_NEGOTIATED";
+pub const ERROR_REQUEST_CANCELLED: &str = "REQUEST_CANCELLED";
+pub const ERROR_INPUT_QUEUE_FULL: &str = "INPUT_QUEUE_FULL";
+pub const ERROR_INPUT_WRITE_TIMEOUT: &str = "INPUT_WRITE_TIMEOUT";
+pub const ERROR_RECORDING_ACTIVE: &str = "RECORDING_ACTIVE";
 
 #[derive(Deserialize, Serialize)]
 pub struct RpcRequest {
@@ -37,13 +51,68 @@ pub struct RpcError {
     pub message: String,
 }
 
+/// Method result. Frames stay typed so that binary encodings serialize them
+/// directly instead of through a `Value` tree.
+#[derive(Clone, Serialize)]
+#[serde(untagged)]
+pub enum RpcResult {
+    Value(Value),
+    Frame(FrameReply),
+}
+
+impl RpcResult {
+    pub fn into_value(self) -> Value {
+        match self {
+            Self::Value(value) => value,
+            Self::Frame(reply) => reply.to_value(),
+        }
+    }
+}
+
+/// Result of `get_window_frame`. Every variant carries the revision of the
+/// output the frame shows, for use as the next `sinceRevision`.
+#[derive(Clone, Serialize)]
+#[serde(untagged)]
+pub enum FrameReply {
+    Full {
+        #[serde(flatten)]
+        frame: Arc<StyledFrame>,
+        revision: u64,
+    },
+    /// Changes from the frame at `sinceRevision`.
+    Patch {
+        #[serde(flatten)]
+        patch: FramePatch,
+        revision: u64,
+        #[serde(rename = "baseRevision")]
+        base_revision: u64,
+    },
+    NotModified {
+        #[serde(rename = "notModified")]
+        not_modified: bool,
+        revision: u64,
+    },
+}
+
+impl FrameReply {
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+impl From<Value> for RpcResult {
+    fn from(value: Value) -> Self {
+        Self::Value(value)
+    }
+}
+
 #[derive(Serialize)]
 pub struct RpcResponse {
     pub ok: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub result: Option<Value>,
+    pub result: Option<RpcResult>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<RpcError>,
 }
@@ -57,6 +126,25 @@ impl RpcError {
     }
 }
 
+impl RpcResponse {
+    pub fn from_outcome(id: Option<u64>, outcome: Result<RpcResult, RpcError>) -> Self {
+        match outcome {
+            Ok(result) => Self {
+                ok: true,
+                id,
+                result: Some(result),
+                error: None,
+            },
+            Err(error) => Self {
+                ok: false,
+                id,
+                result: None,
+                error: Some(error),
+            },
+        }
+    }
+}
+
 impl fmt::Display for RpcError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}: {}", self.code, self.message)
@@ -67,6 +155,17 @@ pub fn invalid_request(message: impl Into<String>) -> RpcError {
     RpcError::new(ERROR_INVALID_REQUEST, message)
 }
 
+pub fn unauthorized(message: impl Into<String>) -> RpcError {
+    RpcError::new(ERROR_UNAUTHORIZED, message)
+}
+
+pub fn request_cancelled(method: &str) -> RpcError {
+    RpcError::new(
+        ERROR_REQUEST_CANCELLED,
+        format!("request for method '{method}' was cancelled"),
+    )
+}
+
 pub fn request_timeout(method: &str, timeout_ms: u64, elapsed_ms: u128) -> RpcError {
     RpcError::new(
         ERROR_REQUEST_TIMEOUT,
@@ -87,40 +186,87 @@ pub fn map_runtime_error(error: String) -> RpcError {
     if error.starts_with("unknown method:") {
         return RpcError::new(ERROR_UNKNOWN_METHOD, error);
     }
+    if error == REQUEST_CANCELLED {
+        return RpcError::new(ERROR_REQUEST_CANCELLED, error);
+    }
+    if error == REQUEST_DEADLINE_EXCEEDED {
+        return RpcError::new(ERROR_REQUEST_TIMEOUT, error);
+    }
+    if error.starts_with(INPUT_QUEUE_FULL) {
+        return RpcError::new(ERROR_INPUT_QUEUE_FULL, error);
+    }
+    if error.starts_with(INPUT_WRITE_TIMEOUT) {
+        return RpcError::new(ERROR_INPUT_WRITE_TIMEOUT, error);
+    }
+    if error.starts_with(RECORDING_ACTIVE) {
+        return RpcError::new(ERROR_RECORDING_ACTIVE, error);
+    }
     RpcError::new(ERROR_INTERNAL, error)
 }
 
+/// Runs `req` under `control`, keeping frame results typed. Waits for window
+/// locks end when the request is cancelled or its deadline passes.
+pub fn dispatch_request(
+    state: &SharedSidecarState,
+    req: RpcRequest,
+    control: &RequestControl,
+    should_shutdown: &mut bool,
+) -> Result<RpcResult, RpcError> {
+    control.check().map_err(map_runtime_error)?;
+    if req.method == "get_window_frame" {
+        return get_window_frame(state, control, &req.params).map(RpcResult::Frame);
+    }
+    handle_request_within(state, req, control, should_shutdown).map(RpcResult::Value)
+}
+
 pub fn handle_request(
     state: &SharedSidecarState,
     req: RpcRequest,
     should_shutdown: &mut bool,
 ) -> Result<Value, RpcError> {
+    handle_request_within(state, req, &RequestControl::default(), should_shutdown)
+}
+
+fn handle_request_within(
+    state: &SharedSidecarState,
+    req: RpcRequest,
+    control: &RequestControl,
+    should_shutdown: &mut bool,
+) -> Result<Value, RpcError> {
+    // Only methods listed in `METHODS` are served, so every arm below is
+    // advertised in `hello`.
+    if !METHODS.iter().any(|(name, _)| *name == req.method) {
+        return Err(unknown_method(&req.method));
+    }
     match req.method.as_str() {
-        "hello" => Ok(json!({ "version": 1 })),
+        "hello" => Ok(Negotiation::default().hello_result()),
         "health" => {
             let guard = lock_state(state);
-            let running_windows = guard
-                .windows
-                .values()
-                .filter(|window| {
-                    window
-                        .lock()
-                        .ok()
-                        .map(|w| w.snapshot.status == "running")
-                        .unwrap_or(false)
-                })
-                .count();
+            let mut running_windows = 0;
+            let mut throttled_windows = 0;
+            let mut output_bytes_per_sec = 0;
+            let mut dropped_output_bytes = 0;
+            for window in guard.windows.values() {
+                let Ok(w) = window.lock() else { continue };
+                if w.snapshot.status == "running" {
+                    running_windows += 1;
+                }
+                if w.output_stats.is_throttled() {
+                    throttled_windows += 1;
+                }
+                output_bytes_per_sec += w.output_stats.current_rate();
+                dropped_output_bytes += w.output_stats.dropped_bytes;
+            }
             let now_unix_ms = now_unix_millis();
             let method_metrics = guard
                 .rpc_observability
                 .methods
                 .iter()
                 .map(|(method, metrics)| {
-                    let avg_latency_ms = if metrics.requests > 0 {
-                        metrics.total_latency_ms / metrics.requests
-                    } else {
-                        0
-                    };
+                    let avg_latency_ms = metrics
+                        .total_latency_ms
+                        .checked_div(metrics.requests)
+                        .unwrap_or(0);
                     (
                         method.clone(),
                         json!({
@@ -138,14 +284,23 @@ pub fn handle_request(
                 "status": "ok",
                 "version": 1,
                 "pid": std::process::id(),
+                "watchdogPid": guard.child_watchdog.as_ref().map(|watchdog| watchdog.pid()),
                 "startedAtUnixMs": guard.started_at_unix_ms,
                 "uptimeMs": now_unix_ms.saturating_sub(guard.started_at_unix_ms),
                 "sessions": guard.sessions.len(),
                 "windows": guard.windows.len(),
                 "runningWindows": running_windows,
+                "output": {
+                    "rateLimitBytesPerSec": guard.output_flow.rate_limit,
+                    "throttleMode": guard.output_flow.mode.name(),
+                    "bytesPerSec": output_bytes_per_sec,
+                    "throttledWindows": throttled_windows,
+                    "droppedBytes": dropped_output_bytes,
+                },
                 "rpc": {
                     "requestsTotal": guard.rpc_observability.requests_total,
                     "errorsTotal": guard.rpc_observability.errors_total,
+                    "rejectedConnections": guard.rpc_observability.rejected_connections,
                     "methods": method_metrics,
                 },
             }))
@@ -191,15 +346,30 @@ pub fn handle_request(
             let session_name = get_str(&req.params, "sessionName")?;
             let window_name = get_str(&req.params, "windowName")?;
             let command = get_str(&req.params, "command")?;
+            let ambiguous_width = match req.params.get("ambiguousWidth") {
+                None | Some(Value::Null) => None,
+                Some(value) => Some(
+                    value
+                        .as_u64()
+                        .and_then(AmbiguousWidth::from_columns)
+                        .ok_or_else(|| {
+                            RpcError::new(
+                                ERROR_INVALID_PARAMS,
+                                "missing or invalid 'ambiguousWidth'",
+                            )
+                        })?,
+                ),
+            };
 
-            start_window(state, session_name, window_name, command).map_err(map_runtime_error)?;
+            start_window(state, session_name, window_name, command, ambiguous_width)
+                .map_err(map_runtime_error)?;
             Ok(json!({ "ok": true }))
         }
         "type_keys" => {
             let session_name = get_str(&req.params, "sessionName")?;
             let window_name = get_str(&req.params, "windowName")?;
             let keys = get_str(&req.params, "keys")?;
-            with_window(state, &session_name, &window_name, |window| {
+            with_window_within(state, control, &session_name, &window_name, |window| {
                 write_input(window, keys.as_bytes())
             })
             .map_err(map_runtime_error)?;
@@ -208,7 +378,7 @@ pub fn handle_request(
         "send_enter" => {
             let session_name = get_str(&req.params, "sessionName")?;
             let window_name = get_str(&req.params, "windowName")?;
-            with_window(state, &session_name, &window_name, |window| {
+            with_window_within(state, control, &session_name, &window_name, |window| {
                 write_input(window, b"\r")
             })
             .map_err(map_runtime_error)?;
@@ -220,7 +390,7 @@ pub fn handle_request(
             let cols = get_u16(&req.params, "cols", 140);
             let rows = get_u16(&req.params, "rows", 40);
 
-            with_window(state, &session_name, &window_name, |window| {
+            with_window_within(state, control, &session_name, &window_name, |window| {
                 resize_window(window, cols, rows);
                 Ok(())
             })
@@ -250,6 +420,14 @@ pub fn handle_request(
                             "exitedAt": w.snapshot.exited_at,
                             "exitCode": w.snapshot.exit_code,
                             "signal": w.snapshot.signal,
+                            "inputQueuedBytes": w.input.as_ref().map_or(0, |input| input.queued_bytes()),
+                            "inputStalled": w.input.as_ref().is_some_and(|input| input.is_stalled()),
+                            "output": w.output_stats.to_value(),
+                            "statusReason": w
+                                .lifecycle_events
+                                .last()
+                                .filter(|ev| ev.to == w.snapshot.status)
+                                .map(|ev| ev.reason.clone()),
                         }))
                     })
                     .collect::<Vec<_>>()
@@ -259,54 +437,76 @@ pub fn handle_request(
         "get_window_buffer" => {
             let session_name = get_str(&req.params, "sessionName")?;
             let window_name = get_str(&req.params, "windowName")?;
-            let buffer = with_window(state, &session_name, &window_name, |window| {
-                Ok(window.buffer.clone())
+            with_window_within(state, control, &session_name, &window_name, |window| {
+                Ok(json!({
+                    "buffer": window.buffer,
+                    "checkpointBytes": window.checkpoint_bytes,
+                    "compactedBytes": window.compacted_bytes,
+                }))
             })
-            .map_err(map_runtime_error)?;
-            Ok(json!({ "buffer": buffer }))
+            .map_err(map_runtime_error)
         }
         "get_window_frame" => {
+            get_window_frame(state, control, &req.params).map(|reply| reply.to_value())
+        }
+        "start_recording" => {
             let session_name = get_str(&req.params, "sessionName")?;
             let window_name = get_str(&req.params, "windowName")?;
-            let requested_cols = get_opt_u16(&req.params, "cols");
-            let requested_rows = get_opt_u16(&req.params, "rows");
-
-            let frame = with_window(state, &session_name, &window_name, |window| {
-                let cols = requested_cols.unwrap_or(window.snapshot.cols);
-                let rows = requested_rows.unwrap_or(window.snapshot.rows);
-                let now_ms = now_unix_millis();
-                if let Some(cache) = &window.frame_cache {
-                    if cache.cols == cols
-                        && cache.rows == rows
-                        && cache.source_revision == window.output_revision
-                    {
-                        return Ok(cache.frame.clone());
-                    }
-                    if should_coalesce_frame(cache, cols, rows, window.output_revision, now_ms) {
-                        return Ok(cache.frame.clone());
-                    }
-                }
+            let path = PathBuf::from(get_str(&req.params, "path")?);
+            let defaults = RecordingOptions::default();
+            let options = RecordingOptions {
+                include_input: get_opt_bool(&req.params, "includeInput")
+                    .unwrap_or(defaults.include_input),
+                include_resize: get_opt_bool(&req.params, "includeResize")
+                    .unwrap_or(defaults.include_resize),
+            };
 
-                let frame = build_styled_frame(&window.buffer, cols, rows);
-                window.frame_cache = Some(FrameRenderCache {
-                    cols,
-                    rows,
-                    source_revision: window.output_revision,
-                    rendered_at_unix_ms: now_ms,
-                    frame: frame.clone(),
-                });
-                Ok(frame)
+            with_window_within(state, control, &session_name, &window_name, |window| {
+                if let Some(active) = &window.recorder {
+                    return Err(format!("{RECORDING_ACTIVE}: {}", active.path().display()));
+                }
+                let recorder = CastRecorder::create(
+                    &path,
+                    window.snapshot.cols,
+                    window.snapshot.rows,
+                    options,
+                )?;
+                window.recorder = Some(recorder);
+                window.recording_error = None;
+                Ok(())
             })
             .map_err(map_runtime_error)?;
-            Ok(frame)
+            Ok(json!({ "ok": true, "path": path.display().to_string() }))
         }
-        "stop_window" => {
+        "stop_recording" => {
             let session_name = get_str(&req.params, "sessionName")?;
             let window_name = get_str(&req.params, "windowName")?;
 
-            let stopped = with_window(state, &session_name, &window_name, stop_window)
+            let stopped =
+                with_window_within(state, control, &session_name, &window_name, |window| {
+                    let failed = window.recording_error.take();
+                    let Some(recorder) = window.recorder.take() else {
+                        return Ok(json!({ "stopped": false, "error": failed }));
+                    };
+                    let path = recorder.path().display().to_string();
+                    let events = recorder.events();
+                    Ok(match recorder.finish() {
+                        Ok(()) => json!({ "stopped": true, "path": path, "events": events }),
+                        Err(err) => json!({ "stopped": false, "path": path, "error": err }),
+                    })
+                })
                 .map_err(map_runtime_error)?;
 
+            Ok(stopped)
+        }
+        "stop_window" => {
+            let session_name = get_str(&req.params, "sessionName")?;
+            let window_name = get_str(&req.params, "windowName")?;
+
+            let stopped =
+                with_window_within(state, control, &session_name, &window_name, stop_window)
+                    .map_err(map_runtime_error)?;
+
             Ok(json!({ "stopped": stopped }))
         }
         "dispose" => {
@@ -317,17 +517,153 @@ pub fn handle_request(
 
             for window in windows {
                 if let Ok(mut window) = window.lock() {
-                    dispose_window(&mut window);
+                    dispose_window(&mut window, "dispose");
                 }
             }
 
             *should_shutdown = true;
             Ok(json!({ "ok": true }))
         }
-        _ => Err(RpcError::new(
-            ERROR_UNKNOWN_METHOD,
-            format!("unknown method: {}", req.method),
-        )),
+        _ => Err(unknown_method(&req.method)),
+    }
+}
+
+fn unknown_method(method: &str) -> RpcError {
+    RpcError::new(ERROR_UNKNOWN_METHOD, format!("unknown method: {method}"))
+}
+
+fn get_window_frame(
+    state: &SharedSidecarState,
+    control: &RequestControl,
+    params: &Value,
+) -> Result<FrameReply, RpcError> {
+    let session_name = get_str(params, "sessionName")?;
+    let window_name = get_str(params, "windowName")?;
+    let requested_cols = get_opt_u16(params, "cols");
+    let requested_rows = get_opt_u16(params, "rows");
+    let since_revision = params.get("sinceRevision").and_then(Value::as_u64);
+    let viewport_offset = params
+        .get("viewportOffset")
+        .and_then(Value::as_u64)
+        .map(|offset| usize::try_from(offset).unwrap_or(usize::MAX));
+    let granularity = match params.get("granularity") {
+        None | Some(Value::Null) => PatchGranularity::default(),
+        Some(raw) => raw
+            .as_str()
+            .and_then(PatchGranularity::parse)
+            .ok_or_else(|| {
+                RpcError::new(
+                    ERROR_INVALID_PARAMS,
+                    format!("unsupported granularity: {raw}; expected lines or spans"),
+                )
+            })?,
+    };
+
+    with_window_within(state, control, &session_name, &window_name, |window| {
+        let cols = requested_cols.unwrap_or(window.snapshot.cols);
+        let rows = requested_rows.unwrap_or(window.snapshot.rows);
+        if viewport_offset.is_none() && (cols, rows) == (window.snapshot.cols, window.snapshot.rows)
+        {
+            return Ok(pane_frame(window, since_revision, granularity));
+        }
+
+        let now_ms = now_unix_millis();
+        let revision = window.output_revision;
+        let cache = match window.frame_cache.take() {
+            Some(cache)
+                if cache.cols == cols
+                    && cache.rows == rows
+                    && cache.viewport_offset == viewport_offset
+                    && (cache.source_revision == revision
+                        || should_coalesce_frame(&cache, cols, rows, revision, now_ms)) =>
+            {
+                window.frame_cache.insert(cache)
+            }
+            _ => {
+                let ambiguous = window.ambiguous_width;
+                let frame = Arc::new(match viewport_offset {
+                    Some(offset) => render_viewport(&window.buffer, cols, rows, ambiguous, offset),
+                    None => render_frame(&window.buffer, cols, rows, ambiguous),
+                });
+                window.frame_cache.insert(FrameRenderCache {
+                    cols,
+                    rows,
+                    viewport_offset,
+                    source_revision: revision,
+                    rendered_at_unix_ms: now_ms,
+                    frame,
+                })
+            }
+        };
+
+        let revision = view_revision(cache.source_revision, cols, rows, viewport_offset);
+        Ok(if since_revision == Some(revision) {
+            FrameReply::NotModified {
+                not_modified: true,
+                revision,
+            }
+        } else {
+            FrameReply::Full {
+                frame: cache.frame.clone(),
+                revision,
+            }
+        })
+    })
+    .map_err(map_runtime_error)
+}
+
+/// Marks the revisions of frames rendered at another size or scrolled into
+/// history. It stays below 2^53 so that JavaScript clients keep it exact.
+const VIEW_REVISION_BIT: u64 = 1 << 52;
+
+/// Revision of a frame other than the live one at the window's own size. It
+/// also stands for the size and offset the frame was rendered with, so it only
+/// matches a later request for the same view, and never a pane revision.
+fn view_revision(revision: u64, cols: u16, rows: u16, viewport_offset: Option<usize>) -> u64 {
+    let offset = viewport_offset.map_or(0, |offset| (offset as u64).saturating_add(1));
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for part in [revision, u64::from(cols), u64::from(rows), offset] {
+        hash = (hash ^ part).wrapping_mul(0x0000_0100_0000_01b3);
+        hash ^= hash >> 29;
+    }
+    VIEW_REVISION_BIT | (hash & (VIEW_REVISION_BIT - 1))
+}
+
+/// Frame of the window at its own size, straight from its pane. With
+/// `since_revision`, it is a patch at `granularity` built from the damage
+/// recorded since, as long as the window's damage log reaches back that far.
+/// A `since_revision` of a frame at another size or scrolled into history is
+/// answered whole, since a patch only applies to a frame of the pane's shape.
+fn pane_frame(
+    window: &WindowState,
+    since_revision: Option<u64>,
+    granularity: PatchGranularity,
+) -> FrameReply {
+    let revision = window.output_revision;
+    match since_revision {
+        Some(since) if since == revision => {
+            return FrameReply::NotModified {
+                not_modified: true,
+                revision,
+            };
+        }
+        Some(since) if since < revision && since & VIEW_REVISION_BIT == 0 => {
+            let patch = window
+                .pane
+                .patch_since(&window.damage_log, since, granularity);
+            if let Some(patch) = patch {
+                return FrameReply::Patch {
+                    patch,
+                    revision,
+                    base_revision: since,
+                };
+            }
+        }
+        _ => {}
+    }
+    FrameReply::Full {
+        frame: Arc::new(window.pane.styled_frame()),
+        revision,
     }
 }
 
@@ -346,6 +682,10 @@ fn get_opt_str(params: &Value, key: &str) -> Option<String> {
         .map(|v| v.to_string())
 }
 
+fn get_opt_bool(params: &Value, key: &str) -> Option<bool> {
+    params.get(key).and_then(|v| v.as_bool())
+}
+
 fn get_opt_u16(params: &Value, key: &str) -> Option<u16> {
     let value = params.get(key)?.as_u64()?;
     Some(value.clamp(10, 400) as u16)
@@ -360,6 +700,7 @@ fn start_window(
     session_name: String,
     window_name: String,
     command: String,
+    ambiguous_width: Option<AmbiguousWidth>,
 ) -> Result<(), String> {
     let key = window_key(&session_name, &window_name);
 
@@ -389,10 +730,17 @@ fn start_window(
         w.snapshot.signal = None;
         w.snapshot.pid = None;
         w.buffer.clear();
-        w.query_carry.clear();
+        w.utf8_carry.clear();
+        w.checkpoint_bytes = 0;
+        w.compacted_bytes = 0;
         w.private_modes.clear();
+        if let Some(ambiguous_width) = ambiguous_width {
+            w.ambiguous_width = ambiguous_width;
+        }
+        w.rebuild_pane();
         w.launch_env.clear();
         w.frame_cache = None;
+        w.command = Some(command.clone());
         w.lifecycle_generation = w.lifecycle_generation.saturating_add(1);
         mark_output_mutation(&mut w);
         w.lifecycle_generation
@@ -404,9 +752,7 @@ fn start_window(
         let mut w = lock_window(&window);
         let _ = transition_window_state(&mut w, WindowLifecycleState::Error, "spawn-failed");
         w.snapshot.exited_at = Some(now_unix_seconds());
-        w.buffer
-            .push_str(&format!("[runtime] process error: {}\n", err));
-        mark_output_mutation(&mut w);
+        append_note(&mut w, &format!("[runtime] process error: {}\n", err));
         return Err(err);
     }
 
@@ -430,7 +776,10 @@ fn now_unix_millis() -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::session_manager::new_shared_state;
+    use crate::handoff::HANDOFF_METHOD;
+    use crate::output_flow::{OutputFlowConfig, ThrottleMode};
+    use crate::protocol::CANCEL_METHOD;
+    use crate::session_manager::{new_shared_state, with_window};
     use std::thread;
     use std::time::Duration;
 
@@ -467,6 +816,41 @@ mod tests {
         .unwrap_or_else(|err| panic!("{method} failed: {err}"))
     }
 
+    #[test]
+    fn every_advertised_method_is_dispatched() {
+        let state = new_shared_state();
+        let _cleanup = Cleanup(state.clone());
+        // `handoff` and `cancel` are served by the connection loop, not by
+        // `handle_request`.
+        for (method, _) in METHODS
+            .iter()
+            .filter(|(name, _)| ![HANDOFF_METHOD, CANCEL_METHOD].contains(name))
+        {
+            let outcome = handle_request(
+                &state,
+                RpcRequest {
+                    id: None,
+                    method: method.to_string(),
+                    params: json!({}),
+                    timeout_ms: None,
+                },
+                &mut false,
+            );
+            if let Err(error) = outcome {
+                assert_ne!(error.code, ERROR_UNKNOWN_METHOD, "{method} is advertised");
+            }
+        }
+        let hello = call(&state, "hello", json!({}));
+        assert_eq!(hello["version"], 1);
+        assert!(hello["capabilities"]["binaryFraming"].is_object());
+    }
+
+    fn push_output(window: &mut WindowState, text: &str) {
+        window.buffer.push_str(text);
+        window.pane.feed(text.as_bytes());
+        mark_output_mutation(window);
+    }
+
     fn line_text(frame: &Value, row: usize) -> String {
         frame["lines"][row]["segments"]
             .as_array()
@@ -641,8 +1025,7 @@ mod tests {
         );
 
         with_window(&state, "proj-c", "win-c", |window| {
-            window.buffer = "A".to_string();
-            window.output_revision = window.output_revision.saturating_add(1);
+            push_output(window, "A");
             window.frame_cache = None;
             Ok(())
         })
@@ -656,8 +1039,7 @@ mod tests {
         assert!(line_text(&frame_a, 0).starts_with('A'));
 
         with_window(&state, "proj-c", "win-c", |window| {
-            window.buffer.push('B');
-            window.output_revision = window.output_revision.saturating_add(1);
+            push_output(window, "B");
             if let Some(cache) = window.frame_cache.as_mut() {
                 cache.rendered_at_unix_ms = u64::MAX;
             }
@@ -688,6 +1070,247 @@ mod tests {
         assert!(line_text(&frame_latest, 0).starts_with("AB"));
     }
 
+    #[test]
+    fn answers_since_revision_with_not_modified_a_patch_or_a_full_frame() {
+        let state = new_shared_state();
+        let _cleanup = Cleanup(state.clone());
+        call(
+            &state,
+            "get_or_create_session",
+            json!({ "projectName": "proj-r", "firstWindowName": "win-r" }),
+        );
+        let append = |text: &str| {
+            with_window(&state, "proj-r", "win-r", |window| {
+                push_output(window, text);
+                if let Some(cache) = window.frame_cache.as_mut() {
+                    cache.rendered_at_unix_ms = 0;
+                }
+                Ok(())
+            })
+            .expect("window should exist");
+        };
+        let fetch = |since: Option<u64>| {
+            let mut params = json!({ "sessionName": "proj-r", "windowName": "win-r" });
+            if let Some(since) = since {
+                params["sinceRevision"] = json!(since);
+            }
+            call(&state, "get_window_frame", params)
+        };
+
+        append("first");
+        let full = fetch(None);
+        let first = full["revision"].as_u64().expect("revision");
+        assert_eq!(line_text(&full, 0), "first");
+
+        let unchanged = fetch(Some(first));
+        assert_eq!(unchanged, json!({ "notModified": true, "revision": first }));
+
+        append("\r\nsecond");
+        let patch = fetch(Some(first));
+        let second = patch["revision"].as_u64().expect("revision");
+        assert!(second > first);
+        assert_eq!(patch["baseRevision"].as_u64(), Some(first));
+        assert!(patch.get("lines").is_none());
+        let changed = patch["changedLines"].as_array().expect("changed lines");
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0]["row"], 1);
+        assert_eq!(changed[0]["line"]["segments"][0]["text"], "second");
+        assert_eq!(patch["cursorCol"], 6);
+
+        append("\x1b[1;1HF");
+        append("\x1b[1;2HI");
+        let merged = fetch(Some(second));
+        let rows: Vec<_> = merged["changedLines"]
+            .as_array()
+            .expect("changed lines")
+            .iter()
+            .map(|changed| changed["row"].as_u64())
+            .collect();
+        assert_eq!(rows, vec![Some(0)]);
+        assert_eq!(
+            merged["changedLines"][0]["line"]["segments"][0]["text"],
+            "FIrst"
+        );
+        let third = merged["revision"].as_u64().expect("revision");
+
+        // Output that changes nothing on screen patches nothing.
+        append("\x1b]0;title\x07");
+        let empty = fetch(Some(third));
+        assert_eq!(empty["baseRevision"].as_u64(), Some(third));
+        assert!(empty["changedLines"].as_array().expect("lines").is_empty());
+        let third = empty["revision"].as_u64().expect("revision");
+        assert_eq!(
+            fetch(Some(third)),
+            json!({ "notModified": true, "revision": third })
+        );
+
+        let unknown = fetch(Some(third + 100));
+        assert_eq!(unknown["revision"].as_u64(), Some(third));
+        assert_eq!(line_text(&unknown, 1), "second");
+
+        // Other sizes are rendered whole, or not at all if unchanged.
+        let params = json!({ "sessionName": "proj-r", "windowName": "win-r", "cols": 30 });
+        let resized = call(&state, "get_window_frame", params.clone());
+        assert_eq!(line_text(&resized, 0), "FIrst");
+        let mut again = params;
+        again["sinceRevision"] = resized["revision"].clone();
+        assert_eq!(call(&state, "get_window_frame", again)["notModified"], true);
+
+        // A frame of another shape is never patched into the window's own.
+        append("\r\nthird");
+        let live = fetch(resized["revision"].as_u64());
+        assert!(live.get("baseRevision").is_none());
+        assert_eq!(line_text(&live, 0), "FIrst");
+        assert_eq!(line_text(&live, 1), "thirdd");
+    }
+
+    #[test]
+    fn patches_changed_columns_as_spans_when_asked() {
+        let state = new_shared_state();
+        let _cleanup = Cleanup(state.clone());
+        call(
+            &state,
+            "get_or_create_session",
+            json!({ "projectName": "proj-g", "firstWindowName": "win-g" }),
+        );
+        let append = |text: &str| {
+            with_window(&state, "proj-g", "win-g", |window| {
+                push_output(window, text);
+                Ok(window.output_revision)
+            })
+            .expect("window should exist")
+        };
+        let fetch = |since: u64, granularity: &str| {
+            call(
+                &state,
+                "get_window_frame",
+                json!({
+                    "sessionName": "proj-g",
+                    "windowName": "win-g",
+                    "sinceRevision": since,
+                    "granularity": granularity,
+                }),
+            )
+        };
+
+        let hello = call(&state, "hello", json!({}));
+        assert_eq!(
+            hello["capabilities"]["patchGranularity"]["granularities"],
+            json!(["lines", "spans"])
+        );
+
+        let base = append("\x1b[2J\x1b[HIndexing the project, please wait [-]");
+        append("\x1b[1;36H\\");
+        let spans = fetch(base, "spans");
+        assert_eq!(spans["baseRevision"].as_u64(), Some(base));
+        assert!(spans["changedLines"].as_array().expect("lines").is_empty());
+        assert_eq!(spans["changedSpans"][0]["row"], 0);
+        assert_eq!(spans["changedSpans"][0]["startCol"], 35);
+        assert_eq!(spans["changedSpans"][0]["segments"][0]["text"], "\\");
+
+        let lines = fetch(base, "lines");
+        assert!(lines.get("changedSpans").is_none());
+        assert_eq!(
+            lines["changedLines"][0]["line"]["segments"][0]["text"],
+            "Indexing the project, please wait [\\]"
+        );
+
+        let error = handle_request(
+            &state,
+            RpcRequest {
+                id: None,
+                method: "get_window_frame".to_string(),
+                params: json!({
+                    "sessionName": "proj-g",
+                    "windowName": "win-g",
+                    "granularity": "cells",
+                }),
+                timeout_ms: None,
+            },
+            &mut false,
+        )
+        .expect_err("unknown granularity");
+        assert_eq!(error.code, ERROR_INVALID_PARAMS);
+    }
+
+    #[test]
+    fn renders_a_viewport_scrolled_into_history() {
+        let state = new_shared_state();
+        let _cleanup = Cleanup(state.clone());
+        call(
+            &state,
+            "get_or_create_session",
+            json!({ "projectName": "proj-v", "firstWindowName": "win-v" }),
+        );
+        with_window(&state, "proj-v", "win-v", |window| {
+            window.buffer = (1..=15)
+                .map(|n| format!("line {n}"))
+                .collect::<Vec<_>>()
+                .join("\r\n");
+            window.output_revision = window.output_revision.saturating_add(1);
+            Ok(())
+        })
+        .expect("window should exist");
+        let params = |offset: u64| {
+            json!({
+                "sessionName": "proj-v",
+                "windowName": "win-v",
+                "cols": 20,
+                "rows": 10,
+                "viewportOffset": offset,
+            })
+        };
+
+        let live = call(&state, "get_window_frame", params(0));
+        assert_eq!(line_text(&live, 0), "line 6");
+        assert_eq!(live["viewportOffset"], 0);
+
+        let scrolled = call(&state, "get_window_frame", params(3));
+        assert_eq!(line_text(&scrolled, 0), "line 3");
+        assert_eq!(line_text(&scrolled, 9), "line 12");
+        assert_eq!(scrolled["viewportOffset"], 3);
+        assert_eq!(scrolled["cursorVisible"], false);
+
+        let top = call(&state, "get_window_frame", params(50));
+        assert_eq!(line_text(&top, 0), "line 1");
+        assert_eq!(top["viewportOffset"], 5);
+
+        let mut same = params(3);
+        same["sinceRevision"] = scrolled["revision"].clone();
+        assert_eq!(call(&state, "get_window_frame", same)["notModified"], true);
+    }
+
+    #[test]
+    fn sends_the_live_frame_after_scrolling_back_without_new_output() {
+        let state = new_shared_state();
+        let _cleanup = Cleanup(state.clone());
+        call(
+            &state,
+            "get_or_create_session",
+            json!({ "projectName": "proj-b", "firstWindowName": "win-b" }),
+        );
+        with_window(&state, "proj-b", "win-b", |window| {
+            let lines = (1..=60).map(|n| format!("line {n}")).collect::<Vec<_>>();
+            push_output(window, &lines.join("\r\n"));
+            Ok(())
+        })
+        .expect("window should exist");
+        let params = json!({ "sessionName": "proj-b", "windowName": "win-b" });
+
+        let mut scroll = params.clone();
+        scroll["viewportOffset"] = json!(3);
+        let scrolled = call(&state, "get_window_frame", scroll);
+        assert_eq!(line_text(&scrolled, 0), "line 18");
+
+        let mut back = params;
+        back["sinceRevision"] = scrolled["revision"].clone();
+        let live = call(&state, "get_window_frame", back);
+        assert!(live.get("notModified").is_none());
+        assert!(live.get("baseRevision").is_none());
+        assert_eq!(line_text(&live, 0), "line 21");
+        assert_eq!(line_text(&live, 39), "line 60");
+    }
+
     #[test]
     fn keeps_cursor_and_frame_consistent_under_rapid_resize() {
         let state = new_shared_state();
@@ -779,6 +1402,194 @@ mod tests {
         assert_eq!(windows[0]["status"].as_str(), Some("exited"));
     }
 
+    #[test]
+    fn rejects_an_ambiguous_width_other_than_one_or_two_columns() {
+        let state = new_shared_state();
+        let _cleanup = Cleanup(state.clone());
+
+        let error = handle_request(
+            &state,
+            RpcRequest {
+                id: None,
+                method: "start_window".to_string(),
+                params: json!({
+                    "sessionName": "proj-w",
+                    "windowName": "win-w",
+                    "command": "cat",
+                    "ambiguousWidth": 3
+                }),
+                timeout_ms: None,
+            },
+            &mut false,
+        )
+        .expect_err("three columns is not an ambiguous width");
+        assert_eq!(error.code, ERROR_INVALID_PARAMS);
+        assert!(!lock_state(&state)
+            .windows
+            .contains_key(&window_key("proj-w", "win-w")));
+    }
+
+    #[test]
+    fn rejects_input_that_does_not_fit_the_window_queue() {
+        let state = new_shared_state();
+        let _cleanup = Cleanup(state.clone());
+        lock_state(&state).input_queue_bytes = 4;
+
+        call(
+            &state,
+            "get_or_create_session",
+            json!({ "projectName": "proj-q", "firstWindowName": "win-q" }),
+        );
+        call(
+            &state,
+            "start_window",
+            json!({ "sessionName": "proj-q", "windowName": "win-q", "command": "cat" }),
+        );
+
+        let error = handle_request(
+            &state,
+            RpcRequest {
+                id: None,
+                method: "type_keys".to_string(),
+                params: json!({ "sessionName": "proj-q", "windowName": "win-q", "keys": "hello" }),
+                timeout_ms: None,
+            },
+            &mut false,
+        )
+        .expect_err("five bytes do not fit a four-byte queue");
+        assert_eq!(error.code, ERROR_INPUT_QUEUE_FULL);
+
+        call(
+            &state,
+            "type_keys",
+            json!({ "sessionName": "proj-q", "windowName": "win-q", "keys": "hi" }),
+        );
+        let listed = call(&state, "list_windows", json!({ "sessionName": "proj-q" }));
+        let queued = listed["windows"][0]["inputQueuedBytes"]
+            .as_u64()
+            .expect("inputQueuedBytes");
+        assert!(queued <= 2);
+        assert_eq!(listed["windows"][0]["inputStalled"], false);
+    }
+
+    #[test]
+    fn keeps_the_tail_of_runaway_output() {
+        let state = new_shared_state();
+        let _cleanup = Cleanup(state.clone());
+        lock_state(&state).output_flow = OutputFlowConfig {
+            rate_limit: Some(1_000),
+            mode: ThrottleMode::KeepTail,
+        };
+
+        call(
+            &state,
+            "get_or_create_session",
+            json!({ "projectName": "proj-o", "firstWindowName": "win-o" }),
+        );
+        call(
+            &state,
+            "start_window",
+            json!({
+                "sessionName": "proj-o",
+                "windowName": "win-o",
+                "command": "head -c 200000 /dev/zero | tr '\\0' x; echo; echo tail-end; sleep 5"
+            }),
+        );
+
+        let params = json!({ "sessionName": "proj-o", "windowName": "win-o" });
+        let mut result = Value::Null;
+        for _ in 0..200 {
+            result = call(&state, "get_window_buffer", params.clone());
+            if result["buffer"]
+                .as_str()
+                .unwrap_or_default()
+                .contains("tail-end")
+            {
+                break;
+            }
+            thread::sleep(Duration::from_millis(25));
+        }
+        let buffer = result["buffer"].as_str().unwrap_or_default();
+        assert!(buffer.contains("bytes skipped)"), "no skip note in output");
+        assert!(buffer.contains("tail-end"), "tail was not kept");
+
+        // How much is dropped depends on how often the reader catches up, but
+        // every byte is either kept, compacted or counted as dropped.
+        let listed = call(&state, "list_windows", json!({ "sessionName": "proj-o" }));
+        let output = &listed["windows"][0]["output"];
+        let dropped = output["droppedBytes"].as_u64().unwrap_or_default();
+        assert!(dropped > 0, "runaway output was not dropped");
+        let raw = buffer.len() as u64 - result["checkpointBytes"].as_u64().unwrap_or_default();
+        let compacted = result["compactedBytes"].as_u64().unwrap_or_default();
+        assert!(raw + compacted + dropped >= 200_000);
+        assert!(output["throttleEvents"].as_u64().unwrap_or_default() >= 1);
+        let health = call(&state, "health", json!({}));
+        assert_eq!(health["output"]["throttleMode"], "keep-tail");
+        assert_eq!(health["output"]["rateLimitBytesPerSec"], 1_000);
+    }
+
+    #[test]
+    fn compacts_long_output_behind_a_checkpoint() {
+        let state = new_shared_state();
+        let _cleanup = Cleanup(state.clone());
+        lock_state(&state).max_buffer_bytes = 4_096;
+
+        call(
+            &state,
+            "get_or_create_session",
+            json!({ "projectName": "proj-c", "firstWindowName": "win-c" }),
+        );
+        call(
+            &state,
+            "start_window",
+            json!({
+                "sessionName": "proj-c",
+                "windowName": "win-c",
+                "command": "printf '\\033[?1049h\\033[1;33mpinned\\r\\n'; seq 1 3000; echo done-c; sleep 5"
+            }),
+        );
+
+        let params = json!({ "sessionName": "proj-c", "windowName": "win-c" });
+        let mut result = Value::Null;
+        for _ in 0..200 {
+            result = call(&state, "get_window_buffer", params.clone());
+            if result["buffer"]
+                .as_str()
+                .unwrap_or_default()
+                .contains("done-c")
+            {
+                break;
+            }
+            thread::sleep(Duration::from_millis(25));
+        }
+        let buffer = result["buffer"].as_str().unwrap_or_default();
+        let checkpoint = result["checkpointBytes"].as_u64().unwrap_or_default() as usize;
+        assert!(buffer.contains("done-c"), "output did not finish");
+        assert!(
+            buffer.len() <= 4_096 + checkpoint,
+            "buffer was not compacted"
+        );
+        assert!(result["compactedBytes"].as_u64().unwrap_or_default() > 10_000);
+        assert!(buffer[..checkpoint].contains("\x1b[?1049h"));
+        assert!(!buffer[checkpoint..].contains("pinned"));
+        let hello = call(&state, "hello", json!({}));
+        assert_eq!(
+            hello["capabilities"]["bufferCheckpoints"]["fields"],
+            json!(["checkpointBytes", "compactedBytes"])
+        );
+
+        let frame = call(&state, "get_window_frame", params);
+        let text = frame["lines"]
+            .as_array()
+            .expect("lines")
+            .iter()
+            .flat_map(|line| line["segments"].as_array().cloned().unwrap_or_default())
+            .filter_map(|segment| segment["text"].as_str().map(str::to_string))
+            .collect::<String>();
+        assert!(text.contains("done-c"), "{text}");
+        assert!(text.contains("2999"), "{text}");
+    }
+
     #[test]
     fn dispose_during_io_clears_runtime_handles() {
         let state = new_shared_state();
@@ -825,7 +1636,7 @@ mod tests {
         with_window(&state, "proj-f", "win-f", |window| {
             assert!(window.child.is_none());
             assert!(window.master.is_none());
-            assert!(window.writer.is_none());
+            assert!(window.input.is_none());
             assert_eq!(window.snapshot.status, "exited");
             Ok(())
         })
@@ -1083,15 +1894,242 @@ mod tests {
         let health = call(&state, "health", json!({}));
         assert_eq!(health["runningWindows"].as_u64(), Some(0));
 
-        let guard = lock_state(&state);
-        for window in guard.windows.values() {
-            let window = window
-                .lock()
-                .unwrap_or_else(|poisoned| poisoned.into_inner());
-            assert_ne!(window.snapshot.status, "running");
-            assert!(window.child.is_none());
-            assert!(window.master.is_none());
-            assert!(window.writer.is_none());
+        {
+            let guard = lock_state(&state);
+            for window in guard.windows.values() {
+                let window = window
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                assert_ne!(window.snapshot.status, "running");
+                assert!(window.child.is_none());
+                assert!(window.master.is_none());
+                assert!(window.input.is_none());
+            }
+        }
+
+        // Abrupt death: a separate sidecar process is SIGKILLed while its
+        // windows run; its watchdog must take every window group down.
+        let groups = run_and_kill_stress_child();
+        assert_eq!(groups.len(), STRESS_CHILD_WINDOWS.len());
+        let mut leftovers = groups.clone();
+        for _ in 0..150 {
+            leftovers.retain(|pgid| group_has_live_members(*pgid));
+            if leftovers.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
         }
+        for pgid in &leftovers {
+            unsafe {
+                libc::kill(-(*pgid as libc::pid_t), libc::SIGKILL);
+            }
+        }
+        assert!(
+            leftovers.is_empty(),
+            "window process groups survived sidecar SIGKILL: {leftovers:?}"
+        );
+    }
+
+    const STRESS_CHILD_ENV: &str = "DISCODE_PTY_SIDECAR_STRESS_CHILD";
+    const STRESS_CHILD_WINDOWS: [&str; 3] = [
+        "sleep 600",
+        "trap '' HUP TERM; sleep 600",
+        "sleep 600 & sleep 600",
+    ];
+
+    /// Re-runs this test binary as a throwaway sidecar, waits for it to report
+    /// its window pids, then SIGKILLs it. Returns the window process groups.
+    fn run_and_kill_stress_child() -> Vec<u32> {
+        let exe = std::env::current_exe().expect("test binary path");
+        let mut child = std::process::Command::new(exe)
+            .args([
+                "--exact",
+                "rpc::tests::stress_child_sidecar_until_killed",
+                "--ignored",
+                "--nocapture",
+                "--test-threads=1",
+            ])
+            .env(STRESS_CHILD_ENV, "1")
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .expect("spawn stress child");
+
+        let stdout = child.stdout.take().expect("stress child stdout");
+        let (tx, rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            use std::io::BufRead;
+            for line in std::io::BufReader::new(stdout)
+                .lines()
+                .map_while(Result::ok)
+            {
+                // libtest may print its own progress on the same line.
+                if let Some((_, pid)) = line.split_once("stress-pid=") {
+                    if tx.send(pid.trim().parse::<u32>().ok()).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let mut groups = Vec::new();
+        while groups.len() < STRESS_CHILD_WINDOWS.len() {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Some(pid)) => groups.push(pid),
+                _ => break,
+            }
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+        groups
+    }
+
+    #[test]
+    #[ignore = "spawned by lifecycle_stress_run_leaves_no_running_windows_or_handles"]
+    fn stress_child_sidecar_until_killed() {
+        if std::env::var_os(STRESS_CHILD_ENV).is_none() {
+            return;
+        }
+        let state = new_shared_state();
+        lock_state(&state).child_watchdog =
+            Some(crate::child_watchdog::ChildWatchdog::spawn().expect("watchdog should fork"));
+
+        for (idx, command) in STRESS_CHILD_WINDOWS.iter().enumerate() {
+            let window_name = format!("win-{idx}");
+            call(
+                &state,
+                "start_window",
+                json!({
+                    "sessionName": "proj-stress",
+                    "windowName": window_name,
+                    "command": command
+                }),
+            );
+            let running = wait_for_window_status(&state, "proj-stress", &window_name, "running");
+            println!("stress-pid={}", running["pid"]);
+        }
+        thread::sleep(Duration::from_secs(60));
+    }
+
+    /// Whether any non-zombie process is still in `pgid`. Orphaned zombies
+    /// may linger if pid 1 does not reap, so `kill(-pgid, 0)` is not enough.
+    fn group_has_live_members(pgid: u32) -> bool {
+        let Ok(entries) = std::fs::read_dir("/proc") else {
+            return unsafe { libc::kill(-(pgid as libc::pid_t), 0) == 0 };
+        };
+        entries.filter_map(Result::ok).any(|entry| {
+            let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+                return false;
+            };
+            let Some((_, fields)) = stat.rsplit_once(')') else {
+                return false;
+            };
+            let mut fields = fields.split_whitespace();
+            let state = fields.next();
+            let process_group = fields.nth(1).and_then(|raw| raw.parse::<u32>().ok());
+            state != Some("Z") && process_group == Some(pgid)
+        })
+    }
+
+    #[test]
+    fn records_window_session_to_asciicast_file() {
+        let state = new_shared_state();
+        let _cleanup = Cleanup(state.clone());
+        let path = std::env::temp_dir().join(format!(
+            "discode-pty-sidecar-rpc-{}-{}.cast",
+            std::process::id(),
+            now_unix_millis()
+        ));
+
+        call(
+            &state,
+            "start_window",
+            json!({
+                "sessionName": "proj-k",
+                "windowName": "win-k",
+                "command": "cat"
+            }),
+        );
+        let _running = wait_for_window_status(&state, "proj-k", "win-k", "running");
+
+        call(
+            &state,
+            "start_recording",
+            json!({
+                "sessionName": "proj-k",
+                "windowName": "win-k",
+                "path": path.display().to_string(),
+                "includeInput": true
+            }),
+        );
+        let second = handle_request(
+            &state,
+            RpcRequest {
+                id: None,
+                method: "start_recording".to_string(),
+                params: json!({
+                    "sessionName": "proj-k",
+                    "windowName": "win-k",
+                    "path": format!("{}.second", path.display())
+                }),
+                timeout_ms: None,
+            },
+            &mut false,
+        )
+        .expect_err("a running recording is not replaced");
+        assert_eq!(second.code, ERROR_RECORDING_ACTIVE);
+        call(
+            &state,
+            "type_keys",
+            json!({ "sessionName": "proj-k", "windowName": "win-k", "keys": "cast-me" }),
+        );
+        call(
+            &state,
+            "resize_window",
+            json!({ "sessionName": "proj-k", "windowName": "win-k", "cols": 90, "rows": 20 }),
+        );
+
+        for _ in 0..40 {
+            let buffer = call(
+                &state,
+                "get_window_buffer",
+                json!({ "sessionName": "proj-k", "windowName": "win-k" }),
+            );
+            if buffer["buffer"]
+                .as_str()
+                .map(|text| text.contains("cast-me"))
+                .unwrap_or(false)
+            {
+                break;
+            }
+            thread::sleep(Duration::from_millis(25));
+        }
+
+        let stopped = call(
+            &state,
+            "stop_recording",
+            json!({ "sessionName": "proj-k", "windowName": "win-k" }),
+        );
+        assert_eq!(stopped["stopped"].as_bool(), Some(true));
+        assert!(stopped["events"].as_u64().unwrap_or(0) >= 3);
+
+        let raw = std::fs::read_to_string(&path).expect("cast file should exist");
+        let _ = std::fs::remove_file(&path);
+        let (header, events) = crate::recording::parse_cast(&raw).expect("cast should parse");
+        assert_eq!((header.width, header.height), (140, 40));
+        assert!(events
+            .iter()
+            .any(|ev| ev.kind == "i" && ev.data == "cast-me"));
+        assert!(events.iter().any(|ev| ev.kind == "r" && ev.data == "90x20"));
+        assert!(events
+            .iter()
+            .any(|ev| ev.kind == "o" && ev.data.contains("cast-me")));
+
+        let again = call(
+            &state,
+            "stop_recording",
+            json!({ "sessionName": "proj-k", "windowName": "win-k" }),
+        );
+        assert_eq!(again["stopped"].as_bool(), Some(false));
     }
 }
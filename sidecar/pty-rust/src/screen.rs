@@ -1,13 +1,153 @@
-use crate::grid_scrollback::{blank_cell, make_row, Cell};
+use crate::grid_scrollback::{Cell, CellTables};
+use std::collections::VecDeque;
+use std::ops::Range;
 
+// Revisions whose damage a log keeps; frames older than that are sent whole.
+const DAMAGE_LOG_REVISIONS: usize = 64;
+
+/// Screen contents as the renderer sees them. Borrows the pane's lines; a
+/// row shorter than `cols`, or missing altogether, renders as blank.
 #[derive(Clone)]
-pub struct ScreenFrame {
+pub struct ScreenFrame<'a> {
     pub cols: usize,
     pub rows: usize,
-    pub lines: Vec<Vec<Cell>>,
+    pub lines: &'a [Vec<Cell>],
+    /// Styles and graphemes the cells refer to.
+    pub tables: &'a CellTables,
     pub cursor_row: usize,
     pub cursor_col: usize,
     pub cursor_visible: bool,
+    pub reverse_video: bool,
+}
+
+impl ScreenFrame<'_> {
+    /// Cells of `row` within the screen width, or `None` past the last line.
+    pub fn row(&self, row: usize) -> Option<&[Cell]> {
+        if row >= self.rows {
+            return None;
+        }
+        self.lines
+            .get(row)
+            .map(|cells| &cells[..cells.len().min(self.cols)])
+    }
+}
+
+/// Cells written since the damage was last taken, as one column range per
+/// row.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Damage {
+    cols: usize,
+    dirty: Vec<Option<Range<usize>>>,
+}
+
+impl Damage {
+    pub fn new(cols: usize, rows: usize) -> Self {
+        Self {
+            cols,
+            dirty: vec![None; rows],
+        }
+    }
+
+    pub fn mark_row(&mut self, row: usize) {
+        self.mark_cells(row, 0..self.cols);
+    }
+
+    /// Marks the columns `cols` of `row`.
+    pub fn mark_cells(&mut self, row: usize, cols: Range<usize>) {
+        let cols = cols.start.min(self.cols)..cols.end.min(self.cols);
+        if cols.is_empty() {
+            return;
+        }
+        if let Some(dirty) = self.dirty.get_mut(row) {
+            *dirty = Some(match dirty.take() {
+                Some(old) => old.start.min(cols.start)..old.end.max(cols.end),
+                None => cols,
+            });
+        }
+    }
+
+    pub fn mark_rows(&mut self, rows: Range<usize>) {
+        for row in rows.start..rows.end.min(self.dirty.len()) {
+            self.mark_row(row);
+        }
+    }
+
+    pub fn mark_all(&mut self) {
+        self.mark_rows(0..self.dirty.len());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirty.iter().all(Option::is_none)
+    }
+
+    /// Damaged rows with the columns written in each.
+    pub fn spans(&self) -> impl Iterator<Item = (usize, Range<usize>)> + '_ {
+        self.dirty
+            .iter()
+            .enumerate()
+            .filter_map(|(row, dirty)| dirty.clone().map(|cols| (row, cols)))
+    }
+
+    /// Returns the damage so far and starts over with none.
+    pub fn take(&mut self) -> Self {
+        let fresh = Self::new(self.cols, self.dirty.len());
+        std::mem::replace(self, fresh)
+    }
+
+    /// Adds the cells marked in `other`.
+    pub fn merge(&mut self, other: &Damage) {
+        for (row, cols) in other.spans() {
+            self.mark_cells(row, cols);
+        }
+    }
+}
+
+/// Damage of a window's latest revisions, so that a frame shown at any of
+/// them can be patched up to the newest.
+#[derive(Default)]
+pub struct DamageLog {
+    // Oldest revision a patch can start from; `None` until a revision is
+    // recorded after the log was cleared.
+    base: Option<u64>,
+    entries: VecDeque<(u64, Damage)>,
+}
+
+impl DamageLog {
+    /// Records `damage` as what changed to reach `revision`.
+    pub fn record(&mut self, revision: u64, damage: Damage) {
+        if self.base.is_none() {
+            self.base = Some(revision);
+            return;
+        }
+        if damage.is_empty() {
+            return;
+        }
+        if self.entries.len() == DAMAGE_LOG_REVISIONS {
+            self.base = self.entries.pop_front().map(|(oldest, _)| oldest);
+        }
+        self.entries.push_back((revision, damage));
+    }
+
+    /// Forgets all damage, for when the whole screen was replaced; frames
+    /// before the next recorded revision can no longer be patched.
+    pub fn clear(&mut self) {
+        self.base = None;
+        self.entries.clear();
+    }
+
+    /// Damage recorded after `revision`, or `None` if the log does not
+    /// reach back that far.
+    pub fn since(&self, revision: u64) -> Option<impl Iterator<Item = &Damage>> {
+        if revision < self.base? {
+            return None;
+        }
+        Some(
+            self.entries
+                .iter()
+                .filter(move |(recorded, _)| *recorded > revision)
+                .map(|(_, damage)| damage),
+        )
+    }
 }
 
 #[derive(Default)]
@@ -18,57 +158,100 @@ impl Screen {
         Self
     }
 
-    pub fn compose(
+    pub fn compose<'a>(
         &self,
-        source_lines: &[Vec<Cell>],
+        lines: &'a [Vec<Cell>],
+        tables: &'a CellTables,
         cols: usize,
         rows: usize,
-        cursor_row: usize,
-        cursor_col: usize,
+        (cursor_row, cursor_col): (usize, usize),
         cursor_visible: bool,
-    ) -> ScreenFrame {
-        let mut lines = source_lines.to_vec();
-
-        if lines.len() < rows {
-            lines.extend((0..(rows - lines.len())).map(|_| make_row(cols)));
-        }
-
-        for row in &mut lines {
-            if row.len() < cols {
-                row.extend((0..(cols - row.len())).map(|_| blank_cell()));
-            } else if row.len() > cols {
-                row.truncate(cols);
-            }
-        }
-
-        if lines.len() > rows {
-            lines.truncate(rows);
-        }
-
+    ) -> ScreenFrame<'a> {
         ScreenFrame {
             cols,
             rows,
             lines,
+            tables,
             cursor_row: cursor_row.min(rows.saturating_sub(1)),
             cursor_col: cursor_col.min(cols.saturating_sub(1)),
             cursor_visible,
+            reverse_video: false,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Screen;
-    use crate::grid_scrollback::make_row;
+    use super::{Damage, DamageLog, Screen, DAMAGE_LOG_REVISIONS};
+    use crate::grid_scrollback::{make_row, CellTables};
 
     #[test]
     fn composes_and_clamps_cursor_metadata() {
         let screen = Screen::new();
-        let frame = screen.compose(&[make_row(10)], 10, 6, 20, 20, true);
+        let tables = CellTables::new();
+        let lines = [make_row(12)];
+        let frame = screen.compose(&lines, &tables, 10, 6, (20, 20), true);
 
-        assert_eq!(frame.lines.len(), 6);
+        assert_eq!(frame.row(0).map(<[_]>::len), Some(10));
+        assert!(frame.row(1).is_none());
         assert_eq!(frame.cursor_row, 5);
         assert_eq!(frame.cursor_col, 9);
         assert!(frame.cursor_visible);
     }
+
+    #[test]
+    fn takes_damaged_rows_once() {
+        let mut damage = Damage::new(10, 6);
+        assert!(damage.is_empty());
+        damage.mark_cells(1, 4..5);
+        damage.mark_cells(1, 2..3);
+        damage.mark_cells(2, 8..20);
+        damage.mark_rows(4..9);
+        damage.mark_row(40);
+
+        let taken = damage.take();
+        assert_eq!(
+            taken.spans().collect::<Vec<_>>(),
+            vec![(1, 2..5), (2, 8..10), (4, 0..10), (5, 0..10)]
+        );
+        assert!(damage.is_empty());
+    }
+
+    #[test]
+    fn patches_from_revisions_the_log_still_reaches() {
+        let damaged = |row| {
+            let mut damage = Damage::new(10, 6);
+            damage.mark_row(row);
+            damage
+        };
+        let rows_since = |log: &DamageLog, revision| {
+            log.since(revision).map(|damage| {
+                let mut merged = Damage::new(10, 6);
+                damage.for_each(|damage| merged.merge(damage));
+                merged.spans().map(|(row, _)| row).collect::<Vec<_>>()
+            })
+        };
+
+        let mut log = DamageLog::default();
+        log.record(3, damaged(5));
+        log.record(4, damaged(1));
+        log.record(5, Damage::new(10, 6));
+        log.record(6, damaged(2));
+        assert_eq!(rows_since(&log, 2), None);
+        assert_eq!(rows_since(&log, 3), Some(vec![1, 2]));
+        assert_eq!(rows_since(&log, 5), Some(vec![2]));
+        assert_eq!(rows_since(&log, 6), Some(vec![]));
+
+        for revision in 7..7 + DAMAGE_LOG_REVISIONS as u64 {
+            log.record(revision, damaged(0));
+        }
+        assert_eq!(rows_since(&log, 5), None);
+        assert_eq!(rows_since(&log, 6), Some(vec![0]));
+
+        log.clear();
+        assert_eq!(rows_since(&log, 80), None);
+        log.record(90, damaged(3));
+        assert_eq!(rows_since(&log, 89), None);
+        assert_eq!(rows_since(&log, 90), Some(vec![]));
+    }
 }
@@ -0,0 +1,1638 @@
+use crate::access_control::{
+    bind_restricted, ensure_socket_parent, read_token_file, AccessPolicy, TOKEN_ENV,
+};
+use crate::child_watchdog::ChildWatchdog;
+use crate::connection::{
+    internal_error_response, reject_connection, serve_channel, write_response,
+};
+use crate::handoff::{prepare_handoff, receive_handoff, resume_handoff, send_handoff};
+use crate::instance_lock::{probe_socket, InstanceLock, SocketProbe};
+use crate::output_flow::OutputFlowConfig;
+use crate::pty_bus::{reap_leftover_process, shutdown_windows};
+use crate::rpc::{
+    handle_request, invalid_request, RpcError, RpcRequest, RpcResponse, ERROR_INTERNAL,
+};
+use crate::session_manager::{
+    lock_state, new_shared_state, record_rejected_connection, SharedSidecarState,
+};
+use crate::signals::{signal_exit_code, signal_name, spawn_signal_thread, SidecarSignal};
+use crate::socket_activation::listener_from_fd;
+use crate::state_store::{load_state, save_state};
+use crate::transport::{LineChannel, ListenAddress, ListenScheme, MessageChannel};
+use crate::websocket::WebSocketChannel;
+use serde::Deserialize;
+use serde_json::json;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::RawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_SNAPSHOT_INTERVAL_MS: u64 = 2_000;
+const DEFAULT_SHUTDOWN_GRACE_MS: u64 = 3_000;
+const DEFAULT_MAX_NETWORK_CONNECTIONS: usize = 32;
+// How long a handoff successor waits for its predecessor to release the lock.
+const HANDOFF_LOCK_WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub socket_path: PathBuf,
+    pub state_dir: Option<PathBuf>,
+    pub snapshot_interval_ms: u64,
+    pub restart_interrupted: bool,
+    pub handoff: bool,
+    pub access: AccessPolicy,
+    pub token_file: Option<PathBuf>,
+    pub shutdown_grace_ms: u64,
+    pub config_file: Option<PathBuf>,
+    pub handle_signals: bool,
+    /// Already-listening socket passed in by a supervisor (systemd
+    /// `LISTEN_FDS` or `--listen-fd`). The supervisor owns the socket
+    /// path, so it is neither bound nor unlinked by the server.
+    pub listen_fd: Option<RawFd>,
+    /// Extra `tcp://` and `ws://` endpoints; they always require a token.
+    pub listen: Vec<ListenAddress>,
+    pub listen_allow_remote: bool,
+    /// `Origin`s allowed to open a `ws://` connection. Requests without an
+    /// `Origin` (non-browser clients) are always allowed.
+    pub ws_allowed_origins: Vec<String>,
+    /// Open connections allowed across all `--listen` endpoints.
+    pub max_network_connections: usize,
+    /// Per-window output rate limit and what happens past it.
+    pub output_flow: OutputFlowConfig,
+}
+
+impl ServerConfig {
+    pub fn new(socket_path: PathBuf) -> Self {
+        Self {
+            socket_path,
+            state_dir: None,
+            snapshot_interval_ms: DEFAULT_SNAPSHOT_INTERVAL_MS,
+            restart_interrupted: false,
+            handoff: false,
+            access: AccessPolicy::same_user(),
+            token_file: None,
+            shutdown_grace_ms: DEFAULT_SHUTDOWN_GRACE_MS,
+            config_file: None,
+            handle_signals: false,
+            listen_fd: None,
+            listen: Vec::new(),
+            listen_allow_remote: false,
+            ws_allowed_origins: Vec::new(),
+            max_network_connections: DEFAULT_MAX_NETWORK_CONNECTIONS,
+            output_flow: OutputFlowConfig::default(),
+        }
+    }
+}
+
+/// Optional JSON file passed with `--config`. Its values override the
+/// command line and are applied again on SIGHUP.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct FileConfig {
+    allowed_uids: Option<Vec<u32>>,
+    token_file: Option<PathBuf>,
+    shutdown_grace_ms: Option<u64>,
+}
+
+/// Settings that can change while the server runs.
+#[derive(Clone)]
+struct RuntimeSettings {
+    access: AccessPolicy,
+    shutdown_grace: Duration,
+}
+
+fn load_settings(config: &ServerConfig) -> Result<RuntimeSettings, String> {
+    let mut access = config.access.clone();
+    let mut grace_ms = config.shutdown_grace_ms;
+    let mut token_file = config.token_file.clone();
+
+    if let Some(path) = &config.config_file {
+        let raw =
+            fs::read_to_string(path).map_err(|e| format!("read config {}: {e}", path.display()))?;
+        let file = serde_json::from_str::<FileConfig>(&raw)
+            .map_err(|e| format!("decode config {}: {e}", path.display()))?;
+        if let Some(uids) = file.allowed_uids {
+            access.allow_uids(&uids);
+        }
+        if let Some(ms) = file.shutdown_grace_ms {
+            grace_ms = ms;
+        }
+        if file.token_file.is_some() {
+            token_file = file.token_file;
+        }
+    }
+    if let Some(path) = token_file {
+        access.token = Some(read_token_file(&path)?);
+    }
+
+    Ok(RuntimeSettings {
+        access,
+        shutdown_grace: Duration::from_millis(grace_ms),
+    })
+}
+
+fn lock_settings(settings: &Mutex<RuntimeSettings>) -> MutexGuard<'_, RuntimeSettings> {
+    settings
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn run_server(config: ServerConfig) -> Result<(), String> {
+    let socket_path = config.socket_path.clone();
+    let state = new_shared_state();
+    let running = Arc::new(AtomicBool::new(true));
+    let settings = Arc::new(Mutex::new(load_settings(&config)?));
+    let token = lock_settings(&settings).access.token.clone();
+    if !config.listen.is_empty() && token.is_none() {
+        return Err(format!(
+            "--listen requires a token (--token-file or {TOKEN_ENV})"
+        ));
+    }
+    for address in &config.listen {
+        address.check_exposure(config.listen_allow_remote)?;
+    }
+    // Forked before any window exists so it holds none of their fds.
+    lock_state(&state).child_watchdog = Some(ChildWatchdog::spawn()?);
+    lock_state(&state).output_flow = config.output_flow;
+
+    ensure_socket_parent(&socket_path)?;
+    let (listener, instance_lock) = if config.handoff {
+        let listener = receive_handoff(&socket_path, &state, token.as_deref())?;
+        // The predecessor releases the lock once it has exited.
+        let lock = InstanceLock::acquire_within(&socket_path, HANDOFF_LOCK_WAIT)?;
+        (listener, lock)
+    } else {
+        let lock = InstanceLock::acquire(&socket_path)?;
+        let listener = match config.listen_fd {
+            Some(fd) => listener_from_fd(fd)?,
+            None => bind_fresh_socket(&socket_path, token.as_deref())?,
+        };
+        if let Some(state_dir) = &config.state_dir {
+            restore_state(&state, state_dir, config.restart_interrupted)?;
+        }
+        (listener, lock)
+    };
+    let instance_lock = Arc::new(Mutex::new(Some(instance_lock)));
+    // A predecessor that handed off may still be releasing its ports.
+    let port_wait = if config.handoff {
+        HANDOFF_LOCK_WAIT
+    } else {
+        Duration::ZERO
+    };
+    let network = NetworkContext {
+        state: state.clone(),
+        settings: settings.clone(),
+        running: running.clone(),
+        socket_path: socket_path.clone(),
+        allowed_origins: config.ws_allowed_origins.clone().into(),
+        slots: Arc::new(ConnectionSlots::new(config.max_network_connections)),
+    };
+    let mut network_listeners = Vec::new();
+    for address in &config.listen {
+        let listener = bind_tcp_within(address.addr, port_wait)?;
+        network_listeners.push(spawn_network_listener(
+            address.scheme,
+            listener,
+            network.clone(),
+        )?);
+    }
+    if config.handle_signals {
+        spawn_signal_handler(
+            config.clone(),
+            state.clone(),
+            settings.clone(),
+            running.clone(),
+            instance_lock.clone(),
+        )?;
+    }
+    if let Some(state_dir) = &config.state_dir {
+        spawn_snapshot_thread(
+            state.clone(),
+            state_dir.clone(),
+            Duration::from_millis(config.snapshot_interval_ms),
+            running.clone(),
+        );
+    }
+
+    let mut handed_off = false;
+    while running.load(Ordering::SeqCst) {
+        let (stream, _) = match listener.accept() {
+            Ok(tuple) => tuple,
+            Err(err) => return Err(format!("accept failed: {err}")),
+        };
+        if !running.load(Ordering::SeqCst) {
+            // Woken up after a network client requested shutdown.
+            break;
+        }
+        let reader = match stream.try_clone() {
+            Ok(reader) => reader,
+            Err(err) => {
+                eprintln!("clone stream for read failed: {err}");
+                continue;
+            }
+        };
+        let mut channel = LineChannel::new(reader, stream);
+
+        let policy = lock_settings(&settings).access.clone();
+        if let Err(err) = policy.check_peer(channel.get_mut()) {
+            reject_connection(&mut channel, &state, None, err);
+            continue;
+        }
+
+        if let Err(err) = handle_connection(
+            &mut channel,
+            &state,
+            &running,
+            &listener,
+            &policy,
+            &mut handed_off,
+        ) {
+            let _ = write_response(&mut channel, &internal_error_response(err));
+        }
+    }
+
+    for network_listener in network_listeners {
+        network_listener.stop();
+    }
+    if !handed_off {
+        finish_shutdown(&state, &config);
+    }
+    // Otherwise the successor now owns the socket path, the PTYs and the
+    // state dir; it is waiting for this lock.
+    drop(take_instance_lock(&instance_lock));
+    Ok(())
+}
+
+/// Binds `socket_path` after making sure no live sidecar is serving it;
+/// a stale socket file left by a crashed server is replaced.
+fn bind_fresh_socket(socket_path: &Path, token: Option<&str>) -> Result<UnixListener, String> {
+    if socket_path.exists() {
+        match probe_socket(socket_path, token) {
+            SocketProbe::Stale => {
+                let _ = fs::remove_file(socket_path);
+            }
+            SocketProbe::Unresponsive => {
+                return Err(format!(
+                    "{} is accepting connections but did not answer hello; refusing to replace it",
+                    socket_path.display()
+                ));
+            }
+            SocketProbe::Answered(_) => {
+                return Err(format!(
+                    "a sidecar is already serving {}; use --handoff to replace it",
+                    socket_path.display()
+                ));
+            }
+        }
+    }
+    bind_restricted(socket_path)
+}
+
+fn finish_shutdown(state: &SharedSidecarState, config: &ServerConfig) {
+    if let Some(state_dir) = &config.state_dir {
+        if let Err(err) = save_state(state, state_dir) {
+            eprintln!("state snapshot failed: {err}");
+        }
+    }
+    if config.listen_fd.is_none() {
+        let _ = fs::remove_file(&config.socket_path);
+    }
+}
+
+/// SIGTERM/SIGINT behave like `dispose` with a grace period and end the
+/// process with `128 + signo`; SIGHUP reloads `--config` and the token file.
+fn spawn_signal_handler(
+    config: ServerConfig,
+    state: SharedSidecarState,
+    settings: Arc<Mutex<RuntimeSettings>>,
+    running: Arc<AtomicBool>,
+    instance_lock: Arc<Mutex<Option<InstanceLock>>>,
+) -> Result<(), String> {
+    spawn_signal_thread(move |signal| match signal {
+        SidecarSignal::Reload => match load_settings(&config) {
+            Ok(next) => *lock_settings(&settings) = next,
+            Err(err) => eprintln!("config reload failed: {err}"),
+        },
+        SidecarSignal::Terminate(signo) => {
+            running.store(false, Ordering::SeqCst);
+            let grace = lock_settings(&settings).shutdown_grace;
+            let code = shutdown_for_signal(&state, &config, grace, signo);
+            // Removes the pidfile; the lock itself goes away with the process.
+            drop(take_instance_lock(&instance_lock));
+            std::process::exit(code);
+        }
+    })
+}
+
+fn take_instance_lock(lock: &Mutex<Option<InstanceLock>>) -> Option<InstanceLock> {
+    lock.lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take()
+}
+
+fn shutdown_for_signal(
+    state: &SharedSidecarState,
+    config: &ServerConfig,
+    grace: Duration,
+    signo: i32,
+) -> i32 {
+    shutdown_windows(state, grace, &format!("signal-{}", signal_name(signo)));
+    finish_shutdown(state, config);
+    signal_exit_code(signo)
+}
+
+fn restore_state(
+    state: &SharedSidecarState,
+    state_dir: &Path,
+    restart_interrupted: bool,
+) -> Result<(), String> {
+    let interrupted = load_state(state, state_dir)?;
+    for window in &interrupted {
+        let Some(pid) = window.pid else {
+            continue;
+        };
+        if reap_leftover_process(pid, window.started_at) {
+            eprintln!(
+                "reaped leftover process group {pid} of {}:{}",
+                window.session_name, window.window_name
+            );
+        }
+    }
+    if !restart_interrupted {
+        return Ok(());
+    }
+
+    for window in interrupted {
+        let Some(command) = window.command else {
+            continue;
+        };
+        let mut should_shutdown = false;
+        let restarted = handle_request(
+            state,
+            RpcRequest {
+                id: None,
+                method: "start_window".to_string(),
+                params: json!({
+                    "sessionName": window.session_name,
+                    "windowName": window.window_name,
+                    "command": command,
+                }),
+                timeout_ms: None,
+            },
+            &mut should_shutdown,
+        );
+        if let Err(err) = restarted {
+            eprintln!(
+                "restart of {}:{} failed: {err}",
+                window.session_name, window.window_name
+            );
+        }
+    }
+    Ok(())
+}
+
+fn spawn_snapshot_thread(
+    state: SharedSidecarState,
+    state_dir: PathBuf,
+    interval: Duration,
+    running: Arc<AtomicBool>,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+        if let Err(err) = save_state(&state, &state_dir) {
+            eprintln!("state snapshot failed: {err}");
+        }
+    });
+}
+
+fn hand_off_to_successor(
+    channel: &mut LineChannel<UnixStream>,
+    state: &SharedSidecarState,
+    listener: &UnixListener,
+    request_id: Option<u64>,
+) -> Result<bool, String> {
+    let package = match prepare_handoff(state) {
+        Ok(package) => package,
+        Err(err) => {
+            write_response(
+                channel,
+                &RpcResponse {
+                    ok: false,
+                    id: request_id,
+                    result: None,
+                    error: Some(RpcError::new(ERROR_INTERNAL, err)),
+                },
+            )?;
+            return Ok(false);
+        }
+    };
+
+    let accepted = write_response(
+        channel,
+        &RpcResponse {
+            ok: true,
+            id: request_id,
+            result: Some(
+                json!({
+                    "windows": package.window_count(),
+                    "manifestBytes": package.manifest_len(),
+                })
+                .into(),
+            ),
+            error: None,
+        },
+    );
+    if let Err(err) = accepted {
+        resume_handoff(state, package);
+        return Err(err);
+    }
+    send_handoff(state, channel.get_mut(), listener, package)?;
+    // The successor tracks the handed-over groups with its own watchdog.
+    if let Some(watchdog) = lock_state(state).child_watchdog.as_ref() {
+        watchdog.release_all();
+    }
+    Ok(true)
+}
+
+/// Serves a unix socket connection, the only transport that can carry a
+/// handoff (it needs `SCM_RIGHTS`).
+fn handle_connection(
+    channel: &mut LineChannel<UnixStream>,
+    state: &SharedSidecarState,
+    running: &Arc<AtomicBool>,
+    listener: &UnixListener,
+    policy: &AccessPolicy,
+    handed_off: &mut bool,
+) -> Result<(), String> {
+    serve_channel(
+        channel,
+        state,
+        running,
+        policy,
+        |channel: &mut LineChannel<UnixStream>, request_id| {
+            let done = hand_off_to_successor(channel, state, listener, request_id)?;
+            *handed_off |= done;
+            Ok(done)
+        },
+    )
+}
+
+fn bind_tcp_within(addr: SocketAddr, wait: Duration) -> Result<TcpListener, String> {
+    let deadline = Instant::now() + wait;
+    loop {
+        match TcpListener::bind(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(err) if err.kind() == ErrorKind::AddrInUse && Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(25));
+            }
+            Err(err) => return Err(format!("listen on {addr}: {err}")),
+        }
+    }
+}
+
+/// Accept thread for one `--listen` endpoint. Each connection gets its
+/// own thread, so a long-lived viewer does not block other clients.
+struct NetworkListener {
+    wake_addr: SocketAddr,
+    thread: thread::JoinHandle<()>,
+}
+
+impl NetworkListener {
+    /// Unblocks the accept loop (which sees `running == false`) and waits
+    /// for it to close the listening socket.
+    fn stop(self) {
+        let _ = TcpStream::connect(self.wake_addr);
+        let _ = self.thread.join();
+    }
+}
+
+/// What every network accept thread shares.
+#[derive(Clone)]
+struct NetworkContext {
+    state: SharedSidecarState,
+    settings: Arc<Mutex<RuntimeSettings>>,
+    running: Arc<AtomicBool>,
+    socket_path: PathBuf,
+    allowed_origins: Arc<[String]>,
+    slots: Arc<ConnectionSlots>,
+}
+
+/// Caps open network connections, each of which holds a thread.
+struct ConnectionSlots {
+    open: AtomicUsize,
+    max: usize,
+}
+
+/// One claimed slot, given back when the connection ends.
+struct ConnectionSlot(Arc<ConnectionSlots>);
+
+impl ConnectionSlots {
+    fn new(max: usize) -> Self {
+        Self {
+            open: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    fn claim(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < self.max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(self.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn spawn_network_listener(
+    scheme: ListenScheme,
+    listener: TcpListener,
+    network: NetworkContext,
+) -> Result<NetworkListener, String> {
+    let mut wake_addr = listener
+        .local_addr()
+        .map_err(|e| format!("listener address: {e}"))?;
+    if wake_addr.ip().is_unspecified() {
+        wake_addr.set_ip(match wake_addr {
+            SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+
+    let thread = thread::spawn(move || {
+        for stream in listener.incoming() {
+            if !network.running.load(Ordering::SeqCst) {
+                break;
+            }
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let Some(slot) = network.slots.claim() else {
+                // Closed without reading anything from the peer.
+                record_rejected_connection(&network.state);
+                continue;
+            };
+            let network = network.clone();
+            let policy = lock_settings(&network.settings).access.clone();
+            thread::spawn(move || {
+                let _slot = slot;
+                if let Err(err) = serve_network_connection(scheme, stream, &network, &policy) {
+                    eprintln!("network connection failed: {err}");
+                }
+                if !network.running.load(Ordering::SeqCst) {
+                    // Wake the unix accept loop so the server can exit.
+                    let _ = UnixStream::connect(&network.socket_path);
+                }
+            });
+        }
+    });
+    Ok(NetworkListener { wake_addr, thread })
+}
+
+fn serve_network_connection(
+    scheme: ListenScheme,
+    stream: TcpStream,
+    network: &NetworkContext,
+    policy: &AccessPolicy,
+) -> Result<(), String> {
+    let _ = stream.set_nodelay(true);
+    let reader = stream
+        .try_clone()
+        .map_err(|e| format!("clone stream for read failed: {e}"))?;
+    let state = &network.state;
+    let running = &network.running;
+    match scheme {
+        ListenScheme::Tcp => serve_network_channel(
+            &mut LineChannel::new(reader, stream),
+            state,
+            running,
+            policy,
+        ),
+        ListenScheme::WebSocket => serve_network_channel(
+            &mut WebSocketChannel::accept(reader, stream, &network.allowed_origins)?,
+            state,
+            running,
+            policy,
+        ),
+    }
+}
+
+fn serve_network_channel<C: MessageChannel>(
+    channel: &mut C,
+    state: &SharedSidecarState,
+    running: &AtomicBool,
+    policy: &AccessPolicy,
+) -> Result<(), String> {
+    // Network peers have no SO_PEERCRED identity; the token is the only
+    // credential, so a reload that removed it locks them out.
+    if !policy.requires_token() {
+        reject_connection(
+            channel,
+            state,
+            None,
+            "network listeners require a token".to_string(),
+        );
+        return Ok(());
+    }
+    let served = serve_channel(
+        channel,
+        state,
+        running,
+        policy,
+        |channel: &mut C, request_id| {
+            write_response(
+                channel,
+                &RpcResponse {
+                    ok: false,
+                    id: request_id,
+                    result: None,
+                    error: Some(invalid_request(
+                        "handoff is only available on the unix socket".to_string(),
+                    )),
+                },
+            )?;
+            Ok(false)
+        },
+    );
+    if let Err(err) = &served {
+        let _ = write_response(channel, &internal_error_response(err.clone()));
+    }
+    served
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_settings, run_server, shutdown_for_signal, ServerConfig};
+    use crate::access_control::current_uid;
+    use crate::client::{instance_status, send_request};
+    use crate::handoff::receive_handoff;
+    use crate::instance_lock::{lock_file_path, probe_socket, SocketProbe};
+    use crate::pty_bus::write_input;
+    use crate::rpc::{handle_request, RpcRequest, ERROR_INVALID_PARAMS};
+    use crate::session_manager::{new_shared_state, with_window, SharedSidecarState};
+    use crate::transport::{LineChannel, ListenAddress, MessageChannel, WireEncoding};
+    use serde_json::{json, Value};
+    use std::fs;
+    use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    fn unique_test_socket() -> PathBuf {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        std::env::temp_dir().join(format!(
+            "discode-pty-sidecar-test-{}-{}.sock",
+            std::process::id(),
+            stamp
+        ))
+    }
+
+    #[test]
+    fn server_removes_socket_file_on_dispose_shutdown() {
+        let socket_path = unique_test_socket();
+        if socket_path.exists() {
+            let _ = fs::remove_file(&socket_path);
+        }
+
+        let server_socket = socket_path.clone();
+        let handle = thread::spawn(move || run_server(ServerConfig::new(server_socket)));
+
+        let mut ready = false;
+        for _ in 0..80 {
+            if socket_path.exists() {
+                ready = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(ready, "socket should appear for server startup");
+
+        let response = send_request(
+            &socket_path,
+            &RpcRequest {
+                id: Some(1),
+                method: "dispose".to_string(),
+                params: json!({}),
+                timeout_ms: Some(2_000),
+            },
+            None,
+        )
+        .unwrap_or_else(|err| panic!("dispose request failed: {err}"));
+        assert!(response.contains("\"ok\":true"));
+
+        let joined = handle
+            .join()
+            .unwrap_or_else(|_| panic!("server thread should not panic"));
+        assert!(joined.is_ok(), "server should stop cleanly");
+
+        assert!(
+            !socket_path.exists(),
+            "socket file should be removed after shutdown"
+        );
+    }
+
+    fn wait_for_socket(socket_path: &std::path::Path) {
+        for _ in 0..80 {
+            if socket_path.exists() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("socket should appear for server startup");
+    }
+
+    fn request(socket_path: &std::path::Path, method: &str, params: Value) -> Value {
+        let raw = send_request(
+            socket_path,
+            &RpcRequest {
+                id: Some(1),
+                method: method.to_string(),
+                params,
+                timeout_ms: Some(2_000),
+            },
+            None,
+        )
+        .unwrap_or_else(|err| panic!("{method} request failed: {err}"));
+        serde_json::from_str(raw.trim()).expect("response should be JSON")
+    }
+
+    #[test]
+    fn second_server_refuses_live_socket_and_status_reports_owner() {
+        let socket_path = unique_test_socket().with_extension("single.sock");
+        let server_socket = socket_path.clone();
+        let first = thread::spawn(move || run_server(ServerConfig::new(server_socket)));
+        wait_for_socket(&socket_path);
+
+        let err = run_server(ServerConfig::new(socket_path.clone()))
+            .expect_err("second server must not take over a live socket");
+        assert!(err.contains("another sidecar instance"), "{err}");
+        assert!(socket_path.exists(), "live socket must not be unlinked");
+
+        let status = instance_status(&socket_path, None);
+        assert_eq!(status["running"], true);
+        assert_eq!(status["lockHeld"], true);
+        assert_eq!(status["responding"], true);
+        assert_eq!(status["pid"].as_u64(), Some(u64::from(std::process::id())));
+        assert!(status["health"]["rpc"].is_object());
+
+        assert_eq!(request(&socket_path, "dispose", json!({}))["ok"], true);
+        let joined = first
+            .join()
+            .unwrap_or_else(|_| panic!("server thread should not panic"));
+        assert!(joined.is_ok(), "server should stop cleanly");
+
+        let status = instance_status(&socket_path, None);
+        assert_eq!(status["running"], false);
+        assert_eq!(status["pid"], Value::Null);
+        let _ = fs::remove_file(lock_file_path(&socket_path));
+    }
+
+    #[test]
+    fn activated_server_serves_inherited_socket_across_restarts() {
+        use std::os::fd::AsRawFd;
+
+        let socket_path = unique_test_socket().with_extension("activated.sock");
+        // Plays the supervisor: owns the bound socket and hands each
+        // server generation its own copy of the fd.
+        let supervisor = std::os::unix::net::UnixListener::bind(&socket_path).expect("bind");
+
+        for generation in 0..2 {
+            let fd = unsafe { libc::dup(supervisor.as_raw_fd()) };
+            assert!(fd >= 0, "dup listener fd");
+            let mut config = ServerConfig::new(socket_path.clone());
+            config.listen_fd = Some(fd);
+            let server = thread::spawn(move || run_server(config));
+
+            let health = request(&socket_path, "health", json!({}));
+            assert_eq!(health["ok"], true, "generation {generation}: {health}");
+            assert_eq!(request(&socket_path, "dispose", json!({}))["ok"], true);
+            let joined = server
+                .join()
+                .unwrap_or_else(|_| panic!("server thread should not panic"));
+            assert!(joined.is_ok(), "server should stop cleanly: {joined:?}");
+            assert!(
+                socket_path.exists(),
+                "inherited socket must stay bound after shutdown"
+            );
+        }
+
+        drop(supervisor);
+        let _ = fs::remove_file(&socket_path);
+        let _ = fs::remove_file(lock_file_path(&socket_path));
+    }
+
+    #[test]
+    fn server_replaces_stale_socket_file() {
+        let socket_path = unique_test_socket().with_extension("stale.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket_path).expect("bind stale"));
+        assert!(socket_path.exists());
+
+        let server_socket = socket_path.clone();
+        let server = thread::spawn(move || run_server(ServerConfig::new(server_socket)));
+        let mut answered = false;
+        for _ in 0..80 {
+            if let SocketProbe::Answered(_) = probe_socket(&socket_path, None) {
+                answered = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(answered, "server should bind over the stale socket");
+
+        assert_eq!(request(&socket_path, "dispose", json!({}))["ok"], true);
+        let joined = server
+            .join()
+            .unwrap_or_else(|_| panic!("server thread should not panic"));
+        assert!(joined.is_ok(), "server should stop cleanly");
+        let _ = fs::remove_file(lock_file_path(&socket_path));
+    }
+
+    #[test]
+    fn token_protected_server_rejects_requests_before_hello() {
+        let socket_path = unique_test_socket().with_extension("token.sock");
+        let mut config = ServerConfig::new(socket_path.clone());
+        config.access.token = Some("s3cret".to_string());
+        let server = thread::spawn(move || run_server(config));
+        wait_for_socket(&socket_path);
+
+        let mode = fs::metadata(&socket_path)
+            .expect("socket metadata")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let rejected = request(&socket_path, "list_windows", json!({}));
+        assert_eq!(rejected["ok"], false);
+        assert_eq!(rejected["error"]["code"], "UNAUTHORIZED");
+
+        let authed = |method: &str| -> Value {
+            let raw = send_request(
+                &socket_path,
+                &RpcRequest {
+                    id: Some(2),
+                    method: method.to_string(),
+                    params: json!({}),
+                    timeout_ms: Some(2_000),
+                },
+                Some("s3cret"),
+            )
+            .unwrap_or_else(|err| panic!("{method} request failed: {err}"));
+            serde_json::from_str(raw.trim()).expect("response should be JSON")
+        };
+        let wrong = send_request(
+            &socket_path,
+            &RpcRequest {
+                id: Some(3),
+                method: "health".to_string(),
+                params: json!({}),
+                timeout_ms: None,
+            },
+            Some("wrong"),
+        )
+        .expect("wrong-token request should still get a response");
+        assert!(wrong.contains("UNAUTHORIZED"));
+
+        let health = authed("health");
+        assert_eq!(health["ok"], true);
+        assert_eq!(health["result"]["rpc"]["rejectedConnections"], 2);
+
+        assert_eq!(authed("dispose")["ok"], true);
+        let joined = server
+            .join()
+            .unwrap_or_else(|_| panic!("server thread should not panic"));
+        assert!(joined.is_ok(), "server should stop cleanly");
+    }
+
+    fn free_tcp_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .map(|addr| addr.port())
+            .expect("free port")
+    }
+
+    fn connect_tcp(port: u16) -> std::net::TcpStream {
+        for _ in 0..80 {
+            if let Ok(stream) = std::net::TcpStream::connect(("127.0.0.1", port)) {
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                return stream;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("listener on port {port} should accept connections");
+    }
+
+    fn ws_send(stream: &mut std::net::TcpStream, text: &str) {
+        let mask = [0x11u8, 0x22, 0x33, 0x44];
+        let payload = text.as_bytes();
+        assert!(payload.len() < 126, "test frames use the short length form");
+        let mut frame = vec![0x81, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        stream.write_all(&frame).expect("write frame");
+    }
+
+    fn ws_receive(stream: &mut std::net::TcpStream) -> Value {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).expect("frame header");
+        assert_eq!(head[0], 0x81, "expected a final text frame");
+        let len = match head[1] {
+            126 => {
+                let mut ext = [0u8; 2];
+                stream.read_exact(&mut ext).expect("length");
+                usize::from(u16::from_be_bytes(ext))
+            }
+            127 => {
+                let mut ext = [0u8; 8];
+                stream.read_exact(&mut ext).expect("length");
+                u64::from_be_bytes(ext) as usize
+            }
+            short => usize::from(short),
+        };
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).expect("payload");
+        serde_json::from_slice(&payload).expect("frame should hold JSON")
+    }
+
+    #[test]
+    fn serves_token_authenticated_tcp_and_websocket_listeners() {
+        let socket_path = unique_test_socket().with_extension("net.sock");
+        let (tcp_port, ws_port) = (free_tcp_port(), free_tcp_port());
+        let mut config = ServerConfig::new(socket_path.clone());
+        config.access.token = Some("s3cret".to_string());
+        config.listen = vec![
+            ListenAddress::parse(&format!("tcp://127.0.0.1:{tcp_port}")).expect("tcp"),
+            ListenAddress::parse(&format!("ws://localhost:{ws_port}/rpc")).expect("ws"),
+        ];
+        let server = thread::spawn(move || run_server(config));
+        wait_for_socket(&socket_path);
+
+        let mut tcp = connect_tcp(tcp_port);
+        let mut tcp_reader = BufReader::new(tcp.try_clone().expect("clone"));
+        let mut tcp_call = |line: &str| -> Value {
+            tcp.write_all(format!("{line}\n").as_bytes())
+                .expect("write");
+            let mut reply = String::new();
+            tcp_reader.read_line(&mut reply).expect("read");
+            serde_json::from_str(reply.trim()).expect("JSON reply")
+        };
+        let rejected = tcp_call(r#"{"id":1,"method":"health"}"#);
+        assert_eq!(rejected["error"]["code"], "UNAUTHORIZED");
+
+        let mut tcp = connect_tcp(tcp_port);
+        let mut tcp_reader = BufReader::new(tcp.try_clone().expect("clone"));
+        let mut tcp_call = |line: &str| -> Value {
+            tcp.write_all(format!("{line}\n").as_bytes())
+                .expect("write");
+            let mut reply = String::new();
+            tcp_reader.read_line(&mut reply).expect("read");
+            serde_json::from_str(reply.trim()).expect("JSON reply")
+        };
+        let hello = tcp_call(r#"{"id":1,"method":"hello","params":{"token":"s3cret"}}"#);
+        assert_eq!(hello["ok"], true);
+        let health = tcp_call(r#"{"id":2,"method":"health"}"#);
+        assert_eq!(health["ok"], true);
+        assert_eq!(health["result"]["rpc"]["rejectedConnections"], 1);
+        let handoff = tcp_call(r#"{"id":3,"method":"handoff"}"#);
+        assert_eq!(handoff["ok"], false);
+
+        let mut ws = connect_tcp(ws_port);
+        ws.write_all(
+            b"GET /rpc HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .expect("write handshake");
+        let mut handshake = Vec::new();
+        while !handshake.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            ws.read_exact(&mut byte).expect("handshake byte");
+            handshake.push(byte[0]);
+        }
+        let handshake = String::from_utf8(handshake).expect("utf8");
+        assert!(handshake.starts_with("HTTP/1.1 101"), "{handshake}");
+        assert!(handshake.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        ws_send(
+            &mut ws,
+            r#"{"id":1,"method":"hello","params":{"token":"s3cret"}}"#,
+        );
+        assert_eq!(ws_receive(&mut ws)["ok"], true);
+        ws_send(&mut ws, r#"{"id":2,"method":"list_windows"}"#);
+        let listed = ws_receive(&mut ws);
+        assert_eq!(listed["ok"], true);
+        assert_eq!(listed["id"], 2);
+
+        // Shutdown requested over the network must also stop the unix loop.
+        ws_send(&mut ws, r#"{"id":3,"method":"dispose"}"#);
+        assert_eq!(ws_receive(&mut ws)["ok"], true);
+        let joined = server
+            .join()
+            .unwrap_or_else(|_| panic!("server thread should not panic"));
+        assert!(joined.is_ok(), "server should stop cleanly: {joined:?}");
+        assert!(!socket_path.exists());
+        assert!(
+            std::net::TcpStream::connect(("127.0.0.1", tcp_port)).is_err(),
+            "network listeners should be closed after shutdown"
+        );
+        let _ = fs::remove_file(lock_file_path(&socket_path));
+    }
+
+    #[test]
+    fn network_listeners_require_token_and_loopback_by_default() {
+        let socket_path = unique_test_socket().with_extension("nettoken.sock");
+        let mut config = ServerConfig::new(socket_path.clone());
+        config.listen = vec![ListenAddress::parse("tcp://127.0.0.1:0").expect("tcp")];
+        let err = run_server(config).expect_err("--listen without a token must fail");
+        assert!(err.contains("requires a token"), "{err}");
+
+        let mut config = ServerConfig::new(socket_path.clone());
+        config.access.token = Some("s3cret".to_string());
+        config.listen = vec![ListenAddress::parse("tcp://0.0.0.0:0").expect("tcp")];
+        let err = run_server(config).expect_err("non-loopback listen must be refused");
+        assert!(err.contains("non-loopback"), "{err}");
+        assert!(
+            !socket_path.exists(),
+            "invalid --listen must fail before binding"
+        );
+    }
+
+    #[test]
+    fn limits_unauthenticated_lines_and_open_network_connections() {
+        let socket_path = unique_test_socket().with_extension("netcap.sock");
+        let tcp_port = free_tcp_port();
+        let mut config = ServerConfig::new(socket_path.clone());
+        config.access.token = Some("s3cret".to_string());
+        config.listen =
+            vec![ListenAddress::parse(&format!("tcp://127.0.0.1:{tcp_port}")).expect("tcp")];
+        config.max_network_connections = 1;
+        let server = thread::spawn(move || run_server(config));
+        wait_for_socket(&socket_path);
+
+        // A line that never ends is cut off before authentication. The
+        // unread rest may reset the connection before the error is read.
+        let mut endless = connect_tcp(tcp_port);
+        let _ = endless.write_all(&vec![b'x'; 256 * 1024]);
+        let mut reply = Vec::new();
+        let closed = match endless.read_to_end(&mut reply) {
+            Ok(_) => true,
+            Err(err) => !matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+        };
+        assert!(closed, "oversized line should close the connection");
+        drop(endless);
+
+        // The slot frees once the rejected connection's thread finishes.
+        let mut held = None;
+        for _ in 0..80 {
+            let mut stream = connect_tcp(tcp_port);
+            let _ = stream
+                .write_all(b"{\"id\":1,\"method\":\"hello\",\"params\":{\"token\":\"s3cret\"}}\n");
+            let mut reader = BufReader::new(stream.try_clone().expect("clone"));
+            let mut hello = String::new();
+            if reader.read_line(&mut hello).unwrap_or(0) > 0 {
+                held = Some((stream, reader));
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (mut held, mut held_reader) = held.expect("a slot should free up");
+
+        let mut refused = connect_tcp(tcp_port);
+        let mut rest = Vec::new();
+        let _ = refused.read_to_end(&mut rest);
+        assert!(rest.is_empty(), "connection past the cap should be closed");
+
+        held.write_all(b"{\"id\":2,\"method\":\"health\"}\n")
+            .expect("write");
+        let mut health = String::new();
+        held_reader.read_line(&mut health).expect("read");
+        let health: Value = serde_json::from_str(health.trim()).expect("JSON reply");
+        assert!(health["result"]["rpc"]["rejectedConnections"].as_u64() >= Some(1));
+
+        held.write_all(b"{\"id\":3,\"method\":\"dispose\"}\n")
+            .expect("write");
+        let mut disposed = String::new();
+        held_reader.read_line(&mut disposed).expect("read");
+        let joined = server
+            .join()
+            .unwrap_or_else(|_| panic!("server thread should not panic"));
+        assert!(joined.is_ok(), "server should stop cleanly: {joined:?}");
+        let _ = fs::remove_file(lock_file_path(&socket_path));
+    }
+
+    #[test]
+    fn negotiates_jsonrpc_and_answers_batches() {
+        let socket_path = unique_test_socket().with_extension("jsonrpc.sock");
+        let mut config = ServerConfig::new(socket_path.clone());
+        config.access.token = Some("s3cret".to_string());
+        let server = thread::spawn(move || run_server(config));
+        wait_for_socket(&socket_path);
+
+        let stream = std::os::unix::net::UnixStream::connect(&socket_path).expect("connect");
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+        let mut writer = stream.try_clone().expect("clone");
+        let mut reader = BufReader::new(stream);
+        let mut call = |message: Value| -> Value {
+            writer
+                .write_all(format!("{message}\n").as_bytes())
+                .expect("write");
+            let mut reply = String::new();
+            reader.read_line(&mut reply).expect("read");
+            serde_json::from_str(reply.trim()).expect("JSON reply")
+        };
+
+        let replies = call(json!([
+            { "jsonrpc": "2.0", "id": "h", "method": "hello", "params": { "token": "s3cret" } },
+            { "jsonrpc": "2.0", "method": "health" },
+            { "jsonrpc": "2.0", "id": 2.5, "method": "list_windows" },
+            { "jsonrpc": "2.0", "id": null, "method": "no_such_method" },
+            { "jsonrpc": "2.0", "id": 4, "method": "window_exists", "params": [1] },
+            42,
+        ]));
+        let replies = replies.as_array().expect("batch reply");
+        assert_eq!(replies.len(), 5, "notification must not be answered");
+        assert_eq!(replies[0]["id"], "h");
+        assert_eq!(replies[0]["jsonrpc"], "2.0");
+        assert!(replies[0]["result"].is_object());
+        assert_eq!(replies[1]["id"], 2.5);
+        assert!(replies[1]["result"]["windows"].is_array());
+        assert_eq!(replies[2]["id"], Value::Null);
+        assert_eq!(replies[2]["error"]["code"], -32601);
+        assert_eq!(replies[2]["error"]["data"]["code"], "UNKNOWN_METHOD");
+        assert_eq!(replies[3]["error"]["code"], -32602);
+        assert_eq!(replies[4]["error"]["code"], -32600);
+
+        let missing = call(json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "get_window_frame",
+            "params": { "sessionName": "none", "windowName": "none" },
+        }));
+        assert_eq!(missing["id"], 7);
+        assert_eq!(missing["error"]["data"]["code"], "WINDOW_NOT_FOUND");
+        assert!(
+            missing.get("ok").is_none(),
+            "no envelope fields in JSON-RPC mode"
+        );
+
+        let empty = call(json!([]));
+        assert_eq!(empty["error"]["code"], -32600);
+
+        let disposed = call(json!({ "jsonrpc": "2.0", "id": 8, "method": "dispose" }));
+        assert!(disposed["result"].is_object());
+        let joined = server
+            .join()
+            .unwrap_or_else(|_| panic!("server thread should not panic"));
+        assert!(joined.is_ok(), "server should stop cleanly");
+        let _ = fs::remove_file(lock_file_path(&socket_path));
+    }
+
+    #[test]
+    fn pipelines_requests_with_deadlines_and_cancel() {
+        let socket_path = unique_test_socket().with_extension("pipeline.sock");
+        let config = ServerConfig::new(socket_path.clone());
+        let server = thread::spawn(move || run_server(config));
+        wait_for_socket(&socket_path);
+
+        let stream = std::os::unix::net::UnixStream::connect(&socket_path).expect("connect");
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+        let mut writer = stream.try_clone().expect("clone");
+        let mut reader = BufReader::new(stream);
+        let mut send = |message: Value| {
+            writer
+                .write_all(format!("{message}\n").as_bytes())
+                .expect("write");
+        };
+        let mut receive = || -> Value {
+            let mut reply = String::new();
+            reader.read_line(&mut reply).expect("read");
+            serde_json::from_str(reply.trim()).expect("JSON reply")
+        };
+
+        send(json!({ "id": 1, "method": "health", "timeoutMs": 0 }));
+        send(json!({ "id": 2, "method": "list_windows" }));
+        let mut replies = [receive(), receive()];
+        replies.sort_by_key(|reply| reply["id"].as_u64());
+        assert_eq!(replies[0]["id"], 1);
+        assert_eq!(replies[0]["error"]["code"], "REQUEST_TIMEOUT");
+        assert_eq!(replies[1]["id"], 2);
+        assert!(replies[1]["result"]["windows"].is_array());
+
+        send(json!({ "id": 3, "method": "cancel", "params": { "id": 99 } }));
+        let cancelled = receive();
+        assert_eq!(cancelled["id"], 3);
+        assert_eq!(cancelled["result"]["cancelled"], false);
+        send(json!({ "id": 4, "method": "cancel", "params": {} }));
+        assert_eq!(receive()["error"]["code"], ERROR_INVALID_PARAMS);
+
+        send(json!({ "id": 5, "method": "dispose" }));
+        assert_eq!(receive()["ok"], true);
+        let joined = server
+            .join()
+            .unwrap_or_else(|_| panic!("server thread should not panic"));
+        assert!(joined.is_ok(), "server should stop cleanly");
+        let _ = fs::remove_file(lock_file_path(&socket_path));
+    }
+
+    #[test]
+    fn negotiates_features_and_msgpack_framing_in_hello() {
+        let socket_path = unique_test_socket().with_extension("msgpack.sock");
+        let config = ServerConfig::new(socket_path.clone());
+        let server = thread::spawn(move || run_server(config));
+        wait_for_socket(&socket_path);
+
+        let stream = std::os::unix::net::UnixStream::connect(&socket_path).expect("connect");
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+        let mut client = LineChannel::new(stream.try_clone().expect("clone"), stream);
+        let call = |client: &mut LineChannel<UnixStream>, message: Value| -> Value {
+            client.send(&message).expect("write");
+            client
+                .read_message()
+                .expect("read")
+                .expect("reply")
+                .expect("decodable reply")
+        };
+
+        let rejected = call(
+            &mut client,
+            json!({ "id": 1, "method": "hello", "params": { "encoding": "cbor" } }),
+        );
+        assert_eq!(rejected["error"]["code"], ERROR_INVALID_PARAMS);
+
+        let not_negotiated = call(
+            &mut client,
+            json!({
+                "id": 2,
+                "method": "hello",
+                "params": { "features": ["recording"], "encoding": "msgpack" },
+            }),
+        );
+        assert_eq!(not_negotiated["error"]["code"], "FEATURE_NOT_NEGOTIATED");
+
+        // The hello reply itself still arrives as JSON.
+        let hello = call(
+            &mut client,
+            json!({
+                "id": 2,
+                "method": "hello",
+                "params": {
+                    "versions": [1, 2],
+                    "features": ["binaryFraming"],
+                    "encoding": "msgpack",
+                },
+            }),
+        );
+        assert_eq!(hello["result"]["encoding"], "msgpack");
+        assert_eq!(hello["result"]["version"], 1);
+        assert!(!hello["result"]["methods"]
+            .as_array()
+            .is_some_and(|methods| methods.contains(&json!("start_recording"))));
+        client.set_encoding(WireEncoding::MessagePack);
+
+        let recording = call(
+            &mut client,
+            json!({
+                "id": 3,
+                "method": "start_recording",
+                "params": { "sessionName": "packed", "windowName": "w", "path": "/dev/null" },
+            }),
+        );
+        assert_eq!(recording["error"]["code"], "FEATURE_NOT_NEGOTIATED");
+
+        let started = call(
+            &mut client,
+            json!({
+                "id": 3,
+                "method": "start_window",
+                "params": {
+                    "sessionName": "packed",
+                    "windowName": "w",
+                    "command": "printf 'packed-frame'; sleep 5",
+                },
+            }),
+        );
+        assert_eq!(started["ok"], true);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let frame = loop {
+            let reply = call(
+                &mut client,
+                json!({
+                    "id": 4,
+                    "method": "get_window_frame",
+                    "params": { "sessionName": "packed", "windowName": "w", "cols": 80 },
+                }),
+            );
+            assert_eq!(reply["id"], 4);
+            let frame = reply["result"].clone();
+            if frame.to_string().contains("packed-frame") || Instant::now() > deadline {
+                break frame;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert_eq!(frame["cols"], 80);
+        assert_eq!(
+            frame["lines"].as_array().map(Vec::len),
+            frame["rows"].as_u64().map(|rows| rows as usize)
+        );
+        assert!(
+            frame["lines"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .flat_map(|line| line["segments"].as_array().into_iter().flatten())
+                .any(|segment| segment["text"]
+                    .as_str()
+                    .is_some_and(|text| text.contains("packed-frame"))),
+            "frame should carry the window output: {frame}"
+        );
+
+        let disposed = call(&mut client, json!({ "id": 5, "method": "dispose" }));
+        assert_eq!(disposed["ok"], true);
+        let joined = server
+            .join()
+            .unwrap_or_else(|_| panic!("server thread should not panic"));
+        assert!(joined.is_ok(), "server should stop cleanly");
+        let _ = fs::remove_file(lock_file_path(&socket_path));
+    }
+
+    fn start_window(state: &SharedSidecarState, window_name: &str, command: &str) -> u32 {
+        let mut should_shutdown = false;
+        handle_request(
+            state,
+            RpcRequest {
+                id: None,
+                method: "start_window".to_string(),
+                params: json!({
+                    "sessionName": "proj-sig",
+                    "windowName": window_name,
+                    "command": command,
+                }),
+                timeout_ms: None,
+            },
+            &mut should_shutdown,
+        )
+        .unwrap_or_else(|err| panic!("start_window failed: {err}"));
+        with_window(state, "proj-sig", window_name, |window| {
+            Ok(window.snapshot.pid.expect("window should have a pid"))
+        })
+        .expect("window should exist")
+    }
+
+    fn process_alive(pid: u32) -> bool {
+        unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+    }
+
+    #[test]
+    fn termination_signal_stops_windows_and_removes_socket() {
+        let socket_path = unique_test_socket().with_extension("signal.sock");
+        fs::write(&socket_path, b"").expect("placeholder socket file");
+        let state = new_shared_state();
+        let polite = start_window(&state, "polite", "sleep 30");
+        let stubborn = start_window(
+            &state,
+            "stubborn",
+            "trap '' TERM; while true; do sleep 1; done",
+        );
+        thread::sleep(Duration::from_millis(200));
+
+        let config = ServerConfig::new(socket_path.clone());
+        let code = shutdown_for_signal(&state, &config, Duration::from_millis(300), libc::SIGTERM);
+        assert_eq!(code, 143);
+        assert!(!socket_path.exists(), "socket should be unlinked");
+
+        for name in ["polite", "stubborn"] {
+            with_window(&state, "proj-sig", name, |window| {
+                assert_eq!(window.snapshot.status, "exited");
+                assert!(window.child.is_none());
+                let last = window.lifecycle_events.last().expect("exit event");
+                assert_eq!(last.to, "exited");
+                assert!(
+                    last.reason == "signal-SIGTERM" || last.reason == "process-exit",
+                    "unexpected reason {}",
+                    last.reason
+                );
+                Ok(())
+            })
+            .expect("window should exist");
+        }
+        let mut gone = false;
+        for _ in 0..50 {
+            if !process_alive(polite) && !process_alive(stubborn) {
+                gone = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(gone, "children should be gone after signal shutdown");
+    }
+
+    #[test]
+    fn reload_applies_config_file_over_command_line() {
+        let dir = unique_test_socket().with_extension("config.d");
+        fs::create_dir_all(&dir).expect("config dir");
+        let token_path = dir.join("token");
+        let config_path = dir.join("sidecar.json");
+        fs::write(&token_path, "first\n").expect("token file");
+
+        let mut config = ServerConfig::new(dir.join("sidecar.sock"));
+        config.shutdown_grace_ms = 1_000;
+        config.config_file = Some(config_path.clone());
+        fs::write(&config_path, r#"{"shutdownGraceMs": 50}"#).expect("config file");
+
+        let settings = load_settings(&config).expect("settings should load");
+        assert_eq!(settings.shutdown_grace, Duration::from_millis(50));
+        assert_eq!(settings.access.token, None);
+
+        fs::write(
+            &config_path,
+            format!(
+                r#"{{"allowedUids": [4242], "tokenFile": "{}"}}"#,
+                token_path.display()
+            ),
+        )
+        .expect("config file");
+        let settings = load_settings(&config).expect("settings should reload");
+        assert_eq!(settings.shutdown_grace, Duration::from_millis(1_000));
+        assert_eq!(settings.access.allowed_uids, vec![current_uid(), 4242]);
+        assert_eq!(settings.access.token.as_deref(), Some("first"));
+
+        fs::write(&config_path, r#"{"bogus": true}"#).expect("config file");
+        assert!(load_settings(&config).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn hands_off_output_written_during_the_handoff_to_successor() {
+        let socket_path = unique_test_socket().with_extension("stream.sock");
+        let server_socket = socket_path.clone();
+        let old = thread::spawn(move || run_server(ServerConfig::new(server_socket)));
+        wait_for_socket(&socket_path);
+
+        request(
+            &socket_path,
+            "start_window",
+            json!({
+                "sessionName": "proj-s",
+                "windowName": "win-s",
+                "command": "i=0; while [ $i -lt 30 ]; do i=$((i+1)); echo n$i; sleep 0.01; done; echo done-s; sleep 5"
+            }),
+        );
+        let params = json!({ "sessionName": "proj-s", "windowName": "win-s" });
+        for _ in 0..200 {
+            let buffer = request(&socket_path, "get_window_buffer", params.clone());
+            if buffer["result"]["buffer"]
+                .as_str()
+                .is_some_and(|buffer| buffer.contains("n1\r"))
+            {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let successor = new_shared_state();
+        let _listener = receive_handoff(&socket_path, &successor, None)
+            .unwrap_or_else(|err| panic!("handoff failed: {err}"));
+        let _ = old.join();
+
+        let mut text = Vec::new();
+        for _ in 0..200 {
+            text = with_window(&successor, "proj-s", "win-s", |window| {
+                Ok(window.pane.frame()["lines"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|line| {
+                        line["segments"]
+                            .as_array()
+                            .cloned()
+                            .unwrap_or_default()
+                            .iter()
+                            .filter_map(|segment| segment["text"].as_str().map(str::to_string))
+                            .collect::<String>()
+                            .trim()
+                            .to_string()
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .expect("adopted window should exist");
+            if text.iter().any(|line| line == "done-s") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(25));
+        }
+        let numbered = text
+            .iter()
+            .filter(|line| line.starts_with('n'))
+            .cloned()
+            .collect::<Vec<_>>();
+        let expected = (1..=30).map(|n| format!("n{n}")).collect::<Vec<_>>();
+        assert_eq!(numbered, expected, "{text:?}");
+
+        let mut should_shutdown = false;
+        let _ = handle_request(
+            &successor,
+            RpcRequest {
+                id: None,
+                method: "dispose".to_string(),
+                params: json!({}),
+                timeout_ms: None,
+            },
+            &mut should_shutdown,
+        );
+        let _ = fs::remove_file(&socket_path);
+    }
+
+    #[test]
+    fn hands_off_listener_and_running_windows_to_successor() {
+        let socket_path = unique_test_socket().with_extension("handoff.sock");
+        let server_socket = socket_path.clone();
+        let old = thread::spawn(move || run_server(ServerConfig::new(server_socket)));
+        wait_for_socket(&socket_path);
+
+        request(
+            &socket_path,
+            "start_window",
+            json!({ "sessionName": "proj-h", "windowName": "win-h", "command": "cat" }),
+        );
+        let mut running = false;
+        for _ in 0..80 {
+            let listed = request(&socket_path, "list_windows", json!({}));
+            if listed["result"]["windows"][0]["status"].as_str() == Some("running") {
+                running = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(25));
+        }
+        assert!(running, "window should be running before handoff");
+
+        let successor = new_shared_state();
+        let listener = receive_handoff(&socket_path, &successor, None)
+            .unwrap_or_else(|err| panic!("handoff failed: {err}"));
+        let joined = old
+            .join()
+            .unwrap_or_else(|_| panic!("old server thread should not panic"));
+        assert!(joined.is_ok(), "old server should stop cleanly");
+        assert!(socket_path.exists(), "socket path must survive handoff");
+
+        let _client = std::os::unix::net::UnixStream::connect(&socket_path)
+            .expect("inherited listener should accept connections");
+        listener
+            .accept()
+            .expect("successor should accept on inherited listener");
+
+        let mut saw_echo = false;
+        for _ in 0..4 {
+            with_window(&successor, "proj-h", "win-h", |window| {
+                assert_eq!(window.snapshot.status, "running");
+                write_input(window, b"after-handoff\r")
+            })
+            .expect("adopted window should accept input");
+            for _ in 0..20 {
+                saw_echo = with_window(&successor, "proj-h", "win-h", |window| {
+                    Ok(window.buffer.contains("after-handoff"))
+                })
+                .expect("adopted window should exist");
+                if saw_echo {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(25));
+            }
+            if saw_echo {
+                break;
+            }
+        }
+        assert!(saw_echo, "adopted PTY should keep echoing input");
+
+        let mut should_shutdown = false;
+        let _ = handle_request(
+            &successor,
+            RpcRequest {
+                id: None,
+                method: "dispose".to_string(),
+                params: json!({}),
+                timeout_ms: None,
+            },
+            &mut should_shutdown,
+        );
+        let _ = fs::remove_file(&socket_path);
+    }
+}
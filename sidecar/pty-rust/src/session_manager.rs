@@ -1,15 +1,25 @@
+use crate::child_watchdog::ChildWatchdog;
+use crate::grid_scrollback::AmbiguousWidth;
+use crate::input_queue::{InputQueue, DEFAULT_INPUT_QUEUE_BYTES, DEFAULT_INPUT_WRITE_TIMEOUT};
+use crate::output_flow::{OutputFlowConfig, OutputStats};
+use crate::pty_bus::ReaderHandle;
+use crate::recording::CastRecorder;
+use crate::renderer::StyledFrame;
+use crate::request_control::RequestControl;
+use crate::screen::DamageLog;
+use crate::terminal_pane::TerminalPane;
 use portable_pty::{Child, MasterPty};
-use serde_json::Value;
 use std::collections::HashMap;
-use std::io::Write;
 use std::sync::{Arc, Mutex, MutexGuard};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const DEFAULT_COLS: u16 = 140;
 const DEFAULT_ROWS: u16 = 40;
 const DEFAULT_MAX_BUFFER_BYTES: usize = 512 * 1024;
 pub const FRAME_COALESCE_WINDOW_MS: u64 = 24;
 const MAX_LIFECYCLE_EVENTS: usize = 128;
+// Lines scrolled off a window's screen that its pane keeps for checkpoints.
+const WINDOW_HISTORY_LINES: usize = 1_000;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WindowLifecycleState {
@@ -107,45 +117,106 @@ impl WindowSnapshot {
 pub struct WindowState {
     pub snapshot: WindowSnapshot,
     pub buffer: String,
-    pub query_carry: String,
+    /// Length of the checkpoint at the start of `buffer`; everything after
+    /// it is raw PTY output.
+    pub checkpoint_bytes: usize,
+    /// Raw output replaced by checkpoints so far.
+    pub compacted_bytes: u64,
+    /// Start of a UTF-8 character cut off at the end of the last read, kept
+    /// out of `buffer` until the rest of it arrives.
+    pub utf8_carry: Vec<u8>,
     pub private_modes: HashMap<i32, bool>,
+    /// Width of East Asian ambiguous characters in this window's output.
+    pub ambiguous_width: AmbiguousWidth,
+    /// Terminal state at the end of `buffer`, at the window's size. Output
+    /// is fed to it as it arrives.
+    pub pane: TerminalPane,
+    /// What changed in `pane` at each recent output revision.
+    pub damage_log: DamageLog,
     pub launch_env: HashMap<String, String>,
     pub lifecycle_events: Vec<WindowLifecycleEvent>,
     pub lifecycle_generation: u64,
     pub output_revision: u64,
+    /// Terminal state last written to a snapshot, with the output revision
+    /// it was taken at.
+    pub persisted_state: Option<(u64, String)>,
+    pub output_stats: OutputStats,
     pub frame_cache: Option<FrameRenderCache>,
-    pub writer: Option<Box<dyn Write + Send>>,
+    pub recorder: Option<CastRecorder>,
+    /// Why the last recording ended on its own, until `stop_recording`
+    /// reports it.
+    pub recording_error: Option<String>,
+    pub command: Option<String>,
+    pub input: Option<InputQueue>,
+    pub reader: Option<ReaderHandle>,
     pub master: Option<Box<dyn MasterPty + Send>>,
     pub child: Option<Box<dyn Child + Send>>,
 }
 
+/// Last frame rendered at a size other than the window's, or scrolled into
+/// history.
 pub struct FrameRenderCache {
     pub cols: u16,
     pub rows: u16,
+    /// `viewportOffset` the frame was requested with.
+    pub viewport_offset: Option<usize>,
     pub source_revision: u64,
     pub rendered_at_unix_ms: u64,
-    pub frame: Value,
+    pub frame: Arc<StyledFrame>,
+}
+
+impl WindowState {
+    /// Rebuilds `pane` from `buffer` after the size, the ambiguous width or
+    /// the buffer itself was replaced.
+    pub fn rebuild_pane(&mut self) {
+        self.pane = window_pane(self.snapshot.cols, self.snapshot.rows);
+        self.pane.set_ambiguous_width(self.ambiguous_width);
+        self.pane.feed(self.buffer.as_bytes());
+        self.pane.feed(&self.utf8_carry);
+        self.damage_log.clear();
+    }
+}
+
+fn window_pane(cols: u16, rows: u16) -> TerminalPane {
+    let mut pane = TerminalPane::new(cols, rows);
+    pane.keep_history(WINDOW_HISTORY_LINES);
+    pane
 }
 
 pub fn idle_window_state(session_name: String, window_name: String) -> WindowState {
     WindowState {
         snapshot: WindowSnapshot::idle(session_name, window_name),
         buffer: String::new(),
-        query_carry: String::new(),
+        checkpoint_bytes: 0,
+        compacted_bytes: 0,
+        utf8_carry: Vec::new(),
         private_modes: HashMap::new(),
+        ambiguous_width: AmbiguousWidth::default(),
+        pane: window_pane(DEFAULT_COLS, DEFAULT_ROWS),
+        damage_log: DamageLog::default(),
         launch_env: HashMap::new(),
         lifecycle_events: Vec::new(),
         lifecycle_generation: 0,
         output_revision: 0,
+        persisted_state: None,
+        output_stats: OutputStats::default(),
         frame_cache: None,
-        writer: None,
+        recorder: None,
+        recording_error: None,
+        command: None,
+        input: None,
+        reader: None,
         master: None,
         child: None,
     }
 }
 
+/// Starts a new output revision, recording what changed in the pane to
+/// reach it.
 pub fn mark_output_mutation(window: &mut WindowState) {
     window.output_revision = window.output_revision.saturating_add(1);
+    let damage = window.pane.take_damage();
+    window.damage_log.record(window.output_revision, damage);
 }
 
 pub fn transition_window_state(
@@ -226,6 +297,7 @@ impl RpcMethodMetrics {
 pub struct RpcObservability {
     pub requests_total: u64,
     pub errors_total: u64,
+    pub rejected_connections: u64,
     pub methods: HashMap<String, RpcMethodMetrics>,
 }
 
@@ -234,6 +306,7 @@ impl RpcObservability {
         Self {
             requests_total: 0,
             errors_total: 0,
+            rejected_connections: 0,
             methods: HashMap::new(),
         }
     }
@@ -243,8 +316,12 @@ pub struct SidecarState {
     pub sessions: SessionRegistry,
     pub windows: WindowRegistry,
     pub max_buffer_bytes: usize,
+    pub input_queue_bytes: usize,
+    pub input_write_timeout: Duration,
+    pub output_flow: OutputFlowConfig,
     pub started_at_unix_ms: u64,
     pub rpc_observability: RpcObservability,
+    pub child_watchdog: Option<ChildWatchdog>,
 }
 
 impl SidecarState {
@@ -253,8 +330,12 @@ impl SidecarState {
             sessions: HashMap::new(),
             windows: HashMap::new(),
             max_buffer_bytes: DEFAULT_MAX_BUFFER_BYTES,
+            input_queue_bytes: DEFAULT_INPUT_QUEUE_BYTES,
+            input_write_timeout: DEFAULT_INPUT_WRITE_TIMEOUT,
+            output_flow: OutputFlowConfig::default(),
             started_at_unix_ms: now_unix_millis(),
             rpc_observability: RpcObservability::new(),
+            child_watchdog: None,
         }
     }
 }
@@ -276,6 +357,7 @@ pub fn window_key(session_name: &str, window_name: &str) -> String {
     format!("{session_name}:{window_name}")
 }
 
+#[cfg(test)]
 pub fn with_window<T>(
     state: &SharedSidecarState,
     session_name: &str,
@@ -295,6 +377,27 @@ pub fn with_window<T>(
     f(&mut guard)
 }
 
+/// `with_window` for request handlers: waiting for the state or window lock
+/// ends when the request is cancelled or its deadline passes.
+pub fn with_window_within<T>(
+    state: &SharedSidecarState,
+    control: &RequestControl,
+    session_name: &str,
+    window_name: &str,
+    mut f: impl FnMut(&mut WindowState) -> Result<T, String>,
+) -> Result<T, String> {
+    let key = window_key(session_name, window_name);
+    let window = control
+        .lock(state)?
+        .windows
+        .get(&key)
+        .cloned()
+        .ok_or_else(|| format!("window not found: {key}"))?;
+    let mut guard = control.lock(&window)?;
+    control.check()?;
+    f(&mut guard)
+}
+
 pub fn record_rpc_observation(
     state: &SharedSidecarState,
     method: &str,
@@ -323,6 +426,12 @@ pub fn record_rpc_observation(
     }
 }
 
+pub fn record_rejected_connection(state: &SharedSidecarState) {
+    let mut guard = lock_state(state);
+    let observability = &mut guard.rpc_observability;
+    observability.rejected_connections = observability.rejected_connections.saturating_add(1);
+}
+
 pub fn lock_state<'a>(state: &'a SharedSidecarState) -> MutexGuard<'a, SidecarState> {
     state
         .lock()
@@ -338,6 +447,7 @@ pub fn lock_window<'a>(window: &'a SharedWindowState) -> MutexGuard<'a, WindowSt
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::terminal_pane::render_frame;
 
     #[test]
     fn records_per_method_rpc_observability() {
@@ -368,9 +478,10 @@ mod tests {
         let cache = FrameRenderCache {
             cols: 80,
             rows: 24,
+            viewport_offset: None,
             source_revision: 10,
             rendered_at_unix_ms: 1_000,
-            frame: Value::Null,
+            frame: Arc::new(render_frame("", 80, 24, AmbiguousWidth::Narrow)),
         };
 
         assert!(should_coalesce_frame(&cache, 80, 24, 11, 1_010));
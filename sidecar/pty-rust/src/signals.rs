@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+
+// Write end of the self-pipe; -1 until handlers are installed.
+static SIGNAL_PIPE_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// Exit code for a shutdown initiated by `signo`, following the shell
+/// convention (SIGTERM -> 143, SIGINT -> 130).
+pub fn signal_exit_code(signo: i32) -> i32 {
+    128 + signo
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SidecarSignal {
+    /// SIGTERM or SIGINT: shut down as if `dispose` had been requested.
+    Terminate(i32),
+    /// SIGHUP: reload configuration.
+    Reload,
+}
+
+pub fn signal_name(signo: i32) -> &'static str {
+    match signo {
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGINT => "SIGINT",
+        libc::SIGHUP => "SIGHUP",
+        _ => "UNKNOWN",
+    }
+}
+
+extern "C" fn forward_signal(signo: libc::c_int) {
+    let fd = SIGNAL_PIPE_WRITE_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        let byte = signo as u8;
+        // write(2) is async-signal-safe; a full pipe just drops the signal.
+        unsafe {
+            libc::write(fd, (&byte as *const u8).cast(), 1);
+        }
+    }
+}
+
+/// Installs handlers for SIGTERM, SIGINT and SIGHUP that forward to a
+/// self-pipe, and spawns a thread that passes each signal to `handler`.
+/// Handlers (unlike a blocked signal mask) are reset on exec, so spawned
+/// children start with default signal behaviour.
+pub fn spawn_signal_thread<F>(mut handler: F) -> Result<(), String>
+where
+    F: FnMut(SidecarSignal) + Send + 'static,
+{
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(format!(
+            "signal pipe failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    for fd in fds {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    unsafe {
+        libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK);
+    }
+    let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+    SIGNAL_PIPE_WRITE_FD.store(fds[1], Ordering::SeqCst);
+
+    for signo in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+        let installed = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = forward_signal as extern "C" fn(libc::c_int) as usize;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signo, &action, std::ptr::null_mut())
+        };
+        if installed != 0 {
+            return Err(format!(
+                "sigaction({}) failed: {}",
+                signal_name(signo),
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+
+    thread::Builder::new()
+        .name("sidecar-signals".to_string())
+        .spawn(move || {
+            let mut byte = [0u8; 1];
+            while let Ok(1) = reader.read(&mut byte) {
+                let signo = i32::from(byte[0]);
+                let signal = if signo == libc::SIGHUP {
+                    SidecarSignal::Reload
+                } else {
+                    SidecarSignal::Terminate(signo)
+                };
+                handler(signal);
+            }
+        })
+        .map(|_| ())
+        .map_err(|e| format!("spawn signal thread: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn delivers_hangup_as_reload_and_term_as_terminate() {
+        let (tx, rx) = mpsc::channel();
+        spawn_signal_thread(move |signal| {
+            let _ = tx.send(signal);
+        })
+        .expect("signal handlers should install");
+
+        unsafe {
+            libc::raise(libc::SIGHUP);
+        }
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(2)),
+            Ok(SidecarSignal::Reload)
+        );
+
+        unsafe {
+            libc::raise(libc::SIGTERM);
+        }
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(2)),
+            Ok(SidecarSignal::Terminate(libc::SIGTERM))
+        );
+        assert_eq!(signal_exit_code(libc::SIGTERM), 143);
+        assert_eq!(signal_name(libc::SIGINT), "SIGINT");
+    }
+}
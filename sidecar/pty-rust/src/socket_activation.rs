@@ -0,0 +1,169 @@
+use std::ffi::OsStr;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+
+// First fd passed by the sd_listen_fds protocol.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Interprets `LISTEN_PID`/`LISTEN_FDS` as systemd sets them. Returns the
+/// first passed fd when the variables are addressed to `own_pid`.
+pub fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    own_pid: u32,
+) -> Result<Option<RawFd>, String> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(None);
+    };
+    let listen_pid = listen_pid
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| format!("invalid LISTEN_PID: {listen_pid}"))?;
+    if listen_pid != own_pid {
+        return Ok(None);
+    }
+    let count = listen_fds
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| format!("invalid LISTEN_FDS: {listen_fds}"))?;
+    match count {
+        0 => Ok(None),
+        1 => Ok(Some(SD_LISTEN_FDS_START)),
+        _ => Err(format!("expected one socket in LISTEN_FDS, got {count}")),
+    }
+}
+
+/// Reads the socket-activation variables of this process and removes them so
+/// that windows spawned later do not inherit them.
+pub fn listen_fd_from_env() -> Result<Option<RawFd>, String> {
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    let listen_fds = std::env::var("LISTEN_FDS").ok();
+    let fd = parse_listen_fds(
+        listen_pid.as_deref(),
+        listen_fds.as_deref(),
+        std::process::id(),
+    )?;
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    Ok(fd)
+}
+
+/// Takes ownership of an inherited listening unix socket.
+pub fn listener_from_fd(fd: RawFd) -> Result<UnixListener, String> {
+    let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+        return Err(format!(
+            "listen fd {fd}: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(format!("listen fd {fd} is not a socket"));
+    }
+    if socket_family(fd)? != libc::AF_UNIX as libc::sa_family_t {
+        return Err(format!("listen fd {fd} is not a unix domain socket"));
+    }
+    let mut accepting: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            (&mut accepting as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 || accepting == 0 {
+        return Err(format!("listen fd {fd} is not a listening socket"));
+    }
+
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    Ok(unsafe { UnixListener::from_raw_fd(fd) })
+}
+
+/// Filesystem path the inherited socket is bound to, if it has one (abstract
+/// and unnamed sockets do not).
+pub fn bound_socket_path(fd: RawFd) -> Option<PathBuf> {
+    let mut addr = unsafe { std::mem::zeroed::<libc::sockaddr_un>() };
+    let mut len = std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    let result =
+        unsafe { libc::getsockname(fd, (&mut addr as *mut libc::sockaddr_un).cast(), &mut len) };
+    if result != 0 || addr.sun_family != libc::AF_UNIX as libc::sa_family_t {
+        return None;
+    }
+    let path_offset = std::mem::size_of_val(&addr.sun_family);
+    let path_len = (len as usize).saturating_sub(path_offset);
+    let bytes = addr.sun_path[..path_len.min(addr.sun_path.len())]
+        .iter()
+        .map(|byte| *byte as u8)
+        .take_while(|byte| *byte != 0)
+        .collect::<Vec<_>>();
+    if bytes.is_empty() {
+        return None;
+    }
+    Some(PathBuf::from(OsStr::from_bytes(&bytes)))
+}
+
+fn socket_family(fd: RawFd) -> Result<libc::sa_family_t, String> {
+    let mut addr = unsafe { std::mem::zeroed::<libc::sockaddr_storage>() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockname(
+            fd,
+            (&mut addr as *mut libc::sockaddr_storage).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(format!(
+            "listen fd {fd}: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(addr.ss_family)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::{AsRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn parses_listen_fds_only_for_this_process() {
+        assert_eq!(parse_listen_fds(None, None, 42), Ok(None));
+        assert_eq!(parse_listen_fds(Some("41"), Some("1"), 42), Ok(None));
+        assert_eq!(parse_listen_fds(Some("42"), Some("1"), 42), Ok(Some(3)));
+        assert_eq!(parse_listen_fds(Some("42"), Some("0"), 42), Ok(None));
+        assert!(parse_listen_fds(Some("42"), Some("2"), 42).is_err());
+        assert!(parse_listen_fds(Some("x"), Some("1"), 42).is_err());
+    }
+
+    #[test]
+    fn adopts_listening_unix_socket_and_rejects_others() {
+        let path = std::env::temp_dir().join(format!(
+            "discode-pty-sidecar-activation-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let bound = UnixListener::bind(&path).expect("bind");
+        let fd = bound.into_raw_fd();
+
+        assert_eq!(bound_socket_path(fd).as_deref(), Some(path.as_path()));
+        let listener = listener_from_fd(fd).expect("listening socket should be adopted");
+        let _client = UnixStream::connect(&path).expect("connect");
+        listener.accept().expect("accept on adopted listener");
+
+        let (left, _right) = UnixStream::pair().expect("socket pair");
+        assert!(listener_from_fd(left.as_raw_fd()).is_err());
+        let file = std::fs::File::open("/dev/null").expect("open /dev/null");
+        assert!(listener_from_fd(file.as_raw_fd()).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
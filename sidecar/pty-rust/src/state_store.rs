@@ -0,0 +1,441 @@
+use crate::grid_scrollback::AmbiguousWidth;
+use crate::pty_bus::CHECKPOINT_HISTORY_SHARE;
+use crate::session_manager::{
+    idle_window_state, lock_state, lock_window, mark_output_mutation, transition_window_state,
+    window_key, SharedSidecarState, WindowLifecycleEvent, WindowLifecycleState,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const STATE_FILE_NAME: &str = "state.json";
+const STATE_FORMAT_VERSION: u32 = 1;
+pub const RESTART_REASON: &str = "sidecar-restart";
+// The state holds session env values and terminal history, so only the owner
+// may read it.
+const STATE_DIR_MODE: u32 = 0o700;
+const STATE_FILE_MODE: u32 = 0o600;
+
+// Held while a snapshot is taken and written, so that the periodic snapshot
+// and the one at shutdown do not interleave.
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistedState {
+    version: u32,
+    saved_at_unix_ms: u64,
+    sessions: HashMap<String, HashMap<String, String>>,
+    windows: Vec<PersistedWindow>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PersistedWindow {
+    session_name: String,
+    window_name: String,
+    status: String,
+    pid: Option<u32>,
+    started_at: Option<i64>,
+    exited_at: Option<i64>,
+    exit_code: Option<i32>,
+    signal: Option<String>,
+    cols: u16,
+    rows: u16,
+    command: Option<String>,
+    lifecycle_events: Vec<PersistedLifecycleEvent>,
+    terminal_state: String,
+    #[serde(default)]
+    output_revision: u64,
+    #[serde(default)]
+    ambiguous_width: AmbiguousWidth,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PersistedLifecycleEvent {
+    from: String,
+    to: String,
+    reason: String,
+    at_unix_ms: u64,
+}
+
+/// A window that was running when the previous sidecar instance went away.
+pub struct InterruptedWindow {
+    pub session_name: String,
+    pub window_name: String,
+    pub command: Option<String>,
+    pub pid: Option<u32>,
+    pub started_at: Option<i64>,
+}
+
+pub fn state_file_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(STATE_FILE_NAME)
+}
+
+/// Captures every session and window. A window's terminal state is
+/// serialized from its pane, and only again once its output revision moves.
+pub fn snapshot_state(state: &SharedSidecarState) -> PersistedState {
+    let (sessions, windows, history_bytes) = {
+        let guard = lock_state(state);
+        (
+            guard.sessions.clone(),
+            guard.windows.values().cloned().collect::<Vec<_>>(),
+            guard.max_buffer_bytes / CHECKPOINT_HISTORY_SHARE,
+        )
+    };
+
+    let mut persisted_windows = windows
+        .iter()
+        .map(|window| {
+            let mut w = lock_window(window);
+            let terminal_state = match &w.persisted_state {
+                Some((revision, saved)) if *revision == w.output_revision => saved.clone(),
+                _ => {
+                    let saved = w.pane.serialize_state(history_bytes);
+                    w.persisted_state = Some((w.output_revision, saved.clone()));
+                    saved
+                }
+            };
+            PersistedWindow {
+                session_name: w.snapshot.session_name.clone(),
+                window_name: w.snapshot.window_name.clone(),
+                status: w.snapshot.status.clone(),
+                pid: w.snapshot.pid,
+                started_at: w.snapshot.started_at,
+                exited_at: w.snapshot.exited_at,
+                exit_code: w.snapshot.exit_code,
+                signal: w.snapshot.signal.clone(),
+                cols: w.snapshot.cols,
+                rows: w.snapshot.rows,
+                command: w.command.clone(),
+                lifecycle_events: w
+                    .lifecycle_events
+                    .iter()
+                    .map(|ev| PersistedLifecycleEvent {
+                        from: ev.from.clone(),
+                        to: ev.to.clone(),
+                        reason: ev.reason.clone(),
+                        at_unix_ms: ev.at_unix_ms,
+                    })
+                    .collect(),
+                terminal_state,
+                output_revision: w.output_revision,
+                ambiguous_width: w.ambiguous_width,
+            }
+        })
+        .collect::<Vec<_>>();
+    persisted_windows
+        .sort_by(|a, b| (&a.session_name, &a.window_name).cmp(&(&b.session_name, &b.window_name)));
+
+    PersistedState {
+        version: STATE_FORMAT_VERSION,
+        saved_at_unix_ms: now_unix_millis(),
+        sessions,
+        windows: persisted_windows,
+    }
+}
+
+/// Writes a snapshot of `state` to the state file, creating a missing state
+/// dir with mode 0700. The file is only readable by the owner and is replaced
+/// whole, so a reader never sees a partly written one.
+pub fn save_state(state: &SharedSidecarState, state_dir: &Path) -> Result<(), String> {
+    let _saving = SAVE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let persisted = snapshot_state(state);
+    let payload =
+        serde_json::to_vec(&persisted).map_err(|e| format!("encode sidecar state: {e}"))?;
+
+    DirBuilder::new()
+        .recursive(true)
+        .mode(STATE_DIR_MODE)
+        .create(state_dir)
+        .map_err(|e| format!("create state dir {}: {e}", state_dir.display()))?;
+    let target = state_file_path(state_dir);
+    // A successor may save into the same dir before this instance is gone.
+    let staging = state_dir.join(format!("{STATE_FILE_NAME}.{}.tmp", std::process::id()));
+    let _ = fs::remove_file(&staging);
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(STATE_FILE_MODE)
+        .open(&staging)
+        .and_then(|mut file| {
+            file.write_all(&payload)?;
+            file.sync_all()
+        });
+    if let Err(err) = written {
+        let _ = fs::remove_file(&staging);
+        return Err(format!("write {}: {err}", staging.display()));
+    }
+    fs::rename(&staging, &target).map_err(|e| format!("replace {}: {e}", target.display()))
+}
+
+/// Loads a previously saved snapshot into `state`. Windows that were running
+/// are restored as `exited` with reason `sidecar-restart` and returned so the
+/// caller can decide whether to restart their recorded commands.
+pub fn load_state(
+    state: &SharedSidecarState,
+    state_dir: &Path,
+) -> Result<Vec<InterruptedWindow>, String> {
+    let path = state_file_path(state_dir);
+    let raw = match fs::read(&path) {
+        Ok(raw) => raw,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(format!("read {}: {err}", path.display())),
+    };
+    let persisted = serde_json::from_slice::<PersistedState>(&raw)
+        .map_err(|e| format!("decode {}: {e}", path.display()))?;
+    restore_snapshot(state, persisted, &HashSet::new())
+}
+
+/// Applies a snapshot to `state`. Windows listed in `live_keys` keep their
+/// recorded status because their process is still reachable; every other
+/// window that was running is marked `exited` with reason `sidecar-restart`.
+pub fn restore_snapshot(
+    state: &SharedSidecarState,
+    persisted: PersistedState,
+    live_keys: &HashSet<String>,
+) -> Result<Vec<InterruptedWindow>, String> {
+    if persisted.version != STATE_FORMAT_VERSION {
+        return Err(format!(
+            "unsupported sidecar state version: {}",
+            persisted.version
+        ));
+    }
+
+    let mut interrupted = Vec::new();
+    let mut guard = lock_state(state);
+    for (session_name, env) in persisted.sessions {
+        guard.sessions.entry(session_name).or_default().extend(env);
+    }
+
+    for saved in persisted.windows {
+        let mut window = idle_window_state(saved.session_name.clone(), saved.window_name.clone());
+        window.snapshot.status = saved.status.clone();
+        window.snapshot.pid = saved.pid;
+        window.snapshot.started_at = saved.started_at;
+        window.snapshot.exited_at = saved.exited_at;
+        window.snapshot.exit_code = saved.exit_code;
+        window.snapshot.signal = saved.signal;
+        window.snapshot.cols = saved.cols;
+        window.snapshot.rows = saved.rows;
+        window.command = saved.command.clone();
+        window.ambiguous_width = saved.ambiguous_width;
+        window.buffer = saved.terminal_state;
+        window.checkpoint_bytes = window.buffer.len();
+        window.rebuild_pane();
+        // Continue past the saved revision so that a client's `sinceRevision`
+        // never matches a frame rendered by the previous instance.
+        window.output_revision = saved.output_revision.saturating_add(1);
+        window.lifecycle_events = saved
+            .lifecycle_events
+            .into_iter()
+            .map(|ev| WindowLifecycleEvent {
+                from: ev.from,
+                to: ev.to,
+                reason: ev.reason,
+                at_unix_ms: ev.at_unix_ms,
+            })
+            .collect();
+
+        let key = window_key(&saved.session_name, &saved.window_name);
+        if matches!(saved.status.as_str(), "running" | "starting") && !live_keys.contains(&key) {
+            transition_window_state(&mut window, WindowLifecycleState::Exited, RESTART_REASON)?;
+            window.snapshot.pid = None;
+            window.snapshot.exited_at = Some(now_unix_seconds());
+            window.snapshot.exit_code = None;
+            window.snapshot.signal = None;
+            interrupted.push(InterruptedWindow {
+                session_name: saved.session_name.clone(),
+                window_name: saved.window_name.clone(),
+                command: saved.command,
+                pid: saved.pid,
+                started_at: saved.started_at,
+            });
+        }
+        mark_output_mutation(&mut window);
+
+        guard
+            .windows
+            .entry(key)
+            .or_insert_with(|| Arc::new(Mutex::new(window)));
+    }
+
+    Ok(interrupted)
+}
+
+fn now_unix_seconds() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn now_unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_manager::{new_shared_state, with_window};
+    use crate::terminal_pane::TerminalPane;
+
+    fn unique_state_dir() -> PathBuf {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        std::env::temp_dir().join(format!(
+            "discode-pty-sidecar-state-{}-{}",
+            std::process::id(),
+            stamp
+        ))
+    }
+
+    fn insert_window(state: &SharedSidecarState, session: &str, name: &str, status: &str) {
+        let mut window = idle_window_state(session.to_string(), name.to_string());
+        window.snapshot.status = status.to_string();
+        window.snapshot.pid = Some(4242);
+        window.snapshot.cols = 30;
+        window.snapshot.rows = 8;
+        window.command = Some(format!("run-{name}"));
+        window.buffer = format!("\x1b[32m{name}-output\x1b[0m\r\nsecond line");
+        window.rebuild_pane();
+        window.output_revision = 7;
+        lock_state(state)
+            .windows
+            .insert(window_key(session, name), Arc::new(Mutex::new(window)));
+    }
+
+    #[test]
+    fn restores_sessions_windows_and_marks_running_windows_exited() {
+        let dir = unique_state_dir();
+        let state = new_shared_state();
+        lock_state(&state)
+            .sessions
+            .entry("proj".to_string())
+            .or_default()
+            .insert("TOKEN".to_string(), "abc".to_string());
+        insert_window(&state, "proj", "live", "running");
+        insert_window(&state, "proj", "done", "exited");
+        with_window(&state, "proj", "done", |window| {
+            window.ambiguous_width = AmbiguousWidth::Wide;
+            Ok(())
+        })
+        .expect("done window should exist");
+
+        save_state(&state, &dir).expect("save should succeed");
+
+        let restored = new_shared_state();
+        let interrupted = load_state(&restored, &dir).expect("load should succeed");
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].window_name, "live");
+        assert_eq!(interrupted[0].command.as_deref(), Some("run-live"));
+        assert_eq!(interrupted[0].pid, Some(4242));
+        assert_eq!(
+            lock_state(&restored).sessions["proj"]
+                .get("TOKEN")
+                .map(String::as_str),
+            Some("abc")
+        );
+
+        with_window(&restored, "proj", "live", |window| {
+            assert_eq!(window.snapshot.status, "exited");
+            assert_eq!(window.snapshot.pid, None);
+            assert_eq!((window.snapshot.cols, window.snapshot.rows), (30, 8));
+            assert!(window.lifecycle_events.iter().any(|ev| ev.from == "running"
+                && ev.to == "exited"
+                && ev.reason == RESTART_REASON));
+            let mut pane = TerminalPane::new(30, 8);
+            pane.feed(window.buffer.as_bytes());
+            let frame = pane.frame();
+            assert_eq!(frame["lines"][0]["segments"][0]["text"], "live-output");
+            assert_eq!(frame["lines"][0]["segments"][0]["fg"], "#0dbc79");
+            Ok(())
+        })
+        .expect("live window should be restored");
+
+        with_window(&restored, "proj", "done", |window| {
+            assert_eq!(window.snapshot.status, "exited");
+            assert_eq!(window.snapshot.pid, Some(4242));
+            assert!(window.lifecycle_events.is_empty());
+            assert!(window.output_revision > 7);
+            assert_eq!(window.ambiguous_width, AmbiguousWidth::Wide);
+            Ok(())
+        })
+        .expect("exited window should be restored");
+    }
+
+    #[test]
+    fn saves_concurrently_into_a_file_only_the_owner_can_read() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = unique_state_dir();
+        let state = new_shared_state();
+        insert_window(&state, "proj", "live", "running");
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..10 {
+                        save_state(&state, &dir).expect("save should succeed");
+                    }
+                });
+            }
+        });
+
+        let mode = |path: &Path| fs::metadata(path).map(|meta| meta.permissions().mode() & 0o777);
+        let dir_mode = mode(&dir);
+        let file_mode = mode(&state_file_path(&dir));
+        let entries = fs::read_dir(&dir).map(Iterator::count);
+        let loaded = load_state(&new_shared_state(), &dir);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(dir_mode.ok(), Some(STATE_DIR_MODE));
+        assert_eq!(file_mode.ok(), Some(STATE_FILE_MODE));
+        assert_eq!(entries.ok(), Some(1), "staging files were left behind");
+        assert_eq!(loaded.expect("load should succeed").len(), 1);
+    }
+
+    #[test]
+    fn serializes_a_window_again_only_after_its_output_moves() {
+        let state = new_shared_state();
+        insert_window(&state, "proj", "busy", "running");
+        let terminal_state =
+            |state: &SharedSidecarState| snapshot_state(state).windows[0].terminal_state.clone();
+        let first = terminal_state(&state);
+        assert!(first.contains("busy-output"), "{first:?}");
+
+        with_window(&state, "proj", "busy", |window| {
+            window.pane.feed(b"\r\nunmarked");
+            Ok(())
+        })
+        .expect("window should exist");
+        assert_eq!(terminal_state(&state), first);
+
+        with_window(&state, "proj", "busy", |window| {
+            mark_output_mutation(window);
+            Ok(())
+        })
+        .expect("window should exist");
+        assert!(terminal_state(&state).contains("unmarked"));
+    }
+
+    #[test]
+    fn missing_state_file_loads_nothing() {
+        let state = new_shared_state();
+        let interrupted = load_state(&state, &unique_state_dir()).expect("load should succeed");
+        assert!(interrupted.is_empty());
+        assert!(lock_state(&state).windows.is_empty());
+    }
+}
@@ -1,9 +1,17 @@
 use crate::grid_scrollback::{
-    blank_cell, char_display_width, make_row, Cell, CellStyle, SavedScreen,
+    blank_cell, cluster_width, extends_cluster, make_row, AmbiguousWidth, Cell, CellStyle,
+    CellTables, Charset, Charsets, Color, Glyph, SavedCursor, SavedScreen,
 };
-use crate::renderer::Renderer;
-use crate::screen::Screen;
+use crate::modes::{Mode, Modes, DECAWM, DECLRMM, DECOM, DECSCNM, IRM, LNM};
+use crate::query_policy::QueryResponder;
+use crate::renderer::{FramePatch, PatchGranularity, Renderer, StyledFrame};
+use crate::screen::{Damage, DamageLog, Screen, ScreenFrame};
+use crate::vt_parser::{Csi, Params, Parser, Perform};
 use serde_json::Value;
+use std::collections::VecDeque;
+
+// Deepest viewport offset served; bounds the history a render keeps.
+pub const MAX_VIEWPORT_OFFSET: usize = 10_000;
 
 struct VtLite {
     cols: usize,
@@ -11,25 +19,79 @@ struct VtLite {
     lines: Vec<Vec<Cell>>,
     cursor_row: usize,
     cursor_col: usize,
-    saved_row: usize,
-    saved_col: usize,
+    saved_cursor: SavedCursor,
     style: CellStyle,
+    charsets: Charsets,
     scroll_top: usize,
     scroll_bottom: usize,
+    // Left and right margins, inclusive; the whole width unless DECSLRM set
+    // them.
+    margin_left: usize,
+    margin_right: usize,
     wrap_pending: bool,
     cursor_visible: bool,
+    modes: Modes,
     saved_primary: Option<SavedScreen>,
-    pending_escape: String,
+    tables: CellTables,
+    damage: Damage,
+    // Lines scrolled off the top of the primary screen, oldest first.
+    history: VecDeque<Vec<Cell>>,
+    history_limit: usize,
+    ambiguous_width: AmbiguousWidth,
+}
+
+/// The parts of one screen, primary or alternate, that a checkpoint restores.
+struct ScreenView<'a> {
+    lines: &'a [Vec<Cell>],
+    cursor: SavedCursor,
+    saved_cursor: SavedCursor,
+    scroll_top: usize,
+    scroll_bottom: usize,
+    margin_left: usize,
+    margin_right: usize,
+    cursor_visible: bool,
+}
+
+/// What a pane fed a serialized state has been told so far.
+#[derive(Default)]
+struct Replay {
+    style: CellStyle,
+    charsets: Charsets,
+    origin: bool,
 }
 
 pub struct TerminalPane {
+    parser: Parser,
     vt: VtLite,
 }
 
+#[cfg(test)]
 pub fn build_styled_frame(buffer: &str, cols: u16, rows: u16) -> Value {
+    render_frame(buffer, cols, rows, AmbiguousWidth::Narrow).to_value()
+}
+
+pub fn render_frame(buffer: &str, cols: u16, rows: u16, ambiguous: AmbiguousWidth) -> StyledFrame {
     let mut pane = TerminalPane::new(cols, rows);
-    pane.feed(buffer);
-    pane.frame()
+    pane.set_ambiguous_width(ambiguous);
+    pane.feed(buffer.as_bytes());
+    pane.styled_frame()
+}
+
+/// Frame of the screen scrolled back `offset` lines into the history that
+/// `buffer` leaves at this width.
+pub fn render_viewport(
+    buffer: &str,
+    cols: u16,
+    rows: u16,
+    ambiguous: AmbiguousWidth,
+    offset: usize,
+) -> StyledFrame {
+    let offset = offset.min(MAX_VIEWPORT_OFFSET);
+    let mut pane = TerminalPane::new(cols, rows);
+    pane.set_ambiguous_width(ambiguous);
+    pane.keep_history(offset);
+    pane.feed(buffer.as_bytes());
+    pane.viewport_frame(offset)
 }
 
 impl TerminalPane {
@@ -37,16 +99,223 @@ impl TerminalPane {
         let safe_cols = cols.clamp(20, 300) as usize;
         let safe_rows = rows.clamp(6, 200) as usize;
         Self {
+            parser: Parser::new(),
             vt: VtLite::new(safe_cols, safe_rows),
         }
     }
 
-    pub fn feed(&mut self, input: &str) {
-        self.vt.feed(input);
+    /// Feeds output as read from the PTY. A UTF-8 character cut off at the
+    /// end is completed by the next input.
+    pub fn feed(&mut self, input: &[u8]) {
+        self.parser.advance(&mut self.vt, input);
+    }
+
+    /// Feeds `input` and lets `responder` answer the queries in it, each
+    /// with the cursor as it is when the query arrives.
+    pub fn feed_answering(&mut self, input: &[u8], responder: &mut QueryResponder<'_>) {
+        let mut performer = Answering {
+            vt: &mut self.vt,
+            responder,
+        };
+        self.parser.advance(&mut performer, input);
     }
 
     pub fn frame(&self) -> Value {
-        self.vt.into_frame()
+        self.vt.to_frame().to_value()
+    }
+
+    pub fn styled_frame(&self) -> StyledFrame {
+        self.vt.to_frame()
+    }
+
+    /// Width given to characters of ambiguous East Asian width from here on.
+    pub fn set_ambiguous_width(&mut self, ambiguous: AmbiguousWidth) {
+        self.vt.ambiguous_width = ambiguous;
+    }
+
+    /// Keeps up to `lines` lines that scroll off the top of the primary
+    /// screen, for `viewport_frame`.
+    pub fn keep_history(&mut self, lines: usize) {
+        self.vt.history_limit = lines;
+    }
+
+    /// Frame scrolled back `offset` lines, or as far as the kept history
+    /// goes; the frame's `viewport_offset` says how far that was. The
+    /// alternate screen has no history.
+    pub fn viewport_frame(&self, offset: usize) -> StyledFrame {
+        self.vt.viewport_frame(offset)
+    }
+
+    /// Drops the styles and grapheme clusters no cell on either screen or in
+    /// the history uses any more, which otherwise pile up for as long as the
+    /// pane lives.
+    pub fn drop_unused_cell_data(&mut self) {
+        let vt = &mut self.vt;
+        let saved = vt
+            .saved_primary
+            .iter_mut()
+            .flat_map(|saved| &mut saved.lines);
+        let cells = vt
+            .lines
+            .iter_mut()
+            .chain(vt.history.iter_mut())
+            .chain(saved)
+            .flatten();
+        vt.tables.retain_used(cells);
+    }
+
+    /// Styles and clusters the pane holds.
+    #[cfg(test)]
+    pub fn cell_data_len(&self) -> usize {
+        self.vt.tables.len()
+    }
+
+    /// Cells changed since the damage was last taken, or since the pane
+    /// was created.
+    pub fn take_damage(&mut self) -> Damage {
+        self.vt.damage.take()
+    }
+
+    /// Patch from the frame shown at `revision` to the current one, built
+    /// from the damage `log` recorded since; `None` if the log does not
+    /// reach back to `revision`.
+    pub fn patch_since(
+        &self,
+        log: &DamageLog,
+        revision: u64,
+        granularity: PatchGranularity,
+    ) -> Option<FramePatch> {
+        let mut damage = Damage::new(self.vt.cols, self.vt.rows);
+        log.since(revision)?
+            .for_each(|recorded| damage.merge(recorded));
+        Some(self.render_patch(&damage, granularity))
+    }
+
+    /// Patch with the cells in `damage` and the current cursor.
+    pub fn render_patch(&self, damage: &Damage, granularity: PatchGranularity) -> FramePatch {
+        Renderer::new().render_patch(&self.vt.screen(), damage, granularity)
+    }
+
+    /// Serializes the terminal state (both screens, cursor and saved cursor,
+    /// scroll region and margins, pending wrap, style, character sets,
+    /// modes and cursor visibility) as an escape sequence stream that
+    /// reproduces it when fed into a fresh pane of the same size. The newest
+    /// kept history lines that fit in `history_bytes` come first, as output
+    /// that scrolls off the top. An unfinished escape sequence is left out.
+    pub fn serialize_state(&self, history_bytes: usize) -> String {
+        self.vt.serialize_state(history_bytes)
+    }
+
+    /// Length of the unfinished escape sequence at the end of the input.
+    pub fn pending_escape_len(&self) -> usize {
+        self.parser.pending_len()
+    }
+}
+
+struct Answering<'a, 'b> {
+    vt: &'a mut VtLite,
+    responder: &'a mut QueryResponder<'b>,
+}
+
+impl Perform for Answering<'_, '_> {
+    fn print(&mut self, ch: char) {
+        self.vt.print(ch);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        self.vt.execute(byte);
+    }
+
+    fn csi_dispatch(&mut self, csi: &Csi<'_>) {
+        self.responder
+            .csi(csi, self.vt.reported_cursor(), self.vt.modes);
+        self.vt.csi_dispatch(csi);
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], final_byte: u8) {
+        self.vt.esc_dispatch(intermediates, final_byte);
+    }
+
+    fn osc_dispatch(&mut self, body: &[u8]) {
+        self.responder.osc(body);
+    }
+
+    fn apc_dispatch(&mut self, body: &[u8]) {
+        self.responder.apc(body);
+    }
+}
+
+impl Perform for VtLite {
+    fn print(&mut self, ch: char) {
+        self.write_char(self.charsets.translate(ch));
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            b'\r' => {
+                self.cursor_col = self.line_start();
+                self.wrap_pending = false;
+            }
+            b'\n' | 0x0b | 0x0c => {
+                self.wrap_pending = false;
+                self.line_feed();
+                if self.modes.is_set(LNM) {
+                    self.cursor_col = self.line_start();
+                }
+            }
+            0x0e => self.charsets.gl = 1,
+            0x0f => self.charsets.gl = 0,
+            0x08 => {
+                self.wrap_pending = false;
+                if self.cursor_col != self.margin_left {
+                    self.cursor_col = self.cursor_col.saturating_sub(1);
+                }
+            }
+            b'\t' => {
+                let spaces = 8usize.saturating_sub(self.cursor_col % 8);
+                for _ in 0..spaces {
+                    self.write_char(' ');
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, csi: &Csi<'_>) {
+        self.handle_csi(csi);
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], final_byte: u8) {
+        if let [intermediate] = intermediates {
+            if let Some(slot) = b"()*+".iter().position(|g| g == intermediate) {
+                self.charsets.slots[slot] = Charset::from_final(final_byte);
+            }
+            return;
+        }
+        if !intermediates.is_empty() {
+            return;
+        }
+        match final_byte {
+            b'7' => self.saved_cursor = self.cursor_state(),
+            b'8' => self.restore_cursor(self.saved_cursor),
+            b'n' => self.charsets.gl = 2,
+            b'o' => self.charsets.gl = 3,
+            b'D' => {
+                self.wrap_pending = false;
+                self.line_feed();
+            }
+            b'E' => {
+                self.wrap_pending = false;
+                self.cursor_col = self.line_start();
+                self.line_feed();
+            }
+            b'M' => {
+                self.wrap_pending = false;
+                self.reverse_index();
+            }
+            b'c' => self.reset(),
+            _ => {}
+        }
     }
 }
 
@@ -58,325 +327,151 @@ impl VtLite {
             lines: vec![make_row(cols); rows],
             cursor_row: 0,
             cursor_col: 0,
-            saved_row: 0,
-            saved_col: 0,
+            saved_cursor: SavedCursor::default(),
             style: CellStyle::default(),
+            charsets: Charsets::default(),
             scroll_top: 0,
             scroll_bottom: rows.saturating_sub(1),
+            margin_left: 0,
+            margin_right: cols.saturating_sub(1),
             wrap_pending: false,
             cursor_visible: true,
+            modes: Modes::default(),
             saved_primary: None,
-            pending_escape: String::new(),
+            tables: CellTables::new(),
+            damage: Damage::new(cols, rows),
+            history: VecDeque::new(),
+            history_limit: 0,
+            ambiguous_width: AmbiguousWidth::default(),
         }
     }
 
-    fn feed(&mut self, input: &str) {
-        let mut merged = String::new();
-        if !self.pending_escape.is_empty() {
-            merged.push_str(&self.pending_escape);
-            self.pending_escape.clear();
+    fn handle_csi(&mut self, csi: &Csi<'_>) {
+        if (csi.private, csi.intermediates, csi.final_byte) == (None, b"!", b'p') {
+            self.soft_reset();
+            return;
         }
-        merged.push_str(input);
-
-        let chars = merged.chars().collect::<Vec<_>>();
-        let mut i = 0usize;
-
-        while i < chars.len() {
-            let ch = chars[i];
-            if ch == '\x1b' {
-                if i + 1 >= chars.len() {
-                    self.pending_escape = chars[i..].iter().collect::<String>();
-                    break;
-                }
-                let next = chars[i + 1];
-
-                if next == '[' {
-                    let mut j = i + 2;
-                    while j < chars.len() {
-                        let code = chars[j] as u32;
-                        if (0x40..=0x7e).contains(&code) {
-                            break;
-                        }
-                        j += 1;
-                    }
-                    if j >= chars.len() {
-                        self.pending_escape = chars[i..].iter().collect::<String>();
-                        break;
-                    }
-
-                    let final_char = chars[j];
-                    let raw = chars[i + 2..j].iter().collect::<String>();
-                    self.handle_csi(&raw, final_char);
-                    i = j + 1;
-                    continue;
-                }
-
-                if next == ']' {
-                    let mut j = i + 2;
-                    let mut terminated = false;
-                    while j < chars.len() {
-                        if chars[j] == '\u{0007}' {
-                            j += 1;
-                            terminated = true;
-                            break;
-                        }
-                        if chars[j] == '\x1b' && j + 1 < chars.len() && chars[j + 1] == '\\' {
-                            j += 2;
-                            terminated = true;
-                            break;
-                        }
-                        j += 1;
-                    }
-                    if !terminated {
-                        self.pending_escape = chars[i..].iter().collect::<String>();
-                        break;
-                    }
-                    i = j;
-                    continue;
-                }
-
-                if matches!(next, 'P' | 'X' | '^' | '_') {
-                    let mut j = i + 2;
-                    let mut terminated = false;
-                    while j < chars.len() {
-                        if chars[j] == '\x1b' && j + 1 < chars.len() && chars[j + 1] == '\\' {
-                            j += 2;
-                            terminated = true;
-                            break;
-                        }
-                        j += 1;
-                    }
-                    if !terminated {
-                        self.pending_escape = chars[i..].iter().collect::<String>();
-                        break;
-                    }
-                    i = j;
-                    continue;
-                }
-
-                if matches!(next, '=' | '>' | '\\') {
-                    i += 2;
-                    continue;
-                }
-
-                if matches!(next, '(' | ')' | '*' | '+' | '-' | '.' | '/') {
-                    if i + 2 >= chars.len() {
-                        self.pending_escape = chars[i..].iter().collect::<String>();
-                        break;
-                    }
-                    i += 3;
-                    continue;
-                }
-
-                match next {
-                    '7' => {
-                        self.saved_row = self.cursor_row;
-                        self.saved_col = self.cursor_col;
-                        self.wrap_pending = false;
-                        i += 2;
-                        continue;
-                    }
-                    '8' => {
-                        self.cursor_row = self.saved_row.min(self.rows.saturating_sub(1));
-                        self.cursor_col = self.saved_col.min(self.cols.saturating_sub(1));
-                        self.wrap_pending = false;
-                        i += 2;
-                        continue;
-                    }
-                    'D' => {
-                        self.wrap_pending = false;
-                        self.line_feed();
-                        i += 2;
-                        continue;
-                    }
-                    'E' => {
-                        self.wrap_pending = false;
-                        self.cursor_col = 0;
-                        self.line_feed();
-                        i += 2;
-                        continue;
-                    }
-                    'M' => {
-                        self.wrap_pending = false;
-                        self.reverse_index();
-                        i += 2;
-                        continue;
-                    }
-                    'c' => {
-                        self.reset();
-                        i += 2;
-                        continue;
-                    }
-                    _ => {
-                        i += 2;
-                        continue;
-                    }
-                }
-            }
-
-            match ch {
-                '\r' => {
-                    self.cursor_col = 0;
-                    self.wrap_pending = false;
-                    i += 1;
-                }
-                '\n' => {
-                    self.wrap_pending = false;
-                    self.line_feed();
-                    i += 1;
-                }
-                '\x08' => {
-                    self.wrap_pending = false;
-                    self.cursor_col = self.cursor_col.saturating_sub(1);
-                    i += 1;
-                }
-                '\t' => {
-                    let spaces = 8usize.saturating_sub(self.cursor_col % 8);
-                    for _ in 0..spaces {
-                        self.write_char(' ');
-                    }
-                    i += 1;
-                }
-                _ => {
-                    let code = ch as u32;
-                    if code < 0x20 || code == 0x7f {
-                        i += 1;
-                        continue;
-                    }
-                    self.write_char(ch);
-                    i += 1;
-                }
-            }
+        let private = match csi.private {
+            None => false,
+            Some(b'?') => true,
+            Some(_) => return,
+        };
+        let final_char = char::from(csi.final_byte);
+        if !csi.intermediates.is_empty() || (private && !matches!(final_char, 'h' | 'l')) {
+            return;
         }
-    }
-
-    fn handle_csi(&mut self, raw: &str, final_char: char) {
-        let private = raw.starts_with('?');
-        let params_raw = if private { &raw[1..] } else { raw };
-        let params = parse_params(params_raw);
+        let params = csi.params;
 
         match final_char {
             'A' => {
-                let n = param_or(&params, 0, 1).max(1) as usize;
+                let n = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
-                self.cursor_row = self.cursor_row.saturating_sub(n);
+                self.cursor_row = self.cursor_row.saturating_sub(n).max(self.row_bounds().0);
             }
             'B' => {
-                let n = param_or(&params, 0, 1).max(1) as usize;
+                let n = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
-                self.cursor_row = (self.cursor_row + n).min(self.rows.saturating_sub(1));
+                self.cursor_row = (self.cursor_row + n).min(self.row_bounds().1);
             }
             'C' => {
-                let n = param_or(&params, 0, 1).max(1) as usize;
+                let n = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
-                self.cursor_col = (self.cursor_col + n).min(self.cols.saturating_sub(1));
+                self.cursor_col = (self.cursor_col + n).min(self.line_end());
             }
             'D' => {
-                let n = param_or(&params, 0, 1).max(1) as usize;
+                let n = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
-                self.cursor_col = self.cursor_col.saturating_sub(n);
+                self.cursor_col = self.cursor_col.saturating_sub(n).max(self.line_start());
             }
             'E' => {
-                let n = param_or(&params, 0, 1).max(1) as usize;
+                let n = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
-                self.cursor_row = (self.cursor_row + n).min(self.rows.saturating_sub(1));
-                self.cursor_col = 0;
+                self.cursor_row = (self.cursor_row + n).min(self.row_bounds().1);
+                self.cursor_col = self.line_start();
             }
             'F' => {
-                let n = param_or(&params, 0, 1).max(1) as usize;
+                let n = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
-                self.cursor_row = self.cursor_row.saturating_sub(n);
-                self.cursor_col = 0;
+                self.cursor_row = self.cursor_row.saturating_sub(n).max(self.row_bounds().0);
+                self.cursor_col = self.line_start();
             }
             'G' => {
-                let col = param_or(&params, 0, 1).max(1) as usize;
+                let col = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
-                self.cursor_col = col.saturating_sub(1).min(self.cols.saturating_sub(1));
+                self.cursor_col = self.address_col(col);
             }
             'd' => {
-                let row = param_or(&params, 0, 1).max(1) as usize;
+                let row = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
-                self.cursor_row = row.saturating_sub(1).min(self.rows.saturating_sub(1));
+                self.cursor_row = self.address_row(row);
             }
             'H' | 'f' => {
-                let row = param_or(&params, 0, 1).max(1) as usize;
-                let col = param_or(&params, 1, 1).max(1) as usize;
+                let row = usize::from(params.or(0, 1).max(1));
+                let col = usize::from(params.or(1, 1).max(1));
                 self.wrap_pending = false;
-                self.cursor_row = row.saturating_sub(1).min(self.rows.saturating_sub(1));
-                self.cursor_col = col.saturating_sub(1).min(self.cols.saturating_sub(1));
+                self.cursor_row = self.address_row(row);
+                self.cursor_col = self.address_col(col);
             }
             'J' => {
                 self.wrap_pending = false;
-                self.erase_display(param_or(&params, 0, 0));
+                self.erase_display(params.or(0, 0));
             }
             'K' => {
                 self.wrap_pending = false;
-                self.erase_line(param_or(&params, 0, 0));
+                self.erase_line(params.or(0, 0));
             }
             'L' => {
-                let n = param_or(&params, 0, 1).max(1) as usize;
+                let n = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
                 self.insert_lines(n);
             }
             'M' => {
-                let n = param_or(&params, 0, 1).max(1) as usize;
+                let n = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
                 self.delete_lines(n);
             }
             'S' => {
-                let n = param_or(&params, 0, 1).max(1) as usize;
+                let n = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
                 self.scroll_region_up(self.scroll_top, self.scroll_bottom, n);
             }
             'T' => {
-                let n = param_or(&params, 0, 1).max(1) as usize;
+                let n = usize::from(params.or(0, 1).max(1));
                 self.wrap_pending = false;
                 self.scroll_region_down(self.scroll_top, self.scroll_bottom, n);
             }
             'm' => {
-                self.apply_sgr(&params);
+                self.apply_sgr(params);
             }
             'r' => {
-                let top = param_or(&params, 0, 1).max(1) as usize;
-                let bottom = param_or(&params, 1, self.rows as i32).max(1) as usize;
+                let top = usize::from(params.or(0, 1).max(1));
+                let bottom = usize::from(params.or(1, self.rows as u16).max(1));
                 let top0 = top.saturating_sub(1).min(self.rows.saturating_sub(1));
                 let bottom0 = bottom.saturating_sub(1).min(self.rows.saturating_sub(1));
                 if top0 < bottom0 {
                     self.scroll_top = top0;
                     self.scroll_bottom = bottom0;
                     self.cursor_row = top0;
-                    self.cursor_col = 0;
+                    self.cursor_col = self.col_bounds().0;
                     self.wrap_pending = false;
                 }
             }
-            's' => {
-                self.saved_row = self.cursor_row;
-                self.saved_col = self.cursor_col;
-                self.wrap_pending = false;
-            }
-            'u' => {
-                self.cursor_row = self.saved_row.min(self.rows.saturating_sub(1));
-                self.cursor_col = self.saved_col.min(self.cols.saturating_sub(1));
+            's' if self.modes.is_set(DECLRMM) => {
+                let left = usize::from(params.or(0, 1).max(1));
+                let right = usize::from(params.or(1, self.cols as u16).max(1)).min(self.cols);
+                if left < right {
+                    self.margin_left = left - 1;
+                    self.margin_right = right - 1;
+                    self.home_cursor();
+                }
                 self.wrap_pending = false;
             }
-            'h' | 'l' if private => {
+            's' => self.saved_cursor = self.cursor_state(),
+            'u' => self.restore_cursor(self.saved_cursor),
+            'h' | 'l' => {
                 let set = final_char == 'h';
-                for param in params {
-                    if let Some(code) = param {
-                        match code {
-                            25 => {
-                                self.cursor_visible = set;
-                            }
-                            47 | 1047 | 1049 => {
-                                if set {
-                                    self.enter_alt_screen();
-                                } else {
-                                    self.leave_alt_screen();
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
+                for number in params.iter().flatten() {
+                    self.set_mode(Mode { private, number }, set);
                 }
                 self.wrap_pending = false;
             }
@@ -384,6 +479,102 @@ impl VtLite {
         }
     }
 
+    fn set_mode(&mut self, mode: Mode, set: bool) {
+        if mode.private {
+            match mode.number {
+                25 => self.cursor_visible = set,
+                47 | 1047 | 1049 if set => self.enter_alt_screen(),
+                47 | 1047 | 1049 => self.leave_alt_screen(),
+                _ => {}
+            }
+        }
+        if !self.modes.set(mode, set) {
+            return;
+        }
+        match mode {
+            DECOM => self.home_cursor(),
+            DECSCNM => self.damage.mark_all(),
+            DECLRMM if !set => {
+                self.margin_left = 0;
+                self.margin_right = self.cols.saturating_sub(1);
+            }
+            _ => {}
+        }
+    }
+
+    /// Rows the cursor can be addressed in: the scroll region in origin
+    /// mode, otherwise the whole screen.
+    fn row_bounds(&self) -> (usize, usize) {
+        if self.modes.is_set(DECOM) {
+            (self.scroll_top, self.scroll_bottom)
+        } else {
+            (0, self.rows.saturating_sub(1))
+        }
+    }
+
+    /// Columns the cursor can be addressed in: between the left and right
+    /// margins in origin mode, otherwise the whole line.
+    fn col_bounds(&self) -> (usize, usize) {
+        if self.modes.is_set(DECOM) {
+            (self.margin_left, self.margin_right)
+        } else {
+            (0, self.cols.saturating_sub(1))
+        }
+    }
+
+    /// Row for the 1-based address `row`, relative to the origin.
+    fn address_row(&self, row: usize) -> usize {
+        let (top, bottom) = self.row_bounds();
+        (top + row - 1).min(bottom)
+    }
+
+    /// Column for the 1-based address `col`, relative to the origin.
+    fn address_col(&self, col: usize) -> usize {
+        let (left, right) = self.col_bounds();
+        (left + col - 1).min(right)
+    }
+
+    fn home_cursor(&mut self) {
+        self.cursor_row = self.row_bounds().0;
+        self.cursor_col = self.col_bounds().0;
+        self.wrap_pending = false;
+    }
+
+    /// Where a carriage return takes the cursor: the left margin, unless
+    /// the cursor is left of it.
+    fn line_start(&self) -> usize {
+        if self.cursor_col >= self.margin_left {
+            self.margin_left
+        } else {
+            0
+        }
+    }
+
+    /// Last column the cursor can print in before wrapping: the right
+    /// margin, unless the cursor is right of it.
+    fn line_end(&self) -> usize {
+        self.line_end_at(self.cursor_col)
+    }
+
+    fn line_end_at(&self, col: usize) -> usize {
+        if col <= self.margin_right {
+            self.margin_right
+        } else {
+            self.cols.saturating_sub(1)
+        }
+    }
+
+    /// The cursor as a cursor position report gives it: relative to the
+    /// origin in origin mode.
+    fn reported_cursor(&self) -> (usize, usize) {
+        let (top, _) = self.row_bounds();
+        let (left, _) = self.col_bounds();
+        (
+            self.cursor_row.saturating_sub(top),
+            self.cursor_col.saturating_sub(left),
+        )
+    }
+
     fn enter_alt_screen(&mut self) {
         if self.saved_primary.is_some() {
             return;
@@ -391,24 +582,25 @@ impl VtLite {
 
         self.saved_primary = Some(SavedScreen {
             lines: self.lines.clone(),
-            cursor_row: self.cursor_row,
-            cursor_col: self.cursor_col,
-            saved_row: self.saved_row,
-            saved_col: self.saved_col,
-            style: self.style.clone(),
+            cursor: self.cursor_state(),
+            saved_cursor: self.saved_cursor,
             scroll_top: self.scroll_top,
             scroll_bottom: self.scroll_bottom,
+            margin_left: self.margin_left,
+            margin_right: self.margin_right,
             cursor_visible: self.cursor_visible,
         });
 
         self.lines = vec![make_row(self.cols); self.rows];
+        self.damage.mark_all();
         self.cursor_row = 0;
         self.cursor_col = 0;
-        self.saved_row = 0;
-        self.saved_col = 0;
+        self.saved_cursor = SavedCursor::default();
         self.style = CellStyle::default();
         self.scroll_top = 0;
         self.scroll_bottom = self.rows.saturating_sub(1);
+        self.margin_left = 0;
+        self.margin_right = self.cols.saturating_sub(1);
         self.cursor_visible = true;
         self.wrap_pending = false;
     }
@@ -416,11 +608,9 @@ impl VtLite {
     fn leave_alt_screen(&mut self) {
         if let Some(saved) = self.saved_primary.take() {
             self.lines = saved.lines;
-            self.cursor_row = saved.cursor_row.min(self.rows.saturating_sub(1));
-            self.cursor_col = saved.cursor_col.min(self.cols.saturating_sub(1));
-            self.saved_row = saved.saved_row.min(self.rows.saturating_sub(1));
-            self.saved_col = saved.saved_col.min(self.cols.saturating_sub(1));
-            self.style = saved.style;
+            self.damage.mark_all();
+            self.restore_cursor(saved.cursor);
+            self.saved_cursor = saved.saved_cursor;
             let max_row = self.rows.saturating_sub(1);
             let top = saved.scroll_top.min(max_row);
             let bottom = saved.scroll_bottom.min(max_row);
@@ -431,47 +621,99 @@ impl VtLite {
                 self.scroll_top = 0;
                 self.scroll_bottom = max_row;
             }
+            self.margin_left = saved.margin_left;
+            self.margin_right = saved.margin_right;
             self.cursor_visible = saved.cursor_visible;
-            self.wrap_pending = false;
         }
     }
 
-    fn erase_display(&mut self, mode: i32) {
+    /// The cursor state DECSC saves.
+    fn cursor_state(&self) -> SavedCursor {
+        SavedCursor {
+            row: self.cursor_row,
+            col: self.cursor_col,
+            style: self.style,
+            charsets: self.charsets,
+            origin: self.modes.is_set(DECOM),
+            wrap_pending: self.wrap_pending,
+        }
+    }
+
+    fn restore_cursor(&mut self, saved: SavedCursor) {
+        self.cursor_row = saved.row.min(self.rows.saturating_sub(1));
+        self.cursor_col = saved.col.min(self.cols.saturating_sub(1));
+        self.style = saved.style;
+        self.charsets = saved.charsets;
+        self.modes.set(DECOM, saved.origin);
+        self.wrap_pending = saved.wrap_pending;
+    }
+
+    /// DECSTR: modes that affect printing and addressing, the margins,
+    /// character sets, rendition and the saved cursor go back to their reset
+    /// values. The screen and the cursor position are kept.
+    fn soft_reset(&mut self) {
+        let reset = Modes::default();
+        for mode in [IRM, DECOM, DECAWM] {
+            self.modes.set(mode, reset.is_set(mode));
+        }
+        self.cursor_visible = true;
+        self.scroll_top = 0;
+        self.scroll_bottom = self.rows.saturating_sub(1);
+        self.margin_left = 0;
+        self.margin_right = self.cols.saturating_sub(1);
+        self.style = CellStyle::default();
+        self.charsets = Charsets::default();
+        self.saved_cursor = SavedCursor::default();
+        self.wrap_pending = false;
+    }
+
+    fn erase_display(&mut self, mode: u16) {
         match mode {
             0 => {
                 self.erase_line(0);
                 for row in (self.cursor_row + 1)..self.rows {
                     self.lines[row] = make_row(self.cols);
                 }
+                self.damage.mark_rows(self.cursor_row + 1..self.rows);
             }
             1 => {
                 for row in 0..self.cursor_row {
                     self.lines[row] = make_row(self.cols);
                 }
+                self.damage.mark_rows(0..self.cursor_row);
                 self.erase_line(1);
             }
             2 | 3 => {
                 for row in 0..self.rows {
                     self.lines[row] = make_row(self.cols);
                 }
+                self.damage.mark_all();
+                if mode == 3 {
+                    self.history.clear();
+                }
             }
             _ => {}
         }
     }
 
-    fn erase_line(&mut self, mode: i32) {
+    fn erase_line(&mut self, mode: u16) {
         match mode {
             0 => {
+                self.damage
+                    .mark_cells(self.cursor_row, self.cursor_col..self.cols);
                 for col in self.cursor_col..self.cols {
                     self.lines[self.cursor_row][col] = blank_cell();
                 }
             }
             1 => {
+                self.damage
+                    .mark_cells(self.cursor_row, 0..self.cursor_col + 1);
                 for col in 0..=self.cursor_col.min(self.cols.saturating_sub(1)) {
                     self.lines[self.cursor_row][col] = blank_cell();
                 }
             }
             2 => {
+                self.damage.mark_row(self.cursor_row);
                 self.lines[self.cursor_row] = make_row(self.cols);
             }
             _ => {}
@@ -506,21 +748,44 @@ impl VtLite {
         if top > bottom || bottom >= self.rows {
             return;
         }
+        if self.has_side_margins() {
+            self.shift_within_margins(top, bottom, count, true);
+            return;
+        }
+        self.damage.mark_rows(top..bottom + 1);
         if top == bottom {
             self.lines[top] = make_row(self.cols);
             return;
         }
         let n = count.max(1).min(bottom - top + 1);
         for _ in 0..n {
-            self.lines.remove(top);
+            let line = self.lines.remove(top);
+            if top == 0 {
+                self.keep_in_history(line);
+            }
             self.lines.insert(bottom, make_row(self.cols));
         }
     }
 
+    fn keep_in_history(&mut self, line: Vec<Cell>) {
+        if self.history_limit == 0 || self.saved_primary.is_some() {
+            return;
+        }
+        if self.history.len() == self.history_limit {
+            self.history.pop_front();
+        }
+        self.history.push_back(line);
+    }
+
     fn scroll_region_down(&mut self, top: usize, bottom: usize, count: usize) {
         if top > bottom || bottom >= self.rows {
             return;
         }
+        if self.has_side_margins() {
+            self.shift_within_margins(top, bottom, count, false);
+            return;
+        }
+        self.damage.mark_rows(top..bottom + 1);
         if top == bottom {
             self.lines[top] = make_row(self.cols);
             return;
@@ -536,6 +801,14 @@ impl VtLite {
         if self.cursor_row < self.scroll_top || self.cursor_row > self.scroll_bottom {
             return;
         }
+        if self.has_side_margins() {
+            if (self.margin_left..=self.margin_right).contains(&self.cursor_col) {
+                self.shift_within_margins(self.cursor_row, self.scroll_bottom, count, false);
+            }
+            return;
+        }
+        self.damage
+            .mark_rows(self.cursor_row..self.scroll_bottom + 1);
         let n = count
             .max(1)
             .min(self.scroll_bottom.saturating_sub(self.cursor_row) + 1);
@@ -549,6 +822,14 @@ impl VtLite {
         if self.cursor_row < self.scroll_top || self.cursor_row > self.scroll_bottom {
             return;
         }
+        if self.has_side_margins() {
+            if (self.margin_left..=self.margin_right).contains(&self.cursor_col) {
+                self.shift_within_margins(self.cursor_row, self.scroll_bottom, count, true);
+            }
+            return;
+        }
+        self.damage
+            .mark_rows(self.cursor_row..self.scroll_bottom + 1);
         let n = count
             .max(1)
             .min(self.scroll_bottom.saturating_sub(self.cursor_row) + 1);
@@ -558,68 +839,185 @@ impl VtLite {
         }
     }
 
+    fn has_side_margins(&self) -> bool {
+        self.margin_left > 0 || self.margin_right + 1 < self.cols
+    }
+
+    /// Scrolls the part of rows `top..=bottom` between the left and right
+    /// margins `count` lines up, or down, leaving the rest of each row. Lines
+    /// scrolled off this way are not kept in history.
+    fn shift_within_margins(&mut self, top: usize, bottom: usize, count: usize, up: bool) {
+        let cols = self.margin_left..self.margin_right + 1;
+        let n = count.max(1).min(bottom - top + 1);
+        let rows: Vec<usize> = if up {
+            (top..=bottom).collect()
+        } else {
+            (top..=bottom).rev().collect()
+        };
+        for row in rows {
+            let source = if up {
+                Some(row + n).filter(|source| *source <= bottom)
+            } else {
+                row.checked_sub(n).filter(|source| *source >= top)
+            };
+            match source {
+                Some(source) => {
+                    let cells = self.lines[source][cols.clone()].to_vec();
+                    self.lines[row][cols.clone()].copy_from_slice(&cells);
+                }
+                None => self.lines[row][cols.clone()].fill(blank_cell()),
+            }
+            self.damage.mark_cells(row, cols.clone());
+        }
+    }
+
     fn write_char(&mut self, ch: char) {
-        if self.rows == 0 || self.cols == 0 {
+        if self.rows == 0 || self.cols == 0 || ch.is_control() {
             return;
         }
 
-        let width = char_display_width(ch);
-        if width == 0 {
-            self.append_combining_char(ch);
+        if let Some(col) = self.cluster_before_cursor(ch) {
+            self.extend_cluster(col, ch);
             return;
         }
 
-        if self.should_join_with_previous(ch) {
+        let width = cluster_width(ch.encode_utf8(&mut [0; 4]), self.ambiguous_width);
+        if width == 0 {
             self.append_combining_char(ch);
             return;
         }
 
         if self.wrap_pending {
-            self.cursor_col = 0;
+            self.cursor_col = self.line_start();
             self.line_feed();
             self.wrap_pending = false;
         }
 
-        if self.cursor_col >= self.cols {
-            self.cursor_col = 0;
-            self.line_feed();
+        let mut end = self.line_end();
+        if self.cursor_col + width > end + 1 {
+            if self.modes.is_set(DECAWM) {
+                self.cursor_col = self.line_start();
+                self.line_feed();
+                end = self.line_end();
+            } else {
+                self.cursor_col = end + 1 - width;
+            }
+        }
+        if self.modes.is_set(IRM) {
+            self.insert_blanks(width, end);
         }
 
-        if width == 1 {
-            self.lines[self.cursor_row][self.cursor_col] = Cell {
-                text: ch.to_string(),
-                style: self.style.clone(),
+        let (row, col) = (self.cursor_row, self.cursor_col);
+        let style = self.tables.intern_style(self.style);
+        self.damage.mark_cells(row, col..col + width);
+        self.lines[row][col] = Cell {
+            glyph: Glyph::Char(ch),
+            style,
+        };
+        if width == 2 {
+            self.lines[row][col + 1] = Cell {
+                glyph: Glyph::Continuation,
+                style,
             };
+        }
+        self.advance_past(col + width - 1, end);
+    }
 
-            if self.cursor_col >= self.cols.saturating_sub(1) {
-                self.wrap_pending = true;
-            } else {
-                self.cursor_col += 1;
-            }
-            return;
+    /// Moves the cursor past a glyph that ends in column `last`, or leaves it
+    /// there with a wrap pending when that is the line's `end`.
+    fn advance_past(&mut self, last: usize, end: usize) {
+        if last < end {
+            self.cursor_col = last + 1;
+        } else {
+            self.cursor_col = end;
+            self.wrap_pending = self.modes.is_set(DECAWM);
         }
+    }
 
-        if self.cursor_col >= self.cols.saturating_sub(1) {
-            self.cursor_col = 0;
-            self.line_feed();
+    /// Shifts the cells from the cursor to column `end` right by `count`,
+    /// as insert mode does before printing; cells pushed past `end` are lost.
+    fn insert_blanks(&mut self, count: usize, end: usize) {
+        let (row, col) = (self.cursor_row, self.cursor_col);
+        let line = &mut self.lines[row];
+        let is_continuation = |cell: &Cell| cell.glyph == Glyph::Continuation;
+        // Wide glyphs cut in two by the shift lose both halves.
+        let splits_left = col > 0 && is_continuation(&line[col]);
+        let splits_end = line.get(end + 1).is_some_and(is_continuation);
+        let pushes_half_out = col + count <= end && is_continuation(&line[end + 1 - count]);
+        if col + count <= end {
+            line.copy_within(col..end + 1 - count, col + count);
         }
+        line[col..(col + count).min(end + 1)].fill(blank_cell());
+        if splits_left {
+            line[col - 1] = blank_cell();
+            if col + count <= end {
+                line[col + count] = blank_cell();
+            }
+        }
+        if splits_end {
+            line[end + 1] = blank_cell();
+        }
+        if pushes_half_out {
+            line[end] = blank_cell();
+        }
+        self.damage.mark_cells(row, col.saturating_sub(1)..end + 2);
+    }
 
-        self.lines[self.cursor_row][self.cursor_col] = Cell {
-            text: ch.to_string(),
-            style: self.style.clone(),
+    /// Column of the cell just written before the cursor, when `ch`
+    /// continues its grapheme cluster.
+    fn cluster_before_cursor(&self, ch: char) -> Option<usize> {
+        if self.cursor_row >= self.rows || (self.cursor_col == 0 && !self.wrap_pending) {
+            return None;
+        }
+        let row = &self.lines[self.cursor_row];
+        let mut col = if self.wrap_pending {
+            self.cursor_col.min(self.cols.saturating_sub(1))
+        } else {
+            self.cursor_col - 1
         };
-        if self.cursor_col + 1 < self.cols {
-            self.lines[self.cursor_row][self.cursor_col + 1] = Cell {
-                text: String::new(),
-                style: self.style.clone(),
-            };
+        while col > 0 && row[col].glyph == Glyph::Continuation {
+            col -= 1;
+        }
+        let glyph = row[col].glyph;
+        if ch.is_ascii() && matches!(glyph, Glyph::Char(prev) if prev.is_ascii()) {
+            return None;
         }
+        let mut cluster = String::new();
+        self.tables.push_glyph(glyph, &mut cluster);
+        extends_cluster(&cluster, ch).then_some(col)
+    }
 
-        if self.cursor_col < self.cols.saturating_sub(2) {
-            self.cursor_col += 2;
+    /// Adds `ch` to the cluster at `col` before the cursor, widening or
+    /// narrowing the cell when that changes the cluster's width, as a
+    /// variation selector or a second regional indicator does.
+    fn extend_cluster(&mut self, col: usize, ch: char) {
+        let row = self.cursor_row;
+        let cell = self.lines[row][col];
+        let glyph = self.tables.extend_glyph(cell.glyph, ch);
+        self.lines[row][col].glyph = glyph;
+
+        let was_wide = self.lines[row]
+            .get(col + 1)
+            .is_some_and(|next| next.glyph == Glyph::Continuation);
+        let mut cluster = String::new();
+        self.tables.push_glyph(glyph, &mut cluster);
+        let wide = cluster_width(&cluster, self.ambiguous_width) == 2;
+        let end = self.line_end_at(col);
+        if wide && !was_wide && col < end {
+            self.damage.mark_cells(row, col..col + 2);
+            self.lines[row][col + 1] = Cell {
+                glyph: Glyph::Continuation,
+                style: cell.style,
+            };
+            self.wrap_pending = false;
+            self.advance_past(col + 1, end);
+        } else if !wide && was_wide {
+            self.damage.mark_cells(row, col..col + 2);
+            self.lines[row][col + 1] = blank_cell();
+            self.wrap_pending = false;
+            self.cursor_col = col + 1;
         } else {
-            self.cursor_col = self.cols.saturating_sub(1);
-            self.wrap_pending = true;
+            self.damage.mark_cells(row, col..col + 1);
         }
     }
 
@@ -636,37 +1034,21 @@ impl VtLite {
             self.cursor_col
         };
 
-        while col > 0 && self.lines[self.cursor_row][col].text.is_empty() {
+        while col > 0 && self.lines[self.cursor_row][col].glyph == Glyph::Continuation {
             col -= 1;
         }
 
         if col < self.cols {
-            self.lines[self.cursor_row][col].text.push(ch);
+            self.damage.mark_cells(self.cursor_row, col..col + 1);
+            let cell = &mut self.lines[self.cursor_row][col];
+            cell.glyph = self.tables.extend_glyph(cell.glyph, ch);
         }
     }
 
-    fn should_join_with_previous(&self, ch: char) -> bool {
-        if self.cursor_row >= self.rows || self.cursor_col == 0 || char_display_width(ch) == 0 {
-            return false;
-        }
-
-        let mut col = self.cursor_col.saturating_sub(1);
-        while col > 0 && self.lines[self.cursor_row][col].text.is_empty() {
-            col -= 1;
-        }
-
-        self.lines[self.cursor_row][col].text.ends_with('\u{200d}')
-    }
-
-    fn apply_sgr(&mut self, params: &[Option<i32>]) {
-        if params.is_empty() {
-            self.style = CellStyle::default();
-            return;
-        }
-
+    fn apply_sgr(&mut self, params: &Params) {
         let mut i = 0usize;
         while i < params.len() {
-            let code = params[i].unwrap_or(0);
+            let code = params.or(i, 0);
             match code {
                 0 => self.style = CellStyle::default(),
                 1 => self.style.bold = true,
@@ -677,35 +1059,36 @@ impl VtLite {
                 23 => self.style.italic = false,
                 24 => self.style.underline = false,
                 27 => self.style.inverse = false,
-                30..=37 => self.style.fg = ansi_16_color((code - 30) as usize),
-                39 => self.style.fg = None,
-                40..=47 => self.style.bg = ansi_16_color((code - 40) as usize),
-                49 => self.style.bg = None,
-                90..=97 => self.style.fg = ansi_16_color((code - 90 + 8) as usize),
-                100..=107 => self.style.bg = ansi_16_color((code - 100 + 8) as usize),
+                30..=37 => self.style.fg = Color::Indexed((code - 30) as u8),
+                39 => self.style.fg = Color::Default,
+                40..=47 => self.style.bg = Color::Indexed((code - 40) as u8),
+                49 => self.style.bg = Color::Default,
+                90..=97 => self.style.fg = Color::Indexed((code - 90 + 8) as u8),
+                100..=107 => self.style.bg = Color::Indexed((code - 100 + 8) as u8),
                 38 | 48 => {
                     let is_fg = code == 38;
-                    let mode = params.get(i + 1).and_then(|v| *v);
+                    let mode = params.get(i + 1);
                     if mode == Some(2) {
-                        let r = params.get(i + 2).and_then(|v| *v);
-                        let g = params.get(i + 3).and_then(|v| *v);
-                        let b = params.get(i + 4).and_then(|v| *v);
+                        let r = params.get(i + 2);
+                        let g = params.get(i + 3);
+                        let b = params.get(i + 4);
                         if let (Some(r), Some(g), Some(b)) = (r, g, b) {
-                            let color = rgb_hex(r, g, b);
+                            let channel = |value: u16| value.min(255) as u8;
+                            let color = Color::Rgb(channel(r), channel(g), channel(b));
                             if is_fg {
-                                self.style.fg = Some(color);
+                                self.style.fg = color;
                             } else {
-                                self.style.bg = Some(color);
+                                self.style.bg = color;
                             }
                         }
                         i += 4;
                     } else if mode == Some(5) {
-                        let index = params.get(i + 2).and_then(|v| *v);
-                        if let Some(idx) = index.and_then(xterm_256_color) {
+                        let index = params.get(i + 2).and_then(|index| u8::try_from(index).ok());
+                        if let Some(index) = index {
                             if is_fg {
-                                self.style.fg = Some(idx);
+                                self.style.fg = Color::Indexed(index);
                             } else {
-                                self.style.bg = Some(idx);
+                                self.style.bg = Color::Indexed(index);
                             }
                         }
                         i += 2;
@@ -719,83 +1102,296 @@ impl VtLite {
 
     fn reset(&mut self) {
         self.lines = vec![make_row(self.cols); self.rows];
+        self.damage.mark_all();
         self.cursor_row = 0;
         self.cursor_col = 0;
-        self.saved_row = 0;
-        self.saved_col = 0;
+        self.saved_cursor = SavedCursor::default();
         self.style = CellStyle::default();
+        self.charsets = Charsets::default();
         self.scroll_top = 0;
         self.scroll_bottom = self.rows.saturating_sub(1);
+        self.margin_left = 0;
+        self.margin_right = self.cols.saturating_sub(1);
         self.wrap_pending = false;
         self.cursor_visible = true;
+        self.modes = Modes::default();
         self.saved_primary = None;
     }
 
-    fn into_frame(&self) -> Value {
-        let screen = Screen::new().compose(
+    fn serialize_state(&self, history_bytes: usize) -> String {
+        let mut out = String::from("\x1b[0m\x1b[H\x1b[2J");
+        let history = self.serialize_history(history_bytes);
+        if !history.is_empty() {
+            for line in &history {
+                out.push_str(line);
+                out.push_str("\r\n");
+            }
+            // Blank lines push the last history line off the screen too.
+            out.push_str(&"\r\n".repeat(self.rows - 1));
+            out.push_str("\x1b[H");
+        }
+        let mut replay = Replay::default();
+        let current = ScreenView {
+            lines: &self.lines,
+            cursor: self.cursor_state(),
+            saved_cursor: self.saved_cursor,
+            scroll_top: self.scroll_top,
+            scroll_bottom: self.scroll_bottom,
+            margin_left: self.margin_left,
+            margin_right: self.margin_right,
+            cursor_visible: self.cursor_visible,
+        };
+        if let Some(primary) = self.saved_primary.as_ref() {
+            self.serialize_screen(
+                &mut out,
+                &ScreenView {
+                    lines: &primary.lines,
+                    cursor: primary.cursor,
+                    saved_cursor: primary.saved_cursor,
+                    scroll_top: primary.scroll_top,
+                    scroll_bottom: primary.scroll_bottom,
+                    margin_left: primary.margin_left,
+                    margin_right: primary.margin_right,
+                    cursor_visible: primary.cursor_visible,
+                },
+                &mut replay,
+            );
+            out.push_str("\x1b[?1049h");
+            replay.style = CellStyle::default();
+        }
+        self.serialize_screen(&mut out, &current, &mut replay);
+        // Origin and margin mode are set by each screen, as they move the
+        // cursor; the others only change how later output lands.
+        for (mode, value) in self.modes.changed() {
+            if mode != DECOM && mode != DECLRMM {
+                out.push_str(&mode.sequence(value));
+            }
+        }
+        out
+    }
+
+    /// The newest history lines whose serialized size adds up to at most
+    /// `max_bytes`, oldest first. Each starts and ends in the default style,
+    /// with no character sets designated.
+    fn serialize_history(&self, max_bytes: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut total = 0;
+        for row in self.history.iter().rev() {
+            let mut replay = Replay::default();
+            let mut line = String::new();
+            self.serialize_cells(&mut line, row, &mut replay);
+            if replay.style != CellStyle::default() {
+                line.push_str("\x1b[0m");
+            }
+            total += line.len() + 2;
+            if total > max_bytes {
+                break;
+            }
+            lines.push(line);
+        }
+        lines.reverse();
+        lines
+    }
+
+    /// Writes `screen` into a cleared screen with the cursor at home, as
+    /// left by a reset or by entering the alternate screen.
+    fn serialize_screen(&self, out: &mut String, screen: &ScreenView, replay: &mut Replay) {
+        // Cells hold what was shown, so they are written with no sets
+        // designated.
+        if replay.charsets != Charsets::default() {
+            replay.charsets = Charsets::default();
+            out.push_str(&replay.charsets.sequence());
+        }
+        for (row_idx, row) in screen.lines.iter().enumerate() {
+            if row_idx > 0 {
+                out.push_str("\r\n");
+            }
+            self.serialize_cells(out, row, replay);
+        }
+
+        if screen.scroll_top != 0 || screen.scroll_bottom != self.rows.saturating_sub(1) {
+            out.push_str(&format!(
+                "\x1b[{};{}r",
+                screen.scroll_top + 1,
+                screen.scroll_bottom + 1
+            ));
+        }
+        if self.modes.is_set(DECLRMM) {
+            out.push_str(&DECLRMM.sequence(true));
+            if screen.margin_left != 0 || screen.margin_right + 1 != self.cols {
+                out.push_str(&format!(
+                    "\x1b[{};{}s",
+                    screen.margin_left + 1,
+                    screen.margin_right + 1
+                ));
+            }
+        }
+        self.serialize_cursor(out, screen, &screen.saved_cursor, replay);
+        out.push_str("\x1b7");
+        self.serialize_cursor(out, screen, &screen.cursor, replay);
+        if !screen.cursor_visible {
+            out.push_str("\x1b[?25l");
+        }
+    }
+
+    /// Writes the cells of `row` up to its trailing blanks.
+    fn serialize_cells(&self, out: &mut String, row: &[Cell], replay: &mut Replay) {
+        let mut end = row.len();
+        while end > 0 && row[end - 1] == blank_cell() {
+            end -= 1;
+        }
+        for cell in row.iter().take(end) {
+            if cell.glyph == Glyph::Continuation {
+                continue;
+            }
+            let style = self.tables.style(cell.style);
+            if style != replay.style {
+                out.push_str(&sgr_sequence(&style));
+                replay.style = style;
+            }
+            self.tables.push_glyph(cell.glyph, out);
+        }
+    }
+
+    /// Brings the cursor of a pane replaying `screen` to the state `cursor`.
+    fn serialize_cursor(
+        &self,
+        out: &mut String,
+        screen: &ScreenView,
+        cursor: &SavedCursor,
+        replay: &mut Replay,
+    ) {
+        // In origin mode the cursor is addressed from the margins.
+        if cursor.origin != replay.origin {
+            out.push_str(&DECOM.sequence(cursor.origin));
+            replay.origin = cursor.origin;
+        }
+        let (origin_row, origin_col) = if cursor.origin {
+            (screen.scroll_top, screen.margin_left)
+        } else {
+            (0, 0)
+        };
+
+        // A pending wrap only comes from writing the last column before the
+        // right edge, so rewrite the glyph that ends there.
+        let last_cell = screen
+            .lines
+            .get(cursor.row)
+            .filter(|row| cursor.col < row.len())
+            .map(|row| (row, cursor.col));
+        match last_cell {
+            Some((row, mut col)) if cursor.wrap_pending => {
+                if row[col].glyph == Glyph::Continuation && col > 0 {
+                    col -= 1;
+                }
+                let cell = row[col];
+                out.push_str(&format!(
+                    "\x1b[{};{}H",
+                    cursor.row.saturating_sub(origin_row) + 1,
+                    col.saturating_sub(origin_col) + 1
+                ));
+                let style = self.tables.style(cell.style);
+                if style != replay.style {
+                    out.push_str(&sgr_sequence(&style));
+                    replay.style = style;
+                }
+                if replay.charsets != Charsets::default() {
+                    replay.charsets = Charsets::default();
+                    out.push_str(&replay.charsets.sequence());
+                }
+                self.tables.push_glyph(cell.glyph, out);
+            }
+            _ => out.push_str(&format!(
+                "\x1b[{};{}H",
+                cursor.row.saturating_sub(origin_row) + 1,
+                cursor.col.saturating_sub(origin_col) + 1
+            )),
+        }
+
+        if cursor.style != replay.style {
+            out.push_str(&sgr_sequence(&cursor.style));
+            replay.style = cursor.style;
+        }
+        if cursor.charsets != replay.charsets {
+            out.push_str(&cursor.charsets.sequence());
+            replay.charsets = cursor.charsets;
+        }
+    }
+
+    fn screen(&self) -> ScreenFrame<'_> {
+        let mut screen = Screen::new().compose(
             &self.lines,
+            &self.tables,
             self.cols,
             self.rows,
-            self.cursor_row,
-            self.cursor_col,
+            (self.cursor_row, self.cursor_col),
             self.cursor_visible,
         );
-        Renderer::new().render_styled_frame(&screen)
+        screen.reverse_video = self.modes.is_set(DECSCNM);
+        screen
     }
-}
 
-fn parse_params(raw: &str) -> Vec<Option<i32>> {
-    if raw.is_empty() {
-        return vec![None];
+    fn to_frame(&self) -> StyledFrame {
+        Renderer::new().render_styled_frame(&self.screen())
     }
-    raw.split(';')
-        .map(|part| {
-            if part.is_empty() {
-                None
-            } else {
-                part.parse::<i32>().ok()
-            }
-        })
-        .collect::<Vec<_>>()
-}
 
-fn param_or(params: &[Option<i32>], index: usize, default: i32) -> i32 {
-    params.get(index).and_then(|v| *v).unwrap_or(default)
-}
-
-fn ansi_16_color(index: usize) -> Option<String> {
-    let palette = [
-        "#000000", "#cd3131", "#0dbc79", "#e5e510", "#2472c8", "#bc3fbc", "#11a8cd", "#e5e5e5",
-        "#666666", "#f14c4c", "#23d18b", "#f5f543", "#3b8eea", "#d670d6", "#29b8db", "#ffffff",
-    ];
-    palette.get(index).map(|v| v.to_string())
-}
-
-fn rgb_hex(r: i32, g: i32, b: i32) -> String {
-    format!(
-        "#{:02x}{:02x}{:02x}",
-        r.clamp(0, 255),
-        g.clamp(0, 255),
-        b.clamp(0, 255)
-    )
+    fn viewport_frame(&self, offset: usize) -> StyledFrame {
+        let offset = if self.saved_primary.is_some() {
+            0
+        } else {
+            offset.min(self.history.len())
+        };
+        let lines: Vec<Vec<Cell>> = self
+            .history
+            .iter()
+            .skip(self.history.len() - offset)
+            .chain(&self.lines)
+            .take(self.rows)
+            .cloned()
+            .collect();
+        let cursor_row = self.cursor_row + offset;
+        let mut screen = Screen::new().compose(
+            &lines,
+            &self.tables,
+            self.cols,
+            self.rows,
+            (cursor_row, self.cursor_col),
+            self.cursor_visible && cursor_row < self.rows,
+        );
+        screen.reverse_video = self.modes.is_set(DECSCNM);
+        let mut frame = Renderer::new().render_styled_frame(&screen);
+        frame.viewport_offset = Some(offset);
+        frame
+    }
 }
 
-fn xterm_256_color(index: i32) -> Option<String> {
-    if !(0..=255).contains(&index) {
-        return None;
+fn sgr_sequence(style: &CellStyle) -> String {
+    let mut out = String::from("\x1b[0");
+    if style.bold {
+        out.push_str(";1");
+    }
+    if style.italic {
+        out.push_str(";3");
     }
-    if index < 16 {
-        return ansi_16_color(index as usize);
+    if style.underline {
+        out.push_str(";4");
     }
-    if index >= 232 {
-        let v = 8 + (index - 232) * 10;
-        return Some(rgb_hex(v, v, v));
+    if style.inverse {
+        out.push_str(";7");
     }
+    push_sgr_color(&mut out, style.fg, 30);
+    push_sgr_color(&mut out, style.bg, 40);
+    out.push('m');
+    out
+}
 
-    let i = index - 16;
-    let r = i / 36;
-    let g = (i % 36) / 6;
-    let b = i % 6;
-    let map = [0, 95, 135, 175, 215, 255];
-    Some(rgb_hex(map[r as usize], map[g as usize], map[b as usize]))
+// `base` is 30 for the foreground and 40 for the background.
+fn push_sgr_color(out: &mut String, color: Color, base: u8) {
+    match color {
+        Color::Default => {}
+        Color::Indexed(index) if index < 8 => out.push_str(&format!(";{}", base + index)),
+        Color::Indexed(index) if index < 16 => out.push_str(&format!(";{}", base + 52 + index)),
+        Color::Indexed(index) => out.push_str(&format!(";{};5;{index}", base + 8)),
+        Color::Rgb(r, g, b) => out.push_str(&format!(";{};2;{r};{g};{b}", base + 8)),
+    }
 }
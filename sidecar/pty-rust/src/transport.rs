@@ -0,0 +1,340 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Largest message accepted from an authenticated client, in any framing.
+pub const MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+/// Largest message accepted before the client has authenticated; `hello`
+/// is far smaller.
+pub const UNAUTHENTICATED_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// Serialization used for messages on a connection. Every connection starts
+/// with JSON; `hello` can switch it to a binary encoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireEncoding {
+    Json,
+    /// MessagePack with structs as maps, so messages stay self-describing.
+    MessagePack,
+}
+
+impl WireEncoding {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Self::Json),
+            "msgpack" => Some(Self::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::MessagePack => "msgpack",
+        }
+    }
+
+    pub fn is_binary(self) -> bool {
+        self != Self::Json
+    }
+
+    pub fn decode(self, payload: &[u8]) -> Result<Value, String> {
+        match self {
+            Self::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+            Self::MessagePack => rmp_serde::from_slice(payload).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, message: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Json => serde_json::to_vec(message),
+            Self::MessagePack => {
+                return rmp_serde::to_vec_named(message)
+                    .map_err(|e| format!("encode response: {e}"));
+            }
+        }
+        .map_err(|e| format!("encode response: {e}"))
+    }
+}
+
+/// Write half of a connection. It owns the connection's encoding, which
+/// also decides how incoming messages are framed.
+pub trait MessageWriter: Send {
+    fn write_payload(&mut self, payload: &[u8]) -> Result<(), String>;
+    fn encoding(&self) -> WireEncoding;
+    /// Takes effect for the next message in either direction.
+    fn set_encoding(&mut self, encoding: WireEncoding);
+}
+
+/// Write half shared by the connection loop and the threads answering
+/// pipelined requests; each message is written under the lock.
+pub type SharedWriter = Arc<Mutex<dyn MessageWriter>>;
+
+pub fn lock_writer(writer: &SharedWriter) -> MutexGuard<'_, dyn MessageWriter + 'static> {
+    writer
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Encodes `message` with the connection's current encoding and writes it.
+pub fn send_message<T: Serialize>(writer: &SharedWriter, message: &T) -> Result<(), String> {
+    let mut writer = lock_writer(writer);
+    let payload = writer.encoding().encode(message)?;
+    writer.write_payload(&payload)
+}
+
+/// A connection carrying requests in and responses out, independent of how
+/// messages are framed on the wire.
+pub trait MessageChannel {
+    /// Next raw message, or `None` once the peer has closed the stream.
+    fn read_payload(&mut self) -> Result<Option<Vec<u8>>, String>;
+    fn writer(&self) -> &SharedWriter;
+    /// Longest message `read_payload` accepts; a longer one is an error
+    /// that ends the connection. Defaults to `MAX_MESSAGE_BYTES`.
+    fn set_message_limit(&mut self, limit: usize);
+
+    fn encoding(&self) -> WireEncoding {
+        lock_writer(self.writer()).encoding()
+    }
+
+    fn set_encoding(&mut self, encoding: WireEncoding) {
+        lock_writer(self.writer()).set_encoding(encoding);
+    }
+
+    /// Next message decoded with the current encoding. The inner error is a
+    /// message that could not be decoded; the connection is still usable.
+    fn read_message(&mut self) -> Result<Option<Result<Value, String>>, String> {
+        let encoding = self.encoding();
+        Ok(self
+            .read_payload()?
+            .map(|payload| encoding.decode(&payload)))
+    }
+
+    fn send<T: Serialize>(&mut self, message: &T) -> Result<(), String> {
+        send_message(self.writer(), message)
+    }
+}
+
+/// Newline-delimited JSON, as spoken on the unix socket and `tcp://`. With a
+/// binary encoding every message is prefixed by its length as a big-endian
+/// `u32` instead.
+pub struct LineChannel<S> {
+    reader: BufReader<S>,
+    writer: SharedWriter,
+    limit: usize,
+}
+
+struct LineWriter<S> {
+    stream: S,
+    encoding: WireEncoding,
+}
+
+impl<S: Read + Write + Send + 'static> LineChannel<S> {
+    /// `reader` and `writer` are two handles to the same stream.
+    pub fn new(reader: S, writer: S) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer: Arc::new(Mutex::new(LineWriter {
+                stream: writer,
+                encoding: WireEncoding::Json,
+            })),
+            limit: MAX_MESSAGE_BYTES,
+        }
+    }
+
+    /// The underlying stream, for socket-level operations such as peer
+    /// credentials or passing descriptors.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.reader.get_mut()
+    }
+}
+
+impl<S: Read + Write + Send + 'static> MessageChannel for LineChannel<S> {
+    fn read_payload(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if !self.encoding().is_binary() {
+            // Reads at most one byte past the limit, so a client that never
+            // sends a newline cannot grow the buffer.
+            let mut raw = Vec::new();
+            let read = (&mut self.reader)
+                .take(self.limit as u64 + 1)
+                .read_until(b'\n', &mut raw)
+                .map_err(|e| format!("failed to read request: {e}"))?;
+            if raw.len() > self.limit && raw.last() != Some(&b'\n') {
+                return Err(format!(
+                    "request exceeds the {} byte message limit",
+                    self.limit
+                ));
+            }
+            return Ok((read > 0).then_some(raw));
+        }
+
+        let mut prefix = [0u8; 4];
+        match self.reader.read_exact(&mut prefix) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(format!("failed to read request: {err}")),
+        }
+        let len = u32::from_be_bytes(prefix) as usize;
+        if len > self.limit {
+            return Err(format!(
+                "request of {len} bytes exceeds the {} byte message limit",
+                self.limit
+            ));
+        }
+        let mut payload = vec![0u8; len];
+        self.reader
+            .read_exact(&mut payload)
+            .map_err(|e| format!("failed to read request: {e}"))?;
+        Ok(Some(payload))
+    }
+
+    fn writer(&self) -> &SharedWriter {
+        &self.writer
+    }
+
+    fn set_message_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+}
+
+impl<S: Write + Send> MessageWriter for LineWriter<S> {
+    fn write_payload(&mut self, payload: &[u8]) -> Result<(), String> {
+        let mut framed = Vec::with_capacity(payload.len() + 4);
+        if self.encoding.is_binary() {
+            let len = u32::try_from(payload.len())
+                .map_err(|_| "response exceeds the message limit".to_string())?;
+            framed.extend_from_slice(&len.to_be_bytes());
+            framed.extend_from_slice(payload);
+        } else {
+            framed.extend_from_slice(payload);
+            framed.push(b'\n');
+        }
+        self.stream
+            .write_all(&framed)
+            .map_err(|e| format!("write response: {e}"))
+    }
+
+    fn encoding(&self) -> WireEncoding {
+        self.encoding
+    }
+
+    fn set_encoding(&mut self, encoding: WireEncoding) {
+        self.encoding = encoding;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListenScheme {
+    /// Newline-delimited JSON over TCP.
+    Tcp,
+    /// One JSON message per WebSocket text frame.
+    WebSocket,
+}
+
+/// Network endpoint given with `--listen tcp://HOST:PORT` or
+/// `--listen ws://HOST:PORT[/path]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenAddress {
+    pub scheme: ListenScheme,
+    pub addr: SocketAddr,
+}
+
+impl ListenAddress {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let (scheme, rest) = raw
+            .split_once("://")
+            .ok_or_else(|| format!("invalid --listen {raw}: expected tcp:// or ws://"))?;
+        let scheme = match scheme {
+            "tcp" => ListenScheme::Tcp,
+            "ws" => ListenScheme::WebSocket,
+            other => return Err(format!("unsupported --listen scheme: {other}")),
+        };
+        // Any path on a ws:// URL is accepted; the server ignores it.
+        let authority = rest.split('/').next().unwrap_or_default();
+        let addr = authority
+            .to_socket_addrs()
+            .map_err(|e| format!("invalid --listen address {authority}: {e}"))?
+            .next()
+            .ok_or_else(|| format!("--listen address {authority} did not resolve"))?;
+        Ok(Self { scheme, addr })
+    }
+
+    /// Non-loopback addresses are refused unless explicitly allowed.
+    pub fn check_exposure(&self, allow_remote: bool) -> Result<(), String> {
+        if self.addr.ip().is_loopback() || allow_remote {
+            return Ok(());
+        }
+        Err(format!(
+            "refusing to listen on non-loopback address {}; pass --listen-allow-remote to expose it",
+            self.addr
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn parses_listen_addresses_and_guards_non_loopback() {
+        let tcp = ListenAddress::parse("tcp://127.0.0.1:7311").expect("tcp");
+        assert_eq!(tcp.scheme, ListenScheme::Tcp);
+        assert_eq!(tcp.addr, "127.0.0.1:7311".parse().expect("addr"));
+        assert!(tcp.check_exposure(false).is_ok());
+
+        let ws = ListenAddress::parse("ws://[::1]:7312/rpc").expect("ws");
+        assert_eq!(ws.scheme, ListenScheme::WebSocket);
+        assert!(ws.addr.ip().is_loopback());
+
+        let open = ListenAddress::parse("tcp://0.0.0.0:7313").expect("any");
+        assert!(open.check_exposure(false).is_err());
+        assert!(open.check_exposure(true).is_ok());
+
+        assert!(ListenAddress::parse("http://127.0.0.1:80").is_err());
+        assert!(ListenAddress::parse("127.0.0.1:80").is_err());
+    }
+
+    #[test]
+    fn line_channel_frames_json_by_newline_and_binary_by_length() {
+        let (left, right) = UnixStream::pair().expect("pair");
+        let mut server = LineChannel::new(left.try_clone().expect("clone"), left);
+        let mut client = LineChannel::new(right.try_clone().expect("clone"), right);
+
+        client.send(&json!({ "method": "hello" })).expect("write");
+        let received = server.read_message().expect("read").expect("message");
+        assert_eq!(received, Ok(json!({ "method": "hello" })));
+
+        lock_writer(client.writer())
+            .write_payload(b"{not json")
+            .expect("write");
+        assert!(matches!(server.read_message(), Ok(Some(Err(_)))));
+
+        for side in [&mut server, &mut client] {
+            side.set_encoding(WireEncoding::MessagePack);
+        }
+        let frame = json!({ "cols": 80, "lines": [{ "segments": [{ "text": "hi" }] }] });
+        client.send(&frame).expect("write binary");
+        assert_eq!(server.read_message(), Ok(Some(Ok(frame))));
+
+        drop(client);
+        assert_eq!(server.read_message(), Ok(None));
+    }
+
+    #[test]
+    fn line_channel_rejects_lines_past_the_message_limit() {
+        let (left, mut right) = UnixStream::pair().expect("pair");
+        let mut server = LineChannel::new(left.try_clone().expect("clone"), left);
+        server.set_message_limit(16);
+
+        right.write_all(b"{\"method\":\"ok\"}\n").expect("write");
+        assert!(matches!(server.read_message(), Ok(Some(Ok(_)))));
+
+        // No newline ever follows; the read stops at the limit.
+        right.write_all(&[b'x'; 64]).expect("write");
+        let err = server.read_payload().expect_err("line is too long");
+        assert!(err.contains("16 byte message limit"), "{err}");
+    }
+}
@@ -1,10 +1,16 @@
-pub use crate::terminal_pane::build_styled_frame;
+pub use crate::terminal_pane::{render_frame, render_viewport};
 
 #[cfg(test)]
 mod tests {
-    use super::build_styled_frame;
+    use crate::grid_scrollback::AmbiguousWidth;
+    use crate::query_policy::QueryResponder;
+    use crate::renderer::{FramePatch, PatchGranularity};
+    use crate::screen::DamageLog;
+    use crate::terminal_pane::build_styled_frame;
     use crate::terminal_pane::TerminalPane;
+    use crate::terminal_pane::{render_frame, render_viewport};
     use serde_json::Value;
+    use std::collections::HashMap;
 
     fn line_text(frame: &Value, row: usize) -> String {
         frame["lines"][row]["segments"]
@@ -63,8 +69,8 @@ mod tests {
     #[test]
     fn supports_split_csi_sequence_across_feeds() {
         let mut pane = TerminalPane::new(20, 6);
-        pane.feed("\x1b[31");
-        pane.feed("mred");
+        pane.feed(b"\x1b[31");
+        pane.feed(b"mred");
 
         let frame = pane.frame();
         let red = frame["lines"][0]["segments"]
@@ -83,8 +89,8 @@ mod tests {
     #[test]
     fn supports_split_osc_sequence_across_feeds() {
         let mut pane = TerminalPane::new(20, 6);
-        pane.feed("\x1b]0;window title");
-        pane.feed("\u{0007}done");
+        pane.feed(b"\x1b]0;window title");
+        pane.feed("\u{0007}done".as_bytes());
 
         let frame = pane.frame();
         let first = line_text(&frame, 0);
@@ -94,8 +100,8 @@ mod tests {
     #[test]
     fn supports_split_scs_sequence_across_feeds() {
         let mut pane = TerminalPane::new(20, 6);
-        pane.feed("\x1b(");
-        pane.feed("BOK");
+        pane.feed(b"\x1b(");
+        pane.feed(b"BOK");
 
         let frame = pane.frame();
         let first = line_text(&frame, 0);
@@ -106,8 +112,8 @@ mod tests {
     #[test]
     fn supports_split_dcs_sequence_across_feeds() {
         let mut pane = TerminalPane::new(20, 6);
-        pane.feed("\x1bPignored");
-        pane.feed("-payload\x1b\\done");
+        pane.feed(b"\x1bPignored");
+        pane.feed(b"-payload\x1b\\done");
 
         let frame = pane.frame();
         let first = line_text(&frame, 0);
@@ -118,8 +124,8 @@ mod tests {
     #[test]
     fn supports_split_apc_sequence_across_feeds() {
         let mut pane = TerminalPane::new(20, 6);
-        pane.feed("\x1b_kitty");
-        pane.feed("-query\x1b\\ok");
+        pane.feed(b"\x1b_kitty");
+        pane.feed(b"-query\x1b\\ok");
 
         let frame = pane.frame();
         let first = line_text(&frame, 0);
@@ -187,13 +193,13 @@ mod tests {
     #[test]
     fn tracks_cursor_visibility_through_alt_screen_transitions() {
         let mut pane = TerminalPane::new(20, 6);
-        pane.feed("\x1b[?25l");
+        pane.feed(b"\x1b[?25l");
         assert_eq!(pane.frame()["cursorVisible"].as_bool(), Some(false));
 
-        pane.feed("\x1b[?1049h");
+        pane.feed(b"\x1b[?1049h");
         assert_eq!(pane.frame()["cursorVisible"].as_bool(), Some(true));
 
-        pane.feed("\x1b[?1049l");
+        pane.feed(b"\x1b[?1049l");
         assert_eq!(pane.frame()["cursorVisible"].as_bool(), Some(false));
     }
 
@@ -219,7 +225,7 @@ mod tests {
     #[test]
     fn tracks_wide_characters_without_cursor_corruption() {
         let mut pane = TerminalPane::new(20, 6);
-        pane.feed("한글A");
+        pane.feed("한글A".as_bytes());
 
         let frame = pane.frame();
         assert_eq!(frame["cursorRow"].as_u64(), Some(0));
@@ -230,9 +236,9 @@ mod tests {
     #[test]
     fn keeps_combining_mark_on_last_cell_before_wrap() {
         let mut pane = TerminalPane::new(20, 6);
-        pane.feed("ABCDEFGHIJ0123456789");
-        pane.feed("\u{0301}");
-        pane.feed("X");
+        pane.feed(b"ABCDEFGHIJ0123456789");
+        pane.feed("\u{0301}".as_bytes());
+        pane.feed(b"X");
 
         let frame = pane.frame();
         assert_eq!(frame["cursorRow"].as_u64(), Some(1));
@@ -241,14 +247,431 @@ mod tests {
         assert!(line_text(&frame, 1).starts_with('X'));
     }
 
+    #[test]
+    fn caps_the_marks_kept_in_one_cell() {
+        let mut pane = TerminalPane::new(20, 6);
+        pane.feed(format!("e{}X", "\u{0301}".repeat(5_000)).as_bytes());
+
+        let frame = pane.frame();
+        assert_eq!(frame["cursorCol"].as_u64(), Some(2));
+        let text = line_text(&frame, 0);
+        assert_eq!(text.trim_end(), format!("e{}X", "\u{0301}".repeat(15)));
+        pane.drop_unused_cell_data();
+        assert_eq!(pane.cell_data_len(), 2);
+    }
+
     #[test]
     fn treats_zwj_emoji_cluster_as_single_glyph_cell_cluster() {
         let mut pane = TerminalPane::new(20, 6);
-        pane.feed("👨\u{200d}💻A");
+        pane.feed("👨\u{200d}💻A".as_bytes());
 
         let frame = pane.frame();
         assert_eq!(frame["cursorRow"].as_u64(), Some(0));
         assert_eq!(frame["cursorCol"].as_u64(), Some(3));
         assert!(line_text(&frame, 0).contains("👨\u{200d}💻A"));
     }
+
+    #[test]
+    fn switches_cell_width_with_presentation_selectors() {
+        // U+2600 is narrow text by default; VS16 asks for the wide emoji.
+        let frame = build_styled_frame("\u{2600}\u{fe0f}A \u{2764}B \u{231a}\u{fe0e}C", 20, 6);
+        assert_eq!(frame["cursorCol"].as_u64(), Some(9));
+        assert_eq!(
+            line_text(&frame, 0).trim_end(),
+            "\u{2600}\u{fe0f}A \u{2764}B \u{231a}\u{fe0e}C"
+        );
+
+        let mut pane = TerminalPane::new(20, 6);
+        pane.feed("\u{2600}\u{fe0f}".as_bytes());
+        pane.feed(b"\rX");
+        assert_eq!(line_text(&pane.frame(), 0).trim_end(), "X");
+    }
+
+    #[test]
+    fn keeps_flags_and_skin_tones_in_one_wide_cell() {
+        let frame = build_styled_frame("\u{1f1ef}\u{1f1f5}\u{1f1fa}|\u{1f44d}\u{1f3fd}|", 20, 6);
+        // A flag pair, a lone regional indicator, then a thumbs up with its
+        // skin tone.
+        assert_eq!(frame["cursorCol"].as_u64(), Some(7));
+        assert_eq!(
+            line_text(&frame, 0).trim_end(),
+            "\u{1f1ef}\u{1f1f5}\u{1f1fa}|\u{1f44d}\u{1f3fd}|"
+        );
+    }
+
+    #[test]
+    fn widens_a_cluster_that_would_pass_the_last_column() {
+        let mut pane = TerminalPane::new(20, 6);
+        pane.feed(format!("{}\u{2600}\u{fe0f}", "x".repeat(19)).as_bytes());
+        let frame = pane.frame();
+        assert_eq!(frame["cursorRow"].as_u64(), Some(0));
+        assert_eq!(frame["cursorCol"].as_u64(), Some(19));
+        pane.feed("\u{231a}\u{fe0e}y".as_bytes());
+        let frame = pane.frame();
+        assert_eq!(line_text(&frame, 1).trim_end(), "\u{231a}\u{fe0e}y");
+        assert_eq!(frame["cursorCol"].as_u64(), Some(2));
+    }
+
+    #[test]
+    fn sizes_ambiguous_characters_per_pane_setting() {
+        let output = "\u{2500}\u{2460}|\u{20000}\u{30000}|";
+        let narrow = render_frame(output, 20, 6, AmbiguousWidth::Narrow).to_value();
+        assert_eq!(narrow["cursorCol"].as_u64(), Some(8));
+        let wide = render_frame(output, 20, 6, AmbiguousWidth::Wide).to_value();
+        assert_eq!(wide["cursorCol"].as_u64(), Some(10));
+        assert_eq!(line_text(&wide, 0).trim_end(), output);
+    }
+
+    #[test]
+    fn serialized_state_reproduces_styled_screen_and_cursor() {
+        let mut pane = TerminalPane::new(20, 6);
+        pane.feed("one\r\n\x1b[1;31mred\x1b[0m 한글\r\n\x1b[44mbg\x1b[3;7H\x1b[?25l".as_bytes());
+        let original = pane.frame();
+
+        let mut restored = TerminalPane::new(20, 6);
+        restored.feed(pane.serialize_state(0).as_bytes());
+        assert_eq!(restored.frame(), original);
+
+        restored.feed(b"X");
+        pane.feed(b"X");
+        assert_eq!(restored.frame(), pane.frame());
+    }
+
+    #[test]
+    fn serialized_state_restores_alt_screen_scroll_region_and_pending_wrap() {
+        let mut pane = TerminalPane::new(20, 6);
+        pane.feed(b"\x1b[32mprimary\x1b[2;5H\x1b7\x1b[4;1H\x1b[?25l\x1b[?1049h");
+        pane.feed(b"\x1b[2;4r\x1b[5;3H\x1b7\x1b[4;1H\x1b[1mABCDEFGHIJ0123456789");
+        let mut restored = TerminalPane::new(20, 6);
+        restored.feed(pane.serialize_state(0).as_bytes());
+        assert_eq!(restored.frame(), pane.frame());
+
+        for input in ["X\n\n\n", "\x1b8Y", "\x1b[?1049l", "\x1b8Z"] {
+            pane.feed(input.as_bytes());
+            restored.feed(input.as_bytes());
+            assert_eq!(restored.frame(), pane.frame(), "after {input:?}");
+        }
+    }
+
+    #[test]
+    fn serialized_state_writes_the_newest_history_that_fits_first() {
+        let mut pane = TerminalPane::new(20, 6);
+        pane.keep_history(50);
+        for n in 1..=30 {
+            pane.feed(format!("\x1b[3{}mline {n}\x1b[0m\r\n", n % 8).as_bytes());
+        }
+        pane.feed(b"\x1b[?1049halt");
+
+        let mut restored = TerminalPane::new(20, 6);
+        restored.keep_history(50);
+        restored.feed(pane.serialize_state(usize::MAX).as_bytes());
+        assert_eq!(restored.frame(), pane.frame());
+        pane.feed(b"\x1b[?1049l");
+        restored.feed(b"\x1b[?1049l");
+        for offset in [1, 10, 25] {
+            assert_eq!(
+                restored.viewport_frame(offset),
+                pane.viewport_frame(offset),
+                "at offset {offset}"
+            );
+        }
+
+        let budget = 5 * "\x1b[0;31mline 25\x1b[0m\r\n".len();
+        let mut bounded = TerminalPane::new(20, 6);
+        bounded.keep_history(50);
+        bounded.feed(pane.serialize_state(budget).as_bytes());
+        let top = bounded.viewport_frame(50).to_value();
+        assert_eq!(top["viewportOffset"], 5);
+        assert_eq!(line_text(&top, 0), "line 21");
+        assert_eq!(bounded.frame(), pane.frame());
+    }
+
+    #[test]
+    fn checkpointed_output_renders_like_the_full_output() {
+        let output = "\x1b[?1049h\x1b[44mtop\x1b[3;5r\x1b[5;1H한글\x1b[31mred\r\n";
+        let mut full = TerminalPane::new(20, 6);
+        full.feed(output.as_bytes());
+        full.feed(b"\x1b[1mmore");
+
+        for cut in (0..=output.len()).filter(|cut| output.is_char_boundary(*cut)) {
+            let mut pane = TerminalPane::new(20, 6);
+            pane.feed(&output.as_bytes()[..cut]);
+            let checkpoint = pane.serialize_state(0);
+            let raw_start = cut - pane.pending_escape_len();
+            let mut compacted = TerminalPane::new(20, 6);
+            compacted.feed(checkpoint.as_bytes());
+            compacted.feed(&output.as_bytes()[raw_start..]);
+            compacted.feed(b"\x1b[1mmore");
+            assert_eq!(compacted.frame(), full.frame(), "cut at {cut}");
+        }
+    }
+
+    #[test]
+    fn answers_queries_with_the_cursor_at_each_query() {
+        let mut pane = TerminalPane::new(20, 6);
+        let mut modes = HashMap::new();
+        pane.feed(b"\x1b[2;1Hab\x1b[");
+        let mut responder = QueryResponder::new(&mut modes, 20, 6);
+        pane.feed_answering(b"6ncd\x1b[?25l\x1b[6n\x1b[?25$p", &mut responder);
+        assert_eq!(responder.into_response(), "\x1b[2;3R\x1b[2;5R\x1b[?25;2$y");
+        assert!(line_text(&pane.frame(), 1).starts_with("abcd"));
+    }
+
+    #[test]
+    fn overwrites_the_last_column_with_autowrap_off() {
+        let frame = build_styled_frame("\x1b[?7l0123456789abcdefghijXYZ\r\n\x1b[?7hok", 20, 6);
+        assert_eq!(line_text(&frame, 0), "0123456789abcdefghiZ");
+        assert_eq!(line_text(&frame, 1), "ok");
+    }
+
+    #[test]
+    fn inserts_characters_and_returns_carriage_on_line_feed() {
+        let frame = build_styled_frame(
+            "abcdef\r\x1b[4hXY\x1b[4l\x1b[20h\nnext\x1b[20l\nlast",
+            20,
+            6,
+        );
+        assert_eq!(line_text(&frame, 0), "XYabcdef");
+        assert_eq!(line_text(&frame, 1), "next");
+        assert_eq!(line_text(&frame, 2), "    last");
+    }
+
+    #[test]
+    fn addresses_the_cursor_from_the_margins_in_origin_mode() {
+        let mut pane = TerminalPane::new(20, 6);
+        let mut modes = HashMap::new();
+        pane.feed(b"\x1b[2;4r\x1b[?69h\x1b[5;12s\x1b[?6h\x1b[1;1HA\x1b[9;30HB\x1b[1;2H");
+        let mut responder = QueryResponder::new(&mut modes, 20, 6);
+        pane.feed_answering(b"\x1b[6n\x1b[?6$p\x1b[?69$p\x1b[?7$p", &mut responder);
+        assert_eq!(
+            responder.into_response(),
+            "\x1b[1;2R\x1b[?6;1$y\x1b[?69;1$y\x1b[?7;1$y"
+        );
+        let frame = pane.frame();
+        assert_eq!(line_text(&frame, 1), "    A");
+        assert_eq!(line_text(&frame, 3), "           B");
+    }
+
+    #[test]
+    fn wraps_and_scrolls_within_left_and_right_margins() {
+        let mut pane = TerminalPane::new(20, 6);
+        pane.feed(b"11111111\r\n22222222\r\n33333333");
+        pane.feed(b"\x1b[?69h\x1b[3;6s\x1b[1;3H\x1b[M\x1b[3;3Habcdefg");
+        let frame = pane.frame();
+        assert_eq!(line_text(&frame, 0), "11222211");
+        assert_eq!(line_text(&frame, 1), "22333322");
+        assert_eq!(line_text(&frame, 2), "33abcd33");
+        assert_eq!(line_text(&frame, 3), "  efg");
+
+        pane.feed(b"\x1b[?69l\x1b[4;1Hwhole line again");
+        assert_eq!(line_text(&pane.frame(), 3), "whole line again");
+    }
+
+    #[test]
+    fn reverses_the_whole_screen() {
+        let frame = build_styled_frame("\x1b[?5h\x1b[31mred", 20, 6);
+        assert_eq!(frame["reverseVideo"], true);
+        assert_eq!(frame["lines"][0]["segments"][0]["bg"], "#cd3131");
+        let frame = build_styled_frame("\x1b[?5h\x1b[?5l", 20, 6);
+        assert!(frame.get("reverseVideo").is_none());
+    }
+
+    #[test]
+    fn serialized_state_restores_modes_and_margins() {
+        let mut pane = TerminalPane::new(20, 6);
+        pane.feed(b"\x1b[2;5r\x1b[?69h\x1b[3;8s\x1b[?6h\x1b[?5h\x1b[2;3Hxy\x1b[4h\x1b[20h");
+        pane.feed(b"\x1b[?1049h\x1b[?7l\x1b[3;1Halt screen text here");
+        let mut restored = TerminalPane::new(20, 6);
+        restored.feed(pane.serialize_state(0).as_bytes());
+        assert_eq!(restored.frame(), pane.frame());
+
+        for input in ["abc", "\x1b[1;1Hq\nr", "\x1b[?1049l", "AB\x1b[1;1Hz\x1b[M"] {
+            pane.feed(input.as_bytes());
+            restored.feed(input.as_bytes());
+            assert_eq!(restored.frame(), pane.frame(), "after {input:?}");
+        }
+    }
+
+    #[test]
+    fn restores_style_charset_and_origin_with_the_saved_cursor() {
+        let frame = build_styled_frame(
+            "\x1b[31m\x1b(0\x1b[2;5r\x1b[?6h\x1b[2;3H\x1b7\x1b[0m\x1b(B\x1b[?6l\x1b[1;1Hplain\x1b8lqqk\x1b[1;1Hx",
+            20,
+            6,
+        );
+        assert_eq!(line_text(&frame, 0), "plain");
+        assert_eq!(line_text(&frame, 1), "\u{2502}");
+        assert_eq!(line_text(&frame, 2), "  \u{250c}\u{2500}\u{2500}\u{2510}");
+        assert_eq!(frame["lines"][2]["segments"][1]["fg"], "#cd3131");
+    }
+
+    #[test]
+    fn keeps_a_saved_cursor_per_screen() {
+        let mut pane = TerminalPane::new(20, 6);
+        pane.feed(b"\x1b[31m\x1b[2;2H\x1b7\x1b[0m\x1b[5;5H\x1b[?1049h");
+        pane.feed(b"\x1b[32m\x1b[3;3H\x1b7\x1b[0m\x1b[1;1H\x1b8A");
+        let frame = pane.frame();
+        assert_eq!(line_text(&frame, 2), "  A");
+        assert_eq!(frame["lines"][2]["segments"][1]["fg"], "#0dbc79");
+
+        pane.feed(b"\x1b[?1049l\x1b8B");
+        let frame = pane.frame();
+        assert_eq!(line_text(&frame, 1), " B");
+        assert_eq!(frame["lines"][1]["segments"][1]["fg"], "#cd3131");
+    }
+
+    #[test]
+    fn soft_reset_restores_modes_and_attributes_but_keeps_the_screen() {
+        let mut pane = TerminalPane::new(20, 6);
+        pane.feed(b"\x1b[1;31m\x1b(0\x1b[2;4r\x1b[?6h\x1b[4h\x1b[?25l\x1b[3;3Hq\x1b7");
+        pane.feed(b"\x1b[!pq\x1b[1;1Hx\x1b8y");
+        let frame = pane.frame();
+        assert_eq!(line_text(&frame, 0), "y");
+        assert_eq!(line_text(&frame, 3), "  \u{2500}q");
+        assert!(frame["lines"][3]["segments"][2].get("fg").is_none());
+        assert_eq!(frame["cursorVisible"], true);
+    }
+
+    #[test]
+    fn serialized_state_restores_saved_cursors_and_charsets() {
+        let mut pane = TerminalPane::new(20, 6);
+        pane.feed(b"\x1b[2;5r\x1b[?6h\x1b[33m\x1b)0\x0e\x1b[2;2H\x1b7");
+        pane.feed(b"\x1b[0m\x0f\x1b[?6l\x1b[6;1Hend\x1b[44m\x1b[?1049h");
+        pane.feed(b"\x1b[35m\x1b(0\x1b[3;3Hlq\x1b7\x1b[0m\x1b(B\x1b[1;1H");
+        let mut restored = TerminalPane::new(20, 6);
+        restored.feed(pane.serialize_state(0).as_bytes());
+        assert_eq!(restored.frame(), pane.frame());
+
+        for input in [
+            "\x1b8qk",
+            "ab",
+            "\x1b[?1049l",
+            "ab",
+            "\x1b8lqk\x1b[1;1Hx",
+            "\x0fab",
+        ] {
+            pane.feed(input.as_bytes());
+            restored.feed(input.as_bytes());
+            assert_eq!(restored.frame(), pane.frame(), "after {input:?}");
+        }
+    }
+
+    fn take_patch(pane: &mut TerminalPane, granularity: PatchGranularity) -> FramePatch {
+        let damage = pane.take_damage();
+        pane.render_patch(&damage, granularity)
+    }
+
+    #[test]
+    fn patches_only_damaged_rows_and_keep_up_with_the_screen() {
+        let mut pane = TerminalPane::new(20, 6);
+        let mut shown = pane.styled_frame();
+        assert!(pane.take_damage().is_empty());
+
+        let steps = [
+            "one\r\ntwo",
+            "\x1b[1;1Hx",
+            "\x1b[?25l",
+            "\x1b[2;3r\x1b[3;1H\nscrolled\x1b[r",
+            "\x1b[?1049halt\x1b[?1049l",
+            "\x1b[6;1H\r\n\r\nbottom",
+            "\x1b[3;2H\x1b[2K\x1b[1J",
+        ];
+        for step in steps {
+            pane.feed(step.as_bytes());
+            let patch = take_patch(&mut pane, PatchGranularity::Lines);
+            for changed in patch.changed_lines {
+                shown.lines[changed.row] = changed.line;
+            }
+            shown.cursor_row = patch.cursor_row;
+            shown.cursor_col = patch.cursor_col;
+            shown.cursor_visible = patch.cursor_visible;
+            assert_eq!(shown, pane.styled_frame(), "after {step:?}");
+        }
+
+        pane.feed(b"\x1b[1;1Hx");
+        let patch = take_patch(&mut pane, PatchGranularity::Lines);
+        let rows: Vec<_> = patch.changed_lines.iter().map(|line| line.row).collect();
+        assert_eq!(rows, vec![0]);
+        assert!(pane.take_damage().is_empty());
+    }
+
+    #[test]
+    fn patches_nothing_after_output_that_leaves_the_screen_alone() {
+        let mut pane = TerminalPane::new(20, 6);
+        let mut log = DamageLog::default();
+        pane.feed(b"hello");
+        log.record(1, pane.take_damage());
+
+        pane.feed(b"\x1b]0;title\x07\x1b[?25h");
+        log.record(2, pane.take_damage());
+        for since in [1, 2] {
+            let patch = pane
+                .patch_since(&log, since, PatchGranularity::Spans)
+                .expect("log reaches back");
+            assert!(patch.changed_lines.is_empty(), "since {since}");
+            assert!(patch.changed_spans.is_empty(), "since {since}");
+            assert_eq!((patch.cursor_row, patch.cursor_col), (0, 5));
+        }
+    }
+
+    #[test]
+    fn patches_a_few_changed_columns_as_spans() {
+        let mut pane = TerminalPane::new(40, 6);
+        pane.feed(b"Indexing the project, please wait [-]\r\nshort");
+        pane.take_damage();
+
+        pane.feed(b"\x1b[1;36H\\\x1b[2;1Hshirt");
+        let patch = take_patch(&mut pane, PatchGranularity::Spans);
+        assert_eq!(patch.changed_spans.len(), 1);
+        let span = &patch.changed_spans[0];
+        assert_eq!((span.row, span.start_col), (0, 35));
+        assert_eq!(span.segments[0].text, "\\");
+        // Rewriting most of a short row is sent as the whole line.
+        assert_eq!(patch.changed_lines.len(), 1);
+        assert_eq!(patch.changed_lines[0].row, 1);
+        assert_eq!(patch.changed_lines[0].line.segments[0].text, "shirt");
+
+        pane.feed(b"\x1b[1;8H\x1b[K");
+        let patch = take_patch(&mut pane, PatchGranularity::Spans);
+        let value = serde_json::to_value(&patch).expect("patch JSON");
+        assert_eq!(
+            value["changedLines"][0]["line"]["segments"][0]["text"],
+            "Indexin"
+        );
+        assert!(value.get("changedSpans").is_none());
+    }
+
+    #[test]
+    fn reflows_history_at_the_viewport_width() {
+        let output = format!("{}\r\nshort\r\n\r\n\r\n\r\n\r\n\r\nend", "x".repeat(30));
+        let narrow = render_viewport(&output, 20, 6, AmbiguousWidth::Narrow, 3).to_value();
+        assert_eq!(line_text(&narrow, 0), "x".repeat(20));
+        assert_eq!(line_text(&narrow, 1), "x".repeat(10));
+        assert_eq!(line_text(&narrow, 2), "short");
+
+        let wide = render_viewport(&output, 40, 6, AmbiguousWidth::Narrow, 3).to_value();
+        assert_eq!(line_text(&wide, 0), "x".repeat(30));
+        assert_eq!(line_text(&wide, 1), "short");
+        assert_eq!(wide["viewportOffset"], 2);
+    }
+
+    #[test]
+    fn keeps_no_history_for_the_alternate_screen_or_after_erasing_it() {
+        let output = "1\r\n2\r\n3\r\n4\r\n5\r\n6\r\n7\r\n8";
+        let mut pane = TerminalPane::new(20, 6);
+        pane.keep_history(10);
+        pane.feed(output.as_bytes());
+        pane.feed(b"\x1b[?1049h\r\n\r\n\r\n\r\n\r\n\r\nalt");
+        let alt = pane.viewport_frame(5).to_value();
+        assert_eq!(alt["viewportOffset"], 0);
+        assert_eq!(line_text(&alt, 0), "");
+
+        pane.feed(b"\x1b[?1049l");
+        let primary = pane.viewport_frame(5).to_value();
+        assert_eq!(primary["viewportOffset"], 2);
+        assert_eq!(line_text(&primary, 0), "1");
+
+        pane.feed(b"\x1b[3J");
+        assert_eq!(pane.viewport_frame(5).to_value()["viewportOffset"], 0);
+    }
 }
@@ -0,0 +1,540 @@
+// Longest parameter list kept for one sequence; later parameters are dropped.
+const MAX_PARAMS: usize = 32;
+const MAX_INTERMEDIATES: usize = 2;
+// Longest OSC or APC body kept for dispatch; the rest of a longer body is
+// skipped.
+const MAX_STRING_BYTES: usize = 4096;
+
+/// States of the DEC/VT500 escape sequence parser.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    CsiEntry,
+    CsiParam,
+    CsiIntermediate,
+    CsiIgnore,
+    DcsEntry,
+    DcsParam,
+    DcsIntermediate,
+    DcsPassthrough,
+    DcsIgnore,
+    OscString,
+    SosPmApcString,
+}
+
+/// Numeric parameters of a control sequence. `;` and `:` both separate
+/// parameters; an empty parameter is reported as missing.
+#[derive(Clone, Copy, Debug)]
+pub struct Params {
+    values: [Option<u16>; MAX_PARAMS],
+    len: usize,
+    full: bool,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            values: [None; MAX_PARAMS],
+            len: 0,
+            full: false,
+        }
+    }
+}
+
+impl Params {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, index: usize) -> Option<u16> {
+        self.values[..self.len].get(index).copied().flatten()
+    }
+
+    /// The parameter at `index`, or `default` when it is missing.
+    pub fn or(&self, index: usize, default: u16) -> u16 {
+        self.get(index).unwrap_or(default)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Option<u16>> + '_ {
+        self.values[..self.len].iter().copied()
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+        self.values[0] = None;
+        self.full = false;
+    }
+
+    fn push_digit(&mut self, digit: u8) {
+        if self.full {
+            return;
+        }
+        if self.len == 0 {
+            self.len = 1;
+        }
+        if let Some(value) = self.values.get_mut(self.len - 1) {
+            let current = value.unwrap_or(0);
+            *value = Some(
+                current
+                    .saturating_mul(10)
+                    .saturating_add(u16::from(digit - b'0')),
+            );
+        }
+    }
+
+    fn next_param(&mut self) {
+        if self.len == 0 {
+            self.len = 1;
+        }
+        if self.len == MAX_PARAMS {
+            self.full = true;
+            return;
+        }
+        self.values[self.len] = None;
+        self.len += 1;
+    }
+
+    // A sequence without parameters still has one missing parameter.
+    fn finish(&mut self) {
+        if self.len == 0 {
+            self.len = 1;
+            self.values[0] = None;
+        }
+    }
+}
+
+/// A complete CSI sequence, or the header of a DCS string.
+pub struct Csi<'a> {
+    /// Private marker (`?`, `>`, `<` or `=`) right after the introducer.
+    pub private: Option<u8>,
+    pub params: &'a Params,
+    pub intermediates: &'a [u8],
+    pub final_byte: u8,
+}
+
+/// Receives the actions of a `Parser`. Every action defaults to doing
+/// nothing, so a performer only implements what it reacts to.
+pub trait Perform {
+    fn print(&mut self, _ch: char) {}
+    /// A C0 control character other than ESC, CAN and SUB.
+    fn execute(&mut self, _byte: u8) {}
+    fn csi_dispatch(&mut self, _csi: &Csi<'_>) {}
+    fn esc_dispatch(&mut self, _intermediates: &[u8], _final_byte: u8) {}
+    fn osc_dispatch(&mut self, _body: &[u8]) {}
+    fn apc_dispatch(&mut self, _body: &[u8]) {}
+    /// Start of a DCS string; its data follows through `put`, then `unhook`.
+    fn hook(&mut self, _header: &Csi<'_>) {}
+    fn put(&mut self, _byte: u8) {}
+    fn unhook(&mut self) {}
+}
+
+/// Byte-level parser following the DEC/VT500 state machine. It keeps its
+/// state between calls, so a sequence may be split across any number of
+/// inputs, and it does not allocate after the first OSC or APC string.
+#[derive(Default)]
+pub struct Parser {
+    state: State,
+    params: Params,
+    private: Option<u8>,
+    intermediates: [u8; MAX_INTERMEDIATES],
+    intermediate_len: usize,
+    string: Vec<u8>,
+    // Bytes of the sequence in progress, counted from its ESC.
+    pending: usize,
+    utf8: [u8; 4],
+    utf8_len: usize,
+    utf8_needed: usize,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Length of the unfinished escape sequence at the end of the input so
+    /// far, or 0 between sequences.
+    pub fn pending_len(&self) -> usize {
+        if self.state == State::Ground {
+            0
+        } else {
+            self.pending
+        }
+    }
+
+    pub fn advance<P: Perform>(&mut self, performer: &mut P, bytes: &[u8]) {
+        for &byte in bytes {
+            self.pending += 1;
+            self.step(performer, byte);
+        }
+    }
+
+    fn step<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        if self.state == State::Ground {
+            self.ground(performer, byte);
+            return;
+        }
+
+        // Transitions that apply in every state but ground.
+        match byte {
+            0x18 | 0x1a => {
+                self.end_string(performer);
+                performer.execute(byte);
+                self.state = State::Ground;
+                return;
+            }
+            0x1b => {
+                self.end_string(performer);
+                self.enter_escape();
+                return;
+            }
+            _ => {}
+        }
+
+        match self.state {
+            State::Ground => {}
+            State::Escape => match byte {
+                0x00..=0x1f => performer.execute(byte),
+                0x20..=0x2f => {
+                    self.collect(byte);
+                    self.state = State::EscapeIntermediate;
+                }
+                b'[' => self.enter_sequence(State::CsiEntry),
+                b']' => self.enter_string(State::OscString),
+                b'P' => self.enter_sequence(State::DcsEntry),
+                b'X' | b'^' | b'_' => {
+                    self.enter_string(State::SosPmApcString);
+                    self.private = Some(byte);
+                }
+                0x30..=0x7e => self.esc_dispatch(performer, byte),
+                _ => {}
+            },
+            State::EscapeIntermediate => match byte {
+                0x00..=0x1f => performer.execute(byte),
+                0x20..=0x2f => self.collect(byte),
+                0x30..=0x7e => self.esc_dispatch(performer, byte),
+                _ => {}
+            },
+            State::CsiEntry | State::CsiParam | State::CsiIntermediate | State::CsiIgnore => {
+                self.csi(performer, byte)
+            }
+            State::DcsEntry | State::DcsParam | State::DcsIntermediate | State::DcsIgnore => {
+                self.dcs_header(performer, byte)
+            }
+            State::DcsPassthrough => {
+                if byte != 0x7f {
+                    performer.put(byte);
+                }
+            }
+            State::OscString => match byte {
+                0x07 => {
+                    performer.osc_dispatch(&self.string);
+                    self.state = State::Ground;
+                }
+                0x00..=0x1f => {}
+                _ => self.push_string(byte),
+            },
+            State::SosPmApcString => self.push_string(byte),
+        }
+    }
+
+    fn ground<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        if self.utf8_needed > 0 {
+            if byte & 0xc0 == 0x80 {
+                self.utf8[self.utf8_len] = byte;
+                self.utf8_len += 1;
+                if self.utf8_len == self.utf8_needed {
+                    let ch = std::str::from_utf8(&self.utf8[..self.utf8_len])
+                        .ok()
+                        .and_then(|text| text.chars().next())
+                        .unwrap_or(char::REPLACEMENT_CHARACTER);
+                    self.utf8_needed = 0;
+                    performer.print(ch);
+                }
+                return;
+            }
+            self.utf8_needed = 0;
+            performer.print(char::REPLACEMENT_CHARACTER);
+        }
+
+        match byte {
+            0x1b => self.enter_escape(),
+            0x00..=0x1f => performer.execute(byte),
+            0x20..=0x7e => performer.print(char::from(byte)),
+            0x7f => {}
+            0xc2..=0xf4 => {
+                self.utf8[0] = byte;
+                self.utf8_len = 1;
+                self.utf8_needed = match byte {
+                    0xc2..=0xdf => 2,
+                    0xe0..=0xef => 3,
+                    _ => 4,
+                };
+            }
+            _ => performer.print(char::REPLACEMENT_CHARACTER),
+        }
+    }
+
+    fn csi<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        match (self.state, byte) {
+            (_, 0x00..=0x1f) => performer.execute(byte),
+            (State::CsiIgnore, 0x40..=0x7e) => self.state = State::Ground,
+            (State::CsiIgnore, _) => {}
+            (State::CsiEntry, 0x3c..=0x3f) => {
+                self.private = Some(byte);
+                self.state = State::CsiParam;
+            }
+            (State::CsiEntry | State::CsiParam, b'0'..=b'9') => {
+                self.params.push_digit(byte);
+                self.state = State::CsiParam;
+            }
+            (State::CsiEntry | State::CsiParam, b':' | b';') => {
+                self.params.next_param();
+                self.state = State::CsiParam;
+            }
+            (State::CsiParam | State::CsiIntermediate, 0x30..=0x3f) => {
+                self.state = State::CsiIgnore;
+            }
+            (_, 0x20..=0x2f) => {
+                self.collect(byte);
+                self.state = State::CsiIntermediate;
+            }
+            (_, 0x40..=0x7e) => {
+                self.params.finish();
+                performer.csi_dispatch(&Csi {
+                    private: self.private,
+                    params: &self.params,
+                    intermediates: &self.intermediates[..self.intermediate_len],
+                    final_byte: byte,
+                });
+                self.state = State::Ground;
+            }
+            _ => {}
+        }
+    }
+
+    fn dcs_header<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        match (self.state, byte) {
+            (_, 0x00..=0x1f) | (_, 0x7f) => {}
+            (State::DcsIgnore, _) => {}
+            (State::DcsEntry, 0x3c..=0x3f) => {
+                self.private = Some(byte);
+                self.state = State::DcsParam;
+            }
+            (State::DcsEntry | State::DcsParam, b'0'..=b'9') => {
+                self.params.push_digit(byte);
+                self.state = State::DcsParam;
+            }
+            (State::DcsEntry | State::DcsParam, b':' | b';') => {
+                self.params.next_param();
+                self.state = State::DcsParam;
+            }
+            (State::DcsParam | State::DcsIntermediate, 0x30..=0x3f) => {
+                self.state = State::DcsIgnore;
+            }
+            (_, 0x20..=0x2f) => {
+                self.collect(byte);
+                self.state = State::DcsIntermediate;
+            }
+            (_, 0x40..=0x7e) => {
+                self.params.finish();
+                performer.hook(&Csi {
+                    private: self.private,
+                    params: &self.params,
+                    intermediates: &self.intermediates[..self.intermediate_len],
+                    final_byte: byte,
+                });
+                self.state = State::DcsPassthrough;
+            }
+            _ => {}
+        }
+    }
+
+    fn enter_escape(&mut self) {
+        self.state = State::Escape;
+        self.pending = 1;
+        self.intermediate_len = 0;
+    }
+
+    fn enter_sequence(&mut self, state: State) {
+        self.state = state;
+        self.params.clear();
+        self.private = None;
+        self.intermediate_len = 0;
+    }
+
+    fn enter_string(&mut self, state: State) {
+        self.state = state;
+        self.private = None;
+        self.string.clear();
+    }
+
+    fn collect(&mut self, byte: u8) {
+        if self.intermediate_len < MAX_INTERMEDIATES {
+            self.intermediates[self.intermediate_len] = byte;
+            self.intermediate_len += 1;
+        }
+    }
+
+    fn push_string(&mut self, byte: u8) {
+        if self.string.len() < MAX_STRING_BYTES {
+            self.string.push(byte);
+        }
+    }
+
+    fn esc_dispatch<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        performer.esc_dispatch(&self.intermediates[..self.intermediate_len], byte);
+        self.state = State::Ground;
+    }
+
+    // Strings end at ESC (the start of ST), CAN or SUB.
+    fn end_string<P: Perform>(&mut self, performer: &mut P) {
+        match self.state {
+            State::OscString => performer.osc_dispatch(&self.string),
+            State::SosPmApcString if self.private == Some(b'_') => {
+                performer.apc_dispatch(&self.string)
+            }
+            State::DcsPassthrough => performer.unhook(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Log(Vec<String>);
+
+    impl Perform for Log {
+        fn print(&mut self, ch: char) {
+            self.0.push(format!("print {ch}"));
+        }
+
+        fn execute(&mut self, byte: u8) {
+            self.0.push(format!("execute {byte:02x}"));
+        }
+
+        fn csi_dispatch(&mut self, csi: &Csi<'_>) {
+            let params = csi
+                .params
+                .iter()
+                .map(|param| param.map(|value| value.to_string()).unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(";");
+            self.0.push(format!(
+                "csi {}{params}{}{}",
+                csi.private
+                    .map(|marker| char::from(marker).to_string())
+                    .unwrap_or_default(),
+                String::from_utf8_lossy(csi.intermediates),
+                char::from(csi.final_byte)
+            ));
+        }
+
+        fn esc_dispatch(&mut self, intermediates: &[u8], final_byte: u8) {
+            self.0.push(format!(
+                "esc {}{}",
+                String::from_utf8_lossy(intermediates),
+                char::from(final_byte)
+            ));
+        }
+
+        fn osc_dispatch(&mut self, body: &[u8]) {
+            self.0
+                .push(format!("osc {}", String::from_utf8_lossy(body)));
+        }
+
+        fn apc_dispatch(&mut self, body: &[u8]) {
+            self.0
+                .push(format!("apc {}", String::from_utf8_lossy(body)));
+        }
+
+        fn hook(&mut self, header: &Csi<'_>) {
+            self.0
+                .push(format!("hook {}", char::from(header.final_byte)));
+        }
+
+        fn put(&mut self, byte: u8) {
+            self.0.push(format!("put {}", char::from(byte)));
+        }
+
+        fn unhook(&mut self) {
+            self.0.push("unhook".to_string());
+        }
+    }
+
+    fn parse_in_pieces(input: &[u8]) -> Vec<String> {
+        let mut whole = Log::default();
+        Parser::new().advance(&mut whole, input);
+        for split in 0..=input.len() {
+            let mut log = Log::default();
+            let mut parser = Parser::new();
+            parser.advance(&mut log, &input[..split]);
+            parser.advance(&mut log, &input[split..]);
+            assert_eq!(log.0, whole.0, "split at {split}");
+        }
+        whole.0
+    }
+
+    #[test]
+    fn dispatches_sequences_identically_wherever_the_input_is_split() {
+        let actions = parse_in_pieces(
+            "a\x1b[?25;1049h\x1b[38:5:1m\x1b[?2004$p\x1b(B\x1b]10;?\x07\x1b_a=q\x1b\\\x1bPq#x\x1b\\é\r\n"
+                .as_bytes(),
+        );
+        assert_eq!(
+            actions,
+            [
+                "print a",
+                "csi ?25;1049h",
+                "csi 38;5;1m",
+                "csi ?2004$p",
+                "esc (B",
+                "osc 10;?",
+                "apc a=q",
+                "esc \\",
+                "hook q",
+                "put #",
+                "put x",
+                "unhook",
+                "esc \\",
+                "print é",
+                "execute 0d",
+                "execute 0a",
+            ]
+        );
+    }
+
+    #[test]
+    fn executes_controls_inside_sequences_and_recovers_from_bad_input() {
+        let actions = parse_in_pieces(b"\x1b[1\n;2H\x1b[1?2Jx\x1b[3\x18y\xff\x1b[m");
+        assert_eq!(
+            actions,
+            [
+                "execute 0a",
+                "csi 1;2H",
+                "print x",
+                "execute 18",
+                "print y",
+                "print \u{fffd}",
+                "csi m",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_the_unfinished_sequence_length() {
+        let mut parser = Parser::new();
+        let mut log = Log::default();
+        parser.advance(&mut log, b"text\x1b]0;title");
+        assert_eq!(parser.pending_len(), "\x1b]0;title".len());
+        parser.advance(&mut log, b"\x07more");
+        assert_eq!(parser.pending_len(), 0);
+        parser.advance(&mut log, b"\x1b[1;");
+        assert_eq!(parser.pending_len(), 4);
+    }
+}
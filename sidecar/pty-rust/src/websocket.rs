@@ -0,0 +1,550 @@
+use crate::transport::{
+    MessageChannel, MessageWriter, SharedWriter, WireEncoding, MAX_MESSAGE_BYTES,
+};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_BYTES: usize = 16 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Server side of an RFC 6455 connection carrying one JSON request or
+/// response per text message, or one binary message once a binary encoding
+/// has been negotiated.
+pub struct WebSocketChannel<S> {
+    reader: BufReader<S>,
+    frames: Arc<Mutex<FrameWriter<S>>>,
+    /// `frames` again, as handed out to reply writers.
+    writer: SharedWriter,
+    /// Upper bound for one (possibly fragmented) request message.
+    limit: usize,
+}
+
+struct FrameWriter<S> {
+    stream: S,
+    encoding: WireEncoding,
+}
+
+impl<S: Read + Write + Send + 'static> WebSocketChannel<S> {
+    /// Reads the HTTP upgrade request from `reader` and answers it on
+    /// `writer` (two handles to the same stream). Browsers always send an
+    /// `Origin`; a request carrying one is refused unless it is listed in
+    /// `allowed_origins`, so a web page cannot reach the sidecar.
+    pub fn accept(reader: S, mut writer: S, allowed_origins: &[String]) -> Result<Self, String> {
+        let mut reader = BufReader::new(reader);
+        let upgrade = match read_upgrade_request(&mut reader) {
+            Ok(upgrade) => upgrade,
+            Err(err) => {
+                let _ = writer.write_all(
+                    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+                );
+                return Err(err);
+            }
+        };
+        if let Some(origin) = upgrade.origin {
+            if !allowed_origins.contains(&origin) {
+                let _ = writer.write_all(
+                    b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+                );
+                return Err(format!("websocket origin not allowed: {origin}"));
+            }
+        }
+        let key = upgrade.key;
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key)
+        );
+        writer
+            .write_all(response.as_bytes())
+            .map_err(|e| format!("write websocket handshake: {e}"))?;
+        let frames = Arc::new(Mutex::new(FrameWriter {
+            stream: writer,
+            encoding: WireEncoding::Json,
+        }));
+        Ok(Self {
+            reader,
+            writer: frames.clone(),
+            frames,
+            limit: MAX_MESSAGE_BYTES,
+        })
+    }
+
+    /// Next frame, or `None` if the peer closed the stream between frames.
+    fn read_frame(&mut self) -> Result<Option<Frame>, String> {
+        let mut head = [0u8; 2];
+        match self.reader.read_exact(&mut head) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(format!("read websocket frame: {err}")),
+        }
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        if head[0] & 0x70 != 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "reserved bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "client frames must be masked"));
+        }
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut ext = [0u8; 2];
+                self.read_bytes(&mut ext)?;
+                u64::from(u16::from_be_bytes(ext))
+            }
+            127 => {
+                let mut ext = [0u8; 8];
+                self.read_bytes(&mut ext)?;
+                u64::from_be_bytes(ext)
+            }
+            short => u64::from(short),
+        };
+        if len > self.limit as u64 {
+            return Err(self.fail(CLOSE_TOO_BIG, "message too large"));
+        }
+        let mut mask = [0u8; 4];
+        self.read_bytes(&mut mask)?;
+        let mut payload = vec![0u8; len as usize];
+        self.read_bytes(&mut payload)?;
+        for (idx, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[idx % 4];
+        }
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        self.reader
+            .read_exact(buf)
+            .map_err(|e| format!("read websocket frame: {e}"))
+    }
+
+    fn frames(&self) -> MutexGuard<'_, FrameWriter<S>> {
+        self.frames
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Sends a close frame with `code` and returns `reason` as the error.
+    fn fail(&mut self, code: u16, reason: &str) -> String {
+        let _ = self.frames().write_frame(OPCODE_CLOSE, &code.to_be_bytes());
+        format!("websocket: {reason}")
+    }
+}
+
+impl<S: Write> FrameWriter<S> {
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), String> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= usize::from(u16::MAX) => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.stream
+            .write_all(&frame)
+            .map_err(|e| format!("write websocket frame: {e}"))
+    }
+
+    fn data_opcode(&self) -> u8 {
+        if self.encoding.is_binary() {
+            OPCODE_BINARY
+        } else {
+            OPCODE_TEXT
+        }
+    }
+}
+
+impl<S: Write + Send> MessageWriter for FrameWriter<S> {
+    fn write_payload(&mut self, payload: &[u8]) -> Result<(), String> {
+        self.write_frame(self.data_opcode(), payload)
+    }
+
+    fn encoding(&self) -> WireEncoding {
+        self.encoding
+    }
+
+    fn set_encoding(&mut self, encoding: WireEncoding) {
+        self.encoding = encoding;
+    }
+}
+
+impl<S: Read + Write + Send + 'static> MessageChannel for WebSocketChannel<S> {
+    fn read_payload(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let data_opcode = self.frames().data_opcode();
+        let mut message = Vec::new();
+        let mut in_message = false;
+        loop {
+            let Some(frame) = self.read_frame()? else {
+                if in_message {
+                    return Err("websocket: connection closed mid-message".to_string());
+                }
+                return Ok(None);
+            };
+            match frame.opcode {
+                OPCODE_PING => self.frames().write_frame(OPCODE_PONG, &frame.payload)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    let _ = self
+                        .frames()
+                        .write_frame(OPCODE_CLOSE, &frame.payload[..frame.payload.len().min(2)]);
+                    return Ok(None);
+                }
+                OPCODE_TEXT | OPCODE_BINARY if frame.opcode != data_opcode => {
+                    let reason = format!(
+                        "{} messages are not accepted with {} encoding",
+                        if frame.opcode == OPCODE_TEXT {
+                            "text"
+                        } else {
+                            "binary"
+                        },
+                        self.encoding().name()
+                    );
+                    return Err(self.fail(CLOSE_UNSUPPORTED_DATA, &reason));
+                }
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    if (frame.opcode == OPCODE_CONTINUATION) != in_message {
+                        return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unexpected fragment"));
+                    }
+                    in_message = true;
+                    if message.len() + frame.payload.len() > self.limit {
+                        return Err(self.fail(CLOSE_TOO_BIG, "message too large"));
+                    }
+                    message.extend_from_slice(&frame.payload);
+                    if !frame.fin {
+                        continue;
+                    }
+                    if data_opcode == OPCODE_TEXT && std::str::from_utf8(&message).is_err() {
+                        return Err(self.fail(CLOSE_PROTOCOL_ERROR, "text message is not UTF-8"));
+                    }
+                    return Ok(Some(message));
+                }
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unknown opcode")),
+            }
+        }
+    }
+
+    fn writer(&self) -> &SharedWriter {
+        &self.writer
+    }
+
+    fn set_message_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+struct UpgradeRequest {
+    key: String,
+    origin: Option<String>,
+}
+
+/// Validates the upgrade request and returns its `Sec-WebSocket-Key` and
+/// `Origin`.
+fn read_upgrade_request<R: BufRead>(reader: &mut R) -> Result<UpgradeRequest, String> {
+    let mut total = 0usize;
+    let mut request_line = None;
+    let mut key = None;
+    let mut origin = None;
+    let mut upgrade = false;
+    let mut connection_upgrade = false;
+    let mut version_ok = false;
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .map_err(|e| format!("read websocket handshake: {e}"))?;
+        total += read;
+        if read == 0 || total > MAX_HANDSHAKE_BYTES {
+            return Err("incomplete websocket handshake".to_string());
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        if request_line.is_none() {
+            request_line = Some(line.to_string());
+            continue;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "connection" => {
+                connection_upgrade = value
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+            }
+            "sec-websocket-key" => key = Some(value.to_string()),
+            "origin" => origin = Some(value.to_string()),
+            "sec-websocket-version" => version_ok = value == "13",
+            _ => {}
+        }
+    }
+
+    let request_line = request_line.unwrap_or_default();
+    if !request_line.starts_with("GET ") {
+        return Err(format!("unexpected websocket request: {request_line}"));
+    }
+    if !upgrade || !connection_upgrade || !version_ok {
+        return Err("not a websocket version 13 upgrade request".to_string());
+    }
+    let key = key.ok_or_else(|| "missing Sec-WebSocket-Key".to_string())?;
+    Ok(UpgradeRequest { key, origin })
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = sha1(format!("{key}{HANDSHAKE_GUID}").as_bytes());
+    base64_encode(&digest)
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (idx, word) in block.chunks_exact(4).enumerate() {
+            w[idx] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for idx in 16..80 {
+            w[idx] = (w[idx - 3] ^ w[idx - 8] ^ w[idx - 14] ^ w[idx - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (idx, word) in w.iter().enumerate() {
+            let (f, k) = match idx {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let next = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = next;
+        }
+        for (slot, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *slot = slot.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, word) in digest.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let triple = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                out.push(ALPHABET[((triple >> (18 - idx * 6)) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Cursor;
+
+    /// In-memory duplex stream: reads from `input`, collects writes.
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn masked_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xFA, 0x21, 0x3D];
+        let mut frame = vec![first, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn computes_rfc6455_accept_key() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(base64_encode(b"ab"), "YWI=");
+        assert_eq!(base64_encode(b"a"), "YQ==");
+    }
+
+    #[test]
+    fn reads_fragmented_text_answers_ping_and_switches_to_binary() {
+        let mut input = b"GET /rpc HTTP/1.1\r\nHost: 127.0.0.1\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n".to_vec();
+        input.extend(masked_frame(0x01, b"{\"method\":"));
+        input.extend(masked_frame(0x89, b"hi"));
+        input.extend(masked_frame(0x80, b"\"hello\"}"));
+        let packed = rmp_serde::to_vec_named(&json!({ "method": "health" })).expect("pack");
+        input.extend(masked_frame(0x82, &packed));
+        input.extend(masked_frame(0x88, &1000u16.to_be_bytes()));
+        let reader = Duplex {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        let writer = Duplex {
+            input: Cursor::new(Vec::new()),
+            output: Vec::new(),
+        };
+
+        let mut channel = WebSocketChannel::accept(reader, writer, &[]).expect("handshake");
+        let handshake = String::from_utf8(channel.frames().stream.output.clone()).expect("utf8");
+        assert!(handshake.starts_with("HTTP/1.1 101"));
+        assert!(handshake.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        channel.frames().stream.output.clear();
+
+        assert_eq!(
+            channel.read_message(),
+            Ok(Some(Ok(json!({ "method": "hello" }))))
+        );
+        assert_eq!(channel.frames().stream.output, vec![0x8A, 2, b'h', b'i']);
+        channel.frames().stream.output.clear();
+
+        channel.send(&json!({ "ok": true })).expect("write");
+        assert_eq!(channel.frames().stream.output[..2], [0x81, 11]);
+        channel.frames().stream.output.clear();
+
+        channel.set_encoding(WireEncoding::MessagePack);
+        assert_eq!(
+            channel.read_message(),
+            Ok(Some(Ok(json!({ "method": "health" }))))
+        );
+        channel.send(&json!({ "ok": true })).expect("write binary");
+        assert_eq!(channel.frames().stream.output[0], 0x82);
+        channel.frames().stream.output.clear();
+
+        assert_eq!(channel.read_message(), Ok(None));
+        assert_eq!(channel.frames().stream.output, vec![0x88, 2, 0x03, 0xE8]);
+    }
+
+    #[test]
+    fn rejects_plain_http_request() {
+        let reader = Duplex {
+            input: Cursor::new(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n".to_vec()),
+            output: Vec::new(),
+        };
+        let writer = Duplex {
+            input: Cursor::new(Vec::new()),
+            output: Vec::new(),
+        };
+        assert!(WebSocketChannel::accept(reader, writer, &[]).is_err());
+    }
+
+    fn upgrade_from(origin: &str) -> (Duplex, Duplex) {
+        let request = format!("GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nOrigin: {origin}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n");
+        let reader = Duplex {
+            input: Cursor::new(request.into_bytes()),
+            output: Vec::new(),
+        };
+        let writer = Duplex {
+            input: Cursor::new(Vec::new()),
+            output: Vec::new(),
+        };
+        (reader, writer)
+    }
+
+    #[test]
+    fn refuses_browser_origins_outside_the_allowlist() {
+        let (reader, writer) = upgrade_from("https://evil.example");
+        let err = WebSocketChannel::accept(reader, writer, &[])
+            .err()
+            .expect("unlisted origin is refused");
+        assert!(err.contains("https://evil.example"), "{err}");
+
+        let allowed = ["http://localhost:3000".to_string()];
+        let (reader, writer) = upgrade_from("http://localhost:3000");
+        assert!(WebSocketChannel::accept(reader, writer, &allowed).is_ok());
+    }
+
+    #[test]
+    fn closes_on_messages_past_the_limit() {
+        let mut input = upgrade_from("http://localhost:3000").0.input.into_inner();
+        input.extend(masked_frame(0x81, &[b'x'; 40]));
+        let reader = Duplex {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        let writer = Duplex {
+            input: Cursor::new(Vec::new()),
+            output: Vec::new(),
+        };
+        let allowed = ["http://localhost:3000".to_string()];
+        let mut channel = WebSocketChannel::accept(reader, writer, &allowed).expect("handshake");
+        channel.frames().stream.output.clear();
+        channel.set_message_limit(32);
+
+        assert!(channel.read_payload().is_err());
+        assert_eq!(channel.frames().stream.output, vec![0x88, 2, 0x03, 0xF1]);
+    }
+}